@@ -0,0 +1,526 @@
+//! Strings as arrays of bits, as used by SETBIT, GETBIT, BITCOUNT, BITPOS,
+//! BITOP and BITFIELD.
+//! Bit 0 is the most significant bit of the first byte.
+
+/// What the ranges of BITCOUNT and BITPOS count in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Byte,
+    Bit,
+}
+
+/// How BITOP combines its sources
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+pub fn get_bit(bytes: &[u8], offset: usize) -> bool {
+    bytes
+        .get(offset / 8)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Sets the bit at `offset`, growing `bytes` with zeros if needed. Returns
+/// the previous value of the bit.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: usize, value: bool) -> bool {
+    let idx = offset / 8;
+    if idx >= bytes.len() {
+        bytes.resize(idx + 1, 0);
+    }
+
+    let mask = 0x80 >> (offset % 8);
+    let previous = bytes[idx] & mask != 0;
+    if value {
+        bytes[idx] |= mask;
+    } else {
+        bytes[idx] &= !mask;
+    }
+    previous
+}
+
+/// Turns `start` and `end`, which count from the end when negative, into an
+/// inclusive range of indexes below `len`. Like Redis, indexes out of range
+/// are clamped. Returns `None` if the range is empty.
+pub fn normalize_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let clamp = |idx: i64| if idx < 0 { (idx + len).max(0) } else { idx };
+    let (start, end) = (clamp(start), clamp(end).min(len - 1));
+    if len == 0 || start > end {
+        return None;
+    }
+    Some((start as usize, end as usize))
+}
+
+/// Counts the set bits between `start` and `end`, which are indexes of bytes
+/// or of bits depending on `unit`
+pub fn count(bytes: &[u8], start: i64, end: i64, unit: Unit) -> usize {
+    let len = match unit {
+        Unit::Byte => bytes.len(),
+        Unit::Bit => bytes.len() * 8,
+    };
+    let Some((start, end)) = normalize_range(start, end, len) else {
+        return 0;
+    };
+
+    match unit {
+        Unit::Byte => bytes[start..=end]
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum(),
+        Unit::Bit => {
+            let (first, last) = (start / 8, end / 8);
+            (first..=last)
+                .map(|idx| {
+                    // Leave out the bits of the first and last bytes that
+                    // are out of the range
+                    let mut byte = bytes[idx];
+                    if idx == first {
+                        byte &= 0xff >> (start % 8);
+                    }
+                    if idx == last {
+                        byte &= 0xff << (7 - end % 8);
+                    }
+                    byte.count_ones() as usize
+                })
+                .sum()
+        }
+    }
+}
+
+/// The offset of the first bit equal to `bit` between `start` and `end`,
+/// which are indexes of bytes or of bits depending on `unit`, or -1 if there's
+/// none. Like Redis, when looking for a clear bit without an `end`, the string
+/// is considered padded with zeros, and an empty string has a clear bit at 0.
+pub fn position(bytes: &[u8], bit: bool, start: i64, end: Option<i64>, unit: Unit) -> i64 {
+    if bytes.is_empty() {
+        return if bit { -1 } else { 0 };
+    }
+    let len = match unit {
+        Unit::Byte => bytes.len(),
+        Unit::Bit => bytes.len() * 8,
+    };
+    let Some((start, last)) = normalize_range(start, end.unwrap_or(-1), len) else {
+        return -1;
+    };
+    let (start, last) = match unit {
+        Unit::Byte => (start * 8, last * 8 + 7),
+        Unit::Bit => (start, last),
+    };
+
+    // Look at a byte at a time, for the bits of the byte that are in the
+    // range and equal to `bit`
+    let (first, last_byte) = (start / 8, last / 8);
+    for (idx, byte) in bytes.iter().enumerate().take(last_byte + 1).skip(first) {
+        let mut matching = if bit { *byte } else { !byte };
+        if idx == first {
+            matching &= 0xff >> (start % 8);
+        }
+        if idx == last_byte {
+            matching &= 0xff << (7 - last % 8);
+        }
+        if matching != 0 {
+            return (idx * 8 + matching.leading_zeros() as usize) as i64;
+        }
+    }
+
+    if !bit && end.is_none() {
+        (last + 1) as i64
+    } else {
+        -1
+    }
+}
+
+/// Combines `sources` byte by byte, the shorter ones being padded with zeros.
+/// NOT only takes one source.
+pub fn bitop(op: BitOp, sources: &[&[u8]]) -> Vec<u8> {
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    (0..len)
+        .map(|idx| {
+            let mut bytes = sources.iter().map(|s| s.get(idx).copied().unwrap_or(0));
+            match op {
+                BitOp::And => bytes.fold(0xff, |a, b| a & b),
+                BitOp::Or => bytes.fold(0, |a, b| a | b),
+                BitOp::Xor => bytes.fold(0, |a, b| a ^ b),
+                BitOp::Not => !bytes.next().unwrap_or(0),
+            }
+        })
+        .collect()
+}
+
+/// An integer type of BITFIELD, such as `i16` or `u8`. Like Redis, signed
+/// integers have up to 64 bits and unsigned ones up to 63, so that values fit
+/// in an `i64`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl IntType {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (signed, max_bits) = match s.chars().next()?.to_ascii_lowercase() {
+            'i' => (true, 64),
+            'u' => (false, 63),
+            _ => return None,
+        };
+        let bits = s[1..]
+            .parse()
+            .ok()
+            .filter(|bits| (1..=max_bits).contains(bits))?;
+        Some(Self { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+}
+
+/// What BITFIELD does when SET or INCRBY produce a value out of the range of
+/// the type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Keep the low bits, as integer arithmetic usually does
+    Wrap,
+    /// Use the minimum or maximum value instead
+    Sat,
+    /// Don't change the value, and reply with a null
+    Fail,
+}
+
+impl Overflow {
+    // The value to store for `value`, or None if it mustn't be stored
+    fn fit(&self, value: i128, ty: IntType) -> Option<i128> {
+        let (min, max) = (ty.min(), ty.max());
+        if (min..=max).contains(&value) {
+            return Some(value);
+        }
+        match self {
+            Overflow::Wrap => Some((value - min).rem_euclid(max - min + 1) + min),
+            Overflow::Sat => Some(value.clamp(min, max)),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitfieldAction {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+/// A GET, SET or INCRBY of BITFIELD, on the integer of type `ty` starting at
+/// the bit `offset`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BitfieldOp {
+    pub ty: IntType,
+    pub offset: usize,
+    pub action: BitfieldAction,
+    pub overflow: Overflow,
+}
+
+impl BitfieldOp {
+    pub fn is_write(&self) -> bool {
+        self.action != BitfieldAction::Get
+    }
+
+    /// The offset of the byte after the integer
+    pub fn end(&self) -> usize {
+        (self.offset + self.ty.bits as usize).div_ceil(8)
+    }
+
+    /// Runs the op, growing `bytes` with zeros if needed. Replies with the
+    /// value for GET, the previous value for SET and the new value for INCRBY,
+    /// or with None if the value overflowed with `Overflow::Fail`.
+    pub fn apply(&self, bytes: &mut Vec<u8>) -> Option<i64> {
+        let current = get_int(bytes, self.offset, self.ty);
+        match self.action {
+            BitfieldAction::Get => Some(current),
+            BitfieldAction::Set(value) => {
+                // Like Redis, values of unsigned types are taken as unsigned
+                let value = if self.ty.signed {
+                    value as i128
+                } else {
+                    value as u64 as i128
+                };
+                let value = self.overflow.fit(value, self.ty)?;
+                set_int(bytes, self.offset, self.ty, value as i64);
+                Some(current)
+            }
+            BitfieldAction::IncrBy(increment) => {
+                let value = self
+                    .overflow
+                    .fit(current as i128 + increment as i128, self.ty)?;
+                set_int(bytes, self.offset, self.ty, value as i64);
+                Some(value as i64)
+            }
+        }
+    }
+}
+
+/// The integer of type `ty` starting at the bit `offset`. Bits past the end
+/// are zeros.
+pub fn get_int(bytes: &[u8], offset: usize, ty: IntType) -> i64 {
+    let mut raw: u64 = 0;
+    for i in 0..ty.bits as usize {
+        raw = (raw << 1) | get_bit(bytes, offset + i) as u64;
+    }
+    // Extend the sign bit
+    if ty.signed && ty.bits < 64 && raw & (1 << (ty.bits - 1)) != 0 {
+        raw |= u64::MAX << ty.bits;
+    }
+    raw as i64
+}
+
+/// Stores the low bits of `value` as an integer of type `ty` starting at the
+/// bit `offset`, growing `bytes` with zeros if needed
+pub fn set_int(bytes: &mut Vec<u8>, offset: usize, ty: IntType, value: i64) {
+    let raw = value as u64;
+    for i in 0..ty.bits {
+        let bit = (raw >> (ty.bits - 1 - i)) & 1 != 0;
+        set_bit(bytes, offset + i as usize, bit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get_across_bytes() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 7, true));
+        assert_eq!(bytes, [0x01]);
+        assert!(!set_bit(&mut bytes, 8, true));
+        assert_eq!(bytes, [0x01, 0x80]);
+        assert!(set_bit(&mut bytes, 7, false));
+        assert_eq!(bytes, [0x00, 0x80]);
+
+        assert!(get_bit(&bytes, 8));
+        assert!(!get_bit(&bytes, 7));
+        assert!(!get_bit(&bytes, 100));
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_range(0, -1, 3), Some((0, 2)));
+        assert_eq!(normalize_range(-2, 10, 3), Some((1, 2)));
+        assert_eq!(normalize_range(-10, -10, 3), Some((0, 0)));
+        assert_eq!(normalize_range(2, 1, 3), None);
+        assert_eq!(normalize_range(0, -1, 0), None);
+    }
+
+    #[test]
+    fn count_bits() {
+        // 11111111 00001111 11110000
+        let bytes = [0xff, 0x0f, 0xf0];
+        assert_eq!(count(&bytes, 0, -1, Unit::Byte), 16);
+        assert_eq!(count(&bytes, 1, 1, Unit::Byte), 4);
+        assert_eq!(count(&bytes, -1, -1, Unit::Byte), 4);
+        // Ranges of bits across byte boundaries
+        assert_eq!(count(&bytes, 6, 13, Unit::Bit), 4);
+        assert_eq!(count(&bytes, 12, 19, Unit::Bit), 8);
+        assert_eq!(count(&bytes, 13, 13, Unit::Bit), 1);
+        assert_eq!(count(&bytes, 5, 3, Unit::Bit), 0);
+        assert_eq!(count(&[], 0, -1, Unit::Bit), 0);
+    }
+
+    #[test]
+    fn find_position() {
+        // 11111111 11110000 00000000
+        let bytes = [0xff, 0xf0, 0x00];
+        assert_eq!(position(&bytes, false, 0, None, Unit::Byte), 12);
+        assert_eq!(position(&bytes, true, 0, None, Unit::Byte), 0);
+        assert_eq!(position(&bytes, true, 1, None, Unit::Byte), 8);
+        assert_eq!(position(&bytes, true, 2, None, Unit::Byte), -1);
+        assert_eq!(position(&bytes, true, -1, Some(-1), Unit::Byte), -1);
+        // Ranges of bits across byte boundaries
+        assert_eq!(position(&bytes, true, 5, Some(6), Unit::Bit), 5);
+        assert_eq!(position(&bytes, false, 7, Some(13), Unit::Bit), 12);
+        assert_eq!(position(&bytes, true, 12, Some(23), Unit::Bit), -1);
+        // An empty range has no bit
+        assert_eq!(position(&bytes, false, 2, Some(1), Unit::Byte), -1);
+        // Within a single byte
+        assert_eq!(position(&[0b0010_0100], true, 3, Some(6), Unit::Bit), 5);
+        assert_eq!(position(&[0b0010_0100], true, 3, Some(4), Unit::Bit), -1);
+    }
+
+    #[test]
+    fn find_clear_bit_in_all_ones() {
+        let bytes = [0xff, 0xff];
+        // Past the end, unless the range has an end
+        assert_eq!(position(&bytes, false, 0, None, Unit::Byte), 16);
+        assert_eq!(position(&bytes, false, 8, None, Unit::Bit), 16);
+        assert_eq!(position(&bytes, false, 0, Some(-1), Unit::Byte), -1);
+        assert_eq!(position(&bytes, false, 0, Some(0), Unit::Byte), -1);
+    }
+
+    #[test]
+    fn find_in_empty_string() {
+        assert_eq!(position(&[], true, 0, None, Unit::Byte), -1);
+        assert_eq!(position(&[], false, 0, None, Unit::Byte), 0);
+    }
+
+    #[test]
+    fn combine() {
+        let a: &[u8] = &[0b1100_1100, 0xff];
+        let b: &[u8] = &[0b1010_1010];
+        assert_eq!(bitop(BitOp::And, &[a, b]), [0b1000_1000, 0x00]);
+        assert_eq!(bitop(BitOp::Or, &[a, b]), [0b1110_1110, 0xff]);
+        assert_eq!(bitop(BitOp::Xor, &[a, b]), [0b0110_0110, 0xff]);
+        assert_eq!(bitop(BitOp::Not, &[b]), [0b0101_0101]);
+        assert!(bitop(BitOp::Or, &[&[], &[]]).is_empty());
+    }
+
+    fn int(s: &str) -> IntType {
+        IntType::parse(s).unwrap()
+    }
+
+    fn incrby(ty: &str, increment: i64, overflow: Overflow) -> BitfieldOp {
+        BitfieldOp {
+            ty: int(ty),
+            offset: 0,
+            action: BitfieldAction::IncrBy(increment),
+            overflow,
+        }
+    }
+
+    #[test]
+    fn parse_int_types() {
+        assert_eq!(
+            int("i8"),
+            IntType {
+                signed: true,
+                bits: 8
+            }
+        );
+        assert_eq!(
+            int("U63"),
+            IntType {
+                signed: false,
+                bits: 63
+            }
+        );
+        assert_eq!(
+            int("i64"),
+            IntType {
+                signed: true,
+                bits: 64
+            }
+        );
+        for s in ["u64", "i65", "i0", "u", "x8", "i-1", ""] {
+            assert_eq!(IntType::parse(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn get_and_set_ints() {
+        // 10101010 11110000
+        let mut bytes = vec![0xaa, 0xf0];
+        assert_eq!(get_int(&bytes, 0, int("u8")), 0xaa);
+        assert_eq!(get_int(&bytes, 0, int("i8")), 0xaa - 256);
+        assert_eq!(get_int(&bytes, 4, int("u8")), 0xaf);
+        assert_eq!(get_int(&bytes, 12, int("u8")), 0);
+        assert_eq!(get_int(&bytes, 7, int("i2")), 1);
+        assert_eq!(get_int(&bytes, 8, int("i2")), -1);
+
+        set_int(&mut bytes, 4, int("u8"), 0x12);
+        assert_eq!(bytes, [0xa1, 0x20]);
+        // Growing with zeros
+        set_int(&mut bytes, 20, int("i4"), -1);
+        assert_eq!(bytes, [0xa1, 0x20, 0x0f]);
+    }
+
+    #[test]
+    fn ints_of_64_bits() {
+        let mut bytes = Vec::new();
+        set_int(&mut bytes, 3, int("i64"), i64::MIN);
+        assert_eq!(get_int(&bytes, 3, int("i64")), i64::MIN);
+        set_int(&mut bytes, 0, int("u63"), i64::MAX);
+        assert_eq!(get_int(&bytes, 0, int("u63")), i64::MAX);
+    }
+
+    #[test]
+    fn incrby_wraps() {
+        let mut bytes = vec![0xfe];
+        assert_eq!(incrby("u8", 3, Overflow::Wrap).apply(&mut bytes), Some(1));
+        assert_eq!(
+            incrby("u8", -2, Overflow::Wrap).apply(&mut bytes),
+            Some(255)
+        );
+        assert_eq!(incrby("i8", 1, Overflow::Wrap).apply(&mut bytes), Some(0));
+        assert_eq!(
+            incrby("i8", 128, Overflow::Wrap).apply(&mut bytes),
+            Some(-128)
+        );
+        assert_eq!(
+            incrby("i8", -1, Overflow::Wrap).apply(&mut bytes),
+            Some(127)
+        );
+    }
+
+    #[test]
+    fn incrby_saturates() {
+        let mut bytes = vec![0x00];
+        assert_eq!(incrby("u4", -1, Overflow::Sat).apply(&mut bytes), Some(0));
+        assert_eq!(incrby("u4", 100, Overflow::Sat).apply(&mut bytes), Some(15));
+        assert_eq!(incrby("i4", 100, Overflow::Sat).apply(&mut bytes), Some(7));
+        assert_eq!(
+            incrby("i4", -100, Overflow::Sat).apply(&mut bytes),
+            Some(-8)
+        );
+        assert_eq!(
+            incrby("i64", i64::MIN, Overflow::Sat).apply(&mut vec![0x80]),
+            Some(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn incrby_fails() {
+        let mut bytes = vec![0xf0];
+        assert_eq!(incrby("u4", 1, Overflow::Fail).apply(&mut bytes), None);
+        assert_eq!(bytes, [0xf0]);
+        assert_eq!(incrby("i4", -7, Overflow::Fail).apply(&mut bytes), Some(-8));
+        assert_eq!(incrby("i4", -1, Overflow::Fail).apply(&mut bytes), None);
+        assert_eq!(bytes, [0x80]);
+    }
+
+    #[test]
+    fn set_with_overflow() {
+        let set = |value, overflow| BitfieldOp {
+            ty: int("u8"),
+            offset: 0,
+            action: BitfieldAction::Set(value),
+            overflow,
+        };
+        let mut bytes = Vec::new();
+        assert_eq!(set(300, Overflow::Wrap).apply(&mut bytes), Some(0));
+        assert_eq!(bytes, [44]);
+        assert_eq!(set(300, Overflow::Sat).apply(&mut bytes), Some(44));
+        assert_eq!(bytes, [255]);
+        assert_eq!(set(0, Overflow::Fail).apply(&mut bytes), Some(255));
+        assert_eq!(set(256, Overflow::Fail).apply(&mut bytes), None);
+        assert_eq!(bytes, [0]);
+        // Negative values are huge unsigned ones
+        assert_eq!(set(-1, Overflow::Wrap).apply(&mut bytes), Some(0));
+        assert_eq!(bytes, [255]);
+        assert_eq!(set(-1, Overflow::Sat).apply(&mut bytes), Some(255));
+        assert_eq!(bytes, [255]);
+    }
+}
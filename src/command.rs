@@ -0,0 +1,8004 @@
+//! Commands that only touch the `Store`. They are shared by the master, which
+//! serves them to clients and replicates the writes, and the replica, which
+//! serves them to clients and applies the writes it receives from the master.
+use crate::bitmap::{self, BitOp, BitfieldAction, BitfieldOp, IntType, Overflow, Unit};
+use crate::command_info;
+use crate::data::Data;
+use crate::geo;
+use crate::glob::glob_match;
+use crate::lcs;
+use crate::sorted_set::{self, AddOptions, Added, Aggregate, Range, RangeQuery};
+use crate::store::{
+    format_fixed, format_score, SetCondition, SetExpiry, SetOp, SetOptions, Store, StoreDb, NUM_DBS,
+};
+use crate::stream::{ClaimOptions, Claimed, Entry, EntryId, GroupEntry, Trim};
+use crate::value::Value;
+use anyhow::{anyhow, bail, Result};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const SYNTAX_ERR_MSG: &str = "ERR syntax error";
+pub const NOT_INTEGER_ERR_MSG: &str = "ERR value is not an integer or out of range";
+pub const NOT_FLOAT_ERR_MSG: &str = "ERR value is not a valid float";
+pub const MAXLEN_ERR_MSG: &str = "ERR The MAXLEN argument must be >= 0.";
+const SET_EXPIRE_ERR_MSG: &str = "ERR invalid expire time in 'set' command";
+const LIMIT_ERR_MSG: &str = "ERR The LIMIT argument must be >= 0.";
+const LIMIT_WITHOUT_APPROX_ERR_MSG: &str =
+    "ERR syntax error, LIMIT cannot be used without the special ~ option";
+const WEIGHT_ERR_MSG: &str = "ERR weight value is not a float";
+const BIT_OFFSET_ERR_MSG: &str = "ERR bit offset is not an integer or out of range";
+const BITFIELD_TYPE_ERR_MSG: &str =
+    "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.";
+
+/// Checks that a command exists and has the right number of arguments,
+/// without running it
+pub fn check(args: &Args) -> Result<()> {
+    let name = args.name()?;
+    match command_info::find(&name) {
+        None => bail!("ERR unknown command '{}'", name),
+        Some(command) => args.check_arity(command.arity),
+    }
+}
+
+pub const OOM_ERR_MSG: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Whether `name` may use more memory. Such commands are refused when the
+/// memory used is over `maxmemory` and nothing more can be evicted.
+pub fn is_deny_oom(name: &str) -> bool {
+    command_info::find(name).is_some_and(|command| command.is_deny_oom())
+}
+
+pub const READONLY_ERR_MSG: &str = "READONLY You can't write against a read only replica.";
+
+/// Whether `name` may modify the data. Replicas refuse them from clients, so
+/// that only the master's writes reach their data.
+pub fn is_write(name: &str) -> bool {
+    command_info::find(name).is_some_and(|command| command.is_write())
+}
+
+/// Parses the database index of SELECT
+pub fn select(args: &Args) -> Result<usize> {
+    args.check_arity(2)?;
+    let db: i64 = args.integer(1)?;
+    usize::try_from(db)
+        .ok()
+        .filter(|&db| db < NUM_DBS)
+        .ok_or(anyhow!("ERR DB index is out of range"))
+}
+
+/// What COPY did. It may copy to another database than the client's.
+pub struct Copied {
+    pub outcome: Outcome,
+    /// The database of the destination, where the modified key is
+    pub destination_db: usize,
+    /// The database that the replicated commands run in
+    pub replication_db: usize,
+}
+
+/// Runs COPY on database `db`. It's not run by `execute` since it may copy to
+/// another database.
+pub fn copy(dbs: &StoreDb, db: usize, args: &Args) -> Result<Copied> {
+    // copy <source> <destination> [db <destination-db>] [replace]
+    args.check_arity(-3)?;
+    let (from, to) = (args.string(1)?, args.string(2)?);
+
+    let mut destination_db = db;
+    let mut replace = false;
+    let mut idx = 3;
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "db" if idx + 1 < args.len() => {
+                destination_db = usize::try_from(args.integer::<i64>(idx + 1)?)
+                    .ok()
+                    .filter(|&db| db < NUM_DBS)
+                    .ok_or(anyhow!("ERR DB index is out of range"))?;
+                idx += 1;
+            }
+            "replace" => replace = true,
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+
+    let destination = dbs.get(destination_db);
+    let other_db = (destination_db != db).then_some(destination);
+    if !dbs.get(db).copy(&from, &to, replace, other_db)? {
+        return Ok(Copied {
+            outcome: Outcome::read(Data::Integer(0)),
+            destination_db,
+            replication_db: db,
+        });
+    }
+
+    // Replicated as the commands that rebuild the copy, so that replicas
+    // don't depend on having the same source, except for streams
+    let (replicate, replication_db) = match rebuild(destination, &to)? {
+        Some(cmds) => (cmds, destination_db),
+        None => (vec![Data::Array(args.vs.to_vec())], db),
+    };
+    Ok(Copied {
+        outcome: Outcome {
+            reply: Data::Integer(1),
+            replicate,
+            modified: vec![to],
+        },
+        destination_db,
+        replication_db,
+    })
+}
+
+// Commands that rebuild the value at `key` with its expiration: SET with PX
+// for a string, like SET is replicated, and for other types DEL, then HSET,
+// SADD or ZADD, then HPEXPIREAT for the hash fields that expire and PEXPIREAT.
+// Returns None for a stream, since its consumer groups can't be rebuilt as
+// they are.
+fn rebuild(store: &Store, key: &str) -> Result<Option<Vec<Data>>> {
+    let bulk = |s: &str| Data::BulkString(s.into());
+    let unix_ms = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64)
+    };
+    let Some(value) = store.get(key) else {
+        return Ok(Some(Vec::new()));
+    };
+    let expiretime_ms = store.expiretime_ms(key);
+
+    let mut cmds = vec![Data::Array(vec![bulk("DEL"), bulk(key)])];
+    match value {
+        Value::String(bytes) => {
+            let mut set = vec![bulk("SET"), bulk(key), Data::BulkString(bytes)];
+            if let Some(ms) = expiretime_ms {
+                let ttl = (ms - unix_ms(SystemTime::now())).max(1);
+                set.extend([bulk("PX"), bulk(&ttl.to_string())]);
+            }
+            return Ok(Some(vec![Data::Array(set)]));
+        }
+        Value::Hash(hash) => {
+            let mut hset = vec![bulk("HSET"), bulk(key)];
+            let mut hpexpireats = Vec::new();
+            for (field, value) in hash.fields().iter() {
+                hset.extend([bulk(field), bulk(value)]);
+                if let Some(expiration) = hash.expiration(field) {
+                    let ms = unix_ms(expiration).to_string();
+                    let hpexpireat = ["HPEXPIREAT", key, &ms, "FIELDS", "1", field];
+                    hpexpireats.push(Data::Array(hpexpireat.map(bulk).to_vec()));
+                }
+            }
+            cmds.push(Data::Array(hset));
+            cmds.extend(hpexpireats);
+        }
+        Value::Set(set) => {
+            let mut sadd = vec![bulk("SADD"), bulk(key)];
+            sadd.extend(set.iter().map(|member| bulk(member)));
+            cmds.push(Data::Array(sadd));
+        }
+        Value::SortedSet(_) => cmds = exact_zstore(store, key)?,
+        Value::Stream(_) => return Ok(None),
+    }
+    if let Some(ms) = expiretime_ms {
+        cmds.push(Data::Array(vec![
+            bulk("PEXPIREAT"),
+            bulk(key),
+            bulk(&ms.to_string()),
+        ]));
+    }
+    Ok(Some(cmds))
+}
+
+/// Parses the optional `ASYNC` or `SYNC` argument of FLUSHDB and FLUSHALL.
+/// Returns whether the flush is asynchronous.
+pub fn flush_async(args: &Args) -> Result<bool> {
+    match args.len() {
+        1 => Ok(false),
+        2 if args.string(1)?.eq_ignore_ascii_case("async") => Ok(true),
+        2 if args.string(1)?.eq_ignore_ascii_case("sync") => Ok(false),
+        _ => bail!(SYNTAX_ERR_MSG),
+    }
+}
+
+/// The result of executing a command
+pub struct Outcome {
+    /// Reply to the client
+    pub reply: Data,
+    /// Commands to send to replicas. Empty if the store wasn't modified.
+    pub replicate: Vec<Data>,
+    /// Keys that were modified, for WATCH
+    pub modified: Vec<String>,
+}
+
+impl Outcome {
+    fn read(reply: Data) -> Self {
+        Self {
+            reply,
+            replicate: Vec::new(),
+            modified: Vec::new(),
+        }
+    }
+
+    // A write to the key at index 1 that is replicated verbatim
+    fn write(reply: Data, args: &Args) -> Self {
+        Self {
+            reply,
+            replicate: vec![Data::Array(args.vs.to_vec())],
+            modified: args.string(1).into_iter().collect(),
+        }
+    }
+}
+
+/// Arguments of a command, including the command name at index 0
+pub struct Args<'a> {
+    vs: &'a [Data],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(vs: &'a [Data]) -> Self {
+        Self { vs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.vs.len()
+    }
+
+    pub fn name(&self) -> Result<String> {
+        Ok(self.string(0)?.to_ascii_lowercase())
+    }
+
+    pub fn string(&self, idx: usize) -> Result<String> {
+        self.vs
+            .get(idx)
+            .and_then(|v| v.get_string())
+            .ok_or(anyhow!("fail to get string"))
+    }
+
+    /// The raw bytes of an argument, which don't have to be UTF-8
+    pub fn bytes(&self, idx: usize) -> Result<Vec<u8>> {
+        match self.vs.get(idx) {
+            Some(Data::SimpleString(s) | Data::BulkString(s)) => Ok(s.clone()),
+            _ => bail!("fail to get string"),
+        }
+    }
+
+    pub fn strings(&self, from: usize) -> Result<Vec<String>> {
+        (from..self.len()).map(|idx| self.string(idx)).collect()
+    }
+
+    /// Parses an integer argument, failing with the error message Redis uses
+    pub fn integer<T: FromStr>(&self, idx: usize) -> Result<T> {
+        self.string(idx)?
+            .parse()
+            .map_err(|_| anyhow!(NOT_INTEGER_ERR_MSG))
+    }
+
+    /// Parses a float argument, rejecting NaN
+    pub fn float(&self, idx: usize) -> Result<f64> {
+        self.string(idx)?
+            .parse::<f64>()
+            .ok()
+            .filter(|f| !f.is_nan())
+            .ok_or(anyhow!(NOT_FLOAT_ERR_MSG))
+    }
+
+    // Checks the number of arguments, including the command name. Positive
+    // `arity` means exactly that many, negative means at least `-arity`.
+    pub fn check_arity(&self, arity: isize) -> Result<()> {
+        let ok = if arity >= 0 {
+            self.len() == arity as usize
+        } else {
+            self.len() >= arity.unsigned_abs()
+        };
+
+        if !ok {
+            return Err(self.arity_error());
+        }
+
+        Ok(())
+    }
+
+    fn arity_error(&self) -> anyhow::Error {
+        anyhow!(
+            "ERR wrong number of arguments for '{}' command",
+            self.string(0).unwrap_or_default().to_ascii_lowercase()
+        )
+    }
+}
+
+// Parses the bit offset of SETBIT and GETBIT, which must be within the
+// largest string allowed by proto-max-bulk-len
+fn bit_offset(store: &Store, args: &Args, idx: usize) -> Result<usize> {
+    check_bit_offset(store, args.string(idx)?.parse().ok())
+}
+
+// Parses the offset of a BITFIELD subcommand, which is in bits, or in
+// multiples of the width of `ty` when prefixed with `#`
+fn bitfield_offset(store: &Store, args: &Args, idx: usize, ty: IntType) -> Result<usize> {
+    let offset = args.string(idx)?;
+    let offset = match offset.strip_prefix('#') {
+        Some(n) => n
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(ty.bits as u64)),
+        None => offset.parse().ok(),
+    };
+    check_bit_offset(store, offset)
+}
+
+fn check_bit_offset(store: &Store, offset: Option<u64>) -> Result<usize> {
+    offset
+        .filter(|offset| offset / 8 < store.max_string_len() as u64)
+        .map(|offset| offset as usize)
+        .ok_or_else(|| anyhow!(BIT_OFFSET_ERR_MSG))
+}
+
+// Parses the optional BYTE or BIT at `idx` of BITCOUNT and BITPOS
+fn bit_unit(args: &Args, idx: usize) -> Result<Unit> {
+    if idx >= args.len() {
+        return Ok(Unit::Byte);
+    }
+    match args.string(idx)?.to_ascii_lowercase().as_str() {
+        "byte" => Ok(Unit::Byte),
+        "bit" => Ok(Unit::Bit),
+        _ => bail!(SYNTAX_ERR_MSG),
+    }
+}
+
+fn bulk_string_or_null(s: Option<String>) -> Data {
+    match s {
+        None => Data::NullBulkString,
+        Some(s) => Data::BulkString(s.into()),
+    }
+}
+
+/// Executes the command in `vs`. Errors are turned into error replies.
+pub fn execute(store: &Store, vs: &[Data]) -> Outcome {
+    let args = Args::new(vs);
+    match execute_args(store, &args) {
+        Ok(outcome) => {
+            store.update_sizes(&outcome.modified);
+            outcome
+        }
+        Err(err) => Outcome::read(Data::SimpleError(err.to_string())),
+    }
+}
+
+fn execute_args(store: &Store, args: &Args) -> Result<Outcome> {
+    match args.name()?.as_str() {
+        "ping" => Ok(Outcome::read(Data::SimpleString("PONG".into()))),
+        "echo" => {
+            args.check_arity(2)?;
+            Ok(Outcome::read(Data::BulkString(args.string(1)?.into())))
+        }
+        "keys" => {
+            args.check_arity(2)?;
+            let pattern = args.string(1)?;
+            let keys = store
+                .keys()
+                .into_iter()
+                .filter(|key| glob_match(&pattern, key))
+                .map(|key| Data::BulkString(key.into()))
+                .collect();
+            Ok(Outcome::read(Data::Array(keys)))
+        }
+        "randomkey" => {
+            args.check_arity(1)?;
+            let key = store.random_key(&mut store.rng());
+            Ok(Outcome::read(bulk_string_or_null(key)))
+        }
+        "type" => {
+            args.check_arity(2)?;
+            let t = store.get_type(args.string(1)?);
+            Ok(Outcome::read(Data::SimpleString(t.into())))
+        }
+        name @ ("expiretime" | "pexpiretime") => {
+            args.check_arity(2)?;
+            let key = args.string(1)?;
+            let time = match store.expiretime_ms(&key) {
+                Some(ms) if name == "expiretime" => ms / 1000,
+                Some(ms) => ms,
+                None if store.contains(&key) => -1,
+                None => -2,
+            };
+            Ok(Outcome::read(Data::Integer(time)))
+        }
+        name @ ("expireat" | "pexpireat") => {
+            // expireat <key> <unix-time-seconds>
+            // pexpireat <key> <unix-time-milliseconds>
+            args.check_arity(3)?;
+            let key = args.string(1)?;
+            let time = args.integer::<i64>(2)?;
+            let unix_ms = if name == "expireat" {
+                time.checked_mul(1000)
+                    .ok_or_else(|| anyhow!("ERR invalid expire time in '{}' command", name))?
+            } else {
+                time
+            };
+            // A negative time has passed, like any other
+            let unix_ms = unix_ms.max(0) as u64;
+            if !store.set_expiry_at_ms(&key, unix_ms) {
+                return Ok(Outcome::read(Data::Integer(0)));
+            }
+            // Replicate with milliseconds, so that replicas expire the key at
+            // the same time as the master
+            let pexpireat = ["PEXPIREAT".to_string(), key.clone(), unix_ms.to_string()];
+            Ok(Outcome {
+                reply: Data::Integer(1),
+                replicate: vec![Data::Array(
+                    pexpireat
+                        .into_iter()
+                        .map(|s| Data::BulkString(s.into()))
+                        .collect(),
+                )],
+                modified: vec![key],
+            })
+        }
+        "command" => Ok(Outcome::read(command_info::reply(args)?)),
+        "object" => object(store, args),
+        "touch" => {
+            args.check_arity(-2)?;
+            let touched = store.touch(&args.strings(1)?);
+            Ok(Outcome::read(Data::Integer(touched as i64)))
+        }
+        "sort" => sort(store, args),
+        "xlen" => {
+            args.check_arity(2)?;
+            let len = store.stream_len(&args.string(1)?)?;
+            Ok(Outcome::read(Data::Integer(len as i64)))
+        }
+        "xadd" => xadd(store, args),
+        "xtrim" => xtrim(store, args),
+        "xrange" => {
+            // xrange <stream> <start> <end> [count <count>]
+            let count = parse_range_count(args)?;
+            let entries = store.get_stream_range(
+                args.string(1)?,
+                EntryId::start_bound(args.string(2)?)?,
+                EntryId::end_bound(args.string(3)?)?,
+                count,
+            )?;
+            Ok(Outcome::read(entries_to_array(entries)))
+        }
+        "xrevrange" => {
+            // xrevrange <stream> <end> <start> [count <count>]
+            let count = parse_range_count(args)?;
+            let entries = store.get_stream_rev_range(
+                &args.string(1)?,
+                EntryId::start_bound(args.string(3)?)?,
+                EntryId::end_bound(args.string(2)?)?,
+                count,
+            )?;
+            Ok(Outcome::read(entries_to_array(entries)))
+        }
+        "xinfo" => Ok(Outcome::read(xinfo(store, args)?)),
+        "xgroup" => xgroup(store, args),
+        // Never blocks here: the master blocks on its own, as for BZPOPMIN
+        "xreadgroup" => xreadgroup(store, args),
+        "xack" => {
+            // xack <stream> <group> <id> [<id> ...]
+            args.check_arity(-4)?;
+            let entry_ids = args
+                .strings(3)?
+                .into_iter()
+                .map(EntryId::create_start)
+                .collect::<Result<Vec<_>>>()?;
+            let acked = store.stream_ack(&args.string(1)?, &args.string(2)?, &entry_ids)?;
+            // Like Redis, only acknowledging something is replicated
+            let reply = Data::Integer(acked as i64);
+            if acked > 0 {
+                Ok(Outcome::write(reply, args))
+            } else {
+                Ok(Outcome::read(reply))
+            }
+        }
+        "xpending" => Ok(Outcome::read(xpending(store, args, SystemTime::now())?)),
+        "xclaim" => xclaim(store, args, SystemTime::now()),
+        "xautoclaim" => xautoclaim(store, args, SystemTime::now()),
+        "xsetid" => {
+            // xsetid <stream> <id> [entriesadded <n>] [maxdeletedid <id>]
+            args.check_arity(-3)?;
+            let (mut entries_added, mut max_deleted_id) = (None, None);
+            let mut idx = 3;
+            while idx < args.len() {
+                if idx + 1 >= args.len() {
+                    bail!(SYNTAX_ERR_MSG);
+                }
+                match args.string(idx)?.to_ascii_lowercase().as_str() {
+                    "entriesadded" => {
+                        let n = args.integer::<i64>(idx + 1)?;
+                        let n = u64::try_from(n)
+                            .map_err(|_| anyhow!("ERR entries_added must be positive"))?;
+                        entries_added = Some(n);
+                    }
+                    "maxdeletedid" => {
+                        max_deleted_id = Some(EntryId::create_start(args.string(idx + 1)?)?);
+                    }
+                    _ => bail!(SYNTAX_ERR_MSG),
+                }
+                idx += 2;
+            }
+            let last_id = EntryId::create_start(args.string(2)?)?;
+            store.stream_set_id(&args.string(1)?, last_id, entries_added, max_deleted_id)?;
+            Ok(Outcome::write(Data::SimpleString("OK".into()), args))
+        }
+        "xdel" => {
+            // xdel <stream> <id> [<id> ...]
+            args.check_arity(-3)?;
+            let entry_ids = args
+                .strings(2)?
+                .into_iter()
+                .map(EntryId::create_start)
+                .collect::<Result<Vec<_>>>()?;
+            let removed = store.stream_remove(&args.string(1)?, &entry_ids)?;
+            let reply = Data::Integer(removed as i64);
+            if removed == 0 {
+                return Ok(Outcome::read(reply));
+            }
+            Ok(Outcome::write(reply, args))
+        }
+        "del" => {
+            args.check_arity(-2)?;
+            let keys = args.strings(1)?;
+            let num_removed = store.del(&keys);
+            if num_removed == 0 {
+                return Ok(Outcome::read(Data::Integer(0)));
+            }
+            Ok(Outcome {
+                reply: Data::Integer(num_removed as i64),
+                replicate: vec![Data::Array(args.vs.to_vec())],
+                modified: keys,
+            })
+        }
+        "rename" | "renamenx" => {
+            args.check_arity(3)?;
+            let (from, to) = (args.string(1)?, args.string(2)?);
+            let reply = if args.name()? == "rename" {
+                store.rename(&from, &to)?;
+                Data::SimpleString("OK".into())
+            } else if store.rename_if_absent(&from, &to)? {
+                Data::Integer(1)
+            } else {
+                return Ok(Outcome::read(Data::Integer(0)));
+            };
+            Ok(Outcome {
+                reply,
+                replicate: vec![Data::Array(args.vs.to_vec())],
+                modified: vec![from, to],
+            })
+        }
+        "get" => {
+            args.check_arity(2)?;
+            let value = store.get_string(&args.string(1)?)?;
+            Ok(Outcome::read(
+                value.map_or(Data::NullBulkString, Data::BulkString),
+            ))
+        }
+        "set" => set(store, args),
+        "setbit" => {
+            // setbit <key> <offset> <0|1>
+            args.check_arity(4)?;
+            let offset = bit_offset(store, args, 2)?;
+            let value = match args.string(3)?.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => bail!("ERR bit is not an integer or out of range"),
+            };
+            let previous = store.setbit(args.string(1)?, offset, value)?;
+            Ok(Outcome::write(Data::Integer(previous as i64), args))
+        }
+        "getbit" => {
+            // getbit <key> <offset>
+            args.check_arity(3)?;
+            let offset = bit_offset(store, args, 2)?;
+            let bit = store.with_string(&args.string(1)?, |s| bitmap::get_bit(s, offset))?;
+            Ok(Outcome::read(Data::Integer(bit.unwrap_or(false) as i64)))
+        }
+        "bitcount" => {
+            // bitcount <key> [<start> <end> [byte|bit]]
+            args.check_arity(-2)?;
+            let range = match args.len() {
+                2 => None,
+                4 | 5 => Some((args.integer(2)?, args.integer(3)?, bit_unit(args, 4)?)),
+                _ => bail!(SYNTAX_ERR_MSG),
+            };
+            let (start, end, unit) = range.unwrap_or((0, -1, Unit::Byte));
+            let count =
+                store.with_string(&args.string(1)?, |s| bitmap::count(s, start, end, unit))?;
+            Ok(Outcome::read(Data::Integer(count.unwrap_or(0) as i64)))
+        }
+        "bitpos" => {
+            // bitpos <key> <0|1> [<start> [<end> [byte|bit]]]
+            args.check_arity(-3)?;
+            if args.len() > 6 {
+                bail!(SYNTAX_ERR_MSG);
+            }
+            let bit = match args.string(2)?.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => bail!("ERR The bit argument must be 1 or 0."),
+            };
+            let start = (args.len() > 3).then(|| args.integer(3)).transpose()?;
+            let end = (args.len() > 4).then(|| args.integer(4)).transpose()?;
+            let position = store.bitpos(&args.string(1)?, bit, start, end, bit_unit(args, 5)?)?;
+            Ok(Outcome::read(Data::Integer(position)))
+        }
+        "bitop" => {
+            // bitop <and|or|xor|not> <destination> <key> [<key> ...]
+            args.check_arity(-4)?;
+            let op = match args.string(1)?.to_ascii_lowercase().as_str() {
+                "and" => BitOp::And,
+                "or" => BitOp::Or,
+                "xor" => BitOp::Xor,
+                "not" => BitOp::Not,
+                _ => bail!(SYNTAX_ERR_MSG),
+            };
+            let keys = args.strings(3)?;
+            if op == BitOp::Not && keys.len() != 1 {
+                bail!("ERR BITOP NOT must be called with a single source key.");
+            }
+            let len = store.bitop(op, args.string(2)?, &keys)?;
+            // The result is deterministic, so replicas get the same one
+            Ok(Outcome {
+                reply: Data::Integer(len as i64),
+                replicate: vec![Data::Array(args.vs.to_vec())],
+                modified: vec![args.string(2)?],
+            })
+        }
+        "bitfield" => bitfield(store, args),
+        "lcs" => lcs(store, args),
+        "geoadd" => geoadd(store, args),
+        "geopos" => {
+            // geopos <key> [<member> ...]
+            args.check_arity(-2)?;
+            let members = args.strings(2)?;
+            let scores = store
+                .with_sorted_set(&args.string(1)?, |zset| {
+                    members.iter().map(|m| zset.score(m)).collect()
+                })?
+                .unwrap_or_else(|| vec![None; members.len()]);
+            let positions = scores
+                .into_iter()
+                .map(|score| match score {
+                    None => Data::NullArray,
+                    Some(score) => position_to_array(geo::decode(score as u64)),
+                })
+                .collect();
+            Ok(Outcome::read(Data::Array(positions)))
+        }
+        "geodist" => {
+            // geodist <key> <member1> <member2> [m|km|ft|mi]
+            args.check_arity(-4)?;
+            let unit = match args.len() {
+                4 => 1.0,
+                5 => distance_unit(args, 4)?,
+                _ => bail!(SYNTAX_ERR_MSG),
+            };
+            let (member1, member2) = (args.string(2)?, args.string(3)?);
+            let scores = store
+                .with_sorted_set(&args.string(1)?, |z| (z.score(&member1), z.score(&member2)))?;
+            let reply = match scores {
+                Some((Some(score1), Some(score2))) => {
+                    let (lon1, lat1) = geo::decode(score1 as u64);
+                    let (lon2, lat2) = geo::decode(score2 as u64);
+                    format_distance(geo::distance(lon1, lat1, lon2, lat2) / unit)
+                }
+                _ => Data::NullBulkString,
+            };
+            Ok(Outcome::read(reply))
+        }
+        "geosearch" => geosearch(store, args),
+        "pfadd" => {
+            // pfadd <key> [<element> ...]
+            args.check_arity(-2)?;
+            let elements = (2..args.len())
+                .map(|idx| args.bytes(idx))
+                .collect::<Result<Vec<_>>>()?;
+            if store.pfadd(args.string(1)?, &elements)? {
+                Ok(Outcome::write(Data::Integer(1), args))
+            } else {
+                Ok(Outcome::read(Data::Integer(0)))
+            }
+        }
+        "pfcount" => {
+            // pfcount <key> [<key> ...]
+            args.check_arity(-2)?;
+            let count = store.pfcount(&args.strings(1)?)?;
+            Ok(Outcome::read(Data::Integer(count as i64)))
+        }
+        "pfmerge" => {
+            // pfmerge <destination> [<source> ...]
+            args.check_arity(-2)?;
+            store.pfmerge(args.string(1)?, &args.strings(2)?)?;
+            Ok(Outcome::write(Data::SimpleString("OK".into()), args))
+        }
+        "dbsize" => {
+            args.check_arity(1)?;
+            Ok(Outcome::read(Data::Integer(store.count() as i64)))
+        }
+        "flushdb" => {
+            // flushdb [async|sync]
+            let keys = store.flush(flush_async(args)?);
+            Ok(Outcome {
+                reply: Data::SimpleString("OK".into()),
+                replicate: vec![Data::Array(args.vs.to_vec())],
+                modified: keys,
+            })
+        }
+        "hset" => {
+            // hset <key> <field> <value> [<field> <value> ...]
+            args.check_arity(-4)?;
+            if !args.len().is_multiple_of(2) {
+                return Err(args.arity_error());
+            }
+
+            let kvs = args
+                .strings(2)?
+                .chunks_exact(2)
+                .map(|kv| (kv[0].clone(), kv[1].clone()))
+                .collect();
+            let num_added = store.hset(args.string(1)?, kvs)?;
+            Ok(Outcome::write(Data::Integer(num_added as i64), args))
+        }
+        "hsetnx" => {
+            args.check_arity(4)?;
+            let set = store.hsetnx(args.string(1)?, args.string(2)?, args.string(3)?)?;
+            if set {
+                Ok(Outcome::write(Data::Integer(1), args))
+            } else {
+                Ok(Outcome::read(Data::Integer(0)))
+            }
+        }
+        "hget" => {
+            args.check_arity(3)?;
+            let value = store.hget(&args.string(1)?, &args.string(2)?)?;
+            Ok(Outcome::read(bulk_string_or_null(value)))
+        }
+        "hmget" => {
+            args.check_arity(-3)?;
+            let fields = args.strings(2)?;
+            let values = store.with_hash(&args.string(1)?, |hash| {
+                fields
+                    .iter()
+                    .map(|field| bulk_string_or_null(hash.get(field).cloned()))
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(values)))
+        }
+        "hgetall" => {
+            args.check_arity(2)?;
+            let kvs = store.with_hash(&args.string(1)?, |hash| {
+                hash.iter()
+                    .flat_map(|(field, value)| {
+                        vec![
+                            Data::BulkString(field.as_str().into()),
+                            Data::BulkString(value.as_str().into()),
+                        ]
+                    })
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(kvs)))
+        }
+        "hkeys" => {
+            args.check_arity(2)?;
+            let fields = store.with_hash(&args.string(1)?, |hash| {
+                hash.keys()
+                    .map(|field| Data::BulkString(field.as_str().into()))
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(fields)))
+        }
+        "hvals" => {
+            args.check_arity(2)?;
+            let values = store.with_hash(&args.string(1)?, |hash| {
+                hash.values()
+                    .map(|value| Data::BulkString(value.as_str().into()))
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(values)))
+        }
+        "hlen" => {
+            args.check_arity(2)?;
+            let len = store.with_hash(&args.string(1)?, |hash| hash.len())?;
+            Ok(Outcome::read(Data::Integer(len as i64)))
+        }
+        "hstrlen" => {
+            args.check_arity(3)?;
+            let field = args.string(2)?;
+            let len = store.with_hash(&args.string(1)?, |hash| {
+                hash.get(&field).map_or(0, |value| value.len())
+            })?;
+            Ok(Outcome::read(Data::Integer(len as i64)))
+        }
+        "hdel" => {
+            args.check_arity(-3)?;
+            let num_removed = store.hdel(&args.string(1)?, &args.strings(2)?)?;
+            if num_removed == 0 {
+                Ok(Outcome::read(Data::Integer(0)))
+            } else {
+                Ok(Outcome::write(Data::Integer(num_removed as i64), args))
+            }
+        }
+        "hincrby" => {
+            args.check_arity(4)?;
+            let new = store.hincrby(args.string(1)?, args.string(2)?, args.integer(3)?)?;
+            Ok(Outcome::write(Data::Integer(new), args))
+        }
+        "hincrbyfloat" => {
+            args.check_arity(4)?;
+            let key = args.string(1)?;
+            let field = args.string(2)?;
+            let new = store.hincrbyfloat(key.clone(), field.clone(), args.float(3)?)?;
+
+            // Replicate the result rather than the increment, so that replicas
+            // end up with exactly the same value
+            let hset = ["HSET", &key, &field, &new]
+                .into_iter()
+                .map(|s| Data::BulkString(s.into()))
+                .collect();
+            Ok(Outcome {
+                reply: Data::BulkString(new.into()),
+                replicate: vec![Data::Array(hset)],
+                modified: vec![key],
+            })
+        }
+        "hexists" => {
+            args.check_arity(3)?;
+            let exists = store.hexists(&args.string(1)?, &args.string(2)?)?;
+            Ok(Outcome::read(Data::Integer(exists as i64)))
+        }
+        "hrandfield" => hrandfield(store, args),
+        "hscan" => hscan(store, args),
+        "scan" => scan(store, args),
+        "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" => hexpire(store, args),
+        "httl" | "hpttl" => {
+            // httl <key> fields <numfields> <field> [<field> ...]
+            args.check_arity(-5)?;
+            let ttls = store.hpttl(&args.string(1)?, &fields_arg(args, 2)?)?;
+
+            let in_seconds = args.name()? == "httl";
+            let ttls = ttls
+                .into_iter()
+                .map(|ttl| match ttl {
+                    ttl if ttl >= 0 && in_seconds => Data::Integer((ttl + 500) / 1000),
+                    ttl => Data::Integer(ttl),
+                })
+                .collect();
+            Ok(Outcome::read(Data::Array(ttls)))
+        }
+        "hpersist" => {
+            // hpersist <key> fields <numfields> <field> [<field> ...]
+            args.check_arity(-5)?;
+            let results = store.hpersist(&args.string(1)?, &fields_arg(args, 2)?)?;
+
+            let persisted = results.contains(&1);
+            let reply = Data::Array(results.into_iter().map(Data::Integer).collect());
+            if persisted {
+                Ok(Outcome::write(reply, args))
+            } else {
+                Ok(Outcome::read(reply))
+            }
+        }
+        "sadd" => {
+            args.check_arity(-3)?;
+            let num_added = store.sadd(args.string(1)?, args.strings(2)?)?;
+            if num_added == 0 {
+                Ok(Outcome::read(Data::Integer(0)))
+            } else {
+                Ok(Outcome::write(Data::Integer(num_added as i64), args))
+            }
+        }
+        "srem" => {
+            args.check_arity(-3)?;
+            let num_removed = store.srem(&args.string(1)?, &args.strings(2)?)?;
+            if num_removed == 0 {
+                Ok(Outcome::read(Data::Integer(0)))
+            } else {
+                Ok(Outcome::write(Data::Integer(num_removed as i64), args))
+            }
+        }
+        "smembers" => {
+            args.check_arity(2)?;
+            let members = store.with_set(&args.string(1)?, |set| {
+                set.iter()
+                    .map(|member| Data::BulkString(member.as_str().into()))
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(members)))
+        }
+        "sismember" => {
+            args.check_arity(3)?;
+            let member = args.string(2)?;
+            let is_member = store.with_set(&args.string(1)?, |set| set.contains(&member))?;
+            Ok(Outcome::read(Data::Integer(is_member as i64)))
+        }
+        "smismember" => {
+            args.check_arity(-3)?;
+            let members = args.strings(2)?;
+            let flags = store.with_set(&args.string(1)?, |set| {
+                members
+                    .iter()
+                    .map(|member| Data::Integer(set.contains(member) as i64))
+                    .collect()
+            })?;
+            Ok(Outcome::read(Data::Array(flags)))
+        }
+        "smove" => {
+            args.check_arity(4)?;
+            let source = args.string(1)?;
+            let destination = args.string(2)?;
+            if !store.smove(&source, destination.clone(), args.string(3)?)? {
+                return Ok(Outcome::read(Data::Integer(0)));
+            }
+            Ok(Outcome {
+                reply: Data::Integer(1),
+                replicate: vec![Data::Array(args.vs.to_vec())],
+                modified: vec![source, destination],
+            })
+        }
+        "scard" => {
+            args.check_arity(2)?;
+            let len = store.with_set(&args.string(1)?, |set| set.len())?;
+            Ok(Outcome::read(Data::Integer(len as i64)))
+        }
+        "sintercard" => sintercard(store, args),
+        "sscan" => sscan(store, args),
+        "spop" => spop(store, args),
+        "srandmember" => srandmember(store, args),
+        "zadd" => zadd(store, args),
+        "zrem" => {
+            args.check_arity(-3)?;
+            let num_removed = store.zrem(&args.string(1)?, &args.strings(2)?)?;
+            if num_removed == 0 {
+                Ok(Outcome::read(Data::Integer(0)))
+            } else {
+                Ok(Outcome::write(Data::Integer(num_removed as i64), args))
+            }
+        }
+        "zremrangebyrank" | "zremrangebyscore" => {
+            // zremrangebyrank <key> <start> <stop>
+            // zremrangebyscore <key> <min> <max>
+            args.check_arity(4)?;
+            let range = if args.name()? == "zremrangebyrank" {
+                Range::Index(args.integer(2)?, args.integer(3)?)
+            } else {
+                Range::Score(
+                    sorted_set::parse_score_bound(&args.string(2)?)?,
+                    sorted_set::parse_score_bound(&args.string(3)?)?,
+                )
+            };
+            let num_removed = store.zremrange(&args.string(1)?, &range)?;
+            if num_removed == 0 {
+                Ok(Outcome::read(Data::Integer(0)))
+            } else {
+                Ok(Outcome::write(Data::Integer(num_removed as i64), args))
+            }
+        }
+        "zpopmin" | "zpopmax" => zpop(store, args),
+        "zmpop" => zmpop(store, args),
+        "zincrby" => {
+            // zincrby <key> <increment> <member>, which is ZADD with INCR
+            args.check_arity(4)?;
+            let options = AddOptions {
+                incr: true,
+                ..Default::default()
+            };
+            let member = (args.float(2)?, args.string(3)?);
+            let added = store.zadd(args.string(1)?, vec![member], &options)?;
+            match added[0] {
+                Added::New(score) | Added::Updated(score) => Ok(Outcome::write(
+                    Data::BulkString(format_score(score).into()),
+                    args,
+                )),
+                Added::Unchanged(score) => {
+                    Ok(Outcome::read(Data::BulkString(format_score(score).into())))
+                }
+                Added::Skipped => unreachable!("no flag skips members"),
+            }
+        }
+        "zrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex"
+        | "zrevrange" => zrange(store, args),
+        "zrangestore" => zrangestore(store, args),
+        "zscan" => zscan(store, args),
+        "zrank" | "zrevrank" => {
+            // zrank <key> <member> [withscore]
+            args.check_arity(-3)?;
+            let withscore = match args.len() {
+                3 => false,
+                4 if args.string(3)?.eq_ignore_ascii_case("withscore") => true,
+                _ => bail!(SYNTAX_ERR_MSG),
+            };
+            let member = args.string(2)?;
+            let rev = args.name()? == "zrevrank";
+
+            let rank = store.with_sorted_set(&args.string(1)?, |zset| {
+                zset.rank(&member, rev)
+                    .map(|rank| (rank, zset.score(&member).unwrap()))
+            })?;
+            let reply = match rank.flatten() {
+                None if withscore => Data::NullArray,
+                None => Data::NullBulkString,
+                Some((rank, score)) if withscore => Data::Array(vec![
+                    Data::Integer(rank as i64),
+                    Data::BulkString(format_score(score).into()),
+                ]),
+                Some((rank, _)) => Data::Integer(rank as i64),
+            };
+            Ok(Outcome::read(reply))
+        }
+        "zscore" => {
+            args.check_arity(3)?;
+            let member = args.string(2)?;
+            let score = store.with_sorted_set(&args.string(1)?, |zset| zset.score(&member))?;
+            Ok(Outcome::read(bulk_string_or_null(
+                score.flatten().map(format_score),
+            )))
+        }
+        "zcard" => {
+            args.check_arity(2)?;
+            let len = store.with_sorted_set(&args.string(1)?, |zset| zset.len())?;
+            Ok(Outcome::read(Data::Integer(len.unwrap_or(0) as i64)))
+        }
+        "zunionstore" | "zinterstore" | "zdiffstore" | "zunion" | "zinter" | "zdiff" => {
+            zset_op(store, args)
+        }
+        "zrandmember" => zrandmember(store, args),
+        "zmscore" => {
+            // zmscore <key> <member> [<member> ...]
+            args.check_arity(-3)?;
+            let members = args.strings(2)?;
+            let scores = store.with_sorted_set(&args.string(1)?, |zset| {
+                members
+                    .iter()
+                    .map(|member| zset.score(member))
+                    .collect::<Vec<_>>()
+            })?;
+            let scores = scores.unwrap_or_else(|| vec![None; members.len()]);
+            Ok(Outcome::read(Data::Array(
+                scores
+                    .into_iter()
+                    .map(|score| bulk_string_or_null(score.map(format_score)))
+                    .collect(),
+            )))
+        }
+        "zcount" | "zlexcount" => {
+            // zcount <key> <min> <max>
+            // zlexcount <key> <min> <max>
+            args.check_arity(4)?;
+            let range = if args.name()? == "zcount" {
+                Range::Score(
+                    sorted_set::parse_score_bound(&args.string(2)?)?,
+                    sorted_set::parse_score_bound(&args.string(3)?)?,
+                )
+            } else {
+                Range::Lex(
+                    sorted_set::parse_lex_bound(&args.string(2)?)?,
+                    sorted_set::parse_lex_bound(&args.string(3)?)?,
+                )
+            };
+            let count = store
+                .with_sorted_set(&args.string(1)?, |zset| zset.range(&range, false).count())?;
+            Ok(Outcome::read(Data::Integer(count.unwrap_or(0) as i64)))
+        }
+        "sinter" | "sunion" | "sdiff" | "sinterstore" | "sunionstore" | "sdiffstore" => {
+            set_op(store, args)
+        }
+        command => bail!("ERR unknown command '{}'", command),
+    }
+}
+
+// Parses `fields <numfields> <field> [<field> ...]`, which starts at `idx`
+// and ends the command
+fn fields_arg(args: &Args, idx: usize) -> Result<Vec<String>> {
+    if !args.string(idx)?.eq_ignore_ascii_case("fields") {
+        bail!("ERR Mandatory argument FIELDS is missing or not at the right position");
+    }
+
+    let num_fields: usize = args.integer(idx + 1)?;
+    if num_fields == 0 {
+        bail!("ERR Parameter `numFields` should be greater than 0");
+    }
+    let fields = args.strings(idx + 2)?;
+    if fields.len() != num_fields {
+        bail!("ERR The `numfields` parameter must match the number of arguments");
+    }
+
+    Ok(fields)
+}
+
+fn hexpire(store: &Store, args: &Args) -> Result<Outcome> {
+    // hexpire <key> <seconds> fields <numfields> <field> [<field> ...]
+    // hpexpire takes milliseconds, and hexpireat and hpexpireat take a unix time
+    args.check_arity(-6)?;
+    let name = args.name()?;
+    let key = args.string(1)?;
+    let time: i64 = args.integer(2)?;
+    let fields = fields_arg(args, 3)?;
+
+    let invalid_time = || anyhow!("ERR invalid expire time in '{}' command", name);
+    let time = u64::try_from(time).map_err(|_| invalid_time())?;
+    let time = if name.starts_with("hp") {
+        Duration::from_millis(time)
+    } else {
+        Duration::from_secs(time)
+    };
+    let expiration = if name.ends_with("at") {
+        UNIX_EPOCH.checked_add(time)
+    } else {
+        SystemTime::now().checked_add(time)
+    }
+    .ok_or_else(invalid_time)?;
+
+    let results = store.hexpire(&key, &fields, expiration)?;
+    let reply = Data::Array(results.iter().map(|&r| Data::Integer(r)).collect());
+
+    // Replicate with an absolute time, so that replicas expire the fields at
+    // the same time as the master, leaving out fields that don't exist
+    let existing: Vec<&String> = fields
+        .iter()
+        .zip(results)
+        .filter(|&(_, result)| result != -2)
+        .map(|(field, _)| field)
+        .collect();
+    if existing.is_empty() {
+        return Ok(Outcome::read(reply));
+    }
+
+    let unix_time_ms = expiration
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let mut hpexpireat = vec![
+        "HPEXPIREAT".to_string(),
+        key.clone(),
+        unix_time_ms.to_string(),
+        "FIELDS".to_string(),
+        existing.len().to_string(),
+    ];
+    hpexpireat.extend(existing.into_iter().cloned());
+
+    Ok(Outcome {
+        reply,
+        replicate: vec![Data::Array(
+            hpexpireat
+                .into_iter()
+                .map(|s| Data::BulkString(s.into()))
+                .collect(),
+        )],
+        modified: vec![key],
+    })
+}
+
+fn set_op(store: &Store, args: &Args) -> Result<Outcome> {
+    // sinter <key> [<key> ...]
+    // sinterstore <destination> <key> [<key> ...]
+    // and the same for sunion and sdiff
+    let name = args.name()?;
+    let (op, is_store) = match name.as_str() {
+        "sinter" => (SetOp::Inter, false),
+        "sunion" => (SetOp::Union, false),
+        "sdiff" => (SetOp::Diff, false),
+        "sinterstore" => (SetOp::Inter, true),
+        "sunionstore" => (SetOp::Union, true),
+        "sdiffstore" => (SetOp::Diff, true),
+        _ => unreachable!(),
+    };
+
+    if is_store {
+        args.check_arity(-3)?;
+        let len = store.set_op_store(op, args.string(1)?, &args.strings(2)?)?;
+        // Set contents are deterministic, so replicas get the same result
+        Ok(Outcome::write(Data::Integer(len as i64), args))
+    } else {
+        args.check_arity(-2)?;
+        let members = store.set_op(op, &args.strings(1)?)?;
+        Ok(Outcome::read(Data::Array(
+            members
+                .into_iter()
+                .map(|member| Data::BulkString(member.into()))
+                .collect(),
+        )))
+    }
+}
+
+// Options of SCAN and friends, which come after the cursor
+struct ScanOptions {
+    cursor: u64,
+    pattern: Option<String>,
+    count: usize,
+    // HSCAN only
+    novalues: bool,
+    // SCAN only
+    type_name: Option<String>,
+}
+
+impl ScanOptions {
+    // Parses `<cursor> [match <pattern>] [count <count>]` starting at `idx`,
+    // plus `novalues` for HSCAN and `type <type>` for SCAN
+    fn parse(args: &Args, idx: usize) -> Result<Self> {
+        let cursor = args
+            .string(idx)?
+            .parse()
+            .map_err(|_| anyhow!("ERR invalid cursor"))?;
+        let name = args.name()?;
+
+        let mut options = Self {
+            cursor,
+            pattern: None,
+            count: 10,
+            novalues: false,
+            type_name: None,
+        };
+        let mut idx = idx + 1;
+        while idx < args.len() {
+            let option = args.string(idx)?.to_ascii_lowercase();
+            match option.as_str() {
+                "match" if idx + 1 < args.len() => {
+                    options.pattern = Some(args.string(idx + 1)?);
+                    idx += 2;
+                }
+                "count" if idx + 1 < args.len() => {
+                    options.count = args.integer(idx + 1)?;
+                    if options.count < 1 {
+                        bail!(SYNTAX_ERR_MSG);
+                    }
+                    idx += 2;
+                }
+                "novalues" if name == "hscan" => {
+                    options.novalues = true;
+                    idx += 1;
+                }
+                "type" if name == "scan" && idx + 1 < args.len() => {
+                    options.type_name = Some(args.string(idx + 1)?.to_ascii_lowercase());
+                    idx += 2;
+                }
+                _ => bail!(SYNTAX_ERR_MSG),
+            }
+        }
+        Ok(options)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|p| glob_match(p, name))
+    }
+}
+
+// The reply of SCAN and friends: the next cursor and the batch
+fn scan_reply(cursor: u64, items: Vec<Data>) -> Data {
+    Data::Array(vec![
+        Data::BulkString(cursor.to_string().into()),
+        Data::Array(items),
+    ])
+}
+
+fn scan(store: &Store, args: &Args) -> Result<Outcome> {
+    // scan <cursor> [match <pattern>] [count <count>] [type <type>]
+    args.check_arity(-2)?;
+    let options = ScanOptions::parse(args, 1)?;
+
+    let (cursor, batch) = store.scan(options.cursor, options.count);
+    let keys = batch
+        .into_iter()
+        .filter(|(key, type_name)| {
+            options.matches(key) && options.type_name.as_ref().is_none_or(|t| t == type_name)
+        })
+        .map(|(key, _)| Data::BulkString(key.into()))
+        .collect();
+
+    Ok(Outcome::read(scan_reply(cursor, keys)))
+}
+
+fn hscan(store: &Store, args: &Args) -> Result<Outcome> {
+    // hscan <key> <cursor> [match <pattern>] [count <count>] [novalues]
+    args.check_arity(-3)?;
+    let key = args.string(1)?;
+    let options = ScanOptions::parse(args, 2)?;
+
+    let (cursor, items) = store.with_hash(&key, |hash| {
+        let (cursor, batch) = hash.scan(options.cursor, options.count);
+        let items = batch
+            .into_iter()
+            .filter(|(field, _)| options.matches(field))
+            .flat_map(|(field, value)| {
+                let mut items = vec![Data::BulkString(field.as_str().into())];
+                if !options.novalues {
+                    items.push(Data::BulkString(value.as_str().into()));
+                }
+                items
+            })
+            .collect();
+        (cursor, items)
+    })?;
+
+    Ok(Outcome::read(scan_reply(cursor, items)))
+}
+
+/// A subcommand of OBJECT
+struct ObjectSubcommand {
+    name: &'static str,
+    /// Number of arguments, including OBJECT and the subcommand
+    arity: usize,
+    /// Lines describing the subcommand in OBJECT HELP
+    help: &'static [&'static str],
+    run: fn(&Store, &Args) -> Result<Data>,
+}
+
+const OBJECT_SUBCOMMANDS: &[ObjectSubcommand] = &[
+    ObjectSubcommand {
+        name: "encoding",
+        arity: 3,
+        help: &[
+            "ENCODING <key>",
+            "    Return the kind of internal representation used in order to store the value",
+            "    associated with a <key>.",
+        ],
+        run: |store, args| {
+            let encoding = store.object_encoding(&args.string(2)?);
+            Ok(bulk_string_or_null(encoding.map(String::from)))
+        },
+    },
+    ObjectSubcommand {
+        name: "freq",
+        arity: 3,
+        help: &[
+            "FREQ <key>",
+            "    Return the access frequency index of the <key>. The returned integer is",
+            "    proportional to the logarithm of the recent access frequency of the key.",
+        ],
+        run: |store, args| match store.access_frequency(&args.string(2)?) {
+            Some(freq) => Ok(Data::Integer(freq as i64)),
+            None => Ok(Data::NullBulkString),
+        },
+    },
+    ObjectSubcommand {
+        name: "idletime",
+        arity: 3,
+        help: &[
+            "IDLETIME <key>",
+            "    Return the idle time of the <key>, that is the approximated number of",
+            "    seconds elapsed since the last access to the key.",
+        ],
+        run: |store, args| match store.idle_time(&args.string(2)?) {
+            Some(idle) => Ok(Data::Integer(idle.as_secs() as i64)),
+            None => Ok(Data::NullBulkString),
+        },
+    },
+    ObjectSubcommand {
+        name: "refcount",
+        arity: 3,
+        help: &[
+            "REFCOUNT <key>",
+            "    Return the number of references of the value associated with the specified",
+            "    <key>.",
+        ],
+        // Values aren't shared, so each has a single reference
+        run: |store, args| {
+            if store.contains(&args.string(2)?) {
+                Ok(Data::Integer(1))
+            } else {
+                Ok(Data::NullBulkString)
+            }
+        },
+    },
+    ObjectSubcommand {
+        name: "help",
+        arity: 2,
+        help: &["HELP", "    Print this help."],
+        run: |_, _| {
+            let mut lines = vec!["OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:"];
+            for subcommand in OBJECT_SUBCOMMANDS {
+                lines.extend(subcommand.help);
+            }
+            Ok(Data::Array(
+                lines
+                    .into_iter()
+                    .map(|line| Data::SimpleString(line.into()))
+                    .collect(),
+            ))
+        },
+    },
+];
+
+fn sort(store: &Store, args: &Args) -> Result<Outcome> {
+    // sort <key> [by <pattern>] [limit <offset> <count>] [get <pattern> ...]
+    // [asc|desc] [alpha]
+    args.check_arity(-2)?;
+    let (mut by, mut limit, mut gets) = (None, None, Vec::new());
+    let (mut desc, mut alpha) = (false, false);
+    let mut idx = 2;
+    while idx < args.len() {
+        let remaining = args.len() - idx - 1;
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "asc" => desc = false,
+            "desc" => desc = true,
+            "alpha" => alpha = true,
+            "limit" if remaining >= 2 => {
+                limit = Some((args.integer::<i64>(idx + 1)?, args.integer::<i64>(idx + 2)?));
+                idx += 2;
+            }
+            "by" if remaining >= 1 => {
+                by = Some(args.string(idx + 1)?);
+                idx += 1;
+            }
+            "get" if remaining >= 1 => {
+                gets.push(args.string(idx + 1)?);
+                idx += 1;
+            }
+            // Its destination would be a list, which doesn't exist
+            "store" if remaining >= 1 => bail!("ERR SORT STORE is not supported"),
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+
+    let mut elements = store.sort_elements(&args.string(1)?)?;
+    // Like Redis, a BY pattern without "*" means not sorting at all
+    if by.as_ref().is_none_or(|by| by.contains('*')) {
+        let weight = |element: &String| match &by {
+            Some(by) => sort_lookup(store, by, element),
+            None => Some(element.as_bytes().to_vec()),
+        };
+        // Ties are broken by the elements, so that the order is deterministic
+        if alpha {
+            // Missing weights come first
+            let mut weighted: Vec<_> = elements
+                .into_iter()
+                .map(|element| (weight(&element), element))
+                .collect();
+            weighted.sort();
+            elements = weighted.into_iter().map(|(_, element)| element).collect();
+        } else {
+            let mut scored = elements
+                .into_iter()
+                .map(|element| Ok((sort_score(weight(&element))?, element)))
+                .collect::<Result<Vec<_>>>()?;
+            scored
+                .sort_by(|(s1, e1), (s2, e2)| s1.partial_cmp(s2).unwrap().then_with(|| e1.cmp(e2)));
+            elements = scored.into_iter().map(|(_, element)| element).collect();
+        }
+    }
+    if desc {
+        elements.reverse();
+    }
+
+    let (offset, count) = limit.unwrap_or((0, -1));
+    let count = usize::try_from(count).unwrap_or(usize::MAX);
+    let page = elements
+        .into_iter()
+        .skip(offset.max(0) as usize)
+        .take(count);
+    let reply = if gets.is_empty() {
+        page.map(|element| Data::BulkString(element.into()))
+            .collect()
+    } else {
+        page.flat_map(|element| {
+            gets.iter()
+                .map(|get| {
+                    sort_lookup(store, get, &element).map_or(Data::NullBulkString, Data::BulkString)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+    };
+    Ok(Outcome::read(Data::Array(reply)))
+}
+
+// The score of an element weighted `weight` in a numeric SORT. Missing
+// weights are 0.
+fn sort_score(weight: Option<Vec<u8>>) -> Result<f64> {
+    let Some(weight) = weight else {
+        return Ok(0.0);
+    };
+    String::from_utf8(weight)
+        .ok()
+        .and_then(|weight| weight.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| anyhow!("ERR One or more scores can't be converted into double"))
+}
+
+// The value that a BY or GET pattern of SORT gives for `element`. "#" is the
+// element itself. Otherwise the first "*" is replaced with the element to get
+// a key, whose string is the value, or the field after "->" if there's one.
+// Missing keys and fields, and keys of other types, have no value.
+fn sort_lookup(store: &Store, pattern: &str, element: &str) -> Option<Vec<u8>> {
+    if pattern == "#" {
+        return Some(element.as_bytes().to_vec());
+    }
+    let star = pattern.find('*')?;
+    let (key_pattern, field) = match pattern[star..].find("->") {
+        Some(arrow) if star + arrow + 2 < pattern.len() => {
+            (&pattern[..star + arrow], Some(&pattern[star + arrow + 2..]))
+        }
+        _ => (pattern, None),
+    };
+    let key = key_pattern.replacen('*', element, 1);
+    match field {
+        None => store.get_string(&key).ok().flatten(),
+        Some(field) => store
+            .hget(&key, field)
+            .ok()
+            .flatten()
+            .map(String::into_bytes),
+    }
+}
+
+fn object(store: &Store, args: &Args) -> Result<Outcome> {
+    args.check_arity(-2)?;
+    let name = args.string(1)?.to_ascii_lowercase();
+    match OBJECT_SUBCOMMANDS
+        .iter()
+        .find(|subcommand| subcommand.name == name && subcommand.arity == args.len())
+    {
+        Some(subcommand) => Ok(Outcome::read((subcommand.run)(store, args)?)),
+        None => bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try OBJECT HELP.",
+            name
+        ),
+    }
+}
+
+/// A stream entry as replied by XRANGE and XREAD: its id, and its fields and
+/// values flattened
+pub fn entry_to_array((entryid, entries): (EntryId, Vec<Entry>)) -> Data {
+    Data::Array(vec![
+        Data::BulkString(entryid.to_string().into()),
+        Data::Array(
+            entries
+                .into_iter()
+                .flat_map(|entry| {
+                    vec![
+                        Data::BulkString(entry.key.into()),
+                        Data::BulkString(entry.value.into()),
+                    ]
+                })
+                .collect(),
+        ),
+    ])
+}
+
+pub fn entries_to_array(entries: Vec<(EntryId, Vec<Entry>)>) -> Data {
+    Data::Array(entries.into_iter().map(entry_to_array).collect())
+}
+
+// Replies with the fields and their values, flattened
+fn fields_to_array<'a>(fields: impl IntoIterator<Item = (&'a str, Data)>) -> Data {
+    Data::Array(
+        fields
+            .into_iter()
+            .flat_map(|(name, value)| [Data::BulkString(name.into()), value])
+            .collect(),
+    )
+}
+
+// XINFO STREAM <stream> | GROUPS <stream>
+fn xinfo(store: &Store, args: &Args) -> Result<Data> {
+    let subcommand = args.string(1)?.to_ascii_lowercase();
+    if !matches!(subcommand.as_str(), "stream" | "groups") || args.len() != 3 {
+        bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try XINFO HELP.",
+            subcommand
+        );
+    }
+    let id = |entryid: EntryId| Data::BulkString(entryid.to_string().into());
+    if subcommand == "groups" {
+        let groups = store.with_stream(&args.string(2)?, |stream| {
+            stream
+                .groups()
+                .map(|(name, group)| {
+                    fields_to_array([
+                        ("name", Data::BulkString(name.clone().into())),
+                        ("consumers", Data::Integer(group.num_consumers() as i64)),
+                        ("pending", Data::Integer(group.pending().len() as i64)),
+                        ("last-delivered-id", id(group.last_delivered_id().clone())),
+                    ])
+                })
+                .collect()
+        })?;
+        return groups
+            .map(Data::Array)
+            .ok_or_else(|| anyhow!("ERR no such key"));
+    }
+
+    let Some(info) = store.stream_info(&args.string(2)?)? else {
+        bail!("ERR no such key");
+    };
+    let recorded_first_entry_id = info
+        .first_entry
+        .as_ref()
+        .map_or(EntryId::default(), |(entryid, _)| entryid.clone());
+    let fields = [
+        ("length", Data::Integer(info.length as i64)),
+        (
+            "radix-tree-keys",
+            Data::Integer(info.radix_tree_keys as i64),
+        ),
+        (
+            "radix-tree-nodes",
+            Data::Integer(info.radix_tree_nodes as i64),
+        ),
+        ("last-generated-id", id(info.last_generated_id)),
+        ("max-deleted-entry-id", id(info.max_deleted_entry_id)),
+        ("entries-added", Data::Integer(info.entries_added as i64)),
+        ("recorded-first-entry-id", id(recorded_first_entry_id)),
+        ("groups", Data::Integer(info.groups as i64)),
+        (
+            "first-entry",
+            info.first_entry
+                .map_or(Data::NullBulkString, entry_to_array),
+        ),
+        (
+            "last-entry",
+            info.last_entry.map_or(Data::NullBulkString, entry_to_array),
+        ),
+    ];
+    Ok(fields_to_array(fields))
+}
+
+// XGROUP CREATE <stream> <group> <id | $> [MKSTREAM]
+//      | DESTROY <stream> <group>
+//      | CREATECONSUMER <stream> <group> <consumer>
+//      | DELCONSUMER <stream> <group> <consumer>
+fn xgroup(store: &Store, args: &Args) -> Result<Outcome> {
+    let subcommand = args.string(1)?.to_ascii_lowercase();
+    let arity_ok = match subcommand.as_str() {
+        "create" => (5..=6).contains(&args.len()),
+        "destroy" => args.len() == 4,
+        "createconsumer" | "delconsumer" => args.len() == 5,
+        _ => false,
+    };
+    if !arity_ok {
+        bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try XGROUP HELP.",
+            subcommand
+        );
+    }
+    let (stream, group) = (args.string(2)?, args.string(3)?);
+    let no_group = || {
+        anyhow!(
+            "NOGROUP No such consumer group '{}' for key name '{}'",
+            group,
+            stream
+        )
+    };
+
+    match subcommand.as_str() {
+        "create" => {
+            let mkstream = args.len() == 6;
+            if mkstream && !args.string(5)?.eq_ignore_ascii_case("mkstream") {
+                bail!(SYNTAX_ERR_MSG);
+            }
+            let entry_id = match args.string(4)?.as_str() {
+                "$" => None,
+                entry_id => Some(EntryId::create_start(entry_id.to_string())?),
+            };
+            let entry_id = store.stream_create_group(&stream, group.clone(), entry_id, mkstream)?;
+
+            // Replicate the id rather than `$`, so that replicas start the
+            // group at the same entry
+            let entry_id = entry_id.to_string();
+            let mut create: Vec<_> = ["XGROUP", "CREATE", &stream, &group, &entry_id]
+                .into_iter()
+                .map(|s| Data::BulkString(s.into()))
+                .collect();
+            create.extend_from_slice(&args.vs[5..]);
+            Ok(Outcome {
+                reply: Data::SimpleString("OK".into()),
+                replicate: vec![Data::Array(create)],
+                modified: vec![stream],
+            })
+        }
+        "destroy" => {
+            if store.with_existing_stream_mut(&stream, |s| s.destroy_group(&group))? {
+                Ok(xgroup_write(Data::Integer(1), args, stream))
+            } else {
+                Ok(Outcome::read(Data::Integer(0)))
+            }
+        }
+        "createconsumer" => {
+            let consumer = args.string(4)?;
+            let created = store
+                .with_existing_stream_mut(&stream, |s| s.create_consumer(&group, &consumer))?
+                .ok_or_else(no_group)?;
+            if created {
+                Ok(xgroup_write(Data::Integer(1), args, stream))
+            } else {
+                Ok(Outcome::read(Data::Integer(0)))
+            }
+        }
+        _ => {
+            let consumer = args.string(4)?;
+            let deleted = store
+                .with_existing_stream_mut(&stream, |s| s.delete_consumer(&group, &consumer))?
+                .ok_or_else(no_group)?;
+            match deleted {
+                Some(num_pending) => Ok(xgroup_write(
+                    Data::Integer(num_pending as i64),
+                    args,
+                    stream,
+                )),
+                None => Ok(Outcome::read(Data::Integer(0))),
+            }
+        }
+    }
+}
+
+// An XGROUP subcommand that changed the stream, replicated as is. The key is
+// the second argument rather than the first.
+fn xgroup_write(reply: Data, args: &Args, stream: String) -> Outcome {
+    Outcome {
+        reply,
+        replicate: vec![Data::Array(args.vs.to_vec())],
+        modified: vec![stream],
+    }
+}
+
+fn group_entry_to_array((entryid, entries): GroupEntry) -> Data {
+    match entries {
+        Some(entries) => entry_to_array((entryid, entries)),
+        None => Data::Array(vec![
+            Data::BulkString(entryid.to_string().into()),
+            Data::NullArray,
+        ]),
+    }
+}
+
+/// The arguments of XREADGROUP
+pub struct XreadGroupArgs {
+    group: String,
+    consumer: String,
+    count: Option<usize>,
+    noack: bool,
+    /// How long to block for, if BLOCK is given. Forever if `None`.
+    pub block: Option<Option<Duration>>,
+    // The index of BLOCK, which isn't replicated
+    block_idx: Option<usize>,
+    pub streams: Vec<String>,
+    // `None` for `>`
+    entry_ids: Vec<Option<EntryId>>,
+}
+
+impl XreadGroupArgs {
+    /// Whether the command may block, which it only does for new entries
+    pub fn may_block(&self) -> bool {
+        self.block.is_some() && self.entry_ids.iter().all(Option::is_none)
+    }
+}
+
+// xreadgroup group <group> <consumer> [count <count>] [block <milliseconds>]
+//   [noack] streams <stream1> <stream2> <entryid1> <entryid2>
+// where an id of `>` reads the entries never delivered to the group
+pub fn parse_xreadgroup(args: &Args) -> Result<XreadGroupArgs> {
+    args.check_arity(-7)?;
+    if !args.string(1)?.eq_ignore_ascii_case("group") {
+        bail!(SYNTAX_ERR_MSG);
+    }
+    let group = args.string(2)?;
+    let consumer = args.string(3)?;
+
+    let (mut count, mut noack, mut block, mut block_idx) = (None, false, None, None);
+    let mut idx = 4;
+    loop {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "count" => {
+                // 0 means no limit, like a negative count
+                count = Some(args.integer::<i64>(idx + 1)?)
+                    .filter(|count| *count > 0)
+                    .map(|count| count as usize);
+                idx += 2;
+            }
+            "block" => {
+                let timeout = args.integer::<i64>(idx + 1)?;
+                if timeout < 0 {
+                    bail!("ERR timeout is negative");
+                }
+                block = Some((timeout > 0).then(|| Duration::from_millis(timeout as u64)));
+                block_idx = Some(idx);
+                idx += 2;
+            }
+            "noack" => {
+                noack = true;
+                idx += 1;
+            }
+            "streams" => break,
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+    }
+    let streams = args.strings(idx + 1)?;
+    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+        bail!("ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.");
+    }
+    let (streams, entry_ids) = streams.split_at(streams.len() / 2);
+    let entry_ids = entry_ids
+        .iter()
+        .map(|entry_id| match entry_id.as_str() {
+            ">" => Ok(None),
+            _ => EntryId::create_start(entry_id.clone()).map(Some),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(XreadGroupArgs {
+        group,
+        consumer,
+        count,
+        noack,
+        block,
+        block_idx,
+        streams: streams.to_vec(),
+        entry_ids,
+    })
+}
+
+/// Reads entries for a consumer of a group, without blocking. The reply is a
+/// null array if there's nothing new, and then the caller may block.
+///
+/// Reading changes the group, so the command is replicated without BLOCK,
+/// and reading the same entries gives replicas the same pending entries.
+pub fn xreadgroup(store: &Store, args: &Args) -> Result<Outcome> {
+    let xreadgroup = parse_xreadgroup(args)?;
+    let XreadGroupArgs {
+        group, consumer, ..
+    } = &xreadgroup;
+
+    let mut reply = Vec::new();
+    for (stream, after) in xreadgroup.streams.iter().zip(&xreadgroup.entry_ids) {
+        let history = after.is_some();
+        let Some(entries) = store.stream_read_group(
+            stream,
+            group,
+            consumer,
+            after.clone(),
+            xreadgroup.count,
+            xreadgroup.noack,
+        )?
+        else {
+            bail!(
+                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                stream,
+                group
+            );
+        };
+        // Streams without new entries are left out, but not the history
+        if history || !entries.is_empty() {
+            reply.push(Data::Array(vec![
+                Data::BulkString(stream.clone().into()),
+                Data::Array(entries.into_iter().map(group_entry_to_array).collect()),
+            ]));
+        }
+    }
+
+    let replicated = args
+        .vs
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| {
+            xreadgroup
+                .block_idx
+                .is_none_or(|block_idx| !(block_idx..=block_idx + 1).contains(idx))
+        })
+        .map(|(_, v)| v.clone())
+        .collect();
+    // Replicated even without entries, as the consumer may have been created
+    let replicate = vec![Data::Array(replicated)];
+    if reply.is_empty() {
+        return Ok(Outcome {
+            reply: Data::NullArray,
+            replicate,
+            modified: Vec::new(),
+        });
+    }
+    Ok(Outcome {
+        reply: Data::Array(reply),
+        replicate,
+        modified: xreadgroup.streams,
+    })
+}
+
+// xpending <stream> <group> [[idle <min-idle-time>] <start> <end> <count> [<consumer>]]
+// Without a range, replies with a summary of the group's pending entries.
+// Idle times are measured up to `now`.
+fn xpending(store: &Store, args: &Args, now: SystemTime) -> Result<Data> {
+    args.check_arity(-3)?;
+    let stream = args.string(1)?;
+    let group = args.string(2)?;
+
+    let mut idx = 3;
+    let mut min_idle = Duration::ZERO;
+    if args.len() > 3 && args.string(3)?.eq_ignore_ascii_case("idle") {
+        min_idle = Duration::from_millis(args.integer(4)?);
+        idx = 5;
+    }
+    let range = match args.len().checked_sub(idx) {
+        Some(0) if idx == 3 => None,
+        Some(3 | 4) => Some((
+            EntryId::start_bound(args.string(idx)?)?,
+            EntryId::end_bound(args.string(idx + 1)?)?,
+            args.integer::<i64>(idx + 2)?.max(0) as usize,
+            args.string(idx + 3).ok(),
+        )),
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let reply = store.with_stream(&stream, |s| {
+        let pending = s.group(&group)?.pending();
+        let Some((start, end, count, consumer)) = range else {
+            let (Some(first), Some(last)) = (pending.keys().next(), pending.keys().last()) else {
+                return Some(Data::Array(vec![
+                    Data::Integer(0),
+                    Data::NullBulkString,
+                    Data::NullBulkString,
+                    Data::NullArray,
+                ]));
+            };
+            let mut counts = BTreeMap::new();
+            for entry in pending.values() {
+                *counts.entry(entry.consumer.as_str()).or_insert(0) += 1;
+            }
+            let counts = counts
+                .into_iter()
+                .map(|(consumer, count): (&str, usize)| {
+                    Data::Array(vec![
+                        Data::BulkString(consumer.into()),
+                        Data::BulkString(count.to_string().into()),
+                    ])
+                })
+                .collect();
+            return Some(Data::Array(vec![
+                Data::Integer(pending.len() as i64),
+                Data::BulkString(first.to_string().into()),
+                Data::BulkString(last.to_string().into()),
+                Data::Array(counts),
+            ]));
+        };
+
+        let entries = s
+            .group(&group)?
+            .pending_range(start, end)
+            .map(|(entryid, entry)| {
+                let idle = now.duration_since(entry.delivery_time).unwrap_or_default();
+                (entryid, entry, idle)
+            })
+            .filter(|(_, entry, idle)| {
+                *idle >= min_idle && consumer.as_ref().is_none_or(|c| *c == entry.consumer)
+            })
+            .take(count)
+            .map(|(entryid, entry, idle)| {
+                Data::Array(vec![
+                    Data::BulkString(entryid.to_string().into()),
+                    Data::BulkString(entry.consumer.clone().into()),
+                    Data::Integer(idle.as_millis() as i64),
+                    Data::Integer(entry.delivery_count as i64),
+                ])
+            })
+            .collect();
+        Some(Data::Array(entries))
+    })?;
+    reply
+        .flatten()
+        .ok_or_else(|| no_key_or_group(&stream, &group))
+}
+
+fn no_key_or_group(stream: &str, group: &str) -> anyhow::Error {
+    anyhow!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        stream,
+        group
+    )
+}
+
+// The minimum idle time of XCLAIM and XAUTOCLAIM, at `idx`
+fn parse_min_idle(args: &Args, idx: usize) -> Result<Duration> {
+    let min_idle = args.integer::<i64>(idx).map_err(|_| {
+        anyhow!(
+            "ERR Invalid min-idle-time argument for {}",
+            args.name().unwrap_or_default().to_ascii_uppercase()
+        )
+    })?;
+    Ok(Duration::from_millis(min_idle.max(0) as u64))
+}
+
+fn xclaim(store: &Store, args: &Args, now: SystemTime) -> Result<Outcome> {
+    // xclaim <stream> <group> <consumer> <min-idle-time> <id> [<id> ...]
+    //   [idle <ms>] [time <unix-time-ms>] [retrycount <count>] [force]
+    //   [justid]
+    args.check_arity(-6)?;
+    let (stream, group, consumer) = (args.string(1)?, args.string(2)?, args.string(3)?);
+    let min_idle = parse_min_idle(args, 4)?;
+
+    // Ids come first, up to the first option
+    let mut idx = 5;
+    let mut entry_ids = Vec::new();
+    while let Some(entryid) = args
+        .string(idx)
+        .ok()
+        .and_then(|s| EntryId::create_start(s).ok())
+    {
+        entry_ids.push(entryid);
+        idx += 1;
+    }
+
+    let mut options = ClaimOptions {
+        min_idle,
+        delivery_time: now,
+        retry_count: None,
+        force: false,
+        justid: false,
+    };
+    while idx < args.len() {
+        let option = args.string(idx)?.to_ascii_lowercase();
+        match option.as_str() {
+            "force" => options.force = true,
+            "justid" => options.justid = true,
+            "idle" | "time" | "retrycount" if idx + 1 < args.len() => {
+                idx += 1;
+                let value = args.integer::<i64>(idx)?;
+                match option.as_str() {
+                    "idle" => {
+                        let idle = Duration::from_millis(value.max(0) as u64);
+                        options.delivery_time = now.checked_sub(idle).unwrap_or(UNIX_EPOCH);
+                    }
+                    "time" => {
+                        // Not clamped to now, so that replicas whose clock
+                        // is behind get the master's time. Entries delivered
+                        // in the future are idle for 0ms.
+                        options.delivery_time =
+                            UNIX_EPOCH + Duration::from_millis(value.max(0) as u64);
+                    }
+                    _ => options.retry_count = u64::try_from(value).ok(),
+                }
+            }
+            _ => bail!("ERR Unrecognized XCLAIM option '{}'", args.string(idx)?),
+        }
+        idx += 1;
+    }
+
+    let claimed = store
+        .with_stream_mut(&stream, |s| {
+            s.claim(&group, &consumer, &entry_ids, &options, now)
+        })?
+        .flatten()
+        .ok_or_else(|| no_key_or_group(&stream, &group))?;
+    let reply = claimed_to_array(&claimed, options.justid);
+    Ok(claim_outcome(reply, stream, &group, &consumer, claimed))
+}
+
+fn xautoclaim(store: &Store, args: &Args, now: SystemTime) -> Result<Outcome> {
+    // xautoclaim <stream> <group> <consumer> <min-idle-time> <start>
+    //   [count <count>] [justid]
+    args.check_arity(-6)?;
+    let (stream, group, consumer) = (args.string(1)?, args.string(2)?, args.string(3)?);
+    let min_idle = parse_min_idle(args, 4)?;
+    let start = EntryId::start_bound(args.string(5)?)?;
+
+    let mut count = 100;
+    let mut justid = false;
+    let mut idx = 6;
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "count" if idx + 1 < args.len() => {
+                idx += 1;
+                // Up to 10 times as many entries are looked at
+                count = usize::try_from(args.integer::<i64>(idx)?)
+                    .ok()
+                    .filter(|count| (1..=usize::MAX / 10).contains(count))
+                    .ok_or(anyhow!("ERR COUNT must be > 0"))?;
+            }
+            "justid" => justid = true,
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+
+    let options = ClaimOptions {
+        min_idle,
+        delivery_time: now,
+        retry_count: None,
+        force: false,
+        justid,
+    };
+    let (next, claimed) = store
+        .with_stream_mut(&stream, |s| {
+            s.auto_claim(&group, &consumer, start, count, &options, now)
+        })?
+        .flatten()
+        .ok_or_else(|| no_key_or_group(&stream, &group))?;
+    let deleted = claimed
+        .iter()
+        .filter(|(_, _, fields)| fields.is_none())
+        .map(|(entryid, _, _)| Data::BulkString(entryid.to_string().into()))
+        .collect();
+    let reply = Data::Array(vec![
+        Data::BulkString(next.to_string().into()),
+        claimed_to_array(&claimed, justid),
+        Data::Array(deleted),
+    ]);
+    Ok(claim_outcome(reply, stream, &group, &consumer, claimed))
+}
+
+// Replies with the claimed entries, or with their ids. Deleted entries
+// aren't claimed, so they're left out.
+fn claimed_to_array(claimed: &[Claimed], justid: bool) -> Data {
+    let entries = claimed
+        .iter()
+        .filter_map(|(entryid, _, fields)| {
+            let fields = fields.as_ref()?;
+            Some(if justid {
+                Data::BulkString(entryid.to_string().into())
+            } else {
+                entry_to_array((entryid.clone(), fields.clone()))
+            })
+        })
+        .collect();
+    Data::Array(entries)
+}
+
+// The outcome of claiming entries. Like Redis, each one is replicated as an
+// XCLAIM that sets its delivery time and count, which replicas apply
+// whatever their clock. Deleted entries are replicated too, as replicas
+// remove them from the PEL the same way.
+fn claim_outcome(
+    reply: Data,
+    stream: String,
+    group: &str,
+    consumer: &str,
+    claimed: Vec<Claimed>,
+) -> Outcome {
+    if claimed.is_empty() {
+        return Outcome::read(reply);
+    }
+    let replicate = claimed
+        .into_iter()
+        .map(|(entryid, pending, _)| {
+            let time = pending
+                .delivery_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+                .to_string();
+            let retry_count = pending.delivery_count.to_string();
+            let id = entryid.to_string();
+            Data::Array(
+                [
+                    "XCLAIM",
+                    &stream,
+                    group,
+                    consumer,
+                    "0",
+                    &id,
+                    "TIME",
+                    &time,
+                    "RETRYCOUNT",
+                    &retry_count,
+                    "FORCE",
+                    "JUSTID",
+                ]
+                .into_iter()
+                .map(|s| Data::BulkString(s.into()))
+                .collect(),
+            )
+        })
+        .collect();
+    Outcome {
+        reply,
+        replicate,
+        modified: vec![stream],
+    }
+}
+
+// Parses the optional count of XRANGE and XREVRANGE. A negative count is taken
+// as 0, like Redis.
+fn parse_range_count(args: &Args) -> Result<Option<usize>> {
+    match args.len() {
+        4 => Ok(None),
+        6 if args.string(4)?.eq_ignore_ascii_case("count") => {
+            Ok(Some(args.integer::<i64>(5)?.max(0) as usize))
+        }
+        _ => bail!(SYNTAX_ERR_MSG),
+    }
+}
+
+// Parses the trimming options of XADD and XTRIM at `idx`:
+// maxlen|minid [=|~] <threshold> [limit <count>]. Returns them with the index
+// of the next argument.
+fn parse_trim(args: &Args, mut idx: usize) -> Result<(Trim, Option<usize>, usize)> {
+    let strategy = args.string(idx)?.to_ascii_lowercase();
+    idx += 1;
+    // Trimming is always exact, which approximate trimming allows too
+    let approximate = match args.string(idx)?.as_str() {
+        "~" => {
+            idx += 1;
+            true
+        }
+        "=" => {
+            idx += 1;
+            false
+        }
+        _ => false,
+    };
+    let trim = match strategy.as_str() {
+        "maxlen" => {
+            let maxlen = args.integer::<i64>(idx)?;
+            if maxlen < 0 {
+                bail!(MAXLEN_ERR_MSG);
+            }
+            Trim::MaxLen(maxlen as usize)
+        }
+        "minid" => Trim::MinId(EntryId::create_start(args.string(idx)?)?),
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+    idx += 1;
+
+    let mut limit = None;
+    if args
+        .string(idx)
+        .is_ok_and(|arg| arg.eq_ignore_ascii_case("limit"))
+    {
+        if !approximate {
+            bail!(LIMIT_WITHOUT_APPROX_ERR_MSG);
+        }
+        let count = args.integer::<i64>(idx + 1)?;
+        if count < 0 {
+            bail!(LIMIT_ERR_MSG);
+        }
+        // 0 means no limit
+        limit = (count > 0).then_some(count as usize);
+        idx += 2;
+    }
+    Ok((trim, limit, idx))
+}
+
+// XTRIM down to the current length of `stream`. Trimming is replicated this
+// way, since with LIMIT the threshold the client gave may not be reached.
+fn exact_xtrim(store: &Store, stream: &str) -> Result<Data> {
+    let len = store.stream_len(stream)?.to_string();
+    Ok(Data::Array(
+        ["XTRIM", stream, "MAXLEN", "=", &len]
+            .into_iter()
+            .map(|s| Data::BulkString(s.into()))
+            .collect(),
+    ))
+}
+
+fn xadd(store: &Store, args: &Args) -> Result<Outcome> {
+    // xadd <stream> [nomkstream] [maxlen|minid [=|~] <threshold> [limit <count>]]
+    // <*|id> <field> <value> [<field> <value> ...]
+    args.check_arity(-5)?;
+    let stream = args.string(1)?;
+    let (mut nomkstream, mut trim) = (false, None);
+    let mut idx = 2;
+    loop {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "nomkstream" => {
+                nomkstream = true;
+                idx += 1;
+            }
+            "maxlen" | "minid" => {
+                let (t, limit, next) = parse_trim(args, idx)?;
+                trim = Some((t, limit));
+                idx = next;
+            }
+            _ => break,
+        }
+    }
+    let fields = args.strings(idx + 1)?;
+    if fields.is_empty() || !fields.len().is_multiple_of(2) {
+        bail!("ERR wrong number of arguments for 'xadd' command");
+    }
+
+    let kvs = fields
+        .chunks_exact(2)
+        .map(|kv| (kv[0].clone(), kv[1].clone()))
+        .collect();
+    let entry_id = if nomkstream {
+        match store.stream_set_existing(stream.clone(), args.string(idx)?, kvs)? {
+            None => return Ok(Outcome::read(Data::NullBulkString)),
+            Some(entry_id) => entry_id,
+        }
+    } else {
+        store.stream_set(stream.clone(), args.string(idx)?, kvs)?
+    }
+    .to_string();
+
+    // Replicate the id rather than `*`, so that replicas use the same one
+    let nomkstream = nomkstream.then_some("NOMKSTREAM");
+    let mut xadd: Vec<_> = ["XADD", &stream]
+        .into_iter()
+        .chain(nomkstream)
+        .chain([entry_id.as_str()])
+        .map(|s| Data::BulkString(s.into()))
+        .collect();
+    xadd.extend_from_slice(&args.vs[idx + 1..]);
+    let mut replicate = vec![Data::Array(xadd)];
+    if let Some((trim, limit)) = trim {
+        if store.stream_trim(&stream, &trim, limit)? > 0 {
+            replicate.push(exact_xtrim(store, &stream)?);
+        }
+    }
+
+    Ok(Outcome {
+        reply: Data::BulkString(entry_id.into()),
+        replicate,
+        modified: vec![stream],
+    })
+}
+
+fn xtrim(store: &Store, args: &Args) -> Result<Outcome> {
+    // xtrim <stream> maxlen|minid [=|~] <threshold> [limit <count>]
+    args.check_arity(-4)?;
+    let stream = args.string(1)?;
+    let (trim, limit, idx) = parse_trim(args, 2)?;
+    if idx != args.len() {
+        bail!(SYNTAX_ERR_MSG);
+    }
+
+    let evicted = store.stream_trim(&stream, &trim, limit)?;
+    let reply = Data::Integer(evicted as i64);
+    if evicted == 0 {
+        return Ok(Outcome::read(reply));
+    }
+    Ok(Outcome {
+        reply,
+        replicate: vec![exact_xtrim(store, &stream)?],
+        modified: vec![stream],
+    })
+}
+
+fn sscan(store: &Store, args: &Args) -> Result<Outcome> {
+    // sscan <key> <cursor> [match <pattern>] [count <count>]
+    args.check_arity(-3)?;
+    let key = args.string(1)?;
+    let options = ScanOptions::parse(args, 2)?;
+
+    let (cursor, members) = store.with_set(&key, |set| {
+        let (cursor, batch) = set.scan(options.cursor, options.count);
+        let members = batch
+            .into_iter()
+            .filter(|member| options.matches(member))
+            .map(|member| Data::BulkString(member.as_str().into()))
+            .collect();
+        (cursor, members)
+    })?;
+
+    Ok(Outcome::read(scan_reply(cursor, members)))
+}
+
+fn zscan(store: &Store, args: &Args) -> Result<Outcome> {
+    // zscan <key> <cursor> [match <pattern>] [count <count>]
+    args.check_arity(-3)?;
+    let key = args.string(1)?;
+    let options = ScanOptions::parse(args, 2)?;
+
+    let (cursor, items) = store
+        .with_sorted_set(&key, |zset| {
+            let (cursor, batch) = zset.scan(options.cursor, options.count);
+            let items = batch
+                .into_iter()
+                .filter(|(member, _)| options.matches(member))
+                .flat_map(|(member, score)| {
+                    [
+                        Data::BulkString(member.as_str().into()),
+                        Data::BulkString(format_score(score).into()),
+                    ]
+                })
+                .collect();
+            (cursor, items)
+        })?
+        .unwrap_or_default();
+
+    Ok(Outcome::read(scan_reply(cursor, items)))
+}
+
+fn zadd(store: &Store, args: &Args) -> Result<Outcome> {
+    // zadd <key> [nx|xx] [gt|lt] [ch] [incr] <score> <member> [<score> <member> ...]
+    args.check_arity(-4)?;
+    let key = args.string(1)?;
+
+    let mut options = AddOptions::default();
+    let mut ch = false;
+    let mut idx = 2;
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "nx" => options.nx = true,
+            "xx" => options.xx = true,
+            "gt" => options.gt = true,
+            "lt" => options.lt = true,
+            "ch" => ch = true,
+            "incr" => options.incr = true,
+            _ => break,
+        }
+        idx += 1;
+    }
+
+    let pairs = args.strings(idx)?;
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        bail!(SYNTAX_ERR_MSG);
+    }
+    if options.nx && options.xx {
+        bail!("ERR XX and NX options at the same time are not compatible");
+    }
+    if (options.gt && options.lt) || ((options.gt || options.lt) && options.nx) {
+        bail!("ERR GT, LT, and/or NX options at the same time are not compatible");
+    }
+    if options.incr && pairs.len() > 2 {
+        bail!("ERR INCR option supports a single increment-element pair");
+    }
+    // Parse all scores before adding anything
+    let members = (idx..args.len())
+        .step_by(2)
+        .map(|i| Ok((args.float(i)?, args.string(i + 1)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let added = store.zadd(key, members, &options)?;
+    let is_write = added
+        .iter()
+        .any(|a| matches!(a, Added::New(_) | Added::Updated(_)));
+    let reply = if options.incr {
+        match added[0] {
+            Added::New(score) | Added::Updated(score) | Added::Unchanged(score) => {
+                Data::BulkString(format_score(score).into())
+            }
+            Added::Skipped => Data::NullBulkString,
+        }
+    } else {
+        let count = added
+            .iter()
+            .filter(|a| matches!(a, Added::New(_)) || (ch && matches!(a, Added::Updated(_))))
+            .count();
+        Data::Integer(count as i64)
+    };
+
+    if is_write {
+        Ok(Outcome::write(reply, args))
+    } else {
+        Ok(Outcome::read(reply))
+    }
+}
+
+fn zset_op(store: &Store, args: &Args) -> Result<Outcome> {
+    // zunionstore <destination> <numkeys> <key> [<key> ...]
+    //   [weights <weight> [<weight> ...]] [aggregate sum|min|max]
+    // zdiffstore <destination> <numkeys> <key> [<key> ...]
+    // zdiff <numkeys> <key> [<key> ...] [withscores]
+    // zunion <numkeys> <key> [<key> ...]
+    //   [weights <weight> [<weight> ...]] [aggregate sum|min|max] [withscores]
+    // and zinterstore and zinter like zunionstore and zunion
+    let name = args.name()?;
+    let (op, is_store) = match name.as_str() {
+        "zunionstore" => (SetOp::Union, true),
+        "zinterstore" => (SetOp::Inter, true),
+        "zdiffstore" => (SetOp::Diff, true),
+        "zunion" => (SetOp::Union, false),
+        "zinter" => (SetOp::Inter, false),
+        "zdiff" => (SetOp::Diff, false),
+        _ => unreachable!(),
+    };
+    let numkeys_idx = if is_store { 2 } else { 1 };
+    args.check_arity(-(numkeys_idx as isize + 2))?;
+
+    let numkeys = args.integer::<i64>(numkeys_idx)?;
+    if numkeys < 1 {
+        bail!("ERR at least 1 input key is needed for '{}' command", name);
+    }
+    let numkeys = numkeys as usize;
+    if numkeys > args.len() - numkeys_idx - 1 {
+        bail!(SYNTAX_ERR_MSG);
+    }
+    let keys_idx = numkeys_idx + 1;
+    let keys = (keys_idx..keys_idx + numkeys)
+        .map(|idx| args.string(idx))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Options come after the keys
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut withscores = false;
+    let mut idx = keys_idx + numkeys;
+    let is_diff = matches!(op, SetOp::Diff);
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "weights" if !is_diff && idx + numkeys < args.len() => {
+                for (i, weight) in weights.iter_mut().enumerate() {
+                    *weight = args
+                        .string(idx + 1 + i)?
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|weight| !weight.is_nan())
+                        .ok_or_else(|| anyhow!(WEIGHT_ERR_MSG))?;
+                }
+                idx += 1 + numkeys;
+            }
+            "aggregate" if !is_diff && idx + 1 < args.len() => {
+                aggregate = match args.string(idx + 1)?.to_ascii_lowercase().as_str() {
+                    "sum" => Aggregate::Sum,
+                    "min" => Aggregate::Min,
+                    "max" => Aggregate::Max,
+                    _ => bail!(SYNTAX_ERR_MSG),
+                };
+                idx += 2;
+            }
+            "withscores" if !is_store => {
+                withscores = true;
+                idx += 1;
+            }
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+    }
+
+    if is_store {
+        let destination = args.string(1)?;
+        let len = store.zset_op_store(op, destination.clone(), &keys, &weights, aggregate)?;
+        return Ok(Outcome {
+            reply: Data::Integer(len as i64),
+            replicate: exact_zstore(store, &destination)?,
+            modified: vec![destination],
+        });
+    }
+
+    let zset = store.zset_op(op, &keys, &weights, aggregate)?;
+    let reply = zset
+        .iter()
+        .flat_map(|(member, score)| {
+            let mut items = vec![Data::BulkString(member.as_str().into())];
+            if withscores {
+                items.push(Data::BulkString(format_score(score).into()));
+            }
+            items
+        })
+        .collect();
+    Ok(Outcome::read(Data::Array(reply)))
+}
+
+fn zrandmember(store: &Store, args: &Args) -> Result<Outcome> {
+    // zrandmember <key> [<count> [withscores]]
+    args.check_arity(-2)?;
+    let key = args.string(1)?;
+
+    if args.len() == 2 {
+        let members = store.zrandmember(&key, 1, &mut store.rng())?;
+        let member = members.and_then(|members| members.into_iter().next());
+        return Ok(Outcome::read(bulk_string_or_null(
+            member.map(|(member, _)| member),
+        )));
+    }
+
+    let count: i64 = args.integer(2)?;
+    let withscores = match args.len() {
+        3 => false,
+        4 if args.string(3)?.eq_ignore_ascii_case("withscores") => true,
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let members = store
+        .zrandmember(&key, count, &mut store.rng())?
+        .unwrap_or_default();
+    let reply = members
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let mut items = vec![Data::BulkString(member.into())];
+            if withscores {
+                items.push(Data::BulkString(format_score(score).into()));
+            }
+            items
+        })
+        .collect();
+    Ok(Outcome::read(Data::Array(reply)))
+}
+
+fn zrange(store: &Store, args: &Args) -> Result<Outcome> {
+    // zrange <key> <start> <stop> [byscore|bylex] [rev] [limit <offset> <count>] [withscores]
+    // zrangebyscore <key> <min> <max> [withscores] [limit <offset> <count>]
+    // zrevrangebyscore <key> <max> <min> [withscores] [limit <offset> <count>]
+    // zrangebylex <key> <min> <max> [limit <offset> <count>]
+    // zrevrangebylex <key> <max> <min> [limit <offset> <count>]
+    // zrevrange <key> <start> <stop> [withscores]
+    args.check_arity(-4)?;
+    let (query, withscores) = parse_range_query(args, 1)?;
+
+    let items = store.with_sorted_set(&args.string(1)?, |zset| {
+        let mut items = Vec::new();
+        for (member, score) in zset.query(&query) {
+            items.push(Data::BulkString(member.as_str().into()));
+            if withscores {
+                items.push(Data::BulkString(format_score(score).into()));
+            }
+        }
+        items
+    })?;
+    Ok(Outcome::read(Data::Array(items.unwrap_or_default())))
+}
+
+fn zrangestore(store: &Store, args: &Args) -> Result<Outcome> {
+    // zrangestore <destination> <source> <start> <stop> [byscore|bylex] [rev]
+    //   [limit <offset> <count>]
+    args.check_arity(-5)?;
+    let (query, _) = parse_range_query(args, 2)?;
+    let destination = args.string(1)?;
+    let len = store.zrangestore(destination.clone(), &args.string(2)?, &query)?;
+    Ok(Outcome {
+        reply: Data::Integer(len as i64),
+        replicate: exact_zstore(store, &destination)?,
+        modified: vec![destination],
+    })
+}
+
+// DEL, then ZADD of every member, which rebuild the sorted set at
+// `destination` as it is now. Storing a sorted set is replicated this way, so
+// that replicas don't depend on having the same sources.
+fn exact_zstore(store: &Store, destination: &str) -> Result<Vec<Data>> {
+    let del = ["DEL", destination]
+        .into_iter()
+        .map(|s| Data::BulkString(s.into()))
+        .collect();
+    let mut replicate = vec![Data::Array(del)];
+
+    let zadd = store.with_sorted_set(destination, |zset| {
+        let mut zadd = vec![
+            Data::BulkString("ZADD".into()),
+            Data::BulkString(destination.into()),
+        ];
+        for (member, score) in zset.iter() {
+            zadd.push(Data::BulkString(format_score(score).into()));
+            zadd.push(Data::BulkString(member.as_str().into()));
+        }
+        Data::Array(zadd)
+    })?;
+    replicate.extend(zadd);
+    Ok(replicate)
+}
+
+// Parses the arguments of ZRANGE and friends after the command name and any
+// destination, starting with the key at `key_idx`. Returns what to select,
+// and whether scores are asked for.
+fn parse_range_query(args: &Args, key_idx: usize) -> Result<(RangeQuery, bool)> {
+    #[derive(PartialEq)]
+    enum By {
+        Index,
+        Score,
+        Lex,
+    }
+    let name = args.name()?;
+    let (mut by, mut rev) = match name.as_str() {
+        "zrangebyscore" => (By::Score, false),
+        "zrevrangebyscore" => (By::Score, true),
+        "zrangebylex" => (By::Lex, false),
+        "zrevrangebylex" => (By::Lex, true),
+        "zrevrange" => (By::Index, true),
+        _ => (By::Index, false),
+    };
+    let has_options = name == "zrange" || name == "zrangestore";
+
+    let mut withscores = false;
+    let mut limit = None;
+    let mut idx = key_idx + 3;
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "withscores" if name != "zrangestore" && !name.ends_with("bylex") => withscores = true,
+            "byscore" if has_options => by = By::Score,
+            "bylex" if has_options => by = By::Lex,
+            "rev" if has_options => rev = true,
+            "limit" if name != "zrevrange" && idx + 2 < args.len() => {
+                limit = Some((args.integer::<i64>(idx + 1)?, args.integer::<i64>(idx + 2)?));
+                idx += 2;
+            }
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+    if limit.is_some() && by == By::Index {
+        bail!(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+        );
+    }
+    if withscores && by == By::Lex {
+        bail!("ERR syntax error, WITHSCORES not supported in combination with BYLEX");
+    }
+
+    // Reversed score and lex ranges are given from the max to the min
+    let (min, max) = if rev && by != By::Index {
+        (args.string(key_idx + 2)?, args.string(key_idx + 1)?)
+    } else {
+        (args.string(key_idx + 1)?, args.string(key_idx + 2)?)
+    };
+    let range = match by {
+        By::Index => Range::Index(args.integer(key_idx + 1)?, args.integer(key_idx + 2)?),
+        By::Score => Range::Score(
+            sorted_set::parse_score_bound(&min)?,
+            sorted_set::parse_score_bound(&max)?,
+        ),
+        By::Lex => Range::Lex(
+            sorted_set::parse_lex_bound(&min)?,
+            sorted_set::parse_lex_bound(&max)?,
+        ),
+    };
+    // A negative offset gives nothing, and a negative count gives everything
+    // after the offset
+    let (offset, count) = match limit {
+        None => (0, usize::MAX),
+        Some((offset, _)) if offset < 0 => (0, 0),
+        Some((offset, count)) => (
+            offset as usize,
+            usize::try_from(count).unwrap_or(usize::MAX),
+        ),
+    };
+
+    let query = RangeQuery {
+        range,
+        rev,
+        offset,
+        count,
+    };
+    Ok((query, withscores))
+}
+
+fn zpop(store: &Store, args: &Args) -> Result<Outcome> {
+    // zpopmin <key> [<count>]
+    args.check_arity(-2)?;
+    let key = args.string(1)?;
+    let count = match args.len() {
+        2 => 1,
+        3 => usize::try_from(args.integer::<i64>(2)?)
+            .map_err(|_| anyhow!("ERR value is out of range, must be positive"))?,
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let popped = store.zpop(&key, count, args.name()? == "zpopmax")?;
+    let reply = Data::Array(
+        popped
+            .iter()
+            .flat_map(|(member, score)| {
+                [
+                    Data::BulkString(member.as_str().into()),
+                    Data::BulkString(format_score(*score).into()),
+                ]
+            })
+            .collect(),
+    );
+    Ok(zpop_outcome(reply, key, &popped))
+}
+
+// The outcome of popping members from a sorted set. Pops are replicated as
+// ZREM of the popped members, which replicas can't block on in the case of
+// BZPOPMIN and BZPOPMAX.
+fn zpop_outcome(reply: Data, key: String, popped: &[(String, f64)]) -> Outcome {
+    if popped.is_empty() {
+        return Outcome::read(reply);
+    }
+    let zrem = ["ZREM", &key]
+        .into_iter()
+        .chain(popped.iter().map(|(member, _)| member.as_str()))
+        .map(|s| Data::BulkString(s.into()))
+        .collect();
+    Outcome {
+        reply,
+        replicate: vec![Data::Array(zrem)],
+        modified: vec![key],
+    }
+}
+
+/// Parses BZPOPMIN and BZPOPMAX: the keys, and how long to block, with None
+/// meaning forever
+pub fn parse_bzpop(args: &Args) -> Result<(Vec<String>, Option<Duration>)> {
+    // bzpopmin <key> [<key> ...] <timeout>
+    args.check_arity(-3)?;
+    let keys = args.strings(1)?[..args.len() - 2].to_vec();
+    let timeout = args
+        .string(args.len() - 1)?
+        .parse::<f64>()
+        .ok()
+        .filter(|timeout| timeout.is_finite())
+        .ok_or(anyhow!("ERR timeout is not a float or out of range"))?;
+    if timeout < 0.0 {
+        bail!("ERR timeout is negative");
+    }
+    Ok((
+        keys,
+        (timeout > 0.0).then(|| Duration::from_secs_f64(timeout)),
+    ))
+}
+
+/// Pops a member from the first key that has some, for BZPOPMIN and
+/// BZPOPMAX. Returns None if none has, and then the caller may block.
+pub fn bzpop(store: &Store, args: &Args) -> Result<Option<Outcome>> {
+    let (keys, _) = parse_bzpop(args)?;
+    let max = args.name()? == "bzpopmax";
+    for key in keys {
+        let popped = store.zpop(&key, 1, max)?;
+        if let Some((member, score)) = popped.first() {
+            let reply = Data::Array(vec![
+                Data::BulkString(key.as_str().into()),
+                Data::BulkString(member.as_str().into()),
+                Data::BulkString(format_score(*score).into()),
+            ]);
+            return Ok(Some(zpop_outcome(reply, key, &popped)));
+        }
+    }
+    Ok(None)
+}
+
+fn zmpop(store: &Store, args: &Args) -> Result<Outcome> {
+    // zmpop <numkeys> <key> [<key> ...] min|max [count <count>]
+    args.check_arity(-4)?;
+    let numkeys = usize::try_from(args.integer::<i64>(1)?)
+        .ok()
+        .filter(|&numkeys| numkeys > 0)
+        .ok_or(anyhow!("ERR numkeys should be greater than 0"))?;
+    let where_idx = numkeys
+        .checked_add(2)
+        .filter(|&idx| idx < args.len())
+        .ok_or(anyhow!(SYNTAX_ERR_MSG))?;
+    let keys = args.strings(2)?[..numkeys].to_vec();
+    let max = match args.string(where_idx)?.to_ascii_lowercase().as_str() {
+        "min" => false,
+        "max" => true,
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+    let count = match args.len() - where_idx {
+        1 => 1,
+        3 if args.string(where_idx + 1)?.eq_ignore_ascii_case("count") => {
+            usize::try_from(args.integer::<i64>(where_idx + 2)?)
+                .ok()
+                .filter(|&count| count > 0)
+                .ok_or(anyhow!("ERR count should be greater than 0"))?
+        }
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let Some((key, popped)) = store.sorted_set_multi_pop(&keys, count, max)? else {
+        return Ok(Outcome::read(Data::NullArray));
+    };
+    let members = popped
+        .iter()
+        .map(|(member, score)| {
+            Data::Array(vec![
+                Data::BulkString(member.as_str().into()),
+                Data::BulkString(format_score(*score).into()),
+            ])
+        })
+        .collect();
+    let reply = Data::Array(vec![
+        Data::BulkString(key.as_str().into()),
+        Data::Array(members),
+    ]);
+    Ok(zpop_outcome(reply, key, &popped))
+}
+
+fn hrandfield(store: &Store, args: &Args) -> Result<Outcome> {
+    // hrandfield <key> [<count> [withvalues]]
+    args.check_arity(-2)?;
+    let key = args.string(1)?;
+
+    if args.len() == 2 {
+        let fields = store.hrandfield(&key, 1, &mut store.rng())?;
+        let field = fields.and_then(|fields| fields.into_iter().next());
+        return Ok(Outcome::read(bulk_string_or_null(
+            field.map(|(field, _)| field),
+        )));
+    }
+
+    let count: i64 = args.integer(2)?;
+    let with_values = match args.len() {
+        3 => false,
+        4 if args.string(3)?.eq_ignore_ascii_case("withvalues") => true,
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let fields = store
+        .hrandfield(&key, count, &mut store.rng())?
+        .unwrap_or_default();
+    let reply = fields
+        .into_iter()
+        .flat_map(|(field, value)| {
+            let mut items = vec![Data::BulkString(field.into())];
+            if with_values {
+                items.push(Data::BulkString(value.into()));
+            }
+            items
+        })
+        .collect();
+    Ok(Outcome::read(Data::Array(reply)))
+}
+
+fn sintercard(store: &Store, args: &Args) -> Result<Outcome> {
+    // sintercard <numkeys> <key> [<key> ...] [limit <limit>]
+    args.check_arity(-3)?;
+    let num_keys: i64 = args.integer(1)?;
+    if num_keys <= 0 {
+        bail!("ERR numkeys should be greater than 0");
+    }
+    let num_keys = num_keys as usize;
+    if num_keys > args.len() - 2 {
+        bail!("ERR Number of keys can't be greater than number of args");
+    }
+    let keys: Vec<String> = (2..2 + num_keys)
+        .map(|idx| args.string(idx))
+        .collect::<Result<_>>()?;
+
+    let limit = match args.len() - 2 - num_keys {
+        0 => 0,
+        2 if args.string(2 + num_keys)?.eq_ignore_ascii_case("limit") => {
+            let limit: i64 = args.integer(3 + num_keys)?;
+            if limit < 0 {
+                bail!("ERR LIMIT can't be negative");
+            }
+            limit as usize
+        }
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let count = store.sintercard(&keys, limit)?;
+    Ok(Outcome::read(Data::Integer(count as i64)))
+}
+
+fn srandmember(store: &Store, args: &Args) -> Result<Outcome> {
+    // srandmember <key> [<count>]
+    args.check_arity(-2)?;
+    let key = args.string(1)?;
+
+    match args.len() {
+        2 => {
+            let members = store.srandmember(&key, 1, &mut store.rng())?;
+            let member = members.and_then(|members| members.into_iter().next());
+            Ok(Outcome::read(bulk_string_or_null(member)))
+        }
+        3 => {
+            // Like Redis, which negates the count, i64::MIN is out of range
+            let count = args.integer::<i64>(2)?;
+            if count == i64::MIN {
+                bail!(
+                    "ERR value is out of range, value must between {} and {}",
+                    -i64::MAX,
+                    i64::MAX
+                );
+            }
+            let members = store
+                .srandmember(&key, count, &mut store.rng())?
+                .unwrap_or_default();
+            Ok(Outcome::read(Data::Array(
+                members
+                    .into_iter()
+                    .map(|member| Data::BulkString(member.into()))
+                    .collect(),
+            )))
+        }
+        _ => bail!(SYNTAX_ERR_MSG),
+    }
+}
+
+fn spop(store: &Store, args: &Args) -> Result<Outcome> {
+    // spop <key> [<count>]
+    args.check_arity(-2)?;
+    let key = args.string(1)?;
+
+    let (count, reply_array) = match args.len() {
+        2 => (1, false),
+        3 => {
+            let count: i64 = args.integer(2)?;
+            if count < 0 {
+                bail!("ERR value is out of range, must be positive");
+            }
+            (count as usize, true)
+        }
+        _ => bail!(SYNTAX_ERR_MSG),
+    };
+
+    let popped = store.spop(&key, count, &mut store.rng())?;
+    let reply = if reply_array {
+        Data::Array(
+            popped
+                .iter()
+                .map(|member| Data::BulkString(member.as_str().into()))
+                .collect(),
+        )
+    } else {
+        bulk_string_or_null(popped.first().cloned())
+    };
+    if popped.is_empty() {
+        return Ok(Outcome::read(reply));
+    }
+
+    // The members are picked at random, so replicate which ones were removed
+    let srem = ["SREM", &key]
+        .into_iter()
+        .chain(popped.iter().map(|member| member.as_str()))
+        .map(|s| Data::BulkString(s.into()))
+        .collect();
+    Ok(Outcome {
+        reply,
+        replicate: vec![Data::Array(srem)],
+        modified: vec![key],
+    })
+}
+
+// The expiration given to SET with EX, PX, EXAT or PXAT
+fn set_expiration(option: &str, n: i64) -> Result<SystemTime> {
+    if n <= 0 {
+        bail!(SET_EXPIRE_ERR_MSG);
+    }
+    let n = n as u64;
+    let expiration = match option {
+        "ex" => SystemTime::now().checked_add(Duration::from_secs(n)),
+        "px" => SystemTime::now().checked_add(Duration::from_millis(n)),
+        "exat" => UNIX_EPOCH.checked_add(Duration::from_secs(n)),
+        _ => UNIX_EPOCH.checked_add(Duration::from_millis(n)),
+    };
+    expiration.ok_or_else(|| anyhow!(SET_EXPIRE_ERR_MSG))
+}
+
+fn set(store: &Store, args: &Args) -> Result<Outcome> {
+    // set <key> <value> [nx|xx] [get] [ex <seconds>|px <milliseconds>|
+    // exat <unix-time-seconds>|pxat <unix-time-milliseconds>|keepttl]
+    args.check_arity(-3)?;
+    let key = args.string(1)?;
+    let value = args.bytes(2)?;
+
+    let mut options = SetOptions::default();
+    // Like Redis, repeating an option is fine, but not combining exclusive
+    // ones
+    let mut expiry_option = None;
+    let mut idx = 3;
+    while idx < args.len() {
+        let option = args.string(idx)?.to_ascii_lowercase();
+        match option.as_str() {
+            "nx" | "xx" => {
+                let condition = if option == "nx" {
+                    SetCondition::IfMissing
+                } else {
+                    SetCondition::IfExists
+                };
+                if options.condition.is_some_and(|c| c != condition) {
+                    bail!(SYNTAX_ERR_MSG);
+                }
+                options.condition = Some(condition);
+            }
+            "get" => options.get = true,
+            "ex" | "px" | "exat" | "pxat" | "keepttl" => {
+                if expiry_option.as_ref().is_some_and(|o| *o != option) {
+                    bail!(SYNTAX_ERR_MSG);
+                }
+                options.expiry = if option == "keepttl" {
+                    SetExpiry::Keep
+                } else {
+                    idx += 1;
+                    if idx == args.len() {
+                        bail!(SYNTAX_ERR_MSG);
+                    }
+                    SetExpiry::At(set_expiration(&option, args.integer(idx)?)?)
+                };
+                expiry_option = Some(option);
+            }
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+
+    let result = store.set_string(key.clone(), value.clone(), options)?;
+    let reply = if options.get {
+        result
+            .previous
+            .map_or(Data::NullBulkString, Data::BulkString)
+    } else if result.done {
+        Data::SimpleString("OK".into())
+    } else {
+        Data::NullBulkString
+    };
+    if !result.done {
+        return Ok(Outcome::read(reply));
+    }
+
+    // Replicated as SET <key> <value> [PX <milliseconds>], so that replicas
+    // don't need to know about the other options. A key that has already
+    // expired is deleted instead.
+    let ttl = result.expiration.map(|expiration| {
+        expiration
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    });
+    let bulk = |s: &str| Data::BulkString(s.into());
+    let cmd = match ttl {
+        Some(ttl) if ttl.is_zero() => vec![bulk("DEL"), bulk(&key)],
+        Some(ttl) => vec![
+            bulk("SET"),
+            bulk(&key),
+            Data::BulkString(value),
+            bulk("PX"),
+            bulk(&ttl.as_millis().max(1).to_string()),
+        ],
+        None => vec![bulk("SET"), bulk(&key), Data::BulkString(value)],
+    };
+    Ok(Outcome {
+        reply,
+        replicate: vec![Data::Array(cmd)],
+        modified: vec![key],
+    })
+}
+
+fn lcs(store: &Store, args: &Args) -> Result<Outcome> {
+    // lcs <key1> <key2> [len] [idx] [minmatchlen <len>] [withmatchlen]
+    args.check_arity(-3)?;
+    let (mut len, mut idx, mut with_match_len) = (false, false, false);
+    let mut min_match_len = 0;
+    let mut i = 3;
+    while i < args.len() {
+        match args.string(i)?.to_ascii_lowercase().as_str() {
+            "len" => len = true,
+            "idx" => idx = true,
+            "withmatchlen" => with_match_len = true,
+            "minmatchlen" if i + 1 < args.len() => {
+                // Like Redis, negative lengths are 0
+                min_match_len = args.integer::<i64>(i + 1)?.max(0) as usize;
+                i += 1;
+            }
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        i += 1;
+    }
+    if len && idx {
+        bail!("ERR If you want both the length and indexes, please just use IDX.");
+    }
+
+    // Missing keys are empty strings
+    let a = store.get_string(&args.string(1)?)?.unwrap_or_default();
+    let b = store.get_string(&args.string(2)?)?.unwrap_or_default();
+    if lcs::table_size(a.len(), b.len()).is_none_or(|size| size > store.max_string_len()) {
+        bail!("ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len");
+    }
+    let (subsequence, matches) = lcs::lcs(&a, &b);
+
+    let reply = if idx {
+        let range = |(start, end): (usize, usize)| {
+            Data::Array(vec![Data::Integer(start as i64), Data::Integer(end as i64)])
+        };
+        let matches = matches
+            .into_iter()
+            .filter(|m| m.len() >= min_match_len)
+            .map(|m| {
+                let mut item = vec![range(m.a), range(m.b)];
+                if with_match_len {
+                    item.push(Data::Integer(m.len() as i64));
+                }
+                Data::Array(item)
+            })
+            .collect();
+        Data::Array(vec![
+            Data::BulkString("matches".into()),
+            Data::Array(matches),
+            Data::BulkString("len".into()),
+            Data::Integer(subsequence.len() as i64),
+        ])
+    } else if len {
+        Data::Integer(subsequence.len() as i64)
+    } else {
+        Data::BulkString(subsequence)
+    };
+    Ok(Outcome::read(reply))
+}
+
+fn bitfield(store: &Store, args: &Args) -> Result<Outcome> {
+    // bitfield <key> [get <type> <offset>] [set <type> <offset> <value>]
+    // [incrby <type> <offset> <increment>] [overflow <wrap|sat|fail>] ...
+    args.check_arity(-2)?;
+    let mut ops = Vec::new();
+    // OVERFLOW applies to the SET and INCRBY after it
+    let mut overflow = Overflow::Wrap;
+    let mut idx = 2;
+    while idx < args.len() {
+        let subcommand = args.string(idx)?.to_ascii_lowercase();
+        let len = match subcommand.as_str() {
+            "overflow" => 2,
+            "get" => 3,
+            "set" | "incrby" => 4,
+            _ => bail!(SYNTAX_ERR_MSG),
+        };
+        if idx + len > args.len() {
+            bail!(SYNTAX_ERR_MSG);
+        }
+
+        if subcommand == "overflow" {
+            overflow = match args.string(idx + 1)?.to_ascii_lowercase().as_str() {
+                "wrap" => Overflow::Wrap,
+                "sat" => Overflow::Sat,
+                "fail" => Overflow::Fail,
+                _ => bail!("ERR Invalid OVERFLOW type specified"),
+            };
+        } else {
+            let ty = IntType::parse(&args.string(idx + 1)?)
+                .ok_or_else(|| anyhow!(BITFIELD_TYPE_ERR_MSG))?;
+            let offset = bitfield_offset(store, args, idx + 2, ty)?;
+            let action = match subcommand.as_str() {
+                "get" => BitfieldAction::Get,
+                "set" => BitfieldAction::Set(args.integer(idx + 3)?),
+                _ => BitfieldAction::IncrBy(args.integer(idx + 3)?),
+            };
+            ops.push(BitfieldOp {
+                ty,
+                offset,
+                action,
+                overflow,
+            });
+        }
+        idx += len;
+    }
+
+    let replies = store.bitfield(args.string(1)?, &ops)?;
+    let reply = Data::Array(
+        replies
+            .into_iter()
+            .map(|value| value.map_or(Data::NullBulkString, Data::Integer))
+            .collect(),
+    );
+    if ops.iter().any(BitfieldOp::is_write) {
+        Ok(Outcome::write(reply, args))
+    } else {
+        Ok(Outcome::read(reply))
+    }
+}
+
+// Parses the longitude and latitude at `idx` and `idx + 1`
+fn lon_lat(args: &Args, idx: usize) -> Result<(f64, f64)> {
+    let (lon, lat) = (args.float(idx)?, args.float(idx + 1)?);
+    if !geo::is_valid(lon, lat) {
+        bail!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat);
+    }
+    Ok((lon, lat))
+}
+
+// Parses the unit of a distance at `idx`, as meters per unit
+fn distance_unit(args: &Args, idx: usize) -> Result<f64> {
+    match args.string(idx)?.to_ascii_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => bail!("ERR unsupported unit provided. please use M, KM, FT, MI"),
+    }
+}
+
+fn format_distance(distance: f64) -> Data {
+    Data::BulkString(format!("{:.4}", distance).into())
+}
+
+fn position_to_array((lon, lat): (f64, f64)) -> Data {
+    Data::Array(vec![
+        Data::BulkString(format_fixed(lon).into()),
+        Data::BulkString(format_fixed(lat).into()),
+    ])
+}
+
+fn geoadd(store: &Store, args: &Args) -> Result<Outcome> {
+    // geoadd <key> [nx|xx] [ch] <longitude> <latitude> <member> [...]
+    args.check_arity(-5)?;
+    let mut options = AddOptions::default();
+    let mut ch = false;
+    let mut idx = 2;
+    while idx < args.len() {
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "nx" => options.nx = true,
+            "xx" => options.xx = true,
+            "ch" => ch = true,
+            _ => break,
+        }
+        idx += 1;
+    }
+
+    let num_args = args.len() - idx;
+    if num_args == 0 || !num_args.is_multiple_of(3) {
+        bail!(SYNTAX_ERR_MSG);
+    }
+    if options.nx && options.xx {
+        bail!("ERR XX and NX options at the same time are not compatible");
+    }
+    // Parse all positions before adding anything
+    let members = (idx..args.len())
+        .step_by(3)
+        .map(|i| {
+            let (lon, lat) = lon_lat(args, i)?;
+            Ok((geo::encode(lon, lat) as f64, args.string(i + 2)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let added = store.zadd(args.string(1)?, members, &options)?;
+    let count = added
+        .iter()
+        .filter(|a| matches!(a, Added::New(_)) || (ch && matches!(a, Added::Updated(_))))
+        .count();
+    let reply = Data::Integer(count as i64);
+    if added
+        .iter()
+        .any(|a| matches!(a, Added::New(_) | Added::Updated(_)))
+    {
+        Ok(Outcome::write(reply, args))
+    } else {
+        Ok(Outcome::read(reply))
+    }
+}
+
+// Where GEOSEARCH searches from
+enum GeoOrigin {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+fn geosearch(store: &Store, args: &Args) -> Result<Outcome> {
+    // geosearch <key> <frommember <member> | fromlonlat <longitude> <latitude>>
+    // <byradius <radius> <unit> | bybox <width> <height> <unit>> [asc|desc]
+    // [count <count> [any]] [withcoord] [withdist] [withhash]
+    args.check_arity(-7)?;
+    let mut origin = None;
+    let mut shape = None;
+    // The unit of the shape, which is also the unit of WITHDIST
+    let mut unit = 1.0;
+    let mut desc = None;
+    let mut count = None;
+    let (mut any, mut withcoord, mut withdist, mut withhash) = (false, false, false, false);
+    let mut idx = 2;
+    while idx < args.len() {
+        let remaining = args.len() - idx - 1;
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "frommember" if remaining >= 1 && origin.is_none() => {
+                origin = Some(GeoOrigin::Member(args.string(idx + 1)?));
+                idx += 1;
+            }
+            "fromlonlat" if remaining >= 2 && origin.is_none() => {
+                let (lon, lat) = lon_lat(args, idx + 1)?;
+                origin = Some(GeoOrigin::LonLat(lon, lat));
+                idx += 2;
+            }
+            "frommember" | "fromlonlat" if origin.is_some() => {
+                bail!("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH")
+            }
+            "byradius" if remaining >= 2 && shape.is_none() => {
+                let radius = args.float(idx + 1)?;
+                if radius < 0.0 {
+                    bail!("ERR radius cannot be negative");
+                }
+                unit = distance_unit(args, idx + 2)?;
+                shape = Some(geo::Shape::Radius(radius * unit));
+                idx += 2;
+            }
+            "bybox" if remaining >= 3 && shape.is_none() => {
+                let (width, height) = (args.float(idx + 1)?, args.float(idx + 2)?);
+                if width < 0.0 || height < 0.0 {
+                    bail!("ERR height or width cannot be negative");
+                }
+                unit = distance_unit(args, idx + 3)?;
+                shape = Some(geo::Shape::Box {
+                    width: width * unit,
+                    height: height * unit,
+                });
+                idx += 3;
+            }
+            "byradius" | "bybox" if shape.is_some() => {
+                bail!("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH")
+            }
+            "asc" => desc = Some(false),
+            "desc" => desc = Some(true),
+            "count" if remaining >= 1 => {
+                let n = args.integer::<i64>(idx + 1)?;
+                if n <= 0 {
+                    bail!("ERR COUNT must be > 0");
+                }
+                count = Some(n as usize);
+                idx += 1;
+            }
+            "any" => any = true,
+            "withcoord" => withcoord = true,
+            "withdist" => withdist = true,
+            "withhash" => withhash = true,
+            _ => bail!(SYNTAX_ERR_MSG),
+        }
+        idx += 1;
+    }
+    let Some(origin) = origin else {
+        bail!("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH");
+    };
+    let Some(shape) = shape else {
+        bail!("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH");
+    };
+    if any && count.is_none() {
+        bail!("ERR the ANY argument requires COUNT argument");
+    }
+
+    // Members found, with their distance and their score. Every member is
+    // checked, in the order of the sorted set.
+    let found = store.with_sorted_set(&args.string(1)?, |zset| {
+        let center = match &origin {
+            GeoOrigin::Member(member) => match zset.score(member) {
+                Some(score) => geo::decode(score as u64),
+                None => bail!("ERR could not decode requested zset member"),
+            },
+            GeoOrigin::LonLat(lon, lat) => (*lon, *lat),
+        };
+        let mut found = Vec::new();
+        for (member, score) in zset.iter() {
+            let position = geo::decode(score as u64);
+            if let Some(distance) = shape.distance_if_within(center, position) {
+                found.push((member.clone(), distance, score));
+                // With ANY, the first members found are enough
+                if any && Some(found.len()) == count {
+                    break;
+                }
+            }
+        }
+        Ok(found)
+    })?;
+    let mut found = found.transpose()?.unwrap_or_default();
+
+    // Like Redis, COUNT without ANY returns the closest members
+    let desc = desc.or((count.is_some() && !any).then_some(false));
+    if let Some(desc) = desc {
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if desc {
+            found.reverse();
+        }
+    }
+    found.truncate(count.unwrap_or(usize::MAX));
+
+    let items = found
+        .into_iter()
+        .map(|(member, distance, score)| {
+            if !(withcoord || withdist || withhash) {
+                return Data::BulkString(member.into());
+            }
+            let mut item = vec![Data::BulkString(member.into())];
+            if withdist {
+                item.push(format_distance(distance / unit));
+            }
+            if withhash {
+                item.push(Data::Integer(score as i64));
+            }
+            if withcoord {
+                item.push(position_to_array(geo::decode(score as u64)));
+            }
+            Data::Array(item)
+        })
+        .collect();
+    Ok(Outcome::read(Data::Array(items)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use crate::store::{EncodingLimits, ListpackLimits, INVALID_HLL_ERR_MSG};
+    use std::collections::{HashMap, HashSet};
+    use std::ops::Bound;
+    use std::thread;
+
+    fn run(store: &Store, cmd: &str) -> Outcome {
+        let vs = cmd
+            .split_ascii_whitespace()
+            .map(|s| Data::BulkString(s.into()))
+            .collect::<Vec<_>>();
+        execute(store, &vs)
+    }
+
+    fn reply(store: &Store, cmd: &str) -> Data {
+        run(store, cmd).reply
+    }
+
+    fn bulk(s: &str) -> Data {
+        Data::BulkString(s.into())
+    }
+
+    fn is_wrong_type(data: Data) -> bool {
+        data == Data::SimpleError(crate::value::WRONG_TYPE_ERR_MSG.into())
+    }
+
+    #[test]
+    fn replicated_commands_are_writes() {
+        let store = Store::new();
+        for cmd in [
+            "SET s 1",
+            "GET s",
+            "SETBIT b 1 1",
+            "BITCOUNT b",
+            "EXPIREAT s 99999999999",
+            "PFADD p a",
+            "PFCOUNT p",
+            "HSET h f 1",
+            "HINCRBYFLOAT h f 1",
+            "HGET h f",
+            "SADD t a b",
+            "SMEMBERS t",
+            "SPOP t",
+            "ZADD z 1 a 2 b",
+            "ZRANGE z 0 -1",
+            "ZRANGESTORE y z 0 -1",
+            "ZPOPMIN z",
+            "XADD x * a 1",
+            "XRANGE x - +",
+            "GEOADD g 1 1 a",
+            "GEOPOS g a",
+            "RENAME s r",
+            "DEL r",
+        ] {
+            let name = cmd.split(' ').next().unwrap().to_ascii_lowercase();
+            let outcome = run(&store, cmd);
+            assert!(!matches!(outcome.reply, Data::SimpleError(_)), "{}", cmd);
+            assert_eq!(is_write(&name), !outcome.replicate.is_empty(), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn expiretime() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "EXPIRETIME a"), Data::Integer(-2));
+        run(&store, "SET a 1");
+        assert_eq!(reply(&store, "PEXPIRETIME a"), Data::Integer(-1));
+
+        run(&store, "SET a 1 PX 10000");
+        let expected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64
+            + 10_000;
+        let Data::Integer(ms) = reply(&store, "PEXPIRETIME a") else {
+            panic!("expected integer");
+        };
+        assert!((expected - 10_000..=expected).contains(&ms), "{}", ms);
+        assert_eq!(reply(&store, "EXPIRETIME a"), Data::Integer(ms / 1000));
+
+        run(&store, "SET b 1 EXAT 2000000000");
+        assert_eq!(reply(&store, "EXPIRETIME b"), Data::Integer(2000000000));
+        assert_eq!(reply(&store, "PEXPIRETIME b"), Data::Integer(2000000000000));
+    }
+
+    #[test]
+    fn set_conditions_and_get() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "SET a 1 XX"), Data::NullBulkString);
+        assert_eq!(reply(&store, "SET a 1 NX"), Data::SimpleString("OK".into()));
+        let outcome = run(&store, "SET a 2 NX");
+        assert_eq!(outcome.reply, Data::NullBulkString);
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "SET a 2 xx get"), bulk("1"));
+        assert_eq!(reply(&store, "SET b 1 GET"), Data::NullBulkString);
+        // With GET, the previous value is returned even if nothing is set
+        assert_eq!(reply(&store, "SET a 3 NX GET"), bulk("2"));
+        assert_eq!(reply(&store, "GET a"), bulk("2"));
+
+        run(&store, "SADD s x");
+        assert!(is_wrong_type(reply(&store, "SET s 1 GET")));
+        assert_eq!(reply(&store, "TYPE s"), Data::SimpleString("set".into()));
+        // Without GET, values of any type are overwritten
+        assert_eq!(reply(&store, "SET s 1"), Data::SimpleString("OK".into()));
+    }
+
+    #[test]
+    fn set_expirations() {
+        let store = Store::new();
+        let replica = Store::new();
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        for (cmd, ttl_ms) in [
+            ("SET a 1 EX 100".to_string(), 100_000),
+            ("SET a 1 PX 5000".to_string(), 5_000),
+            (format!("SET a 1 EXAT {}", now_ms / 1000 + 100), 100_000),
+            (format!("SET a 1 PXAT {}", now_ms + 5000), 5_000),
+        ] {
+            let outcome = run(&store, &cmd);
+            let Data::Array(replicated) = &outcome.replicate[0] else {
+                panic!("expected array");
+            };
+            // Always replicated with a relative PX
+            assert_eq!(
+                replicated[..4],
+                [bulk("SET"), bulk("a"), bulk("1"), bulk("PX")]
+            );
+            let Data::BulkString(ms) = &replicated[4] else {
+                panic!("expected bulk string");
+            };
+            let ms: u64 = String::from_utf8(ms.clone()).unwrap().parse().unwrap();
+            assert!(ms <= ttl_ms && ms + 2000 > ttl_ms, "{}: {}", cmd, ms);
+            replay(&replica, outcome);
+            assert_eq!(reply(&replica, "GET a"), bulk("1"));
+        }
+
+        // KEEPTTL keeps the expiration, which a plain SET removes
+        let replicated = |cmd| match &run(&store, cmd).replicate[..] {
+            [Data::Array(replicated)] => replicated.len(),
+            replicate => panic!("unexpected {:?}", replicate),
+        };
+        assert_eq!(replicated("SET a 2 KEEPTTL"), 5);
+        assert_eq!(replicated("SET a 3"), 3);
+        assert_eq!(replicated("SET a 4 KEEPTTL"), 3);
+
+        // A time in the past deletes the key
+        let outcome = run(&store, "SET a 5 PXAT 1000");
+        assert_eq!(outcome.reply, Data::SimpleString("OK".into()));
+        assert_eq!(outcome.replicate, vec![strings(&["DEL", "a"])]);
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "GET a"), Data::NullBulkString);
+        assert_eq!(reply(&store, "GET a"), Data::NullBulkString);
+
+        for (cmd, err) in [
+            ("SET a 1 EX 0", SET_EXPIRE_ERR_MSG),
+            ("SET a 1 PX -5", SET_EXPIRE_ERR_MSG),
+            ("SET a 1 EX 10 PX 10", SYNTAX_ERR_MSG),
+            ("SET a 1 EX 10 KEEPTTL", SYNTAX_ERR_MSG),
+            ("SET a 1 NX XX", SYNTAX_ERR_MSG),
+            ("SET a 1 EX", SYNTAX_ERR_MSG),
+            ("SET a 1 EX ten", NOT_INTEGER_ERR_MSG),
+            ("SET a 1 FOO", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        // Repeating an option is fine
+        assert_eq!(
+            reply(&store, "SET a 1 NX NX EX 1 EX 10"),
+            Data::SimpleString("OK".into())
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SET a 1 PX 100"));
+        replay(&replica, run(&store, "SADD b x"));
+
+        let outcome = run(&store, "RENAME a b");
+        assert_eq!(outcome.reply, Data::SimpleString("OK".into()));
+        assert_eq!(outcome.modified, vec!["a", "b"]);
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "GET a"), Data::NullBulkString);
+            assert_eq!(reply(store, "GET b"), bulk("1"));
+        }
+        assert_eq!(
+            reply(&store, "RENAME a c"),
+            Data::SimpleError("ERR no such key".into())
+        );
+        assert_eq!(reply(&store, "RENAME b b"), Data::SimpleString("OK".into()));
+
+        // The expiration moved with the value
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(reply(&store, "GET b"), Data::NullBulkString);
+    }
+
+    #[test]
+    fn renamenx() {
+        let store = Store::new();
+        run(&store, "SET a 1");
+        run(&store, "SET b 2");
+
+        let outcome = run(&store, "RENAMENX a b");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "RENAMENX a a"), Data::Integer(0));
+        assert_eq!(reply(&store, "RENAMENX a c"), Data::Integer(1));
+        assert_eq!(reply(&store, "GET c"), bulk("1"));
+        assert_eq!(reply(&store, "GET b"), bulk("2"));
+        assert_eq!(
+            reply(&store, "RENAMENX a d"),
+            Data::SimpleError("ERR no such key".into())
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let store = Store::new();
+        run(&store, "SET hello 1");
+        run(&store, "SET hallo 1");
+        run(&store, "SET hxllo 1");
+        run(&store, "SET h*llo 1");
+        run(&store, "SADD other x");
+        run(&store, "SET gone 1 PX 1");
+        thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(
+            sorted(reply(&store, "KEYS *")),
+            vec!["h*llo", "hallo", "hello", "hxllo", "other"]
+        );
+        assert_eq!(sorted(reply(&store, "KEYS h?llo")).len(), 4);
+        assert_eq!(
+            sorted(reply(&store, "KEYS h[ae]llo")),
+            vec!["hallo", "hello"]
+        );
+        assert_eq!(
+            sorted(reply(&store, "KEYS h[^e]llo")),
+            vec!["h*llo", "hallo", "hxllo"]
+        );
+        assert_eq!(sorted(reply(&store, "KEYS h\\*llo")), vec!["h*llo"]);
+        assert!(sorted(reply(&store, "KEYS nothing*")).is_empty());
+    }
+
+    #[test]
+    fn randomkey() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "RANDOMKEY"), Data::NullBulkString);
+
+        for i in 0..100 {
+            run(&store, &format!("SET key:{} {}", i, i));
+        }
+        run(&store, "SET gone 1 PX 1");
+        thread::sleep(Duration::from_millis(5));
+
+        let mut seen = HashSet::new();
+        for _ in 0..500 {
+            let key = reply(&store, "RANDOMKEY").get_string().unwrap();
+            assert!(key.starts_with("key:"), "{}", key);
+            assert!(key["key:".len()..].parse::<u32>().unwrap() < 100);
+            seen.insert(key);
+        }
+        // Not always the same key
+        assert!(seen.len() > 50);
+    }
+
+    fn copy_reply(dbs: &StoreDb, db: usize, cmd: &str) -> Data {
+        let vs = cmd
+            .split_ascii_whitespace()
+            .map(|s| Data::BulkString(s.into()))
+            .collect::<Vec<_>>();
+        copy(dbs, db, &Args::new(&vs))
+            .map(|copied| copied.outcome.reply)
+            .unwrap_or_else(|err| Data::SimpleError(err.to_string()))
+    }
+
+    #[test]
+    fn copy_within_db() {
+        let dbs = StoreDb::new();
+        let store = dbs.get(0);
+        run(store, "SADD s a b");
+        run(store, "SET t x PX 100");
+
+        assert_eq!(copy_reply(&dbs, 0, "COPY s s2"), Data::Integer(1));
+        assert_eq!(members(store, "s2"), vec!["a", "b"]);
+        // The copy is independent of the source
+        run(store, "SADD s c");
+        assert_eq!(members(store, "s2"), vec!["a", "b"]);
+
+        // The destination isn't overwritten without REPLACE
+        assert_eq!(copy_reply(&dbs, 0, "COPY t s2"), Data::Integer(0));
+        assert_eq!(members(store, "s2"), vec!["a", "b"]);
+        assert_eq!(copy_reply(&dbs, 0, "COPY t s2 REPLACE"), Data::Integer(1));
+        assert_eq!(reply(store, "GET s2"), bulk("x"));
+        assert_eq!(copy_reply(&dbs, 0, "COPY missing s2"), Data::Integer(0));
+
+        // The expiration is copied too
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(reply(store, "GET s2"), Data::NullBulkString);
+    }
+
+    #[test]
+    fn copy_across_dbs() {
+        let dbs = StoreDb::new();
+        run(dbs.get(0), "SET a 1");
+        run(dbs.get(3), "SET a 2");
+
+        assert_eq!(copy_reply(&dbs, 0, "COPY a a DB 3"), Data::Integer(0));
+        let vs: Vec<_> = ["COPY", "a", "a", "db", "3", "replace"]
+            .iter()
+            .map(|s| bulk(s))
+            .collect();
+        let copied = copy(&dbs, 0, &Args::new(&vs)).unwrap();
+        assert_eq!(copied.outcome.reply, Data::Integer(1));
+        assert_eq!(copied.outcome.modified, vec!["a"]);
+        assert_eq!(copied.destination_db, 3);
+        assert_eq!(reply(dbs.get(3), "GET a"), bulk("1"));
+        assert_eq!(copy_reply(&dbs, 3, "COPY a b DB 0"), Data::Integer(1));
+        assert_eq!(reply(dbs.get(0), "GET b"), bulk("1"));
+
+        for (cmd, err) in [
+            (
+                "COPY a a",
+                "ERR source and destination objects are the same",
+            ),
+            (
+                "COPY a a DB 0",
+                "ERR source and destination objects are the same",
+            ),
+            ("COPY a b DB 16", "ERR DB index is out of range"),
+            ("COPY a b DB", SYNTAX_ERR_MSG),
+            ("COPY a b FOO", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(
+                copy_reply(&dbs, 0, cmd),
+                Data::SimpleError(err.into()),
+                "{}",
+                cmd
+            );
+        }
+    }
+
+    #[test]
+    fn copies_are_replicated_as_their_values() {
+        let dbs = StoreDb::new();
+        let replica = StoreDb::new();
+        let copy_to_replica = |cmd: &str| {
+            let vs: Vec<_> = cmd.split_ascii_whitespace().map(bulk).collect();
+            let copied = copy(&dbs, 0, &Args::new(&vs)).unwrap();
+            replay(replica.get(copied.replication_db), copied.outcome);
+        };
+        let store = dbs.get(0);
+        run(store, "SET s x PX 100000");
+        run(store, "HSET h a 1 b 2");
+        run(store, "HPEXPIRE h 100000 FIELDS 1 a");
+        run(store, "PEXPIREAT h 99999999999999");
+        run(store, "SADD set a b");
+        run(store, "ZADD z 1 a 2.5 b");
+        // The replica doesn't have the sources, or has different ones
+        run(replica.get(0), "SET s y");
+
+        copy_to_replica("COPY s s2");
+        let replica0 = replica.get(0);
+        assert_eq!(reply(replica0, "GET s2"), bulk("x"));
+        let (Data::Integer(ms), Data::Integer(expected)) = (
+            reply(replica0, "PEXPIRETIME s2"),
+            reply(store, "PEXPIRETIME s"),
+        ) else {
+            panic!("expected expirations");
+        };
+        assert!((ms - expected).abs() < 1000, "{} {}", ms, expected);
+
+        copy_to_replica("COPY h h2 DB 2");
+        let replica2 = replica.get(2);
+        assert_eq!(reply(replica2, "HGET h2 b"), bulk("2"));
+        assert_eq!(
+            reply(replica2, "HEXPIRETIME h2 FIELDS 2 a b"),
+            reply(store, "HEXPIRETIME h FIELDS 2 a b")
+        );
+        assert_eq!(
+            reply(replica2, "PEXPIRETIME h2"),
+            reply(store, "PEXPIRETIME h")
+        );
+
+        run(replica0, "SADD set2 stale");
+        copy_to_replica("COPY set set2 REPLACE");
+        assert_eq!(members(replica0, "set2"), vec!["a", "b"]);
+
+        copy_to_replica("COPY z z2");
+        assert_eq!(
+            reply(replica0, "ZRANGE z2 0 -1 WITHSCORES"),
+            reply(store, "ZRANGE z 0 -1 WITHSCORES")
+        );
+    }
+
+    #[test]
+    fn hset_and_hget() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "HSET h a 1 b 2"), Data::Integer(2));
+        assert_eq!(reply(&store, "HGET h a"), bulk("1"));
+        assert_eq!(reply(&store, "HGET h c"), Data::NullBulkString);
+        assert_eq!(reply(&store, "HGET missing a"), Data::NullBulkString);
+
+        // Updating an existing field doesn't count as a new field
+        assert_eq!(reply(&store, "HSET h a 10 c 3"), Data::Integer(1));
+        assert_eq!(reply(&store, "HSET h a 100"), Data::Integer(0));
+        assert_eq!(reply(&store, "HGET h a"), bulk("100"));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("hash".into()));
+    }
+
+    #[test]
+    fn hset_wrong_number_of_args() {
+        let store = Store::new();
+        assert!(matches!(reply(&store, "HSET h a"), Data::SimpleError(_)));
+        assert!(matches!(
+            reply(&store, "HSET h a 1 b"),
+            Data::SimpleError(_)
+        ));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn hgetall_is_flat_array_of_pairs() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "HGETALL h"), Data::Array(Vec::new()));
+
+        run(&store, "HSET h a 1 b 2 c 3");
+        let Data::Array(vs) = reply(&store, "HGETALL h") else {
+            panic!("expect array");
+        };
+        assert_eq!(vs.len(), 6);
+
+        let mut pairs = vs
+            .chunks_exact(2)
+            .map(|kv| (kv[0].get_string().unwrap(), kv[1].get_string().unwrap()))
+            .collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), "3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn hdel_and_hexists() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2 c 3");
+        assert_eq!(reply(&store, "HEXISTS h a"), Data::Integer(1));
+        assert_eq!(reply(&store, "HDEL h a b x"), Data::Integer(2));
+        assert_eq!(reply(&store, "HEXISTS h a"), Data::Integer(0));
+        assert_eq!(reply(&store, "HDEL missing a"), Data::Integer(0));
+
+        // Deleting the last field removes the key
+        assert_eq!(reply(&store, "HDEL h c"), Data::Integer(1));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn hmget() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2");
+        assert_eq!(
+            reply(&store, "HMGET h b x a"),
+            Data::Array(vec![bulk("2"), Data::NullBulkString, bulk("1")])
+        );
+
+        // Missing key: all nils
+        assert_eq!(
+            reply(&store, "HMGET missing a b"),
+            Data::Array(vec![Data::NullBulkString, Data::NullBulkString])
+        );
+    }
+
+    #[test]
+    fn hkeys_hvals_hlen_hstrlen() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 22 c 333");
+
+        let sorted = |data: Data| {
+            let Data::Array(vs) = data else {
+                panic!("expect array");
+            };
+            let mut vs = vs
+                .into_iter()
+                .map(|v| v.get_string().unwrap())
+                .collect::<Vec<_>>();
+            vs.sort();
+            vs
+        };
+        assert_eq!(sorted(reply(&store, "HKEYS h")), vec!["a", "b", "c"]);
+        assert_eq!(sorted(reply(&store, "HVALS h")), vec!["1", "22", "333"]);
+        assert_eq!(reply(&store, "HLEN h"), Data::Integer(3));
+        assert_eq!(reply(&store, "HSTRLEN h c"), Data::Integer(3));
+        assert_eq!(reply(&store, "HSTRLEN h x"), Data::Integer(0));
+
+        // Missing keys are empty, not errors
+        assert_eq!(reply(&store, "HKEYS missing"), Data::Array(Vec::new()));
+        assert_eq!(reply(&store, "HVALS missing"), Data::Array(Vec::new()));
+        assert_eq!(reply(&store, "HLEN missing"), Data::Integer(0));
+        assert_eq!(reply(&store, "HSTRLEN missing a"), Data::Integer(0));
+    }
+
+    #[test]
+    fn hincrby() {
+        let store = Store::new();
+
+        // Missing key and field are created
+        assert_eq!(reply(&store, "HINCRBY h a 5"), Data::Integer(5));
+        assert_eq!(reply(&store, "HINCRBY h a -7"), Data::Integer(-2));
+        assert_eq!(reply(&store, "HGET h a"), bulk("-2"));
+
+        run(&store, "HSET h s abc");
+        assert_eq!(
+            reply(&store, "HINCRBY h s 1"),
+            Data::SimpleError("ERR hash value is not an integer".into())
+        );
+        assert_eq!(
+            reply(&store, "HINCRBY h a x"),
+            Data::SimpleError(NOT_INTEGER_ERR_MSG.into())
+        );
+
+        // Overflow is an error, and leaves the value alone
+        run(&store, &format!("HSET h big {}", i64::MAX - 1));
+        assert_eq!(
+            reply(&store, "HINCRBY h big 2"),
+            Data::SimpleError("ERR increment or decrement would overflow".into())
+        );
+        assert_eq!(
+            reply(&store, "HGET h big"),
+            bulk(&(i64::MAX - 1).to_string())
+        );
+    }
+
+    #[test]
+    fn hincrbyfloat() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "HINCRBYFLOAT h a 10.5"), bulk("10.5"));
+        assert_eq!(reply(&store, "HINCRBYFLOAT h a 0.1"), bulk("10.6"));
+        assert_eq!(reply(&store, "HINCRBYFLOAT h a -5.6"), bulk("5"));
+        assert_eq!(reply(&store, "HINCRBYFLOAT h a 2.0e2"), bulk("205"));
+        assert_eq!(
+            reply(&store, "HINCRBYFLOAT h b 1e20"),
+            bulk("100000000000000000000")
+        );
+        assert_eq!(reply(&store, "HINCRBYFLOAT h c 1e-5"), bulk("0.00001"));
+
+        run(&store, "HSET h s abc");
+        assert_eq!(
+            reply(&store, "HINCRBYFLOAT h s 1"),
+            Data::SimpleError("ERR hash value is not a float".into())
+        );
+        assert_eq!(
+            reply(&store, "HINCRBYFLOAT h a nan"),
+            Data::SimpleError(NOT_FLOAT_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "HINCRBYFLOAT h a inf"),
+            Data::SimpleError("ERR increment would produce NaN or Infinity".into())
+        );
+        // No empty hash is left behind
+        assert_eq!(
+            reply(&store, "HINCRBYFLOAT missing a -inf"),
+            Data::SimpleError("ERR increment would produce NaN or Infinity".into())
+        );
+        assert_eq!(
+            reply(&store, "TYPE missing"),
+            Data::SimpleString("none".into())
+        );
+    }
+
+    #[test]
+    fn hincrbyfloat_is_replicated_as_hset() {
+        let store = Store::new();
+        let outcome = run(&store, "HINCRBYFLOAT h a 1.5");
+        assert_eq!(
+            outcome.replicate,
+            vec![Data::Array(vec![
+                bulk("HSET"),
+                bulk("h"),
+                bulk("a"),
+                bulk("1.5")
+            ])]
+        );
+    }
+
+    #[test]
+    fn hash_wrong_type() {
+        let store = Store::new();
+        run(&store, "SET s v");
+        assert!(is_wrong_type(reply(&store, "HSET s a 1")));
+        assert!(is_wrong_type(reply(&store, "HGET s a")));
+        assert!(is_wrong_type(reply(&store, "HGETALL s")));
+        assert!(is_wrong_type(reply(&store, "HDEL s a")));
+        assert!(is_wrong_type(reply(&store, "HEXISTS s a")));
+        assert!(is_wrong_type(reply(&store, "HMGET s a")));
+        assert!(is_wrong_type(reply(&store, "HKEYS s")));
+        assert!(is_wrong_type(reply(&store, "HVALS s")));
+        assert!(is_wrong_type(reply(&store, "HLEN s")));
+        assert!(is_wrong_type(reply(&store, "HSTRLEN s a")));
+
+        run(&store, "HSET h a 1");
+        assert!(is_wrong_type(reply(&store, "GET h")));
+    }
+
+    #[test]
+    fn hash_writes_are_replicated() {
+        let store = Store::new();
+        assert_eq!(run(&store, "HSET h a 1").replicate.len(), 1);
+        assert_eq!(run(&store, "HGET h a").replicate.len(), 0);
+        assert_eq!(run(&store, "HDEL h x").replicate.len(), 0);
+        assert_eq!(run(&store, "HDEL h a").replicate.len(), 1);
+
+        // Replaying the replicated commands gives the same hash
+        let replica = Store::new();
+        for cmd in run(&store, "HSET h a 1 b 2").replicate {
+            let Data::Array(vs) = cmd else { panic!() };
+            execute(&replica, &vs);
+        }
+        assert_eq!(reply(&replica, "HGET h b"), bulk("2"));
+    }
+
+    #[test]
+    fn hsetnx() {
+        let store = Store::new();
+
+        // Creates the hash if the key is missing
+        assert_eq!(reply(&store, "HSETNX h a 1"), Data::Integer(1));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("hash".into()));
+
+        // Doesn't overwrite an existing field
+        assert_eq!(reply(&store, "HSETNX h a 2"), Data::Integer(0));
+        assert_eq!(reply(&store, "HGET h a"), bulk("1"));
+        assert_eq!(reply(&store, "HSETNX h b 2"), Data::Integer(1));
+        assert_eq!(reply(&store, "HLEN h"), Data::Integer(2));
+
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "HSETNX s a 1")));
+        assert!(matches!(reply(&store, "HSETNX h a"), Data::SimpleError(_)));
+    }
+
+    #[test]
+    fn hsetnx_is_replicated_only_when_it_writes() {
+        let store = Store::new();
+        let replica = Store::new();
+
+        let mut replicated = Vec::new();
+        for cmd in ["HSETNX h a 1", "HSETNX h a 2", "HSETNX h a 3"] {
+            replicated.extend(run(&store, cmd).replicate);
+        }
+        assert_eq!(replicated.len(), 1);
+
+        for cmd in replicated {
+            let Data::Array(vs) = cmd else { panic!() };
+            execute(&replica, &vs);
+        }
+        assert_eq!(reply(&replica, "HGET h a"), bulk("1"));
+    }
+
+    #[test]
+    fn hrandfield_single() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "HRANDFIELD missing"), Data::NullBulkString);
+        assert_eq!(reply(&store, "HRANDFIELD missing 3"), Data::Array(vec![]));
+
+        run(&store, "HSET h a 1 b 2 c 3");
+        for _ in 0..10 {
+            let field = reply(&store, "HRANDFIELD h");
+            assert!([bulk("a"), bulk("b"), bulk("c")].contains(&field));
+        }
+        assert_eq!(reply(&store, "HRANDFIELD h 0"), Data::Array(vec![]));
+    }
+
+    #[test]
+    fn hrandfield_positive_count_is_distinct() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2 c 3 d 4 e 5");
+
+        for _ in 0..10 {
+            let Data::Array(fields) = reply(&store, "HRANDFIELD h 3") else {
+                panic!("expected array");
+            };
+            assert_eq!(fields.len(), 3);
+            let mut distinct = fields.clone();
+            distinct.sort_by_key(|d| d.to_string());
+            distinct.dedup();
+            assert_eq!(distinct.len(), 3);
+        }
+
+        // Asking for more fields than there are returns each field once
+        let Data::Array(mut fields) = reply(&store, "HRANDFIELD h 10") else {
+            panic!("expected array");
+        };
+        fields.sort_by_key(|d| d.to_string());
+        assert_eq!(
+            fields,
+            vec![bulk("a"), bulk("b"), bulk("c"), bulk("d"), bulk("e")]
+        );
+    }
+
+    #[test]
+    fn hrandfield_negative_count_may_repeat() {
+        let store = Store::new();
+        run(&store, "HSET h a 1");
+        assert_eq!(
+            reply(&store, "HRANDFIELD h -3"),
+            Data::Array(vec![bulk("a"), bulk("a"), bulk("a")])
+        );
+
+        run(&store, "HSET h b 2");
+        let Data::Array(fields) = reply(&store, "HRANDFIELD h -20") else {
+            panic!("expected array");
+        };
+        assert_eq!(fields.len(), 20);
+        assert!(fields.iter().all(|f| *f == bulk("a") || *f == bulk("b")));
+    }
+
+    #[test]
+    fn hrandfield_withvalues() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2 c 3");
+
+        for cmd in ["HRANDFIELD h 2 WITHVALUES", "HRANDFIELD h -5 withvalues"] {
+            let Data::Array(items) = reply(&store, cmd) else {
+                panic!("expected array");
+            };
+            for pair in items.chunks(2) {
+                let field = pair[0].get_string().unwrap();
+                let value = reply(&store, &format!("HGET h {}", field));
+                assert_eq!(pair[1], value);
+            }
+        }
+
+        assert_eq!(
+            reply(&store, "HRANDFIELD h 2 VALUES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "HRANDFIELD h x"),
+            Data::SimpleError(NOT_INTEGER_ERR_MSG.into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "HRANDFIELD s")));
+    }
+
+    #[test]
+    fn hrandfield_is_deterministic_given_seed() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2 c 3 d 4 e 5");
+
+        for count in [2, -8] {
+            let first = store.hrandfield("h", count, &mut Rng::new(3)).unwrap();
+            let second = store.hrandfield("h", count, &mut Rng::new(3)).unwrap();
+            assert_eq!(first, second);
+        }
+    }
+
+    // Runs HSCAN until the cursor gets back to 0. Returns the items of all
+    // batches and the number of batches.
+    fn hscan_all(store: &Store, key: &str, options: &str) -> (Vec<String>, usize) {
+        scan_all(store, &format!("HSCAN {}", key), options)
+    }
+
+    // Runs `command` with cursors until the scan is done. Returns all the
+    // items and the number of batches.
+    fn scan_all(store: &Store, command: &str, options: &str) -> (Vec<String>, usize) {
+        let mut items = Vec::new();
+        let mut num_batches = 0;
+        let mut cursor = "0".to_string();
+        loop {
+            let Data::Array(reply) = reply(store, &format!("{} {} {}", command, cursor, options))
+            else {
+                panic!("expected array");
+            };
+            let [next, Data::Array(batch)] = reply.as_slice() else {
+                panic!("expected cursor and array");
+            };
+            items.extend(batch.iter().map(|d| d.get_string().unwrap()));
+            num_batches += 1;
+
+            cursor = next.get_string().unwrap();
+            if cursor == "0" {
+                return (items, num_batches);
+            }
+        }
+    }
+
+    fn hset_many(store: &Store, key: &str, num_fields: usize) {
+        let kvs: Vec<String> = (0..num_fields)
+            .map(|i| format!("field:{} value:{}", i, i))
+            .collect();
+        run(store, &format!("HSET {} {}", key, kvs.join(" ")));
+    }
+
+    #[test]
+    fn hscan_covers_all_fields() {
+        let store = Store::new();
+        hset_many(&store, "h", 1000);
+
+        let (items, num_batches) = hscan_all(&store, "h", "COUNT 50");
+        assert_eq!(num_batches, 20);
+        assert_eq!(items.len(), 2000);
+
+        let mut fields = HashSet::new();
+        for pair in items.chunks(2) {
+            assert_eq!(pair[1], pair[0].replace("field", "value"));
+            assert!(fields.insert(pair[0].clone()));
+        }
+        assert_eq!(fields.len(), 1000);
+    }
+
+    #[test]
+    fn hscan_match() {
+        let store = Store::new();
+        hset_many(&store, "h", 1000);
+
+        let (items, _) = hscan_all(&store, "h", "MATCH field:1?? COUNT 50");
+        let mut fields: Vec<_> = items.chunks(2).map(|pair| pair[0].clone()).collect();
+        fields.sort();
+        let expected: Vec<_> = (100..200).map(|i| format!("field:{}", i)).collect();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn hscan_novalues() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2 c 3");
+
+        let (mut fields, num_batches) = hscan_all(&store, "h", "NOVALUES");
+        assert_eq!(num_batches, 1);
+        fields.sort();
+        assert_eq!(fields, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn hscan_errors() {
+        let store = Store::new();
+        assert_eq!(
+            reply(&store, "HSCAN missing 0"),
+            Data::Array(vec![bulk("0"), Data::Array(vec![])])
+        );
+
+        run(&store, "HSET h a 1");
+        assert_eq!(
+            reply(&store, "HSCAN h x"),
+            Data::SimpleError("ERR invalid cursor".into())
+        );
+        for options in ["COUNT 0", "COUNT", "MATCH", "FOO"] {
+            assert_eq!(
+                reply(&store, &format!("HSCAN h 0 {}", options)),
+                Data::SimpleError(SYNTAX_ERR_MSG.into())
+            );
+        }
+
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "HSCAN s 0")));
+    }
+
+    #[test]
+    fn scan_covers_all_keys() {
+        let store = Store::new();
+        for i in 0..500 {
+            run(&store, &format!("SET key:{} {}", i, i));
+        }
+
+        let (keys, num_batches) = scan_all(&store, "SCAN", "COUNT 20");
+        assert_eq!(num_batches, 25);
+        let keys: HashSet<_> = keys.into_iter().collect();
+        assert_eq!(keys.len(), 500);
+
+        let (mut keys, _) = scan_all(&store, "SCAN", "MATCH key:4?? COUNT 20");
+        keys.sort();
+        let expected: Vec<_> = (400..500).map(|i| format!("key:{}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn xlen() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(0));
+        for id in ["1-1", "1-2", "2-0"] {
+            store
+                .stream_set("s".into(), id.into(), vec![("a".into(), "1".into())])
+                .unwrap();
+        }
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(3));
+
+        store
+            .stream_remove("s", &[EntryId::create_from_complete("1-2".into()).unwrap()])
+            .unwrap();
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(2));
+
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "XLEN str")));
+        assert!(matches!(reply(&store, "XLEN"), Data::SimpleError(_)));
+    }
+
+    fn stream_ids(store: &Store, stream: &str) -> Vec<String> {
+        store
+            .get_stream_range(stream.into(), Bound::Unbounded, Bound::Unbounded, None)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reading_streams_does_not_create_them() {
+        let store = Store::new();
+        for cmd in [
+            "XRANGE s - +",
+            "XREVRANGE s + -",
+            "XLEN s",
+            "XINFO STREAM s",
+            "XPENDING s g",
+            "XADD s NOMKSTREAM * f v",
+            "XGROUP CREATE s g $",
+        ] {
+            run(&store, cmd);
+            assert_eq!(
+                reply(&store, "TYPE s"),
+                Data::SimpleString("none".into()),
+                "{}",
+                cmd
+            );
+        }
+        assert_eq!(reply(&store, "DBSIZE"), Data::Integer(0));
+    }
+
+    #[test]
+    fn xadd_never_reuses_deleted_ids() {
+        let store = Store::new();
+        let xadd = |cmd: &str| match reply(&store, cmd) {
+            Data::BulkString(id) => {
+                EntryId::create_from_complete(String::from_utf8(id.to_vec()).unwrap()).unwrap()
+            }
+            reply => panic!("expected id, got {:?}", reply),
+        };
+        xadd("XADD s 1-0 k 1");
+        xadd("XADD s 2-0 k 2");
+        let last = xadd("XADD s * k 3");
+
+        // Deleting the newest entry doesn't move the last generated id back
+        assert_eq!(reply(&store, &format!("XDEL s {}", last)), Data::Integer(1));
+        assert_eq!(
+            xinfo_fields(&store, "s")["last-generated-id"],
+            bulk(&last.to_string())
+        );
+        let next = xadd("XADD s * k 4");
+        assert!(next > last, "{} after {}", next, last);
+
+        // Nor does trimming everything
+        run(&store, "XTRIM s MAXLEN 0");
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(0));
+        assert_eq!(
+            xinfo_fields(&store, "s")["last-generated-id"],
+            bulk(&next.to_string())
+        );
+        assert!(matches!(
+            reply(&store, &format!("XADD s {} k 6", next)),
+            Data::SimpleError(_)
+        ));
+
+        // `<ms>-*` continues from the last id too
+        run(&store, "XADD t 5-3 k 1");
+        run(&store, "XDEL t 5-3");
+        assert_eq!(reply(&store, "XADD t 5-* k 2"), bulk("5-4"));
+    }
+
+    #[test]
+    fn xadd_nomkstream() {
+        let store = Store::new();
+        let replica = Store::new();
+
+        let outcome = run(&store, "XADD s NOMKSTREAM 1-0 a 1");
+        assert_eq!(outcome.reply, Data::NullBulkString);
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "TYPE s"), Data::SimpleString("none".into()));
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(0));
+
+        replay(&replica, run(&store, "XADD s 1-0 a 1"));
+        let outcome = run(&store, "XADD s NOMKSTREAM MAXLEN 1 * b 2");
+        let Data::BulkString(id) = &outcome.reply else {
+            panic!("expected id");
+        };
+        let id = String::from_utf8(id.clone()).unwrap();
+        assert_eq!(
+            outcome.replicate,
+            vec![
+                strings(&["XADD", "s", "NOMKSTREAM", &id, "b", "2"]),
+                strings(&["XTRIM", "s", "MAXLEN", "=", "1"]),
+            ]
+        );
+        replay(&replica, outcome);
+        assert_eq!(stream_ids(&replica, "s"), vec![id.clone()]);
+
+        // The options can come in any order
+        run(&store, "XADD s MAXLEN 5 NOMKSTREAM 9999999999999-0 c 3");
+        assert_eq!(stream_ids(&store, "s"), vec![id, "9999999999999-0".into()]);
+    }
+
+    #[test]
+    fn xadd_with_maxlen() {
+        let store = Store::new();
+        let replica = Store::new();
+        for i in 1..=8 {
+            let outcome = run(&store, &format!("XADD s MAXLEN 5 {}-0 k v", i));
+            assert_eq!(outcome.reply, bulk(&format!("{}-0", i)));
+            replay(&replica, outcome);
+        }
+        assert_eq!(reply(&store, "XLEN s"), Data::Integer(5));
+        assert_eq!(stream_ids(&store, "s"), ["4-0", "5-0", "6-0", "7-0", "8-0"]);
+        assert_eq!(stream_ids(&replica, "s"), stream_ids(&store, "s"));
+
+        // The generated id is replicated, with the trimming
+        let outcome = run(&store, "XADD s MAXLEN ~ 2 * k v");
+        let Data::BulkString(id) = &outcome.reply else {
+            panic!("expected id");
+        };
+        assert_eq!(
+            outcome.replicate,
+            vec![
+                Data::Array(vec![
+                    bulk("XADD"),
+                    bulk("s"),
+                    Data::BulkString(id.clone()),
+                    bulk("k"),
+                    bulk("v")
+                ]),
+                strings(&["XTRIM", "s", "MAXLEN", "=", "2"])
+            ]
+        );
+        replay(&replica, outcome);
+        assert_eq!(stream_ids(&replica, "s"), stream_ids(&store, "s"));
+
+        assert_eq!(
+            reply(&store, "XADD s MAXLEN -1 * k v"),
+            Data::SimpleError(MAXLEN_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "XADD s MAXLEN 1 * k"),
+            Data::SimpleError("ERR wrong number of arguments for 'xadd' command".into())
+        );
+    }
+
+    #[test]
+    fn xtrim() {
+        let store = Store::new();
+        let replica = Store::new();
+        for id in ["1-0", "2-0", "2-1", "3-0", "4-0"] {
+            for s in [&store, &replica] {
+                s.stream_set("s".into(), id.into(), vec![("k".into(), "v".into())])
+                    .unwrap();
+            }
+        }
+
+        // Entries with the minimum id are kept
+        let outcome = run(&store, "XTRIM s MINID 2-1");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        assert_eq!(
+            outcome.replicate,
+            vec![strings(&["XTRIM", "s", "MAXLEN", "=", "3"])]
+        );
+        replay(&replica, outcome);
+        assert_eq!(stream_ids(&replica, "s"), ["2-1", "3-0", "4-0"]);
+        assert_eq!(reply(&store, "XTRIM s MINID = 2-1"), Data::Integer(0));
+        // Without a sequence number, it's 0
+        assert_eq!(reply(&store, "XTRIM s MINID 3"), Data::Integer(1));
+
+        assert_eq!(
+            reply(&store, "XTRIM s MAXLEN ~ 0 LIMIT 1"),
+            Data::Integer(1)
+        );
+        assert_eq!(stream_ids(&store, "s"), ["4-0"]);
+        let outcome = run(&store, "XTRIM s MAXLEN 5");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "XTRIM missing MAXLEN 0"), Data::Integer(0));
+
+        for (cmd, err) in [
+            ("XTRIM s MAXLEN = 0 LIMIT 1", LIMIT_WITHOUT_APPROX_ERR_MSG),
+            ("XTRIM s MAXLEN ~ 0 LIMIT -1", LIMIT_ERR_MSG),
+            ("XTRIM s MAXLEN -1", MAXLEN_ERR_MSG),
+            ("XTRIM s MAXLEN 1 extra", SYNTAX_ERR_MSG),
+            ("XTRIM s MAXMEN 1", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "XTRIM str MAXLEN 0")));
+    }
+
+    #[test]
+    fn expireat() {
+        let store = Store::new();
+        let replica = Store::new();
+        assert_eq!(reply(&store, "PEXPIREAT a 1"), Data::Integer(0));
+        replay(&replica, run(&store, "SET a 1"));
+
+        let outcome = run(&store, "EXPIREAT a 2000000000");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        assert_eq!(
+            outcome.replicate,
+            vec![strings(&["PEXPIREAT", "a", "2000000000000"])]
+        );
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&replica, "PEXPIRETIME a"),
+            Data::Integer(2000000000000)
+        );
+        replay(&replica, run(&store, "PEXPIREAT a 2000000000123"));
+        assert_eq!(
+            reply(&replica, "PEXPIRETIME a"),
+            Data::Integer(2000000000123)
+        );
+
+        // A time that has passed deletes the key
+        let outcome = run(&store, "PEXPIREAT a 1000");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        assert_eq!(reply(&store, "GET a"), Data::NullBulkString);
+        assert_eq!(reply(&store, "PEXPIRETIME a"), Data::Integer(-2));
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "GET a"), Data::NullBulkString);
+
+        run(&store, "SET b 1");
+        assert_eq!(reply(&store, "EXPIREAT b -5"), Data::Integer(1));
+        assert_eq!(reply(&store, "GET b"), Data::NullBulkString);
+
+        assert_eq!(
+            reply(&store, "EXPIREAT b 9223372036854775807"),
+            Data::SimpleError("ERR invalid expire time in 'expireat' command".into())
+        );
+        assert_eq!(
+            reply(&store, "PEXPIREAT b x"),
+            Data::SimpleError(NOT_INTEGER_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn xsetid() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "XADD s 5-0 a 1"));
+
+        // Forward, so that generated ids come after the new one
+        let outcome = run(&store, "XSETID s 1000-5");
+        assert_eq!(outcome.reply, Data::SimpleString("OK".into()));
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "XADD s 1000-* b 2"), bulk("1000-6"));
+            assert!(matches!(
+                reply(store, "XADD s 1000-6 c 3"),
+                Data::SimpleError(_)
+            ));
+        }
+        let Data::BulkString(id) = reply(&store, "XADD s * c 3") else {
+            panic!("expected id");
+        };
+        let id = EntryId::create_from_complete(String::from_utf8(id).unwrap()).unwrap();
+        assert!(id > EntryId::create_from_complete("1000-6".into()).unwrap());
+
+        // Backwards, below the last entry
+        assert_eq!(
+            reply(&store, "XSETID s 6-0"),
+            Data::SimpleError(
+                "ERR The ID specified in XSETID is smaller than the target stream top item".into()
+            )
+        );
+
+        // `*` goes on from a last id in the future
+        run(&store, "XADD u 1-0 a 1");
+        run(&store, "XSETID u 99999999999999-7");
+        assert_eq!(reply(&store, "XADD u * a 2"), bulk("99999999999999-8"));
+        assert_eq!(reply(&store, "XADD u * a 3"), bulk("99999999999999-9"));
+
+        run(&store, "XADD t 5-0 a 1");
+        run(&store, "XDEL t 5-0");
+        // An empty stream can go back
+        assert_eq!(
+            reply(&store, "XSETID t 3 ENTRIESADDED 7 MAXDELETEDID 2-0"),
+            Data::SimpleString("OK".into())
+        );
+        let fields = xinfo_fields(&store, "t");
+        assert_eq!(fields["last-generated-id"], bulk("3-0"));
+        assert_eq!(fields["entries-added"], Data::Integer(7));
+        assert_eq!(fields["max-deleted-entry-id"], bulk("2-0"));
+
+        for (command, err) in [
+            ("XSETID missing 1-0", "ERR no such key"),
+            ("XSETID t 4-0 MAXDELETEDID 5-0", "max_deleted_entry_id"),
+            ("XSETID s 99999999999999-0 ENTRIESADDED 2", "entries_added"),
+            ("XSETID t 4-0 ENTRIESADDED -1", "must be positive"),
+            ("XSETID t 4-0 ENTRIESADDED", "syntax error"),
+            ("XSETID t 4-0 FOO 1", "syntax error"),
+        ] {
+            match reply(&store, command) {
+                Data::SimpleError(e) => assert!(e.contains(err), "{}: {}", command, e),
+                data => panic!("{}: expected error, got {:?}", command, data),
+            }
+        }
+    }
+
+    fn xinfo_fields(store: &Store, stream: &str) -> HashMap<String, Data> {
+        let Data::Array(fields) = reply(store, &format!("XINFO STREAM {}", stream)) else {
+            panic!("expected array");
+        };
+        fields
+            .chunks_exact(2)
+            .map(|pair| (pair[0].get_string().unwrap(), pair[1].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn xgroup() {
+        let store = Store::new();
+        let replica = Store::new();
+        let groups = |store: &Store| {
+            let Data::Array(groups) = reply(store, "XINFO GROUPS s") else {
+                panic!("expected array");
+            };
+            groups
+                .into_iter()
+                .map(|group| {
+                    let Data::Array(fields) = group else {
+                        panic!("expected group");
+                    };
+                    // The name, consumers and last delivered id
+                    (
+                        fields[1].get_string().unwrap(),
+                        fields[3].clone(),
+                        fields[7].get_string().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert!(matches!(
+            reply(&store, "XGROUP CREATE s g $"),
+            Data::SimpleError(e) if e.starts_with("ERR The XGROUP subcommand requires the key to exist")
+        ));
+        replay(&replica, run(&store, "XADD s 1-0 k v"));
+        replay(&replica, run(&store, "XADD s 2-0 k v"));
+
+        // `$` is replicated as the id it stands for
+        let outcome = run(&store, "XGROUP CREATE s latest $");
+        assert_eq!(
+            outcome.replicate,
+            vec![strings(&["XGROUP", "CREATE", "s", "latest", "2-0"])]
+        );
+        replay(&replica, outcome);
+        replay(&replica, run(&store, "XGROUP CREATE s early 1-0"));
+        replay(&replica, run(&store, "XGROUP CREATE t g 0 MKSTREAM"));
+        assert_eq!(
+            reply(&store, "XGROUP CREATE s early 0"),
+            Data::SimpleError("BUSYGROUP Consumer Group name already exists".into())
+        );
+        for store in [&store, &replica] {
+            assert_eq!(
+                groups(store),
+                vec![
+                    ("early".into(), Data::Integer(0), "1-0".into()),
+                    ("latest".into(), Data::Integer(0), "2-0".into()),
+                ]
+            );
+            assert_eq!(reply(store, "TYPE t"), Data::SimpleString("stream".into()));
+        }
+
+        let outcome = run(&store, "XGROUP CREATECONSUMER s early alice");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        replay(&replica, outcome);
+        let outcome = run(&store, "XGROUP CREATECONSUMER s early alice");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(groups(&replica)[0].1, Data::Integer(1));
+
+        // Deleting a consumer drops its pending entries
+        store
+            .stream_read_group("s", "early", "bob", None, None, false)
+            .unwrap();
+        assert_eq!(
+            reply(&store, "XGROUP DELCONSUMER s early bob"),
+            Data::Integer(1)
+        );
+        assert_eq!(
+            reply(&store, "XGROUP DELCONSUMER s early bob"),
+            Data::Integer(0)
+        );
+        let outcome = run(&store, "XGROUP DELCONSUMER s early alice");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert_eq!(outcome.replicate.len(), 1);
+        replay(&replica, outcome);
+        assert_eq!(groups(&replica)[0].1, Data::Integer(0));
+
+        let outcome = run(&store, "XGROUP DESTROY s early");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        replay(&replica, outcome);
+        assert_eq!(reply(&store, "XGROUP DESTROY s early"), Data::Integer(0));
+        assert_eq!(groups(&replica).len(), 1);
+
+        for (command, err) in [
+            (
+                "XGROUP CREATECONSUMER s missing c",
+                "NOGROUP No such consumer group 'missing' for key name 's'",
+            ),
+            (
+                "XGROUP DELCONSUMER s missing c",
+                "NOGROUP No such consumer group 'missing' for key name 's'",
+            ),
+            (
+                "XGROUP DESTROY missing g",
+                "ERR The XGROUP subcommand requires the key to exist",
+            ),
+            (
+                "XGROUP DESTROY s",
+                "ERR unknown subcommand or wrong number of arguments for 'destroy'",
+            ),
+            (
+                "XGROUP FOO s g",
+                "ERR unknown subcommand or wrong number of arguments for 'foo'",
+            ),
+            ("XGROUP CREATE s g2 $ FOO", SYNTAX_ERR_MSG),
+        ] {
+            match reply(&store, command) {
+                Data::SimpleError(e) => assert!(e.starts_with(err), "{}: {}", command, e),
+                data => panic!("{}: expected error, got {:?}", command, data),
+            }
+        }
+    }
+
+    #[test]
+    fn xreadgroup() {
+        let store = Store::new();
+        let replica = Store::new();
+        for command in [
+            "XADD s 1-0 k 1",
+            "XADD s 2-0 k 2",
+            "XADD s 3-0 k 3",
+            "XGROUP CREATE s g 0",
+        ] {
+            replay(&replica, run(&store, command));
+        }
+        let ids = |reply: Data| match reply {
+            Data::Array(mut streams) => match streams.remove(0) {
+                Data::Array(mut stream) => match stream.remove(1) {
+                    Data::Array(entries) => entries
+                        .into_iter()
+                        .map(|entry| match entry {
+                            Data::Array(mut entry) => entry.remove(0).get_string().unwrap(),
+                            _ => panic!("expected entry"),
+                        })
+                        .collect::<Vec<_>>(),
+                    _ => panic!("expected entries"),
+                },
+                _ => panic!("expected stream"),
+            },
+            reply => panic!("expected streams, got {:?}", reply),
+        };
+
+        // Two consumers split the stream, and BLOCK isn't replicated
+        let outcome = run(
+            &store,
+            "XREADGROUP GROUP g alice COUNT 2 BLOCK 10 STREAMS s >",
+        );
+        assert_eq!(
+            outcome.replicate,
+            vec![strings(&[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "2",
+                "STREAMS",
+                "s",
+                ">"
+            ])]
+        );
+        assert_eq!(ids(outcome.reply.clone()), ["1-0", "2-0"]);
+        replay(&replica, outcome);
+        let outcome = run(&store, "XREADGROUP GROUP g bob STREAMS s >");
+        assert_eq!(ids(outcome.reply.clone()), ["3-0"]);
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&store, "XREADGROUP GROUP g bob STREAMS s >"),
+            Data::NullArray
+        );
+
+        // After a crash, each consumer reads its pending entries again, on
+        // the replica too
+        for store in [&store, &replica] {
+            assert_eq!(
+                ids(reply(store, "XREADGROUP GROUP g alice STREAMS s 0")),
+                ["1-0", "2-0"]
+            );
+            assert_eq!(
+                ids(reply(store, "XREADGROUP GROUP g bob STREAMS s 0")),
+                ["3-0"]
+            );
+            assert_eq!(
+                ids(reply(store, "XREADGROUP GROUP g alice STREAMS s 1-0")),
+                ["2-0"]
+            );
+        }
+
+        // NOACK delivers without adding to the pending entries
+        replay(&replica, run(&store, "XADD s 4-0 k 4"));
+        replay(
+            &replica,
+            run(&store, "XREADGROUP GROUP g carol NOACK STREAMS s >"),
+        );
+        for store in [&store, &replica] {
+            assert_eq!(
+                ids(reply(store, "XREADGROUP GROUP g carol STREAMS s 0")),
+                Vec::<String>::new()
+            );
+            assert_eq!(
+                reply(store, "XREADGROUP GROUP g carol STREAMS s >"),
+                Data::NullArray
+            );
+        }
+
+        assert_eq!(
+            reply(&store, "XREADGROUP GROUP missing c STREAMS s >"),
+            Data::SimpleError(
+                "NOGROUP No such key 's' or consumer group 'missing' in XREADGROUP with GROUP option"
+                    .into()
+            )
+        );
+        assert_eq!(
+            reply(&store, "XREADGROUP GROUP g c BLOCK -1 STREAMS s >"),
+            Data::SimpleError("ERR timeout is negative".into())
+        );
+    }
+
+    #[test]
+    fn xinfo_stream() {
+        let store = Store::new();
+        let replica = Store::new();
+
+        for command in [
+            "XADD s 1-1 a 1",
+            "XADD s 2-1 b 2 c 3",
+            "XADD s 3-1 d 4",
+            "XADD s 4-1 e 5",
+            "XDEL s 2-1",
+            "XTRIM s MAXLEN 2",
+        ] {
+            replay(&replica, run(&store, command));
+        }
+        for store in [&store, &replica] {
+            let fields = xinfo_fields(store, "s");
+            assert_eq!(fields["length"], Data::Integer(2));
+            assert_eq!(fields["last-generated-id"], bulk("4-1"));
+            // Trimming removed 1-1, below the deleted 2-1
+            assert_eq!(fields["max-deleted-entry-id"], bulk("2-1"));
+            assert_eq!(fields["entries-added"], Data::Integer(4));
+            assert_eq!(fields["recorded-first-entry-id"], bulk("3-1"));
+            assert_eq!(
+                fields["first-entry"],
+                Data::Array(vec![bulk("3-1"), strings(&["d", "4"])])
+            );
+            assert_eq!(
+                fields["last-entry"],
+                Data::Array(vec![bulk("4-1"), strings(&["e", "5"])])
+            );
+        }
+
+        // Deleting the last entry keeps the last generated id
+        run(&store, "XDEL s 3-1 4-1");
+        let fields = xinfo_fields(&store, "s");
+        assert_eq!(fields["length"], Data::Integer(0));
+        assert_eq!(fields["last-generated-id"], bulk("4-1"));
+        assert_eq!(fields["max-deleted-entry-id"], bulk("4-1"));
+        assert_eq!(fields["first-entry"], Data::NullBulkString);
+        assert_eq!(fields["last-entry"], Data::NullBulkString);
+
+        assert_eq!(
+            reply(&store, "XINFO STREAM missing"),
+            Data::SimpleError("ERR no such key".into())
+        );
+    }
+
+    #[test]
+    fn xrange_count_and_exclusive_bounds() {
+        let store = Store::new();
+        for id in ["1-0", "1-1", "2-0", "3-0"] {
+            run(&store, &format!("XADD s {} f v", id));
+        }
+        let ids = |command: &str| match reply(&store, command) {
+            Data::Array(entries) => entries
+                .into_iter()
+                .map(|entry| match entry {
+                    Data::Array(mut entry) => entry.remove(0),
+                    _ => panic!("expected entry"),
+                })
+                .collect::<Vec<_>>(),
+            data => panic!("expected array, got {:?}", data),
+        };
+        let expected = |ids: &[&str]| ids.iter().map(|id| bulk(id)).collect::<Vec<_>>();
+
+        // An exclusive start skips the entry with exactly that id
+        assert_eq!(ids("XRANGE s (1-1 +"), expected(&["2-0", "3-0"]));
+        // Without a sequence number, (1 as the start excludes only 1-0,
+        assert_eq!(ids("XRANGE s (1 (3-0"), expected(&["1-1", "2-0"]));
+        // and as the end, only 1-18446744073709551615
+        assert_eq!(ids("XRANGE s - (1"), expected(&["1-0", "1-1"]));
+        assert_eq!(ids("XRANGE s (2-0 (2-0"), expected(&[]));
+        assert_eq!(ids("XREVRANGE s (3-0 (1-0"), expected(&["2-0", "1-1"]));
+
+        assert_eq!(ids("XRANGE s - + COUNT 2"), expected(&["1-0", "1-1"]));
+        assert_eq!(ids("XRANGE s (1-0 + count 2"), expected(&["1-1", "2-0"]));
+        assert_eq!(ids("XRANGE s - + COUNT 0"), expected(&[]));
+        assert_eq!(ids("XRANGE s - + COUNT -1"), expected(&[]));
+
+        for command in [
+            "XRANGE s (- +",
+            "XRANGE s - (+",
+            "XRANGE s (1-x +",
+            "XRANGE s - 1-2-3",
+            "XRANGE s - + COUNT",
+            "XRANGE s - + LIMIT 1",
+            "XRANGE s - + COUNT x",
+        ] {
+            assert!(
+                matches!(reply(&store, command), Data::SimpleError(_)),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn xrevrange() {
+        let store = Store::new();
+        for id in ["1-1", "1-2", "2-0", "3-5"] {
+            store
+                .stream_set("s".into(), id.into(), vec![("k".into(), id.into())])
+                .unwrap();
+        }
+        let ids = |data: Data| match data {
+            Data::Array(entries) => entries
+                .into_iter()
+                .map(|entry| match entry {
+                    Data::Array(mut entry) => entry.remove(0),
+                    entry => panic!("unexpected entry {:?}", entry),
+                })
+                .collect::<Vec<_>>(),
+            data => panic!("unexpected reply {:?}", data),
+        };
+
+        assert_eq!(
+            reply(&store, "XREVRANGE s + -"),
+            Data::Array(vec![
+                Data::Array(vec![bulk("3-5"), strings(&["k", "3-5"])]),
+                Data::Array(vec![bulk("2-0"), strings(&["k", "2-0"])]),
+                Data::Array(vec![bulk("1-2"), strings(&["k", "1-2"])]),
+                Data::Array(vec![bulk("1-1"), strings(&["k", "1-1"])]),
+            ])
+        );
+        assert_eq!(
+            ids(reply(&store, "XREVRANGE s + - COUNT 2")),
+            [bulk("3-5"), bulk("2-0")]
+        );
+        assert_eq!(
+            ids(reply(&store, "XREVRANGE s + - count 0")),
+            Vec::<Data>::new()
+        );
+        // Partial ids cover every sequence number of their time
+        assert_eq!(
+            ids(reply(&store, "XREVRANGE s 1 1")),
+            [bulk("1-2"), bulk("1-1")]
+        );
+        assert_eq!(
+            ids(reply(&store, "XREVRANGE s 2-0 1-2")),
+            [bulk("2-0"), bulk("1-2")]
+        );
+        // The end comes first, unlike XRANGE
+        assert_eq!(ids(reply(&store, "XREVRANGE s - +")), Vec::<Data>::new());
+        assert_eq!(ids(reply(&store, "XRANGE s - +")).len(), 4);
+        assert_eq!(reply(&store, "XREVRANGE missing + -"), Data::Array(vec![]));
+
+        assert_eq!(
+            reply(&store, "XREVRANGE s + - LIMIT 2"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "XREVRANGE str + -")));
+    }
+
+    #[test]
+    fn xdel() {
+        let store = Store::new();
+        let replica = Store::new();
+        for s in [&store, &replica] {
+            for id in ["1-1", "1-2", "2-0"] {
+                s.stream_set("s".into(), id.into(), vec![("a".into(), "1".into())])
+                    .unwrap();
+            }
+        }
+
+        let outcome = run(&store, "XDEL s 1-2 2 9-9");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        assert_eq!(outcome.modified, vec!["s"]);
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "XLEN s"), Data::Integer(1));
+
+        // Nothing to replicate when nothing was deleted
+        let outcome = run(&store, "XDEL s 1-2");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "XDEL missing 1-1"), Data::Integer(0));
+
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "XDEL str 1-1")));
+        assert!(matches!(reply(&store, "XDEL s"), Data::SimpleError(_)));
+        assert!(matches!(reply(&store, "XDEL s x"), Data::SimpleError(_)));
+    }
+
+    #[test]
+    fn scan_type() {
+        let store = Store::new();
+        run(&store, "SET s1 x");
+        run(&store, "SET s2 x");
+        run(&store, "HSET h a 1");
+
+        let (mut keys, _) = scan_all(&store, "SCAN", "TYPE string");
+        keys.sort();
+        assert_eq!(keys, vec!["s1", "s2"]);
+        let (keys, _) = scan_all(&store, "SCAN", "TYPE HASH");
+        assert_eq!(keys, vec!["h"]);
+        let (keys, _) = scan_all(&store, "SCAN", "TYPE stream");
+        assert!(keys.is_empty());
+
+        assert_eq!(
+            reply(&store, "SCAN -1"),
+            Data::SimpleError("ERR invalid cursor".into())
+        );
+        for options in ["NOVALUES", "TYPE", "COUNT 0"] {
+            assert_eq!(
+                reply(&store, &format!("SCAN 0 {}", options)),
+                Data::SimpleError(SYNTAX_ERR_MSG.into())
+            );
+        }
+    }
+
+    fn integers(vs: &[i64]) -> Data {
+        Data::Array(vs.iter().map(|&i| Data::Integer(i)).collect())
+    }
+
+    #[test]
+    fn xack_and_xpending() {
+        let store = Store::new();
+        let replica = Store::new();
+        for command in [
+            "XADD s 1-0 k 1",
+            "XADD s 2-0 k 2",
+            "XADD s 3-0 k 3",
+            "XADD s 4-0 k 4",
+            "XGROUP CREATE s g 0",
+            "XREADGROUP GROUP g alice COUNT 3 STREAMS s >",
+            "XREADGROUP GROUP g bob STREAMS s >",
+        ] {
+            replay(&replica, run(&store, command));
+        }
+        let xpending = |store: &Store, cmd: &str, idle: u64| {
+            let vs = cmd
+                .split_ascii_whitespace()
+                .map(|s| Data::BulkString(s.into()))
+                .collect::<Vec<_>>();
+            let now = SystemTime::now() + Duration::from_millis(idle);
+            xpending(store, &Args::new(&vs), now).unwrap()
+        };
+        let row = |id: &str, consumer: &str, idle: i64, delivery_count: i64| {
+            Data::Array(vec![
+                bulk(id),
+                bulk(consumer),
+                Data::Integer(idle),
+                Data::Integer(delivery_count),
+            ])
+        };
+
+        // Acknowledging is replicated, unless nothing was pending
+        let outcome = run(&store, "XACK s g 1-0 3-0 9-0");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        assert_eq!(
+            outcome.replicate,
+            vec![strings(&["XACK", "s", "g", "1-0", "3-0", "9-0"])]
+        );
+        assert_eq!(outcome.modified, vec!["s".to_string()]);
+        replay(&replica, outcome);
+        let outcome = run(&store, "XACK s g 1-0");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "XACK missing g 1-0"), Data::Integer(0));
+        assert_eq!(reply(&store, "XACK s missing 2-0"), Data::Integer(0));
+
+        for store in [&store, &replica] {
+            assert_eq!(
+                reply(store, "XPENDING s g"),
+                Data::Array(vec![
+                    Data::Integer(2),
+                    bulk("2-0"),
+                    bulk("4-0"),
+                    Data::Array(vec![strings(&["alice", "1"]), strings(&["bob", "1"])]),
+                ])
+            );
+        }
+
+        // The extended form, 5 seconds later
+        assert_eq!(
+            xpending(&store, "XPENDING s g - + 10", 5000),
+            Data::Array(vec![
+                row("2-0", "alice", 5000, 1),
+                row("4-0", "bob", 5000, 1)
+            ])
+        );
+        assert_eq!(
+            xpending(&store, "XPENDING s g (2-0 + 10", 5000),
+            Data::Array(vec![row("4-0", "bob", 5000, 1)])
+        );
+        assert_eq!(
+            xpending(&store, "XPENDING s g - + 1 bob", 5000),
+            Data::Array(vec![row("4-0", "bob", 5000, 1)])
+        );
+        assert_eq!(
+            xpending(&store, "XPENDING s g - + 10 carol", 5000),
+            Data::Array(vec![])
+        );
+
+        // Reading history delivers again, which resets the idle time
+        run(&store, "XREADGROUP GROUP g alice STREAMS s 0");
+        assert_eq!(
+            xpending(&store, "XPENDING s g IDLE 3000 - + 10", 5000),
+            Data::Array(vec![
+                row("2-0", "alice", 5000, 2),
+                row("4-0", "bob", 5000, 1)
+            ])
+        );
+        assert_eq!(
+            xpending(&store, "XPENDING s g IDLE 6000 - + 10", 5000),
+            Data::Array(vec![])
+        );
+        assert_eq!(
+            xpending(&store, "XPENDING s g IDLE 6000 - + 10 bob", 7000),
+            Data::Array(vec![row("4-0", "bob", 7000, 1)])
+        );
+
+        assert_eq!(
+            reply(&store, "XPENDING s missing"),
+            Data::SimpleError("NOGROUP No such key 's' or consumer group 'missing'".into())
+        );
+        assert_eq!(
+            reply(&store, "XPENDING s g - +"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn xclaim() {
+        let store = Store::new();
+        let replica = Store::new();
+        for command in [
+            "XADD s 1-0 k 1",
+            "XADD s 2-0 k 2",
+            "XADD s 3-0 k 3",
+            "XADD s 4-0 k 4",
+            "XGROUP CREATE s g 0",
+            "XREADGROUP GROUP g alice STREAMS s >",
+        ] {
+            replay(&replica, run(&store, command));
+        }
+        // Runs a command `later` ms from now
+        let start = SystemTime::now();
+        let at = |store: &Store, cmd: &str, later: u64| {
+            let vs = cmd
+                .split_ascii_whitespace()
+                .map(|s| Data::BulkString(s.into()))
+                .collect::<Vec<_>>();
+            let args = Args::new(&vs);
+            let now = start + Duration::from_millis(later);
+            match args.name().unwrap().as_str() {
+                "xclaim" => super::xclaim(store, &args, now),
+                "xpending" => xpending(store, &args, now).map(Outcome::read),
+                _ => panic!("unexpected command"),
+            }
+            .unwrap_or_else(|e| Outcome::read(Data::SimpleError(e.to_string())))
+        };
+        let row = |id: &str, consumer: &str, idle: i64, delivery_count: i64| {
+            Data::Array(vec![
+                bulk(id),
+                bulk(consumer),
+                Data::Integer(idle),
+                Data::Integer(delivery_count),
+            ])
+        };
+
+        // Entries idle for less than the minimum aren't claimed
+        let outcome = at(&store, "XCLAIM s g bob 10000 1-0 2-0", 5000);
+        assert_eq!(outcome.reply, strings(&[]));
+        assert!(outcome.replicate.is_empty());
+
+        let outcome = at(&store, "XCLAIM s g bob 10000 1-0 2-0 9-0", 20000);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![
+                Data::Array(vec![bulk("1-0"), strings(&["k", "1"])]),
+                Data::Array(vec![bulk("2-0"), strings(&["k", "2"])]),
+            ])
+        );
+        let time = (start + Duration::from_millis(20000))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .to_string();
+        assert_eq!(
+            outcome.replicate[0],
+            strings(&[
+                "XCLAIM",
+                "s",
+                "g",
+                "bob",
+                "0",
+                "1-0",
+                "TIME",
+                &time,
+                "RETRYCOUNT",
+                "2",
+                "FORCE",
+                "JUSTID"
+            ])
+        );
+        assert_eq!(outcome.replicate.len(), 2);
+        replay(&replica, outcome);
+
+        // JUSTID leaves the delivery count as is, while RETRYCOUNT sets it
+        let outcome = at(&store, "XCLAIM s g carol 0 2-0 JUSTID", 20000);
+        assert_eq!(outcome.reply, strings(&["2-0"]));
+        replay(&replica, outcome);
+        let outcome = at(
+            &store,
+            "XCLAIM s g carol 0 3-0 IDLE 5000 RETRYCOUNT 7",
+            20000,
+        );
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![Data::Array(vec![bulk("3-0"), strings(&["k", "3"])])])
+        );
+        replay(&replica, outcome);
+        assert_eq!(
+            at(&store, "XPENDING s g - + 10", 25000).reply,
+            Data::Array(vec![
+                row("1-0", "bob", 5000, 2),
+                row("2-0", "carol", 5000, 2),
+                row("3-0", "carol", 10000, 7),
+                row("4-0", "alice", 25000, 1),
+            ])
+        );
+        // The replica gets the same delivery times and counts
+        assert_eq!(
+            at(&replica, "XPENDING s g - + 3", 25000).reply,
+            Data::Array(vec![
+                row("1-0", "bob", 5000, 2),
+                row("2-0", "carol", 5000, 2),
+                row("3-0", "carol", 10000, 7),
+            ])
+        );
+
+        // FORCE claims entries that aren't pending, if they exist
+        run(&store, "XACK s g 4-0");
+        assert_eq!(
+            at(&store, "XCLAIM s g dave 0 4-0", 20000).reply,
+            strings(&[])
+        );
+        let outcome = at(&store, "XCLAIM s g dave 60000 4-0 9-0 FORCE JUSTID", 20000);
+        assert_eq!(outcome.reply, strings(&["4-0"]));
+        replay(&replica, outcome);
+        assert_eq!(
+            at(&store, "XPENDING s g - + 10 dave", 20000).reply,
+            Data::Array(vec![row("4-0", "dave", 0, 0)])
+        );
+
+        // Deleted entries are removed from the PEL instead, on the replica
+        // too
+        replay(&replica, run(&store, "XDEL s 1-0"));
+        let outcome = at(&store, "XCLAIM s g bob 0 1-0", 20000);
+        assert_eq!(outcome.reply, strings(&[]));
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(
+                at(store, "XPENDING s g - + 10 bob", 20000).reply,
+                Data::Array(vec![])
+            );
+        }
+
+        for (command, error) in [
+            (
+                "XCLAIM s missing bob 0 1-0",
+                "NOGROUP No such key 's' or consumer group 'missing'",
+            ),
+            (
+                "XCLAIM missing g bob 0 1-0",
+                "NOGROUP No such key 'missing' or consumer group 'g'",
+            ),
+            (
+                "XCLAIM s g bob x 1-0",
+                "ERR Invalid min-idle-time argument for XCLAIM",
+            ),
+            (
+                "XCLAIM s g bob 0 1-0 LASTID 1-0",
+                "ERR Unrecognized XCLAIM option 'LASTID'",
+            ),
+            (
+                "XCLAIM s g bob 0 1-0 IDLE",
+                "ERR Unrecognized XCLAIM option 'IDLE'",
+            ),
+        ] {
+            assert_eq!(
+                at(&store, command, 0).reply,
+                Data::SimpleError(error.into())
+            );
+        }
+    }
+
+    #[test]
+    fn xautoclaim() {
+        let store = Store::new();
+        let replica = Store::new();
+        for command in [
+            "XADD s 1-0 k 1",
+            "XADD s 2-0 k 2",
+            "XADD s 3-0 k 3",
+            "XADD s 4-0 k 4",
+            "XADD s 5-0 k 5",
+            "XGROUP CREATE s g 0",
+            "XREADGROUP GROUP g alice STREAMS s >",
+        ] {
+            replay(&replica, run(&store, command));
+        }
+        let start = SystemTime::now();
+        let at = |store: &Store, cmd: &str, later: u64| {
+            let vs = cmd
+                .split_ascii_whitespace()
+                .map(|s| Data::BulkString(s.into()))
+                .collect::<Vec<_>>();
+            super::xautoclaim(store, &Args::new(&vs), start + Duration::from_millis(later))
+                .unwrap_or_else(|e| Outcome::read(Data::SimpleError(e.to_string())))
+        };
+
+        // Nothing is idle for long enough
+        let outcome = at(&store, "XAUTOCLAIM s g bob 10000 -", 0);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![bulk("0-0"), strings(&[]), strings(&[])])
+        );
+        assert!(outcome.replicate.is_empty());
+
+        // Pages through the PEL with the returned cursor
+        let outcome = at(&store, "XAUTOCLAIM s g bob 10000 - COUNT 2", 20000);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![
+                bulk("3-0"),
+                Data::Array(vec![
+                    Data::Array(vec![bulk("1-0"), strings(&["k", "1"])]),
+                    Data::Array(vec![bulk("2-0"), strings(&["k", "2"])]),
+                ]),
+                strings(&[]),
+            ])
+        );
+        assert_eq!(outcome.replicate.len(), 2);
+        replay(&replica, outcome);
+        let outcome = at(&store, "XAUTOCLAIM s g bob 10000 3-0 COUNT 2 JUSTID", 20000);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![bulk("5-0"), strings(&["3-0", "4-0"]), strings(&[])])
+        );
+        replay(&replica, outcome);
+        let outcome = at(&store, "XAUTOCLAIM s g bob 10000 5-0 COUNT 2 JUSTID", 20000);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![bulk("0-0"), strings(&["5-0"]), strings(&[])])
+        );
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(
+                reply(store, "XPENDING s g"),
+                Data::Array(vec![
+                    Data::Integer(5),
+                    bulk("1-0"),
+                    bulk("5-0"),
+                    Data::Array(vec![strings(&["bob", "5"])]),
+                ])
+            );
+        }
+
+        // Deleted entries count towards COUNT, and are removed from the PEL
+        replay(&replica, run(&store, "XDEL s 2-0 3-0"));
+        let outcome = at(&store, "XAUTOCLAIM s g carol 0 (1-0 COUNT 2 JUSTID", 20000);
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![bulk("4-0"), strings(&[]), strings(&["2-0", "3-0"])])
+        );
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            let Data::Array(summary) = reply(store, "XPENDING s g") else {
+                panic!("expected summary");
+            };
+            assert_eq!(summary[0], Data::Integer(3));
+        }
+
+        for (command, error) in [
+            (
+                "XAUTOCLAIM s missing bob 0 -",
+                "NOGROUP No such key 's' or consumer group 'missing'",
+            ),
+            ("XAUTOCLAIM s g bob 0 - COUNT 0", "ERR COUNT must be > 0"),
+            ("XAUTOCLAIM s g bob 0 - COUNT", SYNTAX_ERR_MSG),
+            ("XAUTOCLAIM s g bob 0 - FORCE", SYNTAX_ERR_MSG),
+            (
+                "XAUTOCLAIM s g bob -x -",
+                "ERR Invalid min-idle-time argument for XAUTOCLAIM",
+            ),
+        ] {
+            assert_eq!(
+                at(&store, command, 0).reply,
+                Data::SimpleError(error.into())
+            );
+        }
+    }
+
+    fn replay(replica: &Store, outcome: Outcome) {
+        for cmd in outcome.replicate {
+            let Data::Array(vs) = cmd else { panic!() };
+            execute(replica, &vs);
+        }
+    }
+
+    #[test]
+    fn hpexpire_and_hpttl() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2");
+
+        assert_eq!(
+            reply(&store, "HPEXPIRE h 50 FIELDS 2 a c"),
+            integers(&[1, -2])
+        );
+        let Data::Array(ttls) = reply(&store, "HPTTL h FIELDS 3 a b c") else {
+            panic!("expected array");
+        };
+        assert!(matches!(ttls[0], Data::Integer(ttl) if ttl > 0 && ttl <= 50));
+        assert_eq!(ttls[1..], [Data::Integer(-1), Data::Integer(-2)]);
+
+        // Expired fields are removed on access
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reply(&store, "HGET h a"), Data::NullBulkString);
+        assert_eq!(reply(&store, "HLEN h"), Data::Integer(1));
+        assert_eq!(reply(&store, "HPTTL h FIELDS 1 a"), integers(&[-2]));
+        assert_eq!(reply(&store, "HGET h b"), bulk("2"));
+    }
+
+    #[test]
+    fn hash_disappears_when_last_field_expires() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2");
+        run(&store, "HPEXPIRE h 30 FIELDS 2 a b");
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("hash".into()));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("none".into()));
+        assert_eq!(reply(&store, "HGETALL h"), Data::Array(vec![]));
+
+        // Expiring in the past deletes right away
+        run(&store, "HSET h a 1 b 2");
+        assert_eq!(reply(&store, "HEXPIRE h 0 FIELDS 1 a"), integers(&[2]));
+        assert_eq!(reply(&store, "HEXPIREAT h 1 FIELDS 1 b"), integers(&[2]));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn httl_and_hpersist() {
+        let store = Store::new();
+        run(&store, "HSET h a 1 b 2");
+        assert_eq!(reply(&store, "HEXPIRE h 100 FIELDS 1 a"), integers(&[1]));
+        assert_eq!(
+            reply(&store, "HTTL h FIELDS 3 a b c"),
+            integers(&[100, -1, -2])
+        );
+        assert_eq!(
+            reply(&store, "HTTL missing FIELDS 2 a b"),
+            integers(&[-2, -2])
+        );
+
+        assert_eq!(
+            reply(&store, "HPERSIST h FIELDS 3 a b c"),
+            integers(&[1, -1, -2])
+        );
+        assert_eq!(reply(&store, "HTTL h FIELDS 1 a"), integers(&[-1]));
+
+        // Overwriting a field with HSET removes its expiration, HINCRBY keeps it
+        run(&store, "HEXPIRE h 100 FIELDS 2 a b");
+        run(&store, "HSET h a 10");
+        run(&store, "HINCRBY h b 1");
+        assert_eq!(reply(&store, "HTTL h FIELDS 2 a b"), integers(&[-1, 100]));
+    }
+
+    #[test]
+    fn hexpire_errors() {
+        let store = Store::new();
+        run(&store, "HSET h a 1");
+
+        let error = |cmd| match reply(&store, cmd) {
+            Data::SimpleError(err) => err,
+            reply => panic!("expected error, got {}", reply),
+        };
+        assert!(error("HEXPIRE h 10 a").contains("wrong number of arguments"));
+        assert!(error("HEXPIRE h 10 FOO 1 a").contains("FIELDS"));
+        assert!(error("HEXPIRE h 10 FIELDS 2 a").contains("numfields"));
+        assert!(error("HEXPIRE h 10 FIELDS 0 a").contains("numFields"));
+        assert!(error("HEXPIRE h -1 FIELDS 1 a").contains("invalid expire time"));
+        assert_eq!(error("HEXPIRE h x FIELDS 1 a"), NOT_INTEGER_ERR_MSG);
+
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "HTTL s FIELDS 1 a")));
+    }
+
+    #[test]
+    fn hexpire_is_replicated_as_hpexpireat() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "HSET h a 1 b 2"));
+
+        // Nothing to replicate if no field exists
+        assert!(run(&store, "HEXPIRE h 100 FIELDS 1 c").replicate.is_empty());
+
+        let outcome = run(&store, "HEXPIRE h 100 FIELDS 2 a c");
+        let [Data::Array(cmd)] = outcome.replicate.as_slice() else {
+            panic!("expected one command");
+        };
+        let cmd: Vec<String> = cmd.iter().map(|d| d.get_string().unwrap()).collect();
+        assert_eq!(cmd[0], "HPEXPIREAT");
+        assert_eq!(cmd[3..], ["FIELDS", "1", "a"]);
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "HTTL h FIELDS 2 a b"), integers(&[100, -1]));
+
+        let outcome = run(&store, "HPERSIST h FIELDS 1 a");
+        assert_eq!(outcome.replicate.len(), 1);
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "HTTL h FIELDS 1 a"), integers(&[-1]));
+        assert!(run(&store, "HPERSIST h FIELDS 1 a").replicate.is_empty());
+    }
+
+    fn members(store: &Store, key: &str) -> Vec<String> {
+        let Data::Array(members) = reply(store, &format!("SMEMBERS {}", key)) else {
+            panic!("expected array");
+        };
+        let mut members: Vec<_> = members.iter().map(|d| d.get_string().unwrap()).collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn sadd_and_srem() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "SADD s a b a c b"), Data::Integer(3));
+        assert_eq!(reply(&store, "SADD s a d"), Data::Integer(1));
+        assert_eq!(members(&store, "s"), vec!["a", "b", "c", "d"]);
+        assert_eq!(reply(&store, "SCARD s"), Data::Integer(4));
+        assert_eq!(reply(&store, "SISMEMBER s a"), Data::Integer(1));
+        assert_eq!(reply(&store, "SISMEMBER s x"), Data::Integer(0));
+
+        assert_eq!(reply(&store, "SREM s a x"), Data::Integer(1));
+        assert_eq!(reply(&store, "SREM s x"), Data::Integer(0));
+        assert_eq!(reply(&store, "SREM missing a"), Data::Integer(0));
+
+        // Removing the last member removes the key
+        assert_eq!(reply(&store, "SREM s b c d"), Data::Integer(3));
+        assert_eq!(reply(&store, "TYPE s"), Data::SimpleString("none".into()));
+        assert_eq!(reply(&store, "SCARD s"), Data::Integer(0));
+        assert!(members(&store, "s").is_empty());
+    }
+
+    #[test]
+    fn set_wrong_type() {
+        let store = Store::new();
+        run(&store, "SET str x");
+        run(&store, "SADD s a");
+        assert!(is_wrong_type(reply(&store, "SADD str a")));
+        assert!(is_wrong_type(reply(&store, "SMEMBERS str")));
+        assert!(is_wrong_type(reply(&store, "SCARD str")));
+        assert!(is_wrong_type(reply(&store, "HGET s a")));
+        assert!(is_wrong_type(reply(&store, "GET s")));
+    }
+
+    #[test]
+    fn set_writes_are_replicated() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SADD s a b c"));
+        // Nothing changes, so nothing to replicate
+        assert!(run(&store, "SADD s a").replicate.is_empty());
+        assert!(run(&store, "SREM s x").replicate.is_empty());
+        replay(&replica, run(&store, "SREM s b"));
+
+        assert_eq!(members(&replica, "s"), vec!["a", "c"]);
+    }
+
+    fn sorted(data: Data) -> Vec<String> {
+        let Data::Array(members) = data else {
+            panic!("expected array");
+        };
+        let mut members: Vec<_> = members.iter().map(|d| d.get_string().unwrap()).collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn set_algebra() {
+        let store = Store::new();
+        run(&store, "SADD a 1 2 3 4");
+        run(&store, "SADD b 2 3 4 5");
+        run(&store, "SADD c 3 4 6");
+
+        assert_eq!(sorted(reply(&store, "SINTER a b c")), vec!["3", "4"]);
+        assert_eq!(
+            sorted(reply(&store, "SUNION a b c")),
+            vec!["1", "2", "3", "4", "5", "6"]
+        );
+        assert_eq!(sorted(reply(&store, "SDIFF a b c")), vec!["1"]);
+        assert_eq!(sorted(reply(&store, "SDIFF b a")), vec!["5"]);
+
+        // Missing keys are empty sets
+        assert!(sorted(reply(&store, "SINTER a missing")).is_empty());
+        assert_eq!(
+            sorted(reply(&store, "SUNION missing c")),
+            vec!["3", "4", "6"]
+        );
+        assert_eq!(
+            sorted(reply(&store, "SDIFF c missing")),
+            vec!["3", "4", "6"]
+        );
+
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "SINTER a s")));
+        assert!(is_wrong_type(reply(&store, "SUNIONSTORE d a s")));
+    }
+
+    #[test]
+    fn set_algebra_store() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SADD a 1 2 3"));
+        replay(&replica, run(&store, "SADD b 2 3 4"));
+
+        // The destination is one of the sources
+        let outcome = run(&store, "SINTERSTORE a a b");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        replay(&replica, outcome);
+        assert_eq!(members(&store, "a"), vec!["2", "3"]);
+        assert_eq!(members(&replica, "a"), vec!["2", "3"]);
+
+        // Replaces a value of another type
+        run(&store, "SET d x");
+        assert_eq!(reply(&store, "SUNIONSTORE d a b"), Data::Integer(3));
+        assert_eq!(members(&store, "d"), vec!["2", "3", "4"]);
+
+        // An empty result removes the destination
+        assert_eq!(reply(&store, "SDIFFSTORE d a b"), Data::Integer(0));
+        assert_eq!(reply(&store, "TYPE d"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn spop() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SADD s a b c d e"));
+
+        let outcome = run(&store, "SPOP s");
+        let popped = outcome.reply.get_string().unwrap();
+        let [Data::Array(srem)] = outcome.replicate.as_slice() else {
+            panic!("expected one command");
+        };
+        assert_eq!(srem[0], bulk("SREM"));
+        assert_eq!(srem[2..], [bulk(&popped)]);
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&store, &format!("SISMEMBER s {}", popped)),
+            Data::Integer(0)
+        );
+
+        let outcome = run(&store, "SPOP s 2");
+        assert_eq!(sorted(outcome.reply.clone()).len(), 2);
+        replay(&replica, outcome);
+        assert_eq!(members(&replica, "s"), members(&store, "s"));
+
+        // A count larger than the set pops everything and removes the key
+        let outcome = run(&store, "SPOP s 10");
+        assert_eq!(sorted(outcome.reply.clone()).len(), 2);
+        replay(&replica, outcome);
+        assert_eq!(reply(&store, "TYPE s"), Data::SimpleString("none".into()));
+        assert_eq!(reply(&replica, "TYPE s"), Data::SimpleString("none".into()));
+
+        let outcome = run(&store, "SPOP s");
+        assert_eq!(outcome.reply, Data::NullBulkString);
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "SPOP s 3"), Data::Array(vec![]));
+    }
+
+    #[test]
+    fn srandmember() {
+        let store = Store::new();
+        run(&store, "SADD s a b c");
+
+        let member = reply(&store, "SRANDMEMBER s").get_string().unwrap();
+        assert!(["a", "b", "c"].contains(&member.as_str()));
+        assert_eq!(
+            sorted(reply(&store, "SRANDMEMBER s 5")),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(sorted(reply(&store, "SRANDMEMBER s 2")).len(), 2);
+        // With a count, the reply is an array even for one member
+        let Data::Array(one) = reply(&store, "SRANDMEMBER s 1") else {
+            panic!("expected array");
+        };
+        assert_eq!(one.len(), 1);
+        assert_eq!(reply(&store, "SRANDMEMBER s 0"), Data::Array(vec![]));
+
+        // A negative count allows repeats
+        let repeated = sorted(reply(&store, "SRANDMEMBER s -10"));
+        assert_eq!(repeated.len(), 10);
+        assert!(repeated
+            .iter()
+            .all(|m| ["a", "b", "c"].contains(&m.as_str())));
+
+        // Nothing is removed
+        assert_eq!(reply(&store, "SCARD s"), Data::Integer(3));
+        assert_eq!(reply(&store, "SRANDMEMBER missing"), Data::NullBulkString);
+        assert_eq!(reply(&store, "SRANDMEMBER missing 2"), Data::Array(vec![]));
+        assert_eq!(
+            reply(&store, "SRANDMEMBER s -9223372036854775808"),
+            Data::SimpleError(
+                "ERR value is out of range, value must between -9223372036854775807 and 9223372036854775807"
+                    .into()
+            )
+        );
+
+        // The same seed picks the same members
+        run(&store, "SADD s d e f g");
+        for count in [3, -8] {
+            let first = store.srandmember("s", count, &mut Rng::new(3)).unwrap();
+            let second = store.srandmember("s", count, &mut Rng::new(3)).unwrap();
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn smove() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SADD src a b"));
+        replay(&replica, run(&store, "SADD dst b"));
+
+        let outcome = run(&store, "SMOVE src dst a");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        assert_eq!(outcome.modified, vec!["src", "dst"]);
+        replay(&replica, outcome);
+        assert_eq!(reply(&store, "SMOVE src dst x"), Data::Integer(0));
+
+        // Already in the destination, and the last member of the source
+        replay(&replica, run(&store, "SMOVE src dst b"));
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE src"), Data::SimpleString("none".into()));
+            assert_eq!(members(store, "dst"), vec!["a", "b"]);
+        }
+
+        // Same source and destination
+        assert_eq!(reply(&store, "SMOVE dst dst a"), Data::Integer(1));
+        assert_eq!(reply(&store, "SMOVE dst dst x"), Data::Integer(0));
+        assert_eq!(members(&store, "dst"), vec!["a", "b"]);
+
+        assert_eq!(reply(&store, "SMOVE missing dst a"), Data::Integer(0));
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "SMOVE dst s a")));
+        assert!(is_wrong_type(reply(&store, "SMOVE s dst a")));
+        assert!(is_wrong_type(reply(&store, "SMOVE missing s a")));
+        assert_eq!(members(&store, "dst"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn smismember() {
+        let store = Store::new();
+        run(&store, "SADD s a b");
+        assert_eq!(reply(&store, "SMISMEMBER s a x b"), integers(&[1, 0, 1]));
+        assert_eq!(reply(&store, "SMISMEMBER missing a"), integers(&[0]));
+    }
+
+    #[test]
+    fn sintercard() {
+        let store = Store::new();
+        let mut rng = Rng::new(1);
+        for key in ["a", "b", "c"] {
+            let members: Vec<String> = (0..200).map(|_| rng.index(100).to_string()).collect();
+            run(&store, &format!("SADD {} {}", key, members.join(" ")));
+        }
+
+        for keys in ["a", "a b", "b c", "a b c", "c a b"] {
+            let Data::Array(members) = reply(&store, &format!("SINTER {}", keys)) else {
+                panic!("expected array");
+            };
+            let num_keys = keys.split(' ').count();
+            assert_eq!(
+                reply(&store, &format!("SINTERCARD {} {}", num_keys, keys)),
+                Data::Integer(members.len() as i64)
+            );
+        }
+
+        // Counting stops at the limit
+        let Data::Integer(card) = reply(&store, "SINTERCARD 2 a b") else {
+            panic!("expected integer");
+        };
+        assert!(card > 5);
+        assert_eq!(reply(&store, "SINTERCARD 2 a b LIMIT 5"), Data::Integer(5));
+        assert_eq!(
+            reply(&store, &format!("SINTERCARD 2 a b LIMIT {}", card + 10)),
+            Data::Integer(card)
+        );
+        assert_eq!(
+            reply(&store, "SINTERCARD 2 a b LIMIT 0"),
+            Data::Integer(card)
+        );
+        assert_eq!(reply(&store, "SINTERCARD 2 a missing"), Data::Integer(0));
+    }
+
+    #[test]
+    fn sintercard_errors() {
+        let store = Store::new();
+        run(&store, "SADD a x");
+        run(&store, "SET s x");
+        let error = |msg: &str| Data::SimpleError(msg.into());
+
+        assert_eq!(
+            reply(&store, "SINTERCARD 0 a"),
+            error("ERR numkeys should be greater than 0")
+        );
+        assert_eq!(
+            reply(&store, "SINTERCARD 3 a b"),
+            error("ERR Number of keys can't be greater than number of args")
+        );
+        assert_eq!(
+            reply(&store, "SINTERCARD 1 a LIMIT -1"),
+            error("ERR LIMIT can't be negative")
+        );
+        assert_eq!(reply(&store, "SINTERCARD 1 a b"), error(SYNTAX_ERR_MSG));
+        assert!(is_wrong_type(reply(&store, "SINTERCARD 2 missing s")));
+    }
+
+    #[test]
+    fn del() {
+        let store = Store::new();
+        run(&store, "SET a 1");
+        run(&store, "SADD b x");
+        run(&store, "SET c 1 PX 1");
+        thread::sleep(Duration::from_millis(5));
+
+        let outcome = run(&store, "DEL a b c missing");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        assert_eq!(outcome.replicate.len(), 1);
+        assert_eq!(reply(&store, "TYPE a"), Data::SimpleString("none".into()));
+        assert!(run(&store, "DEL a").replicate.is_empty());
+    }
+
+    #[test]
+    fn object() {
+        let store = Store::new();
+        let long = "x".repeat(45);
+        reply(&store, "set int 12345");
+        reply(&store, "set short hello");
+        reply(&store, &format!("set long {}", long));
+        reply(&store, "hset hash a 1 b 2");
+        reply(&store, &format!("hset bighash a {}", "x".repeat(65)));
+        reply(&store, "sadd set a b c");
+        let many = (0..129).map(|i| i.to_string()).collect::<Vec<_>>();
+        reply(&store, &format!("sadd bigset {}", many.join(" ")));
+        reply(&store, "zadd zset 1 a 2 b");
+        reply(&store, &format!("zadd bigzset 1 {}", "x".repeat(65)));
+
+        for (key, encoding) in [
+            ("int", "int"),
+            ("short", "embstr"),
+            ("long", "raw"),
+            ("hash", "listpack"),
+            ("bighash", "hashtable"),
+            ("set", "listpack"),
+            ("bigset", "hashtable"),
+            ("zset", "listpack"),
+            ("bigzset", "skiplist"),
+        ] {
+            assert_eq!(
+                reply(&store, &format!("object encoding {}", key)),
+                bulk(encoding),
+                "{}",
+                key
+            );
+        }
+        assert_eq!(
+            reply(&store, "object encoding missing"),
+            Data::NullBulkString
+        );
+
+        assert_eq!(reply(&store, "OBJECT IDLETIME int"), Data::Integer(0));
+        let outcome = run(&store, "TOUCH int missing short int");
+        assert_eq!(outcome.reply, Data::Integer(3));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(
+            reply(&store, "object idletime missing"),
+            Data::NullBulkString
+        );
+        assert!(matches!(
+            reply(&store, "object encoding"),
+            Data::SimpleError(e) if e.contains("Try OBJECT HELP")
+        ));
+        assert!(matches!(
+            reply(&store, "object help extra"),
+            Data::SimpleError(e) if e.contains("Try OBJECT HELP")
+        ));
+    }
+
+    #[test]
+    fn object_encoding_limits() {
+        let store = Store::new();
+        reply(&store, "hset hash a 1 b 2");
+        reply(&store, "sadd set abc");
+        reply(&store, "zadd zset 1 a");
+
+        let limits = |max_entries, max_value| ListpackLimits {
+            max_entries,
+            max_value,
+        };
+        store.set_encoding_limits(EncodingLimits {
+            hash: limits(1, 64),
+            set: limits(128, 2),
+            zset: limits(1, 1),
+        });
+        for (key, encoding) in [
+            ("hash", "hashtable"),
+            ("set", "hashtable"),
+            ("zset", "listpack"),
+        ] {
+            assert_eq!(
+                reply(&store, &format!("object encoding {}", key)),
+                bulk(encoding),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn object_encoding_conversion() {
+        let store = Store::new();
+        let encoding = |key: &str| reply(&store, &format!("OBJECT ENCODING {}", key));
+
+        // Growing past the maximum number of entries converts
+        for i in 0..129 {
+            run(&store, &format!("HSET hash f{} v", i));
+            run(&store, &format!("SADD set m{}", i));
+            run(&store, &format!("ZADD zset {} m{}", i, i));
+            let expected = if i < 128 {
+                ["listpack"; 3]
+            } else {
+                ["hashtable", "hashtable", "skiplist"]
+            };
+            for (key, expected) in ["hash", "set", "zset"].into_iter().zip(expected) {
+                assert_eq!(encoding(key), bulk(expected), "{} with {}", key, i + 1);
+            }
+        }
+
+        // and shrinking back doesn't
+        run(&store, "HDEL hash f0 f1 f2");
+        run(&store, "SPOP set 100");
+        run(&store, "ZREMRANGEBYRANK zset 0 -2");
+        assert_eq!(encoding("hash"), bulk("hashtable"));
+        assert_eq!(encoding("set"), bulk("hashtable"));
+        assert_eq!(encoding("zset"), bulk("skiplist"));
+
+        // Nor does removing a long element
+        let long = "x".repeat(65);
+        run(&store, "ZADD long 1 a");
+        assert_eq!(encoding("long"), bulk("listpack"));
+        run(&store, &format!("ZADD long 2 {}", long));
+        assert_eq!(encoding("long"), bulk("skiplist"));
+        run(&store, &format!("ZREM long {}", long));
+        assert_eq!(encoding("long"), bulk("skiplist"));
+
+        // A new value starts as a listpack again
+        run(&store, "DEL hash");
+        run(&store, "HSET hash f v");
+        assert_eq!(encoding("hash"), bulk("listpack"));
+        // The limits are configurable
+        store.set_encoding_limits(EncodingLimits {
+            hash: ListpackLimits {
+                max_entries: 2,
+                max_value: 64,
+            },
+            ..EncodingLimits::default()
+        });
+        run(&store, "HSET hash g v");
+        assert_eq!(encoding("hash"), bulk("listpack"));
+        run(&store, "HSET hash h v");
+        run(&store, "HDEL hash h");
+        assert_eq!(encoding("hash"), bulk("hashtable"));
+    }
+
+    #[test]
+    fn object_freq_and_refcount() {
+        let store = Store::new();
+        run(&store, "SET a 1");
+        // New keys start at 5, and the first access always increments
+        assert_eq!(reply(&store, "OBJECT FREQ a"), Data::Integer(5));
+        assert_eq!(reply(&store, "OBJECT FREQ a"), Data::Integer(5));
+        run(&store, "GET a");
+        assert_eq!(reply(&store, "OBJECT FREQ a"), Data::Integer(6));
+        assert_eq!(reply(&store, "OBJECT FREQ missing"), Data::NullBulkString);
+
+        assert_eq!(reply(&store, "OBJECT REFCOUNT a"), Data::Integer(1));
+        assert_eq!(
+            reply(&store, "OBJECT REFCOUNT missing"),
+            Data::NullBulkString
+        );
+
+        let Data::Array(help) = reply(&store, "OBJECT HELP") else {
+            panic!("expected array");
+        };
+        for subcommand in [
+            "ENCODING <key>",
+            "FREQ <key>",
+            "IDLETIME <key>",
+            "REFCOUNT <key>",
+        ] {
+            assert!(help.contains(&Data::SimpleString(subcommand.into())));
+        }
+    }
+
+    #[test]
+    fn zadd() {
+        let store = Store::new();
+        let replica = Store::new();
+        let outcome = run(&store, "ZADD z 1 a 2 b 2.5 c");
+        assert_eq!(outcome.reply, Data::Integer(3));
+        replay(&replica, outcome);
+
+        // GT doesn't lower a score, but still adds new members
+        assert_eq!(reply(&store, "ZADD z GT 0 a 1 d"), Data::Integer(1));
+        assert_eq!(reply(&store, "ZSCORE z a"), bulk("1"));
+        // CH counts changed members too, but not unchanged ones
+        let outcome = run(&store, "ZADD z CH 5 a 2 b 3 e");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        replay(&replica, outcome);
+        assert_eq!(reply(&store, "ZADD z 2 b"), Data::Integer(0));
+        // Nothing changed, so nothing to replicate
+        assert!(run(&store, "ZADD z XX 3 x").replicate.is_empty());
+
+        let outcome = run(&store, "ZADD z INCR 1.5 a");
+        assert_eq!(outcome.reply, bulk("6.5"));
+        replay(&replica, outcome);
+        assert_eq!(reply(&store, "ZADD z INCR -1 new"), bulk("-1"));
+        assert_eq!(reply(&store, "ZADD z NX INCR 1 a"), Data::NullBulkString);
+        assert_eq!(reply(&store, "ZADD z LT INCR 1 a"), Data::NullBulkString);
+
+        assert_eq!(reply(&replica, "ZSCORE z a"), bulk("6.5"));
+        assert_eq!(reply(&replica, "ZSCORE z c"), bulk("2.5"));
+        assert_eq!(reply(&replica, "ZCARD z"), Data::Integer(4));
+        assert_eq!(reply(&store, "ZCARD z"), Data::Integer(6));
+        assert_eq!(reply(&store, "TYPE z"), Data::SimpleString("zset".into()));
+    }
+
+    fn strings(items: &[&str]) -> Data {
+        Data::Array(items.iter().map(|item| bulk(item)).collect())
+    }
+
+    #[test]
+    fn zrange() {
+        let store = Store::new();
+        run(&store, "ZADD z 1 a 2 b 3 c 4 d 5 e");
+
+        assert_eq!(reply(&store, "ZRANGE z 1 -2"), strings(&["b", "c", "d"]));
+        assert_eq!(reply(&store, "ZREVRANGE z 0 1"), strings(&["e", "d"]));
+        assert_eq!(
+            reply(&store, "ZRANGE z 0 1 REV WITHSCORES"),
+            strings(&["e", "5", "d", "4"])
+        );
+        assert_eq!(reply(&store, "ZRANGE missing 0 -1"), strings(&[]));
+
+        assert_eq!(reply(&store, "ZRANGE z (2 4 BYSCORE"), strings(&["c", "d"]));
+        assert_eq!(
+            reply(&store, "ZRANGEBYSCORE z 2 (4 WITHSCORES"),
+            strings(&["b", "2", "c", "3"])
+        );
+        assert_eq!(
+            reply(&store, "ZRANGEBYSCORE z -inf +inf LIMIT 3 -1"),
+            strings(&["d", "e"])
+        );
+        assert_eq!(reply(&store, "ZRANGE z (1 (2 BYSCORE"), strings(&[]));
+        // Reversed ranges go from the max to the min
+        assert_eq!(
+            reply(&store, "ZRANGE z (5 2 BYSCORE REV LIMIT 1 2"),
+            strings(&["c", "b"])
+        );
+        assert_eq!(
+            reply(&store, "ZREVRANGEBYSCORE z +inf (3 LIMIT 0 1"),
+            strings(&["e"])
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE z 2 5 BYSCORE LIMIT -1 2"),
+            strings(&[])
+        );
+    }
+
+    #[test]
+    fn zrem() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD z 1 a 2 b 3 c"));
+
+        let outcome = run(&store, "ZREM z a x a");
+        assert_eq!(outcome.reply, Data::Integer(1));
+        replay(&replica, outcome);
+        // Non-members aren't removed, and nothing is replicated
+        let outcome = run(&store, "ZREM z x y");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        assert!(outcome.replicate.is_empty());
+        assert_eq!(reply(&store, "ZREM missing a"), Data::Integer(0));
+
+        replay(&replica, run(&store, "ZREM z b c"));
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE z"), Data::SimpleString("none".into()));
+        }
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZREM s a")));
+    }
+
+    #[test]
+    fn zremrangebyrank() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD z 1 a 2 b 3 c 4 d 5 e"));
+
+        let outcome = run(&store, "ZREMRANGEBYRANK z -2 -1");
+        assert_eq!(outcome.reply, Data::Integer(2));
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "ZRANGE z 0 -1"), strings(&["a", "b", "c"]));
+        assert_eq!(reply(&store, "ZREMRANGEBYRANK z 5 10"), Data::Integer(0));
+
+        // The whole set
+        replay(&replica, run(&store, "ZREMRANGEBYRANK z 0 -1"));
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE z"), Data::SimpleString("none".into()));
+        }
+        assert_eq!(reply(&store, "ZREMRANGEBYRANK z 0 -1"), Data::Integer(0));
+    }
+
+    #[test]
+    fn zremrangebyscore() {
+        let store = Store::new();
+        run(&store, "ZADD z 1 a 2 b 3 c 4 d 5 e");
+
+        assert_eq!(reply(&store, "ZREMRANGEBYSCORE z (1 (3"), Data::Integer(1));
+        assert_eq!(
+            reply(&store, "ZRANGE z 0 -1"),
+            strings(&["a", "c", "d", "e"])
+        );
+        assert_eq!(
+            reply(&store, "ZREMRANGEBYSCORE z (4 +inf"),
+            Data::Integer(1)
+        );
+        assert_eq!(reply(&store, "ZREMRANGEBYSCORE z (3 (4"), Data::Integer(0));
+        assert_eq!(reply(&store, "ZREMRANGEBYSCORE z -inf 4"), Data::Integer(3));
+        assert_eq!(reply(&store, "TYPE z"), Data::SimpleString("none".into()));
+        assert_eq!(
+            reply(&store, "ZREMRANGEBYSCORE z x 1"),
+            Data::SimpleError("ERR min or max is not a float".into())
+        );
+    }
+
+    #[test]
+    fn zpop() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD z 1 a 2 b 3 c 4 d"));
+
+        let outcome = run(&store, "ZPOPMIN z");
+        assert_eq!(outcome.reply, strings(&["a", "1"]));
+        assert_eq!(outcome.replicate, vec![strings(&["ZREM", "z", "a"])]);
+        replay(&replica, outcome);
+        let outcome = run(&store, "ZPOPMAX z 2");
+        assert_eq!(outcome.reply, strings(&["d", "4", "c", "3"]));
+        replay(&replica, outcome);
+        assert_eq!(reply(&replica, "ZRANGE z 0 -1"), strings(&["b"]));
+
+        // More than there are
+        replay(&replica, run(&store, "ZPOPMIN z 10"));
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE z"), Data::SimpleString("none".into()));
+        }
+        let outcome = run(&store, "ZPOPMIN z");
+        assert_eq!(outcome.reply, strings(&[]));
+        assert!(outcome.replicate.is_empty());
+
+        assert_eq!(
+            reply(&store, "ZPOPMIN z -1"),
+            Data::SimpleError("ERR value is out of range, must be positive".into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZPOPMAX s")));
+    }
+
+    #[test]
+    fn zmpop() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD b 1 x 2 y 3 z"));
+        replay(&replica, run(&store, "ZADD c 10 w"));
+
+        // The first key with members is popped from
+        let outcome = run(&store, "ZMPOP 3 a b c MIN");
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![bulk("b"), Data::Array(vec![strings(&["x", "1"])])])
+        );
+        assert_eq!(outcome.replicate, vec![strings(&["ZREM", "b", "x"])]);
+        assert_eq!(outcome.modified, vec!["b".to_string()]);
+        replay(&replica, outcome);
+        let outcome = run(&store, "ZMPOP 3 a b c max COUNT 5");
+        assert_eq!(
+            outcome.reply,
+            Data::Array(vec![
+                bulk("b"),
+                Data::Array(vec![strings(&["z", "3"]), strings(&["y", "2"])])
+            ])
+        );
+        assert_eq!(outcome.replicate, vec![strings(&["ZREM", "b", "z", "y"])]);
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE b"), Data::SimpleString("none".into()));
+        }
+        assert_eq!(
+            reply(&store, "ZMPOP 2 b c MIN"),
+            Data::Array(vec![bulk("c"), Data::Array(vec![strings(&["w", "10"])])])
+        );
+
+        // Nothing to pop
+        let outcome = run(&store, "ZMPOP 2 b c MIN COUNT 2");
+        assert_eq!(outcome.reply, Data::NullArray);
+        assert!(outcome.replicate.is_empty());
+
+        for (command, error) in [
+            ("ZMPOP 0 a MIN", "ERR numkeys should be greater than 0"),
+            ("ZMPOP -1 a MIN", "ERR numkeys should be greater than 0"),
+            (
+                "ZMPOP 1 a MIN COUNT 0",
+                "ERR count should be greater than 0",
+            ),
+            (
+                "ZMPOP 1 a MIN COUNT -1",
+                "ERR count should be greater than 0",
+            ),
+            ("ZMPOP 2 a MIN", SYNTAX_ERR_MSG),
+            ("ZMPOP 1 a LEFT", SYNTAX_ERR_MSG),
+            ("ZMPOP 1 a MIN COUNT", SYNTAX_ERR_MSG),
+            ("ZMPOP 1 a MIN COUNT 1 COUNT 1", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(
+                reply(&store, command),
+                Data::SimpleError(error.into()),
+                "{}",
+                command
+            );
+        }
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZMPOP 2 s c MIN")));
+    }
+
+    #[test]
+    fn zrank() {
+        let store = Store::new();
+        // Members with the same score are ordered by member
+        run(&store, "ZADD z 2 c 1 b 2 a 3 d");
+
+        for (member, rank) in [("b", 0), ("a", 1), ("c", 2), ("d", 3)] {
+            assert_eq!(
+                reply(&store, &format!("ZRANK z {}", member)),
+                Data::Integer(rank)
+            );
+            assert_eq!(
+                reply(&store, &format!("ZREVRANK z {}", member)),
+                Data::Integer(3 - rank)
+            );
+        }
+        assert_eq!(
+            reply(&store, "ZRANK z c WITHSCORE"),
+            Data::Array(vec![Data::Integer(2), bulk("2")])
+        );
+        assert_eq!(
+            reply(&store, "ZREVRANK z b withscore"),
+            Data::Array(vec![Data::Integer(3), bulk("1")])
+        );
+
+        assert_eq!(reply(&store, "ZRANK z x"), Data::NullBulkString);
+        assert_eq!(reply(&store, "ZRANK missing a"), Data::NullBulkString);
+        assert_eq!(reply(&store, "ZRANK z x WITHSCORE"), Data::NullArray);
+        assert_eq!(
+            reply(&store, "ZRANK z a WITHSCORES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZREVRANK s a")));
+    }
+
+    #[test]
+    fn zrange_bylex() {
+        let store = Store::new();
+        run(&store, "ZADD z 0 a 0 b 0 c 0 d 0 e");
+
+        assert_eq!(reply(&store, "ZRANGE z [b (d BYLEX"), strings(&["b", "c"]));
+        assert_eq!(reply(&store, "ZRANGE z - (c BYLEX"), strings(&["a", "b"]));
+        assert_eq!(
+            reply(&store, "ZRANGE z + [c BYLEX REV LIMIT 1 5"),
+            strings(&["d", "c"])
+        );
+        assert_eq!(reply(&store, "ZRANGE z (c (c BYLEX"), strings(&[]));
+
+        assert_eq!(reply(&store, "ZRANGEBYLEX z [b (d"), strings(&["b", "c"]));
+        assert_eq!(
+            reply(&store, "ZRANGEBYLEX z - + LIMIT 1 2"),
+            strings(&["b", "c"])
+        );
+        assert_eq!(
+            reply(&store, "ZREVRANGEBYLEX z (d -"),
+            strings(&["c", "b", "a"])
+        );
+        assert_eq!(
+            reply(&store, "ZREVRANGEBYLEX z + [c limit 0 1"),
+            strings(&["e"])
+        );
+        assert_eq!(reply(&store, "ZRANGEBYLEX missing - +"), strings(&[]));
+        for command in ["ZRANGEBYLEX z - + WITHSCORES", "ZRANGEBYLEX z - + REV"] {
+            assert_eq!(
+                reply(&store, command),
+                Data::SimpleError(SYNTAX_ERR_MSG.into()),
+                "{}",
+                command
+            );
+        }
+        assert_eq!(
+            reply(&store, "ZRANGEBYLEX z b d"),
+            Data::SimpleError("ERR min or max not valid string range item".into())
+        );
+
+        // Members compare by their bytes, which for UTF-8 is by code point
+        run(
+            &store,
+            "ZADD u 0 zebra 0 école 0 äpfel 0 日本 0 Ωmega 0 apple",
+        );
+        assert_eq!(
+            reply(&store, "ZRANGEBYLEX u - +"),
+            strings(&["apple", "zebra", "äpfel", "école", "Ωmega", "日本"])
+        );
+        assert_eq!(
+            reply(&store, "ZRANGEBYLEX u (zebra [Ωmega"),
+            strings(&["äpfel", "école", "Ωmega"])
+        );
+        assert_eq!(
+            reply(&store, "ZREVRANGEBYLEX u + (Ωmega"),
+            strings(&["日本"])
+        );
+        assert_eq!(reply(&store, "ZLEXCOUNT u [äpfel (日本"), Data::Integer(3));
+    }
+
+    #[test]
+    fn zcount() {
+        let store = Store::new();
+        run(&store, "ZADD z 1 a 2 b 3 c 4 d");
+
+        assert_eq!(reply(&store, "ZCOUNT z 2 3"), Data::Integer(2));
+        assert_eq!(reply(&store, "ZCOUNT z (2 3"), Data::Integer(1));
+        assert_eq!(reply(&store, "ZCOUNT z (2 (3"), Data::Integer(0));
+        assert_eq!(reply(&store, "ZCOUNT z -inf +inf"), Data::Integer(4));
+        assert_eq!(reply(&store, "ZCOUNT z 3 2"), Data::Integer(0));
+        assert_eq!(reply(&store, "ZCOUNT missing 0 1"), Data::Integer(0));
+        assert_eq!(
+            reply(&store, "ZCOUNT z a 1"),
+            Data::SimpleError("ERR min or max is not a float".into())
+        );
+
+        run(&store, "ZADD l 0 a 0 b 0 c 0 d");
+        assert_eq!(reply(&store, "ZLEXCOUNT l [b [c"), Data::Integer(2));
+        assert_eq!(reply(&store, "ZLEXCOUNT l (b [c"), Data::Integer(1));
+        assert_eq!(reply(&store, "ZLEXCOUNT l (b (c"), Data::Integer(0));
+        assert_eq!(reply(&store, "ZLEXCOUNT l - +"), Data::Integer(4));
+        assert_eq!(
+            reply(&store, "ZLEXCOUNT l b c"),
+            Data::SimpleError("ERR min or max not valid string range item".into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZCOUNT s 0 1")));
+    }
+
+    #[test]
+    fn zunionstore_and_zinterstore() {
+        let store = Store::new();
+        run(&store, "ZADD a 1 x 2 y 3 z");
+        run(&store, "ZADD b 10 y 20 z 30 w");
+        run(&store, "SADD s x w");
+
+        assert_eq!(reply(&store, "ZUNIONSTORE out 2 a b"), Data::Integer(4));
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["x", "1", "y", "12", "z", "23", "w", "30"])
+        );
+
+        // Negative weights
+        assert_eq!(
+            reply(&store, "ZINTERSTORE out 2 a b WEIGHTS 2 -1"),
+            Data::Integer(2)
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["z", "-14", "y", "-6"])
+        );
+
+        assert_eq!(
+            reply(&store, "ZUNIONSTORE out 2 a b AGGREGATE MIN"),
+            Data::Integer(4)
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["x", "1", "y", "2", "z", "3", "w", "30"])
+        );
+
+        // Members of plain sets have a score of 1
+        assert_eq!(
+            reply(&store, "ZINTERSTORE out 2 s a weights 5 1 aggregate max"),
+            Data::Integer(1)
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["x", "5"])
+        );
+
+        // The destination may be a source
+        assert_eq!(reply(&store, "ZUNIONSTORE a 2 a a"), Data::Integer(3));
+        assert_eq!(
+            reply(&store, "ZRANGE a 0 -1 WITHSCORES"),
+            strings(&["x", "2", "y", "4", "z", "6"])
+        );
+
+        // An empty result removes the destination
+        assert_eq!(
+            reply(&store, "ZINTERSTORE out 2 a missing"),
+            Data::Integer(0)
+        );
+        assert_eq!(reply(&store, "TYPE out"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn zdiff() {
+        let store = Store::new();
+        run(&store, "ZADD a 1 x 2 y 3 z");
+        run(&store, "ZADD b 5 y");
+        run(&store, "SADD s z");
+
+        assert_eq!(reply(&store, "ZDIFF 2 a b"), strings(&["x", "z"]));
+        assert_eq!(
+            reply(&store, "ZDIFF 3 a b s WITHSCORES"),
+            strings(&["x", "1"])
+        );
+        assert_eq!(reply(&store, "ZDIFF 2 missing a"), strings(&[]));
+        assert_eq!(
+            reply(&store, "ZDIFF 1 a WEIGHTS 1"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+
+        assert_eq!(reply(&store, "ZDIFFSTORE out 2 a b"), Data::Integer(2));
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["x", "1", "z", "3"])
+        );
+        assert_eq!(
+            reply(&store, "ZDIFFSTORE out 1 a WITHSCORES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        // An empty first set empties the destination
+        assert_eq!(
+            reply(&store, "ZDIFFSTORE out 2 missing a"),
+            Data::Integer(0)
+        );
+        assert_eq!(reply(&store, "TYPE out"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn zunion_and_zinter() {
+        let store = Store::new();
+        run(&store, "ZADD a 1 x 2 y 3 z");
+        run(&store, "ZADD b 10 y 20 z 30 w");
+
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b"),
+            strings(&["x", "y", "z", "w"])
+        );
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b WEIGHTS 1 -1 WITHSCORES"),
+            strings(&["w", "-30", "z", "-17", "y", "-8", "x", "1"])
+        );
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b AGGREGATE MAX WITHSCORES"),
+            strings(&["y", "10", "z", "20"])
+        );
+        // Options in any order
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b WITHSCORES AGGREGATE MIN WEIGHTS 3 1"),
+            strings(&["y", "6", "z", "9"])
+        );
+        assert_eq!(reply(&store, "ZINTER 2 a missing"), strings(&[]));
+        assert_eq!(reply(&store, "ZUNION 1 missing"), strings(&[]));
+
+        // Nothing is stored
+        let outcome = run(&store, "ZUNION 2 a b");
+        assert!(outcome.replicate.is_empty());
+        assert!(outcome.modified.is_empty());
+
+        assert_eq!(
+            reply(&store, "ZUNIONSTORE out 1 a WITHSCORES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b WEIGHTS 1"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "ZUNION 0 a"),
+            Data::SimpleError("ERR at least 1 input key is needed for 'zunion' command".into())
+        );
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "ZINTER 2 a str")));
+    }
+
+    #[test]
+    fn zunion_aggregate_min_and_max() {
+        let store = Store::new();
+        run(&store, "ZADD a -inf x 0 y 5 z");
+        run(&store, "ZADD b inf x -0 y 5 z");
+        run(&store, "SADD s x y");
+
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b AGGREGATE MIN WITHSCORES"),
+            strings(&["x", "-inf", "y", "0", "z", "5"])
+        );
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b AGGREGATE MAX WITHSCORES"),
+            strings(&["y", "0", "z", "5", "x", "inf"])
+        );
+        // inf + -inf is 0, rather than NaN
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b WITHSCORES"),
+            strings(&["x", "0", "y", "0", "z", "10"])
+        );
+
+        // A weight of 0 makes infinite scores 0 too, so MIN and MAX compare
+        // them as 0
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b WEIGHTS 0 1 AGGREGATE MAX WITHSCORES"),
+            strings(&["y", "0", "z", "5", "x", "inf"])
+        );
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b WEIGHTS 1 0 AGGREGATE MIN WITHSCORES"),
+            strings(&["x", "-inf", "y", "0", "z", "0"])
+        );
+        // A negative weight swaps which score is the minimum
+        assert_eq!(
+            reply(&store, "ZINTER 2 a b WEIGHTS -1 1 AGGREGATE MIN WITHSCORES"),
+            strings(&["z", "-5", "y", "0", "x", "inf"])
+        );
+
+        // Members of plain sets have a score of 1, before weights
+        assert_eq!(
+            reply(&store, "ZINTER 2 s a WEIGHTS 2 1 AGGREGATE MAX WITHSCORES"),
+            strings(&["x", "2", "y", "2"])
+        );
+        assert_eq!(
+            reply(&store, "ZINTERSTORE out 2 s b WEIGHTS 2 1 AGGREGATE MIN"),
+            Data::Integer(2)
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["y", "0", "x", "2"])
+        );
+
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b AGGREGATE AVG"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "ZUNION 2 a b AGGREGATE"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn zdiffstore_replicates() {
+        let store = Store::new();
+        run(&store, "ZADD a 1 x 2 y");
+        let outcome = run(&store, "ZDIFFSTORE out 1 a");
+        assert_eq!(outcome.modified, vec!["out".to_string()]);
+        assert!(!outcome.replicate.is_empty());
+        assert!(run(&store, "ZDIFF 1 a").replicate.is_empty());
+    }
+
+    #[test]
+    fn stored_sorted_sets_are_replicated_as_zadd() {
+        let store = Store::new();
+        // The replica's sources differ, and aren't read
+        let replica = Store::new();
+        run(&store, "ZADD a 1 x 2.5 y 3 z");
+        run(&store, "ZADD b 1e300 y");
+        run(&replica, "ZADD a 100 other");
+        run(&replica, "ZADD out 7 stale");
+
+        let outcome = run(&store, "ZRANGESTORE out a 1 -1");
+        assert_eq!(
+            outcome.replicate,
+            vec![
+                strings(&["DEL", "out"]),
+                strings(&["ZADD", "out", "2.5", "y", "3", "z"])
+            ]
+        );
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&replica, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["y", "2.5", "z", "3"])
+        );
+
+        replay(&replica, run(&store, "ZUNIONSTORE out 2 a b"));
+        assert_eq!(
+            reply(&replica, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["x", "1", "z", "3", "y", "1e+300"])
+        );
+        replay(&replica, run(&store, "ZINTERSTORE out 2 a b WEIGHTS 0 1"));
+        assert_eq!(
+            reply(&replica, "ZRANGE out 0 -1 WITHSCORES"),
+            strings(&["y", "1e+300"])
+        );
+
+        // An empty result only deletes the destination
+        let outcome = run(&store, "ZDIFFSTORE out 2 b a");
+        assert_eq!(outcome.replicate, vec![strings(&["DEL", "out"])]);
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&replica, "TYPE out"),
+            Data::SimpleString("none".into())
+        );
+    }
+
+    #[test]
+    fn setbit_and_getbit() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "GETBIT b 7"), Data::Integer(0));
+        assert_eq!(reply(&store, "SETBIT b 7 1"), Data::Integer(0));
+        assert_eq!(reply(&store, "SETBIT b 7 1"), Data::Integer(1));
+        // Crossing into the next byte grows the string
+        assert_eq!(reply(&store, "SETBIT b 9 1"), Data::Integer(0));
+        assert_eq!(
+            reply(&store, "GET b"),
+            Data::BulkString(vec![0b0000_0001, 0b0100_0000])
+        );
+        assert_eq!(reply(&store, "GETBIT b 9"), Data::Integer(1));
+        assert_eq!(reply(&store, "GETBIT b 8"), Data::Integer(0));
+        assert_eq!(reply(&store, "GETBIT b 1000"), Data::Integer(0));
+
+        run(&store, "SET s a");
+        // 'a' is 01100001
+        assert_eq!(reply(&store, "GETBIT s 1"), Data::Integer(1));
+        assert_eq!(reply(&store, "SETBIT s 6 1"), Data::Integer(0));
+        assert_eq!(reply(&store, "GET s"), bulk("c"));
+
+        for (cmd, err) in [
+            ("SETBIT b -1 1", BIT_OFFSET_ERR_MSG),
+            ("SETBIT b 4294967296 1", BIT_OFFSET_ERR_MSG),
+            ("SETBIT b 0 2", "ERR bit is not an integer or out of range"),
+            ("GETBIT b x", BIT_OFFSET_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "HSET h f v");
+        assert!(is_wrong_type(reply(&store, "SETBIT h 0 1")));
+        assert!(is_wrong_type(reply(&store, "GETBIT h 0")));
+    }
+
+    #[test]
+    fn setbit_pads_with_zeros() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "SETBIT b 100 1"), Data::Integer(0));
+        let mut expected = vec![0; 13];
+        expected[12] = 0b0000_1000;
+        assert_eq!(reply(&store, "GET b"), Data::BulkString(expected));
+
+        // Offsets are limited by the largest string allowed
+        store.set_max_string_len(2);
+        assert_eq!(reply(&store, "SETBIT c 15 1"), Data::Integer(0));
+        assert_eq!(
+            reply(&store, "SETBIT c 16 1"),
+            Data::SimpleError(BIT_OFFSET_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn bitcount() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "BITCOUNT missing"), Data::Integer(0));
+
+        run(&store, "SET s foobar");
+        assert_eq!(reply(&store, "BITCOUNT s"), Data::Integer(26));
+        assert_eq!(reply(&store, "BITCOUNT s 0 0"), Data::Integer(4));
+        assert_eq!(reply(&store, "BITCOUNT s 1 1"), Data::Integer(6));
+        assert_eq!(reply(&store, "BITCOUNT s 1 1 BYTE"), Data::Integer(6));
+        assert_eq!(reply(&store, "BITCOUNT s 5 30 BIT"), Data::Integer(17));
+        assert_eq!(reply(&store, "BITCOUNT s -1 -2"), Data::Integer(0));
+        assert_eq!(
+            reply(&store, "BITCOUNT s 0"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(
+            reply(&store, "BITCOUNT s 0 1 WORD"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn bitpos() {
+        let store = Store::new();
+        // A missing key is an empty string
+        assert_eq!(reply(&store, "BITPOS missing 1"), Data::Integer(-1));
+        assert_eq!(reply(&store, "BITPOS missing 0"), Data::Integer(0));
+
+        run(&store, "SETBIT s 12 1");
+        // 00000000 00001000
+        assert_eq!(reply(&store, "BITPOS s 1"), Data::Integer(12));
+        assert_eq!(reply(&store, "BITPOS s 1 1"), Data::Integer(12));
+        assert_eq!(reply(&store, "BITPOS s 1 0 0"), Data::Integer(-1));
+        assert_eq!(reply(&store, "BITPOS s 1 9 12 BIT"), Data::Integer(12));
+        assert_eq!(reply(&store, "BITPOS s 0 12 -1 bit"), Data::Integer(13));
+        assert_eq!(reply(&store, "BITPOS s 1 -1 -1 BYTE"), Data::Integer(12));
+
+        // All ones: a clear bit is found past the end, unless there's an end
+        run(&store, "SETBIT zeros 15 0");
+        run(&store, "BITOP NOT ones zeros");
+        assert_eq!(reply(&store, "BITPOS ones 0"), Data::Integer(16));
+        assert_eq!(reply(&store, "BITPOS ones 0 1"), Data::Integer(16));
+        assert_eq!(reply(&store, "BITPOS ones 0 0 -1"), Data::Integer(-1));
+
+        for (cmd, err) in [
+            ("BITPOS s 2", "ERR The bit argument must be 1 or 0."),
+            ("BITPOS s 1 x", NOT_INTEGER_ERR_MSG),
+            ("BITPOS s 1 0 1 WORD", SYNTAX_ERR_MSG),
+            ("BITPOS s 1 0 1 BIT x", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "HSET h f v");
+        assert!(is_wrong_type(reply(&store, "BITPOS h 1")));
+    }
+
+    #[test]
+    fn bitop() {
+        let store = Store::new();
+        run(&store, "SET a abc");
+        run(&store, "SET b ab");
+
+        assert_eq!(reply(&store, "BITOP AND dest a b"), Data::Integer(3));
+        assert_eq!(
+            reply(&store, "GET dest"),
+            Data::BulkString(b"ab\0".to_vec())
+        );
+        assert_eq!(reply(&store, "BITOP OR dest a b missing"), Data::Integer(3));
+        assert_eq!(reply(&store, "GET dest"), bulk("abc"));
+        assert_eq!(reply(&store, "BITOP XOR dest a b"), Data::Integer(3));
+        assert_eq!(
+            reply(&store, "GET dest"),
+            Data::BulkString(vec![0, 0, b'c'])
+        );
+        assert_eq!(reply(&store, "BITOP NOT dest b"), Data::Integer(2));
+        assert_eq!(
+            reply(&store, "GET dest"),
+            Data::BulkString(vec![!b'a', !b'b'])
+        );
+
+        // The result replaces any value, and an empty one removes it
+        run(&store, "HSET h f v");
+        assert_eq!(reply(&store, "BITOP OR h a"), Data::Integer(3));
+        assert_eq!(reply(&store, "BITOP OR h missing"), Data::Integer(0));
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("none".into()));
+
+        assert_eq!(
+            reply(&store, "BITOP NOT dest a b"),
+            Data::SimpleError("ERR BITOP NOT must be called with a single source key.".into())
+        );
+        assert_eq!(
+            reply(&store, "BITOP NAND dest a b"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        run(&store, "HSET h f v");
+        assert!(is_wrong_type(reply(&store, "BITOP AND dest a h")));
+    }
+
+    #[test]
+    fn bitop_not_and_replication() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "SET a abc"));
+        replay(&replica, run(&store, "SET b a"));
+        replay(&replica, run(&store, "SET dest x"));
+
+        // NOT of a missing key is empty, so it removes the destination
+        let outcome = run(&store, "BITOP NOT dest missing");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        replay(&replica, outcome);
+        let outcome = run(&store, "BITOP XOR other a b");
+        assert_eq!(outcome.reply, Data::Integer(3));
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "TYPE dest"), Data::SimpleString("none".into()));
+            assert_eq!(
+                reply(store, "GET other"),
+                Data::BulkString(vec![0, b'b', b'c'])
+            );
+        }
+    }
+
+    fn bitfield_replies(values: &[Option<i64>]) -> Data {
+        Data::Array(
+            values
+                .iter()
+                .map(|v| v.map_or(Data::NullBulkString, Data::Integer))
+                .collect(),
+        )
+    }
+
+    // The example of the Redis documentation
+    fn sicily(store: &Store) {
+        run(
+            store,
+            "GEOADD Sicily 13.361389 38.115556 Palermo 15.087269 37.502669 Catania",
+        );
+    }
+
+    #[test]
+    fn geoadd() {
+        let store = Store::new();
+        let replica = Store::new();
+        let outcome = run(
+            &store,
+            "GEOADD Sicily 13.361389 38.115556 Palermo 15.087269 37.502669 Catania",
+        );
+        assert_eq!(outcome.reply, Data::Integer(2));
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(
+                reply(store, "ZSCORE Sicily Palermo"),
+                bulk("3479099956230698")
+            );
+        }
+        assert_eq!(
+            reply(&store, "TYPE Sicily"),
+            Data::SimpleString("zset".into())
+        );
+
+        assert_eq!(
+            reply(&store, "GEOADD Sicily NX 0 0 Palermo"),
+            Data::Integer(0)
+        );
+        assert_eq!(reply(&store, "GEOADD Sicily XX 0 0 Rome"), Data::Integer(0));
+        assert!(run(&store, "GEOADD Sicily 13.361389 38.115556 Palermo")
+            .replicate
+            .is_empty());
+        assert_eq!(
+            reply(&store, "GEOADD Sicily CH 0 0 Palermo"),
+            Data::Integer(1)
+        );
+        assert_eq!(reply(&store, "ZCARD Sicily"), Data::Integer(2));
+
+        for (cmd, err) in [
+            (
+                "GEOADD Sicily 200 100 x",
+                "ERR invalid longitude,latitude pair 200.000000,100.000000",
+            ),
+            (
+                "GEOADD Sicily 0 86 x",
+                "ERR invalid longitude,latitude pair 0.000000,86.000000",
+            ),
+            ("GEOADD Sicily 0 0 x 1", SYNTAX_ERR_MSG),
+            ("GEOADD Sicily NX 0 0", SYNTAX_ERR_MSG),
+            ("GEOADD Sicily a 0 x", NOT_FLOAT_ERR_MSG),
+            (
+                "GEOADD Sicily NX XX 0 0 x",
+                "ERR XX and NX options at the same time are not compatible",
+            ),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "GEOADD s 0 0 x")));
+    }
+
+    #[test]
+    fn geopos_and_geodist() {
+        let store = Store::new();
+        sicily(&store);
+
+        let Data::Array(positions) = reply(&store, "GEOPOS Sicily Palermo missing") else {
+            panic!();
+        };
+        assert_eq!(positions[1], Data::NullArray);
+        let Data::Array(palermo) = &positions[0] else {
+            panic!();
+        };
+        let coordinate = |data: &Data| match data {
+            Data::BulkString(s) => String::from_utf8(s.clone())
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+            _ => panic!(),
+        };
+        assert!((coordinate(&palermo[0]) - 13.361389).abs() < 1e-5);
+        assert!((coordinate(&palermo[1]) - 38.115556).abs() < 1e-5);
+        assert_eq!(
+            reply(&store, "GEOPOS missing a"),
+            Data::Array(vec![Data::NullArray])
+        );
+
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Catania"),
+            bulk("166274.1516")
+        );
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Catania KM"),
+            bulk("166.2742")
+        );
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Catania mi"),
+            bulk("103.3182")
+        );
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Catania ft"),
+            bulk("545518.8700")
+        );
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Rome"),
+            Data::NullBulkString
+        );
+        assert_eq!(reply(&store, "GEODIST missing a b"), Data::NullBulkString);
+        assert_eq!(
+            reply(&store, "GEODIST Sicily Palermo Catania yd"),
+            Data::SimpleError("ERR unsupported unit provided. please use M, KM, FT, MI".into())
+        );
+    }
+
+    #[test]
+    fn geosearch() {
+        let store = Store::new();
+        sicily(&store);
+        run(
+            &store,
+            "GEOADD Sicily 12.758489 38.788135 edge1 17.241510 38.788135 edge2",
+        );
+
+        assert_eq!(
+            reply(
+                &store,
+                "GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 200 km ASC"
+            ),
+            strings(&["Catania", "Palermo"])
+        );
+        assert_eq!(
+            reply(
+                &store,
+                "GEOSEARCH Sicily FROMLONLAT 15 37 BYBOX 400 400 km DESC WITHDIST"
+            ),
+            Data::Array(vec![
+                strings(&["edge1", "279.7405"]),
+                strings(&["edge2", "279.7403"]),
+                strings(&["Palermo", "190.4424"]),
+                strings(&["Catania", "56.4413"]),
+            ])
+        );
+        // COUNT returns the closest members
+        assert_eq!(
+            reply(
+                &store,
+                "GEOSEARCH Sicily FROMMEMBER Palermo BYRADIUS 500 km COUNT 2"
+            ),
+            strings(&["Palermo", "edge1"])
+        );
+        let Data::Array(positions) = reply(&store, "GEOPOS Sicily Catania") else {
+            panic!();
+        };
+        assert_eq!(
+            reply(
+                &store,
+                "GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 100 km WITHHASH WITHCOORD"
+            ),
+            Data::Array(vec![Data::Array(vec![
+                bulk("Catania"),
+                Data::Integer(3479447370796909),
+                positions[0].clone(),
+            ])])
+        );
+        let Data::Array(found) = reply(
+            &store,
+            "GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 500 km COUNT 1 ANY",
+        ) else {
+            panic!();
+        };
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            reply(&store, "GEOSEARCH missing FROMMEMBER a BYRADIUS 1 m"),
+            strings(&[])
+        );
+    }
+
+    #[test]
+    fn geosearch_errors() {
+        let store = Store::new();
+        sicily(&store);
+        for (cmd, err) in [
+            (
+                "GEOSEARCH Sicily FROMMEMBER Rome BYRADIUS 1 km",
+                "ERR could not decode requested zset member",
+            ),
+            (
+                "GEOSEARCH Sicily FROMMEMBER Palermo FROMLONLAT 0 0 BYRADIUS 1 km",
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH",
+            ),
+            (
+                "GEOSEARCH Sicily BYRADIUS 1 km ASC WITHDIST",
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS 1 km BYBOX 1 1 km",
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 181 0 BYRADIUS 1 km",
+                "ERR invalid longitude,latitude pair 181.000000,0.000000",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS 1 km ANY",
+                "ERR the ANY argument requires COUNT argument",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS 1 km COUNT 0",
+                "ERR COUNT must be > 0",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS -1 km",
+                "ERR radius cannot be negative",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS 1 yd",
+                "ERR unsupported unit provided. please use M, KM, FT, MI",
+            ),
+            (
+                "GEOSEARCH Sicily FROMLONLAT 0 0 BYRADIUS 1 km FOO",
+                SYNTAX_ERR_MSG,
+            ),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn pfadd_and_pfcount() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "PFCOUNT h"), Data::Integer(0));
+        assert_eq!(reply(&store, "PFADD h"), Data::Integer(1));
+        assert_eq!(reply(&store, "PFADD h"), Data::Integer(0));
+        assert_eq!(reply(&store, "PFADD h a b c"), Data::Integer(1));
+        assert_eq!(reply(&store, "PFADD h a b"), Data::Integer(0));
+        assert_eq!(reply(&store, "PFCOUNT h"), Data::Integer(3));
+        run(&store, "PFADD g c d");
+        assert_eq!(reply(&store, "PFCOUNT h g missing"), Data::Integer(4));
+        // HyperLogLogs are strings
+        assert_eq!(reply(&store, "TYPE h"), Data::SimpleString("string".into()));
+
+        run(&store, "SET s abc");
+        run(&store, "HSET hash f v");
+        let invalid = Data::SimpleError(INVALID_HLL_ERR_MSG.into());
+        assert_eq!(reply(&store, "PFADD s a"), invalid);
+        assert_eq!(reply(&store, "PFCOUNT h s"), invalid);
+        assert!(is_wrong_type(reply(&store, "PFCOUNT hash")));
+    }
+
+    #[test]
+    fn pfmerge() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "PFADD a 1 2 3"));
+        replay(&replica, run(&store, "PFADD b 3 4"));
+        replay(&replica, run(&store, "PFADD dest 5"));
+        let outcome = run(&store, "PFMERGE dest a b missing");
+        assert_eq!(outcome.reply, Data::SimpleString("OK".into()));
+        replay(&replica, outcome);
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "PFCOUNT dest"), Data::Integer(5));
+        }
+        // Unchanged sources
+        assert_eq!(reply(&store, "PFCOUNT a"), Data::Integer(3));
+
+        assert_eq!(
+            reply(&store, "PFMERGE empty"),
+            Data::SimpleString("OK".into())
+        );
+        assert_eq!(reply(&store, "PFCOUNT empty"), Data::Integer(0));
+        run(&store, "SET s abc");
+        assert_eq!(
+            reply(&store, "PFMERGE s a"),
+            Data::SimpleError(INVALID_HLL_ERR_MSG.into())
+        );
+        assert_eq!(reply(&store, "GET s"), bulk("abc"));
+    }
+
+    #[test]
+    fn pfcount_of_many_keys_estimates_the_union() {
+        let store = Store::new();
+        let replica = Store::new();
+        // 20k elements in two overlapping keys, 15k distinct
+        for (key, range) in [("a", 0..10_000), ("b", 5_000..15_000)] {
+            for chunk in range.collect::<Vec<_>>().chunks(1_000) {
+                let elements: Vec<_> = chunk.iter().map(|i| format!("e{}", i)).collect();
+                let outcome = run(&store, &format!("PFADD {} {}", key, elements.join(" ")));
+                assert_eq!(outcome.reply, Data::Integer(1));
+                replay(&replica, outcome);
+            }
+        }
+        let within = |reply: Data, n: f64| {
+            let Data::Integer(count) = reply else {
+                panic!("expected integer");
+            };
+            let error = (count as f64 - n).abs() / n;
+            assert!(error < 0.02, "{} for {}", count, n);
+        };
+        for store in [&store, &replica] {
+            within(reply(store, "PFCOUNT a"), 10_000.0);
+            within(reply(store, "PFCOUNT a b"), 15_000.0);
+        }
+        assert_eq!(reply(&store, "PFCOUNT a b"), reply(&replica, "PFCOUNT a b"));
+    }
+
+    #[test]
+    fn command() {
+        let store = Store::new();
+        let Data::Integer(count) = reply(&store, "COMMAND COUNT") else {
+            panic!("expected integer");
+        };
+        let Data::Array(commands) = reply(&store, "COMMAND") else {
+            panic!("expected array");
+        };
+        assert_eq!(commands.len(), count as usize);
+
+        let simple_strings = |strings: &[&str]| {
+            Data::Array(
+                strings
+                    .iter()
+                    .map(|s| Data::SimpleString((*s).into()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            reply(&store, "COMMAND INFO GET nosuchcommand"),
+            Data::Array(vec![
+                Data::Array(vec![
+                    bulk("get"),
+                    Data::Integer(2),
+                    simple_strings(&["readonly", "fast"]),
+                    Data::Integer(1),
+                    Data::Integer(1),
+                    Data::Integer(1),
+                    simple_strings(&["@read", "@string", "@fast"]),
+                    Data::Array(vec![]),
+                    Data::Array(vec![]),
+                    Data::Array(vec![]),
+                ]),
+                Data::NullBulkString,
+            ])
+        );
+        assert_eq!(reply(&store, "COMMAND INFO"), Data::Array(commands));
+
+        assert_eq!(
+            reply(&store, "COMMAND DOCS get nosuchcommand"),
+            Data::Array(vec![
+                bulk("get"),
+                strings(&[
+                    "summary",
+                    "Returns the string value of a key.",
+                    "group",
+                    "string"
+                ]),
+            ])
+        );
+        let Data::Array(docs) = reply(&store, "COMMAND DOCS") else {
+            panic!("expected array");
+        };
+        assert_eq!(docs.len(), 2 * count as usize);
+        let Data::SimpleError(_) = reply(&store, "COMMAND COUNT 1") else {
+            panic!("expected error");
+        };
+    }
+
+    #[test]
+    fn sort() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "SORT missing"), Data::Array(vec![]));
+        run(&store, "SADD s 3 10 1 2.5");
+        assert_eq!(reply(&store, "SORT s"), strings(&["1", "2.5", "3", "10"]));
+        assert_eq!(
+            reply(&store, "SORT s DESC"),
+            strings(&["10", "3", "2.5", "1"])
+        );
+        assert_eq!(
+            reply(&store, "SORT s ALPHA"),
+            strings(&["1", "10", "2.5", "3"])
+        );
+
+        run(&store, "SADD words b a c");
+        assert_eq!(
+            reply(&store, "SORT words"),
+            Data::SimpleError("ERR One or more scores can't be converted into double".into())
+        );
+        assert_eq!(
+            reply(&store, "SORT words ALPHA DESC"),
+            strings(&["c", "b", "a"])
+        );
+
+        // Without "*", BY keeps the order of sorted sets
+        run(&store, "ZADD z 1 c 2 a 3 b");
+        assert_eq!(reply(&store, "SORT z BY nosort"), strings(&["c", "a", "b"]));
+        assert_eq!(
+            reply(&store, "SORT z BY nosort DESC LIMIT 0 2"),
+            strings(&["b", "a"])
+        );
+        assert_eq!(reply(&store, "SORT z ALPHA"), strings(&["a", "b", "c"]));
+
+        for (cmd, err) in [
+            ("SORT s LIMIT 1", SYNTAX_ERR_MSG),
+            ("SORT s LIMIT a 1", NOT_INTEGER_ERR_MSG),
+            ("SORT s FOO", SYNTAX_ERR_MSG),
+            ("SORT s STORE dest", "ERR SORT STORE is not supported"),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "SET str a");
+        assert!(is_wrong_type(reply(&store, "SORT str")));
+    }
+
+    #[test]
+    fn sort_by_and_get_patterns() {
+        let store = Store::new();
+        run(&store, "SADD users 1 2 3 4");
+        run(&store, "HSET user:1 name alice age 30");
+        run(&store, "HSET user:2 name bob age 25");
+        run(&store, "HSET user:3 name carol age 35");
+        // User 4 has no hash, so its age is 0
+        assert_eq!(
+            reply(&store, "SORT users BY user:*->age"),
+            strings(&["4", "2", "1", "3"])
+        );
+        let nil = Data::NullBulkString;
+        assert_eq!(
+            reply(&store, "SORT users BY user:*->age GET # GET user:*->name"),
+            Data::Array(vec![
+                bulk("4"),
+                nil.clone(),
+                bulk("2"),
+                bulk("bob"),
+                bulk("1"),
+                bulk("alice"),
+                bulk("3"),
+                bulk("carol"),
+            ])
+        );
+
+        // Paging through the sorted users
+        let page = |offset| {
+            reply(
+                &store,
+                &format!(
+                    "SORT users BY user:*->age DESC LIMIT {} 2 GET user:*->name",
+                    offset
+                ),
+            )
+        };
+        assert_eq!(page(0), strings(&["carol", "alice"]));
+        assert_eq!(page(2), Data::Array(vec![bulk("bob"), nil.clone()]));
+        assert_eq!(page(4), Data::Array(vec![]));
+        assert_eq!(
+            reply(&store, "SORT users LIMIT -1 -1"),
+            strings(&["1", "2", "3", "4"])
+        );
+
+        // Missing names come first
+        assert_eq!(
+            reply(
+                &store,
+                "SORT users BY user:*->name ALPHA DESC GET user:*->name"
+            ),
+            Data::Array(vec![bulk("carol"), bulk("bob"), bulk("alice"), nil.clone()])
+        );
+        assert_eq!(
+            reply(&store, "SORT users BY user:*->name"),
+            Data::SimpleError("ERR One or more scores can't be converted into double".into())
+        );
+
+        // Strings, and values that aren't any
+        run(&store, "SET weight_1 4");
+        run(&store, "SET weight_2 3");
+        run(&store, "SET weight_3 2");
+        run(&store, "SADD weight_4 1");
+        assert_eq!(
+            reply(
+                &store,
+                "SORT users BY weight_* GET weight_* GET nostar GET user:*->"
+            ),
+            Data::Array(
+                [
+                    vec![nil.clone(); 3],
+                    vec![bulk("2"), nil.clone(), nil.clone()],
+                    vec![bulk("3"), nil.clone(), nil.clone()],
+                    vec![bulk("4"), nil.clone(), nil],
+                ]
+                .concat()
+            )
+        );
+    }
+
+    #[test]
+    fn lcs() {
+        let store = Store::new();
+        run(&store, "SET key1 ohmytext");
+        run(&store, "SET key2 mynewtext");
+        assert_eq!(reply(&store, "LCS key1 key2"), bulk("mytext"));
+        assert_eq!(reply(&store, "LCS key1 key2 LEN"), Data::Integer(6));
+        assert_eq!(reply(&store, "LCS key1 missing"), bulk(""));
+        assert_eq!(reply(&store, "LCS missing key2 LEN"), Data::Integer(0));
+
+        let range = |start, end| integers(&[start, end]);
+        let idx_reply = |matches: Vec<Data>| {
+            Data::Array(vec![
+                bulk("matches"),
+                Data::Array(matches),
+                bulk("len"),
+                Data::Integer(6),
+            ])
+        };
+        assert_eq!(
+            reply(&store, "LCS key1 key2 IDX"),
+            idx_reply(vec![
+                Data::Array(vec![range(4, 7), range(5, 8)]),
+                Data::Array(vec![range(2, 3), range(0, 1)]),
+            ])
+        );
+        // Shorter matches are left out, but still count in the length
+        assert_eq!(
+            reply(&store, "LCS key1 key2 IDX MINMATCHLEN 4 WITHMATCHLEN"),
+            idx_reply(vec![Data::Array(vec![
+                range(4, 7),
+                range(5, 8),
+                Data::Integer(4)
+            ])])
+        );
+        assert_eq!(
+            reply(&store, "LCS key1 key2 IDX MINMATCHLEN -1"),
+            reply(&store, "LCS key1 key2 IDX")
+        );
+
+        for (cmd, err) in [
+            (
+                "LCS key1 key2 LEN IDX",
+                "ERR If you want both the length and indexes, please just use IDX.",
+            ),
+            ("LCS key1 key2 MINMATCHLEN", SYNTAX_ERR_MSG),
+            ("LCS key1 key2 MINMATCHLEN x", NOT_INTEGER_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "HSET h f v");
+        assert!(is_wrong_type(reply(&store, "LCS key1 h")));
+
+        // The table would be larger than proto-max-bulk-len
+        store.set_max_string_len(50);
+        assert_eq!(
+            reply(&store, "LCS key1 key2"),
+            Data::SimpleError(
+                "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn bitfield_get_set_and_incrby() {
+        let store = Store::new();
+        assert_eq!(
+            reply(
+                &store,
+                "BITFIELD b SET u8 0 200 GET u8 0 INCRBY i5 100 1 GET u4 0"
+            ),
+            bitfield_replies(&[Some(0), Some(200), Some(1), Some(12)])
+        );
+        // 11001000, then 00001 at offset 100
+        let mut expected = vec![0; 14];
+        expected[0] = 0b1100_1000;
+        expected[13] = 0b1000_0000;
+        assert_eq!(reply(&store, "GET b"), Data::BulkString(expected));
+        assert_eq!(
+            reply(&store, "BITFIELD b GET i8 0 GET u2 1000"),
+            bitfield_replies(&[Some(-56), Some(0)])
+        );
+    }
+
+    #[test]
+    fn bitfield_offsets_in_type_widths() {
+        let store = Store::new();
+        assert_eq!(
+            reply(
+                &store,
+                "BITFIELD b SET u8 #1 255 SET u4 #0 15 GET u8 8 GET u16 #0"
+            ),
+            bitfield_replies(&[Some(0), Some(0), Some(255), Some(0xf0ff)])
+        );
+    }
+
+    #[test]
+    fn bitfield_overflow() {
+        let store = Store::new();
+        run(&store, "BITFIELD b SET u8 0 250");
+        // WRAP is the default, and OVERFLOW only applies to the subcommands
+        // after it
+        assert_eq!(
+            reply(
+                &store,
+                "BITFIELD b INCRBY u8 0 10 OVERFLOW SAT INCRBY u8 0 300 \
+                 OVERFLOW FAIL INCRBY u8 0 1 INCRBY u8 0 -5 SET u8 0 256"
+            ),
+            bitfield_replies(&[Some(4), Some(255), None, Some(250), None])
+        );
+        assert_eq!(reply(&store, "GET b"), Data::BulkString(vec![250]));
+        assert_eq!(
+            reply(
+                &store,
+                "BITFIELD b OVERFLOW SAT INCRBY i8 0 -200 SET i8 0 1000"
+            ),
+            bitfield_replies(&[Some(-128), Some(-128)])
+        );
+        assert_eq!(reply(&store, "GET b"), Data::BulkString(vec![0x7f]));
+    }
+
+    #[test]
+    fn bitfield_missing_key() {
+        let store = Store::new();
+        assert_eq!(
+            reply(&store, "BITFIELD b GET i16 0 GET u8 #3"),
+            bitfield_replies(&[Some(0), Some(0)])
+        );
+        assert_eq!(reply(&store, "TYPE b"), Data::SimpleString("none".into()));
+        assert_eq!(reply(&store, "BITFIELD b"), Data::Array(vec![]));
+
+        // Like Redis, writes grow the string even when they fail
+        assert_eq!(
+            reply(&store, "BITFIELD b OVERFLOW FAIL INCRBY u4 8 100"),
+            bitfield_replies(&[None])
+        );
+        assert_eq!(reply(&store, "GET b"), Data::BulkString(vec![0, 0]));
+    }
+
+    #[test]
+    fn bitfield_replication() {
+        let store = Store::new();
+        let replica = Store::new();
+        let outcome = run(&store, "BITFIELD b GET u8 0");
+        assert!(outcome.replicate.is_empty());
+        replay(
+            &replica,
+            run(&store, "BITFIELD b SET i8 0 -1 INCRBY u4 8 3"),
+        );
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "GET b"), Data::BulkString(vec![0xff, 0x30]));
+        }
+    }
+
+    #[test]
+    fn bitfield_errors() {
+        let store = Store::new();
+        store.set_max_string_len(2);
+        for (cmd, err) in [
+            ("BITFIELD b GET u64 0", BITFIELD_TYPE_ERR_MSG),
+            ("BITFIELD b GET i65 0", BITFIELD_TYPE_ERR_MSG),
+            ("BITFIELD b GET x8 0", BITFIELD_TYPE_ERR_MSG),
+            ("BITFIELD b GET u8 -1", BIT_OFFSET_ERR_MSG),
+            ("BITFIELD b GET u8 16", BIT_OFFSET_ERR_MSG),
+            ("BITFIELD b GET u8 #2", BIT_OFFSET_ERR_MSG),
+            ("BITFIELD b GET u8 #x", BIT_OFFSET_ERR_MSG),
+            ("BITFIELD b GET u8", SYNTAX_ERR_MSG),
+            ("BITFIELD b SET u8 0", SYNTAX_ERR_MSG),
+            ("BITFIELD b FOO u8 0", SYNTAX_ERR_MSG),
+            (
+                "BITFIELD b OVERFLOW NONE",
+                "ERR Invalid OVERFLOW type specified",
+            ),
+            (
+                "BITFIELD b INCRBY u8 0 x",
+                "ERR value is not an integer or out of range",
+            ),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        // Nothing runs if a subcommand is invalid
+        assert_eq!(reply(&store, "TYPE b"), Data::SimpleString("none".into()));
+        run(&store, "HSET h f v");
+        assert!(is_wrong_type(reply(&store, "BITFIELD h GET u8 0")));
+        assert!(is_wrong_type(reply(&store, "BITFIELD h SET u8 0 1")));
+    }
+
+    #[test]
+    fn zmscore() {
+        let store = Store::new();
+        run(&store, "ZADD z 1.5 a 2 b");
+        assert_eq!(
+            reply(&store, "ZMSCORE z a x b"),
+            Data::Array(vec![bulk("1.5"), Data::NullBulkString, bulk("2")])
+        );
+        assert_eq!(
+            reply(&store, "ZMSCORE missing a b"),
+            Data::Array(vec![Data::NullBulkString, Data::NullBulkString])
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZMSCORE s a")));
+    }
+
+    #[test]
+    fn zrandmember() {
+        let store = Store::new();
+        assert_eq!(reply(&store, "ZRANDMEMBER missing"), Data::NullBulkString);
+        assert_eq!(reply(&store, "ZRANDMEMBER missing 3"), strings(&[]));
+
+        run(&store, "ZADD z 1 a 2 b 3 c");
+        let member = reply(&store, "ZRANDMEMBER z");
+        assert!([bulk("a"), bulk("b"), bulk("c")].contains(&member));
+
+        // A positive count gives distinct members, at most all of them
+        assert_eq!(sorted(reply(&store, "ZRANDMEMBER z 10")), ["a", "b", "c"]);
+        // A negative count may repeat members
+        let Data::Array(repeated) = reply(&store, "ZRANDMEMBER z -7") else {
+            panic!("expected array");
+        };
+        assert_eq!(repeated.len(), 7);
+
+        let Data::Array(with_scores) = reply(&store, "ZRANDMEMBER z -4 WITHSCORES") else {
+            panic!("expected array");
+        };
+        for pair in with_scores.chunks_exact(2) {
+            let expected = match &pair[0] {
+                Data::BulkString(member) if member == b"a" => bulk("1"),
+                Data::BulkString(member) if member == b"b" => bulk("2"),
+                _ => bulk("3"),
+            };
+            assert_eq!(pair[1], expected);
+        }
+        assert_eq!(
+            reply(&store, "ZRANDMEMBER z 1 WITHVALUES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+
+        // The same seed picks the same members
+        for count in [2, -5] {
+            let first = store.zrandmember("z", count, &mut Rng::new(3)).unwrap();
+            let second = store.zrandmember("z", count, &mut Rng::new(3)).unwrap();
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn zunionstore_errors() {
+        let store = Store::new();
+        run(&store, "ZADD a 1 x");
+        for (cmd, err) in [
+            (
+                "ZUNIONSTORE out 0 a",
+                "ERR at least 1 input key is needed for 'zunionstore' command",
+            ),
+            ("ZUNIONSTORE out 3 a b", SYNTAX_ERR_MSG),
+            ("ZUNIONSTORE out x a", NOT_INTEGER_ERR_MSG),
+            ("ZUNIONSTORE out 2 a b WEIGHTS 1", SYNTAX_ERR_MSG),
+            ("ZUNIONSTORE out 1 a WEIGHTS x", WEIGHT_ERR_MSG),
+            ("ZUNIONSTORE out 1 a AGGREGATE AVG", SYNTAX_ERR_MSG),
+            ("ZUNIONSTORE out 1 a AGGREGATE", SYNTAX_ERR_MSG),
+            ("ZINTERSTORE out 1 a FOO", SYNTAX_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZUNIONSTORE out 2 a s")));
+    }
+
+    #[test]
+    fn zrangestore() {
+        let store = Store::new();
+        run(&store, "ZADD src 1 a 2 b 3 c 4 d 5 e");
+
+        assert_eq!(reply(&store, "ZRANGESTORE dst src 1 -2"), Data::Integer(3));
+        assert_eq!(
+            reply(&store, "ZRANGE dst 0 -1 WITHSCORES"),
+            strings(&["b", "2", "c", "3", "d", "4"])
+        );
+
+        // REV with LIMIT takes a slice from the top
+        assert_eq!(
+            reply(
+                &store,
+                "ZRANGESTORE dst src +inf -inf BYSCORE REV LIMIT 1 2"
+            ),
+            Data::Integer(2)
+        );
+        assert_eq!(
+            reply(&store, "ZRANGE dst 0 -1 WITHSCORES"),
+            strings(&["c", "3", "d", "4"])
+        );
+
+        // An empty result removes the destination
+        assert_eq!(
+            reply(&store, "ZRANGESTORE dst src (5 +inf BYSCORE"),
+            Data::Integer(0)
+        );
+        assert_eq!(reply(&store, "TYPE dst"), Data::SimpleString("none".into()));
+
+        // The source may be the destination
+        assert_eq!(
+            reply(&store, "ZRANGESTORE src src [b [c BYLEX"),
+            Data::Integer(2)
+        );
+        assert_eq!(reply(&store, "ZRANGE src 0 -1"), strings(&["b", "c"]));
+
+        assert_eq!(
+            reply(&store, "ZRANGESTORE dst src 0 -1 WITHSCORES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZRANGESTORE dst s 0 -1")));
+        // Replicated like the other writes
+        let outcome = run(&store, "ZRANGESTORE dst src 0 -1");
+        assert_eq!(outcome.modified, vec!["dst".to_string()]);
+        assert!(!outcome.replicate.is_empty());
+    }
+
+    #[test]
+    fn zrangestore_ranges() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD src 1 a 2 b 3 c 4 d"));
+        replay(&replica, run(&store, "ZADD lex 0 a 0 b 0 c 0 d"));
+
+        for (command, expected) in [
+            ("ZRANGESTORE dst src 0 1 REV", &["c", "d"][..]),
+            ("ZRANGESTORE dst src (1 3 BYSCORE", &["b", "c"]),
+            ("ZRANGESTORE dst src 3 (1 BYSCORE REV", &["b", "c"]),
+            ("ZRANGESTORE dst src -inf +inf BYSCORE LIMIT 1 1", &["b"]),
+            ("ZRANGESTORE dst lex (a [c BYLEX", &["b", "c"]),
+            ("ZRANGESTORE dst lex + (b BYLEX REV", &["c", "d"]),
+            ("ZRANGESTORE dst lex + - BYLEX REV LIMIT 0 1", &["d"]),
+        ] {
+            let outcome = run(&store, command);
+            assert_eq!(
+                outcome.reply,
+                Data::Integer(expected.len() as i64),
+                "{}",
+                command
+            );
+            replay(&replica, outcome);
+            // Replicas store the same members
+            for store in [&store, &replica] {
+                assert_eq!(
+                    reply(store, "ZRANGE dst 0 -1"),
+                    strings(expected),
+                    "{}",
+                    command
+                );
+            }
+        }
+
+        // A missing source gives nothing, and the destination is removed
+        let outcome = run(&store, "ZRANGESTORE dst missing 0 -1");
+        assert_eq!(outcome.reply, Data::Integer(0));
+        replay(&replica, outcome);
+        assert_eq!(
+            reply(&replica, "TYPE dst"),
+            Data::SimpleString("none".into())
+        );
+
+        // The destination is overwritten, whatever its type
+        run(&store, "SET dst x");
+        assert_eq!(reply(&store, "ZRANGESTORE dst src 0 0"), Data::Integer(1));
+        assert_eq!(
+            reply(&store, "ZRANGE dst 0 -1 WITHSCORES"),
+            strings(&["a", "1"])
+        );
+    }
+
+    #[test]
+    fn zscan() {
+        let store = Store::new();
+        let members: Vec<String> = (0..1000).map(|i| format!("{} m:{}", i, i)).collect();
+        run(&store, &format!("ZADD z {}", members.join(" ")));
+
+        let (scanned, num_batches) = scan_all(&store, "ZSCAN z", "COUNT 25");
+        assert_eq!(num_batches, 40);
+        // Each member appears once, followed by its score
+        let pairs: Vec<_> = scanned.chunks_exact(2).collect();
+        assert_eq!(pairs.len(), 1000);
+        let mut seen = HashSet::new();
+        for pair in pairs {
+            assert!(seen.insert(pair[0].clone()), "{} returned twice", pair[0]);
+            assert_eq!(pair[0], format!("m:{}", pair[1]));
+        }
+
+        let (scanned, _) = scan_all(&store, "ZSCAN z", "MATCH m:99? COUNT 25");
+        let mut matched: Vec<_> = scanned
+            .chunks_exact(2)
+            .map(|pair| pair[0].clone())
+            .collect();
+        matched.sort();
+        let expected: Vec<_> = (990..1000).map(|i| format!("m:{}", i)).collect();
+        assert_eq!(matched, expected);
+
+        run(&store, "ZADD f 1.5 x");
+        assert_eq!(
+            reply(&store, "ZSCAN f 0"),
+            Data::Array(vec![bulk("0"), strings(&["x", "1.5"])])
+        );
+        assert_eq!(
+            reply(&store, "ZSCAN missing 0"),
+            Data::Array(vec![bulk("0"), Data::Array(vec![])])
+        );
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "ZSCAN str 0")));
+    }
+
+    #[test]
+    fn zrange_errors() {
+        let store = Store::new();
+        run(&store, "ZADD z 1 a");
+        for (cmd, err) in [
+            ("ZRANGE z 0 1 LIMIT 0 1", "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"),
+            ("ZRANGE z a b BYLEX WITHSCORES", "ERR syntax error, WITHSCORES not supported in combination with BYLEX"),
+            ("ZRANGE z 0 1 BYSCORE LIMIT 0", SYNTAX_ERR_MSG),
+            ("ZRANGE z 0 1 FOO", SYNTAX_ERR_MSG),
+            ("ZREVRANGE z 0 1 REV", SYNTAX_ERR_MSG),
+            ("ZRANGEBYSCORE z 0 1 BYLEX", SYNTAX_ERR_MSG),
+            ("ZRANGE z a 1", NOT_INTEGER_ERR_MSG),
+            ("ZRANGE z [0 1 BYSCORE", "ERR min or max is not a float"),
+            ("ZRANGE z a b BYLEX", "ERR min or max not valid string range item"),
+            ("ZRANGE z 0 1 BYSCORE LIMIT x 1", NOT_INTEGER_ERR_MSG),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZRANGE s 0 -1")));
+    }
+
+    #[test]
+    fn zincrby() {
+        let store = Store::new();
+        let replica = Store::new();
+
+        // A missing member starts at the increment
+        let outcome = run(&store, "ZINCRBY z 2.5 a");
+        assert_eq!(outcome.reply, bulk("2.5"));
+        replay(&replica, outcome);
+        // Crossing zero
+        let outcome = run(&store, "ZINCRBY z -3.75 a");
+        assert_eq!(outcome.reply, bulk("-1.25"));
+        replay(&replica, outcome);
+        replay(&replica, run(&store, "ZINCRBY z 0.1 b"));
+        replay(&replica, run(&store, "ZINCRBY z 0.2 b"));
+        assert!(run(&store, "ZINCRBY z 0 a").replicate.is_empty());
+
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "ZSCORE z a"), bulk("-1.25"));
+            assert_eq!(reply(store, "ZSCORE z b"), bulk("0.30000000000000004"));
+        }
+
+        run(&store, "ZINCRBY z +inf a");
+        assert_eq!(
+            reply(&store, "ZINCRBY z -inf a"),
+            Data::SimpleError("ERR resulting score is not a number (NaN)".into())
+        );
+        assert_eq!(reply(&store, "ZSCORE z a"), bulk("inf"));
+        assert_eq!(
+            reply(&store, "ZINCRBY z x a"),
+            Data::SimpleError(NOT_FLOAT_ERR_MSG.into())
+        );
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZINCRBY s 1 a")));
+    }
+
+    #[test]
+    fn scores_of_large_and_small_magnitude() {
+        let store = Store::new();
+        let replica = Store::new();
+        replay(&replica, run(&store, "ZADD z 1e308 a 1e17 b 1e16 c"));
+        replay(&replica, run(&store, "ZADD z 0.0001 d 0.00001 e -2.5e-7 f"));
+        replay(&replica, run(&store, "ZINCRBY z 1e300 g"));
+
+        for store in [&store, &replica] {
+            assert_eq!(reply(store, "ZSCORE z a"), bulk("1e+308"));
+            assert_eq!(reply(store, "ZSCORE z b"), bulk("1e+17"));
+            assert_eq!(reply(store, "ZSCORE z c"), bulk("10000000000000000"));
+            assert_eq!(reply(store, "ZSCORE z d"), bulk("0.0001"));
+            assert_eq!(reply(store, "ZSCORE z e"), bulk("1e-05"));
+            assert_eq!(reply(store, "ZSCORE z f"), bulk("-2.5e-07"));
+            assert_eq!(reply(store, "ZSCORE z g"), bulk("1e+300"));
+        }
+        assert_eq!(
+            reply(&store, "ZRANGE z 0 1 WITHSCORES"),
+            strings(&["f", "-2.5e-07", "e", "1e-05"])
+        );
+    }
+
+    #[test]
+    fn zadd_errors() {
+        let store = Store::new();
+        for (cmd, err) in [
+            ("ZADD z 1 a 2", SYNTAX_ERR_MSG),
+            ("ZADD z NX 1", SYNTAX_ERR_MSG),
+            ("ZADD z nan a", NOT_FLOAT_ERR_MSG),
+            ("ZADD z 1 a x b", NOT_FLOAT_ERR_MSG),
+            (
+                "ZADD z NX XX 1 a",
+                "ERR XX and NX options at the same time are not compatible",
+            ),
+            (
+                "ZADD z GT LT 1 a",
+                "ERR GT, LT, and/or NX options at the same time are not compatible",
+            ),
+            (
+                "ZADD z INCR 1 a 2 b",
+                "ERR INCR option supports a single increment-element pair",
+            ),
+        ] {
+            assert_eq!(reply(&store, cmd), Data::SimpleError(err.into()), "{}", cmd);
+        }
+        // Nothing was added by the failed commands, and XX doesn't create
+        // the key
+        assert_eq!(reply(&store, "ZADD z XX 1 a"), Data::Integer(0));
+        assert_eq!(reply(&store, "TYPE z"), Data::SimpleString("none".into()));
+
+        run(&store, "ZADD z 1 a");
+        assert_eq!(
+            reply(&store, "ZADD z INCR -inf a"),
+            Data::BulkString("-inf".into())
+        );
+        assert_eq!(
+            reply(&store, "ZADD z INCR +inf a"),
+            Data::SimpleError("ERR resulting score is not a number (NaN)".into())
+        );
+        assert_eq!(reply(&store, "ZSCORE z missing"), Data::NullBulkString);
+        assert_eq!(reply(&store, "ZCARD missing"), Data::Integer(0));
+        run(&store, "SET s x");
+        assert!(is_wrong_type(reply(&store, "ZADD s 1 a")));
+        assert!(is_wrong_type(reply(&store, "ZSCORE s a")));
+    }
+
+    #[test]
+    fn sscan() {
+        let store = Store::new();
+        let members: Vec<String> = (0..1000).map(|i| format!("m:{}", i)).collect();
+        run(&store, &format!("SADD s {}", members.join(" ")));
+
+        let (scanned, num_batches) = scan_all(&store, "SSCAN s", "COUNT 25");
+        assert_eq!(num_batches, 40);
+        let scanned: HashSet<_> = scanned.into_iter().collect();
+        assert_eq!(scanned, members.into_iter().collect());
+
+        let (mut scanned, _) = scan_all(&store, "SSCAN s", "MATCH m:99? COUNT 25");
+        scanned.sort();
+        let expected: Vec<_> = (990..1000).map(|i| format!("m:{}", i)).collect();
+        assert_eq!(scanned, expected);
+
+        assert_eq!(
+            reply(&store, "SSCAN missing 0"),
+            Data::Array(vec![bulk("0"), Data::Array(vec![])])
+        );
+        assert_eq!(
+            reply(&store, "SSCAN s 0 NOVALUES"),
+            Data::SimpleError(SYNTAX_ERR_MSG.into())
+        );
+        run(&store, "SET str x");
+        assert!(is_wrong_type(reply(&store, "SSCAN str 0")));
+    }
+
+    #[test]
+    fn sscan_concurrent_changes() {
+        let store = Store::new();
+        let members: Vec<String> = (0..500).map(|i| format!("m:{}", i)).collect();
+        run(&store, &format!("SADD s {}", members.join(" ")));
+
+        // Between batches, remove some members and add others. The members
+        // that are there for the whole scan must all be returned.
+        let mut scanned = HashSet::new();
+        let mut cursor = "0".to_string();
+        let mut round = 0;
+        loop {
+            let Data::Array(reply) = reply(&store, &format!("SSCAN s {} COUNT 25", cursor)) else {
+                panic!("expected array");
+            };
+            let [next, Data::Array(batch)] = reply.as_slice() else {
+                panic!("expected cursor and array");
+            };
+            scanned.extend(batch.iter().map(|d| d.get_string().unwrap()));
+
+            run(&store, &format!("SREM s m:{}", 400 + round));
+            run(&store, &format!("SADD s new:{}", round));
+            round += 1;
+
+            cursor = next.get_string().unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        for member in &members[..400] {
+            assert!(scanned.contains(member), "{} not returned", member);
+        }
+    }
+}
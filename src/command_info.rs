@@ -0,0 +1,762 @@
+//! What the server knows of every command it implements, including the ones
+//! handled by the master or the replica: the arity checked before running
+//! them, and what COMMAND reports, in Redis' format.
+use crate::command::Args;
+use crate::data::Data;
+use anyhow::{bail, Result};
+
+const WRITE: &str = "write";
+const READONLY: &str = "readonly";
+/// Refused when the memory used is over `maxmemory` and nothing more can be
+/// evicted, as it may use more memory
+const DENYOOM: &str = "denyoom";
+const ADMIN: &str = "admin";
+const PUBSUB: &str = "pubsub";
+const NOSCRIPT: &str = "noscript";
+const BLOCKING: &str = "blocking";
+const LOADING: &str = "loading";
+const STALE: &str = "stale";
+const FAST: &str = "fast";
+const NO_AUTH: &str = "no_auth";
+/// Keys can't be found from the key positions alone
+const MOVABLEKEYS: &str = "movablekeys";
+
+type KeyPositions = (isize, isize, isize);
+
+const NO_KEYS: KeyPositions = (0, 0, 0);
+/// The first argument only
+const KEY: KeyPositions = (1, 1, 1);
+/// Every argument
+const ALL_KEYS: KeyPositions = (1, -1, 1);
+/// The first two arguments, a source and a destination
+const TWO_KEYS: KeyPositions = (1, 2, 1);
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// A negative arity means at least that many arguments. Arities count the
+    /// command name.
+    pub arity: isize,
+    flags: &'static [&'static str],
+    /// Positions of the first and the last key, and the step between keys. A
+    /// negative last key counts from the end.
+    keys: KeyPositions,
+    /// The group of the documentation, such as "string"
+    group: &'static str,
+}
+
+const fn command(
+    name: &'static str,
+    arity: isize,
+    flags: &'static [&'static str],
+    keys: KeyPositions,
+    group: &'static str,
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        arity,
+        flags,
+        keys,
+        group,
+    }
+}
+
+const COMMANDS: &[CommandInfo] = &[
+    // Connection and server
+    command("ping", -1, &[FAST], NO_KEYS, "connection"),
+    command(
+        "auth",
+        -2,
+        &[NOSCRIPT, LOADING, STALE, FAST, NO_AUTH],
+        NO_KEYS,
+        "connection",
+    ),
+    command("echo", 2, &[FAST], NO_KEYS, "connection"),
+    command(
+        "hello",
+        -1,
+        &[NOSCRIPT, LOADING, STALE, FAST, NO_AUTH],
+        NO_KEYS,
+        "connection",
+    ),
+    command(
+        "reset",
+        1,
+        &[NOSCRIPT, LOADING, STALE, FAST, NO_AUTH],
+        NO_KEYS,
+        "connection",
+    ),
+    command(
+        "quit",
+        -1,
+        &[NOSCRIPT, LOADING, STALE, FAST, NO_AUTH],
+        NO_KEYS,
+        "connection",
+    ),
+    command("info", -1, &[LOADING, STALE], NO_KEYS, "server"),
+    command(
+        "config",
+        -2,
+        &[ADMIN, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "server",
+    ),
+    command(
+        "latency",
+        -2,
+        &[ADMIN, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "server",
+    ),
+    command(
+        "debug",
+        -2,
+        &[ADMIN, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "server",
+    ),
+    command(
+        "client",
+        -2,
+        &[NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "connection",
+    ),
+    command("command", -1, &[LOADING, STALE], NO_KEYS, "server"),
+    command("keys", 2, &[READONLY], NO_KEYS, "generic"),
+    command("randomkey", 1, &[READONLY], NO_KEYS, "generic"),
+    command("type", 2, &[READONLY, FAST], KEY, "generic"),
+    command("expiretime", 2, &[READONLY, FAST], KEY, "generic"),
+    command("pexpiretime", 2, &[READONLY, FAST], KEY, "generic"),
+    command("expireat", 3, &[WRITE, FAST], KEY, "generic"),
+    command("pexpireat", 3, &[WRITE, FAST], KEY, "generic"),
+    command("object", -2, &[READONLY], (2, 2, 1), "generic"),
+    command("touch", -2, &[READONLY, FAST], ALL_KEYS, "generic"),
+    // Without STORE, which isn't supported
+    command("sort", -2, &[READONLY], KEY, "generic"),
+    command("del", -2, &[WRITE], ALL_KEYS, "generic"),
+    command("rename", 3, &[WRITE], TWO_KEYS, "generic"),
+    command("renamenx", 3, &[WRITE, FAST], TWO_KEYS, "generic"),
+    command("copy", -3, &[WRITE, DENYOOM], TWO_KEYS, "generic"),
+    command("select", 2, &[LOADING, STALE, FAST], NO_KEYS, "connection"),
+    command("dbsize", 1, &[READONLY, FAST], NO_KEYS, "server"),
+    command("flushdb", -1, &[WRITE], NO_KEYS, "server"),
+    command("flushall", -1, &[WRITE], NO_KEYS, "server"),
+    // Transactions
+    command(
+        "multi",
+        1,
+        &[NOSCRIPT, LOADING, STALE, FAST],
+        NO_KEYS,
+        "transactions",
+    ),
+    command(
+        "exec",
+        1,
+        &[NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "transactions",
+    ),
+    command(
+        "discard",
+        1,
+        &[NOSCRIPT, LOADING, STALE, FAST],
+        NO_KEYS,
+        "transactions",
+    ),
+    command(
+        "watch",
+        -2,
+        &[NOSCRIPT, LOADING, STALE, FAST],
+        ALL_KEYS,
+        "transactions",
+    ),
+    command(
+        "unwatch",
+        1,
+        &[NOSCRIPT, LOADING, STALE, FAST],
+        NO_KEYS,
+        "transactions",
+    ),
+    // Strings
+    command("get", 2, &[READONLY, FAST], KEY, "string"),
+    command("set", -3, &[WRITE, DENYOOM], KEY, "string"),
+    command("setbit", 4, &[WRITE, DENYOOM], KEY, "bitmap"),
+    command("getbit", 3, &[READONLY, FAST], KEY, "bitmap"),
+    command("bitcount", -2, &[READONLY], KEY, "bitmap"),
+    command("bitpos", -3, &[READONLY], KEY, "bitmap"),
+    command("bitop", -4, &[WRITE, DENYOOM], (2, -1, 1), "bitmap"),
+    command("bitfield", -2, &[WRITE, DENYOOM], KEY, "bitmap"),
+    command("lcs", -3, &[READONLY], TWO_KEYS, "string"),
+    // Geospatial indexes
+    command("geoadd", -5, &[WRITE, DENYOOM], KEY, "geo"),
+    command("geopos", -2, &[READONLY], KEY, "geo"),
+    command("geodist", -4, &[READONLY], KEY, "geo"),
+    command("geosearch", -7, &[READONLY], KEY, "geo"),
+    // HyperLogLogs
+    command("pfadd", -2, &[WRITE, DENYOOM, FAST], KEY, "hyperloglog"),
+    command("pfcount", -2, &[READONLY], ALL_KEYS, "hyperloglog"),
+    command("pfmerge", -2, &[WRITE, DENYOOM], ALL_KEYS, "hyperloglog"),
+    // Hashes
+    command("hset", -4, &[WRITE, DENYOOM, FAST], KEY, "hash"),
+    command("hsetnx", 4, &[WRITE, DENYOOM, FAST], KEY, "hash"),
+    command("hget", 3, &[READONLY, FAST], KEY, "hash"),
+    command("hmget", -3, &[READONLY, FAST], KEY, "hash"),
+    command("hgetall", 2, &[READONLY], KEY, "hash"),
+    command("hkeys", 2, &[READONLY], KEY, "hash"),
+    command("hvals", 2, &[READONLY], KEY, "hash"),
+    command("hlen", 2, &[READONLY, FAST], KEY, "hash"),
+    command("hstrlen", 3, &[READONLY, FAST], KEY, "hash"),
+    command("hdel", -3, &[WRITE, FAST], KEY, "hash"),
+    command("hincrby", 4, &[WRITE, DENYOOM, FAST], KEY, "hash"),
+    command("hincrbyfloat", 4, &[WRITE, DENYOOM, FAST], KEY, "hash"),
+    command("hexists", 3, &[READONLY, FAST], KEY, "hash"),
+    command("hrandfield", -2, &[READONLY], KEY, "hash"),
+    command("hscan", -3, &[READONLY], KEY, "hash"),
+    command("scan", -2, &[READONLY], NO_KEYS, "generic"),
+    command("hexpire", -6, &[WRITE, FAST], KEY, "hash"),
+    command("hpexpire", -6, &[WRITE, FAST], KEY, "hash"),
+    command("hexpireat", -6, &[WRITE, FAST], KEY, "hash"),
+    command("hpexpireat", -6, &[WRITE, FAST], KEY, "hash"),
+    command("httl", -5, &[READONLY, FAST], KEY, "hash"),
+    command("hpttl", -5, &[READONLY, FAST], KEY, "hash"),
+    command("hpersist", -5, &[WRITE, FAST], KEY, "hash"),
+    // Sets
+    command("sadd", -3, &[WRITE, DENYOOM, FAST], KEY, "set"),
+    command("srem", -3, &[WRITE, FAST], KEY, "set"),
+    command("smembers", 2, &[READONLY], KEY, "set"),
+    command("sismember", 3, &[READONLY, FAST], KEY, "set"),
+    command("smismember", -3, &[READONLY, FAST], KEY, "set"),
+    command("smove", 4, &[WRITE, FAST], TWO_KEYS, "set"),
+    command("scard", 2, &[READONLY, FAST], KEY, "set"),
+    command("sscan", -3, &[READONLY], KEY, "set"),
+    command("spop", -2, &[WRITE, FAST], KEY, "set"),
+    command("srandmember", -2, &[READONLY], KEY, "set"),
+    command("sinter", -2, &[READONLY], ALL_KEYS, "set"),
+    command("sintercard", -3, &[READONLY, MOVABLEKEYS], NO_KEYS, "set"),
+    command("sunion", -2, &[READONLY], ALL_KEYS, "set"),
+    command("sdiff", -2, &[READONLY], ALL_KEYS, "set"),
+    command("sinterstore", -3, &[WRITE, DENYOOM], ALL_KEYS, "set"),
+    command("sunionstore", -3, &[WRITE, DENYOOM], ALL_KEYS, "set"),
+    command("sdiffstore", -3, &[WRITE, DENYOOM], ALL_KEYS, "set"),
+    // Sorted sets
+    command("zadd", -4, &[WRITE, DENYOOM, FAST], KEY, "sorted-set"),
+    command("zincrby", 4, &[WRITE, DENYOOM, FAST], KEY, "sorted-set"),
+    command("zrem", -3, &[WRITE, FAST], KEY, "sorted-set"),
+    command("zremrangebyrank", 4, &[WRITE], KEY, "sorted-set"),
+    command("zremrangebyscore", 4, &[WRITE], KEY, "sorted-set"),
+    command("zpopmin", -2, &[WRITE, FAST], KEY, "sorted-set"),
+    command("zmpop", -4, &[WRITE, MOVABLEKEYS], NO_KEYS, "sorted-set"),
+    command("zpopmax", -2, &[WRITE, FAST], KEY, "sorted-set"),
+    command(
+        "bzpopmin",
+        -3,
+        &[WRITE, NOSCRIPT, BLOCKING, FAST],
+        (1, -2, 1),
+        "sorted-set",
+    ),
+    command(
+        "bzpopmax",
+        -3,
+        &[WRITE, NOSCRIPT, BLOCKING, FAST],
+        (1, -2, 1),
+        "sorted-set",
+    ),
+    command("zscore", 3, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zcard", 2, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zcount", 4, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zlexcount", 4, &[READONLY, FAST], KEY, "sorted-set"),
+    command(
+        "zunionstore",
+        -4,
+        &[WRITE, DENYOOM, MOVABLEKEYS],
+        KEY,
+        "sorted-set",
+    ),
+    command(
+        "zinterstore",
+        -4,
+        &[WRITE, DENYOOM, MOVABLEKEYS],
+        KEY,
+        "sorted-set",
+    ),
+    command(
+        "zdiffstore",
+        -4,
+        &[WRITE, DENYOOM, MOVABLEKEYS],
+        KEY,
+        "sorted-set",
+    ),
+    command(
+        "zunion",
+        -3,
+        &[READONLY, MOVABLEKEYS],
+        NO_KEYS,
+        "sorted-set",
+    ),
+    command(
+        "zinter",
+        -3,
+        &[READONLY, MOVABLEKEYS],
+        NO_KEYS,
+        "sorted-set",
+    ),
+    command("zdiff", -3, &[READONLY, MOVABLEKEYS], NO_KEYS, "sorted-set"),
+    command("zmscore", -3, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zrandmember", -2, &[READONLY], KEY, "sorted-set"),
+    command("zrank", -3, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zrevrank", -3, &[READONLY, FAST], KEY, "sorted-set"),
+    command("zrange", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrangebyscore", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrevrangebyscore", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrangebylex", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrevrangebylex", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrevrange", -4, &[READONLY], KEY, "sorted-set"),
+    command("zrangestore", -5, &[WRITE, DENYOOM], TWO_KEYS, "sorted-set"),
+    command("zscan", -3, &[READONLY], KEY, "sorted-set"),
+    // Streams
+    command("xadd", -5, &[WRITE, DENYOOM, FAST], KEY, "stream"),
+    command("xrange", -4, &[READONLY], KEY, "stream"),
+    command("xrevrange", -4, &[READONLY], KEY, "stream"),
+    command("xlen", 2, &[READONLY, FAST], KEY, "stream"),
+    command("xdel", -3, &[WRITE, FAST], KEY, "stream"),
+    command("xtrim", -4, &[WRITE], KEY, "stream"),
+    command("xinfo", -2, &[READONLY], NO_KEYS, "stream"),
+    command("xsetid", -3, &[WRITE, DENYOOM, FAST], KEY, "stream"),
+    command("xgroup", -2, &[WRITE], NO_KEYS, "stream"),
+    command(
+        "xreadgroup",
+        -7,
+        &[WRITE, BLOCKING, MOVABLEKEYS],
+        NO_KEYS,
+        "stream",
+    ),
+    command("xack", -4, &[WRITE, FAST], KEY, "stream"),
+    command("xpending", -3, &[READONLY], KEY, "stream"),
+    command("xclaim", -6, &[WRITE, FAST], KEY, "stream"),
+    command("xautoclaim", -6, &[WRITE, FAST], KEY, "stream"),
+    command(
+        "xread",
+        -4,
+        &[READONLY, BLOCKING, MOVABLEKEYS],
+        NO_KEYS,
+        "stream",
+    ),
+    // Pub/sub
+    command(
+        "subscribe",
+        -2,
+        &[PUBSUB, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "pubsub",
+    ),
+    command(
+        "unsubscribe",
+        -1,
+        &[PUBSUB, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "pubsub",
+    ),
+    command(
+        "psubscribe",
+        -2,
+        &[PUBSUB, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "pubsub",
+    ),
+    command(
+        "punsubscribe",
+        -1,
+        &[PUBSUB, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "pubsub",
+    ),
+    command(
+        "publish",
+        3,
+        &[PUBSUB, LOADING, STALE, FAST],
+        NO_KEYS,
+        "pubsub",
+    ),
+    command("pubsub", -2, &[PUBSUB, LOADING, STALE], NO_KEYS, "pubsub"),
+    // Replication
+    command(
+        "replconf",
+        -1,
+        &[ADMIN, NOSCRIPT, LOADING, STALE],
+        NO_KEYS,
+        "server",
+    ),
+    command("psync", -3, &[ADMIN, NOSCRIPT], NO_KEYS, "server"),
+    command("wait", 3, &[NOSCRIPT], NO_KEYS, "generic"),
+];
+
+// The summary of the documentation of a command, mostly Redis' own
+fn summary(name: &str) -> &'static str {
+    match name {
+        "ping" => "Returns the server's liveliness response.",
+        "auth" => "Authenticates the connection.",
+        "reset" => "Resets the connection.",
+        "hello" => "Handshakes with the Redis server.",
+        "quit" => "Closes the connection.",
+        "echo" => "Returns the given string.",
+        "info" => "Returns information and statistics about the server.",
+        "config" => "A container for server configuration commands.",
+        "latency" => "A container for latency diagnostics commands.",
+        "debug" => "A container for debugging commands.",
+        "client" => "A container for client connection commands.",
+        "command" => "Returns detailed information about all commands.",
+        "keys" => "Returns all key names that match a pattern.",
+        "randomkey" => "Returns a random key name from the database.",
+        "type" => "Determines the type of value stored at a key.",
+        "expiretime" => "Returns the expiration time of a key as a Unix timestamp.",
+        "pexpiretime" => "Returns the expiration time of a key as a Unix milliseconds timestamp.",
+        "expireat" => "Sets the expiration time of a key to a Unix timestamp.",
+        "pexpireat" => "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+        "object" => "A container for object introspection commands.",
+        "touch" => "Returns the number of existing keys out of those specified after updating the time they were last accessed.",
+        "sort" => "Sorts the elements in a set or a sorted set.",
+        "del" => "Deletes one or more keys.",
+        "rename" => "Renames a key and overwrites the destination.",
+        "renamenx" => "Renames a key only when the target key name doesn't exist.",
+        "copy" => "Copies the value of a key to a new key.",
+        "select" => "Changes the selected database.",
+        "dbsize" => "Returns the number of keys in the database.",
+        "flushdb" => "Removes all keys from the current database.",
+        "flushall" => "Removes all keys from all databases.",
+        "multi" => "Starts a transaction.",
+        "exec" => "Executes all commands in a transaction.",
+        "discard" => "Discards a transaction.",
+        "watch" => "Monitors changes to keys to determine the execution of a transaction.",
+        "unwatch" => "Forgets about watched keys of a transaction.",
+        "get" => "Returns the string value of a key.",
+        "set" => "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        "setbit" => "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist.",
+        "getbit" => "Returns a bit value by offset.",
+        "bitcount" => "Counts the number of set bits (population counting) in a string.",
+        "bitpos" => "Finds the first set (1) or clear (0) bit in a string.",
+        "bitop" => "Performs bitwise operations on multiple strings, and stores the result.",
+        "bitfield" => "Performs arbitrary bitfield integer operations on strings.",
+        "lcs" => "Finds the longest common subsequence of two strings.",
+        "geoadd" => "Adds one or more members to a geospatial index. The key is created if it doesn't exist.",
+        "geopos" => "Returns the longitude and latitude of members from a geospatial index.",
+        "geodist" => "Returns the distance between two members of a geospatial index.",
+        "geosearch" => "Queries a geospatial index for members inside an area of a box or a circle.",
+        "pfadd" => "Adds elements to a HyperLogLog key. Creates the key if it doesn't exist.",
+        "pfcount" => "Returns the approximated cardinality of the set(s) observed by the HyperLogLog key(s).",
+        "pfmerge" => "Merges one or more HyperLogLog values into a single key.",
+        "hset" => "Creates or modifies the value of a field in a hash.",
+        "hsetnx" => "Sets the value of a field in a hash only when the field doesn't exist.",
+        "hget" => "Returns the value of a field in a hash.",
+        "hmget" => "Returns the values of all fields in a hash.",
+        "hgetall" => "Returns all fields and values in a hash.",
+        "hkeys" => "Returns all fields in a hash.",
+        "hvals" => "Returns all values in a hash.",
+        "hlen" => "Returns the number of fields in a hash.",
+        "hstrlen" => "Returns the length of the value of a field.",
+        "hdel" => "Deletes one or more fields and their values from a hash. Deletes the hash if no fields remain.",
+        "hincrby" => "Increments the integer value of a field in a hash by a number. Uses 0 as initial value if the field doesn't exist.",
+        "hincrbyfloat" => "Increments the floating point value of a field by a number. Uses 0 as initial value if the field doesn't exist.",
+        "hexists" => "Determines whether a field exists in a hash.",
+        "hrandfield" => "Returns one or more random fields from a hash.",
+        "hscan" => "Iterates over fields and values of a hash.",
+        "scan" => "Iterates over the key names in the database.",
+        "hexpire" => "Sets the expiration time of hash fields, in seconds from now.",
+        "hpexpire" => "Sets the expiration time of hash fields, in milliseconds from now.",
+        "hexpireat" => "Sets the expiration time of hash fields, as a Unix time in seconds.",
+        "hpexpireat" => "Sets the expiration time of hash fields, as a Unix time in milliseconds.",
+        "httl" => "Returns the time to live of hash fields, in seconds.",
+        "hpttl" => "Returns the time to live of hash fields, in milliseconds.",
+        "hpersist" => "Removes the expiration time of hash fields.",
+        "sadd" => "Adds one or more members to a set. Creates the key if it doesn't exist.",
+        "srem" => "Removes one or more members from a set. Deletes the set if the last member was removed.",
+        "smembers" => "Returns all members of a set.",
+        "sismember" => "Determines whether a member belongs to a set.",
+        "smismember" => "Determines whether multiple members belong to a set.",
+        "smove" => "Moves a member from one set to another.",
+        "scard" => "Returns the number of members in a set.",
+        "sscan" => "Iterates over members of a set.",
+        "spop" => "Returns one or more random members from a set after removing them. Deletes the set if the last member was popped.",
+        "srandmember" => "Returns one or more random members from a set.",
+        "sinter" => "Returns the intersect of multiple sets.",
+        "sintercard" => "Returns the number of members of the intersect of multiple sets.",
+        "sunion" => "Returns the union of multiple sets.",
+        "sdiff" => "Returns the difference of multiple sets.",
+        "sinterstore" => "Stores the intersect of multiple sets in a key.",
+        "sunionstore" => "Stores the union of multiple sets in a key.",
+        "sdiffstore" => "Stores the difference of multiple sets in a key.",
+        "zadd" => "Adds one or more members to a sorted set, or updates their scores. Creates the key if it doesn't exist.",
+        "zincrby" => "Increments the score of a member in a sorted set.",
+        "zrem" => "Removes one or more members from a sorted set. Deletes the sorted set if all members were removed.",
+        "zremrangebyrank" => "Removes members in a sorted set within a range of indexes. Deletes the sorted set if all members were removed.",
+        "zremrangebyscore" => "Removes members in a sorted set within a range of scores. Deletes the sorted set if all members were removed.",
+        "zmpop" => "Returns the highest- or lowest-scoring members from one or more sorted sets after removing them. Deletes the sorted set if the last member was popped.",
+        "zpopmin" => "Returns the lowest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped.",
+        "zpopmax" => "Returns the highest-scoring members from a sorted set after removing them. Deletes the sorted set if the last member was popped.",
+        "bzpopmin" => "Removes and returns the member with the lowest score from one or more sorted sets. Blocks until a member is available otherwise.",
+        "bzpopmax" => "Removes and returns the member with the highest score from one or more sorted sets. Blocks until a member is available otherwise.",
+        "zscore" => "Returns the score of a member in a sorted set.",
+        "zcard" => "Returns the number of members in a sorted set.",
+        "zcount" => "Returns the count of members in a sorted set that have scores within a range.",
+        "zlexcount" => "Returns the number of members in a sorted set within a lexicographical range.",
+        "zunionstore" => "Stores the union of multiple sorted sets in a key.",
+        "zinterstore" => "Stores the intersect of multiple sorted sets in a key.",
+        "zdiffstore" => "Stores the difference of multiple sorted sets in a key.",
+        "zunion" => "Returns the union of multiple sorted sets.",
+        "zinter" => "Returns the intersect of multiple sorted sets.",
+        "zdiff" => "Returns the difference between multiple sorted sets.",
+        "zmscore" => "Returns the score of one or more members in a sorted set.",
+        "zrandmember" => "Returns one or more random members from a sorted set.",
+        "zrank" => "Returns the index of a member in a sorted set ordered by ascending scores.",
+        "zrevrank" => "Returns the index of a member in a sorted set ordered by descending scores.",
+        "zrange" => "Returns members in a sorted set within a range of indexes.",
+        "zrangebyscore" => "Returns members in a sorted set within a range of scores.",
+        "zrevrangebyscore" => "Returns members in a sorted set within a range of scores in reverse order.",
+        "zrangebylex" => "Returns members in a sorted set within a lexicographical range.",
+        "zrevrangebylex" => "Returns members in a sorted set within a lexicographical range in reverse order.",
+        "zrevrange" => "Returns members in a sorted set within a range of indexes in reverse order.",
+        "zrangestore" => "Stores a range of members from sorted set in a key.",
+        "zscan" => "Iterates over members and scores of a sorted set.",
+        "xadd" => "Appends a new message to a stream. Creates the key if it doesn't exist.",
+        "xrange" => "Returns the messages from a stream within a range of IDs.",
+        "xrevrange" => "Returns the messages from a stream within a range of IDs in reverse order.",
+        "xlen" => "Returns the number of messages in a stream.",
+        "xdel" => "Returns the number of messages after removing them from a stream.",
+        "xtrim" => "Deletes messages from the beginning of a stream.",
+        "xinfo" => "A container for stream introspection commands.",
+        "xsetid" => "An internal command for replicating stream values.",
+        "xgroup" => "A container for consumer groups commands.",
+        "xreadgroup" => "Returns new or historical messages from a stream for a consumer in a group. Blocks until a message is available otherwise.",
+        "xack" => "Returns the number of messages that were successfully acknowledged by the consumer group member of a stream.",
+        "xclaim" => "Changes, or acquires, ownership of a message in a consumer group, as if the message was delivered a consumer group member.",
+        "xautoclaim" => "Changes, or acquires, ownership of messages in a consumer group, as if the messages were delivered to as consumer group member.",
+        "xpending" => "Returns the information and entries from a stream consumer group's pending entries list.",
+        "xread" => "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise.",
+        "subscribe" => "Listens for messages published to channels.",
+        "unsubscribe" => "Stops listening to messages posted to channels.",
+        "psubscribe" => "Listens for messages published to channels that match one or more patterns.",
+        "punsubscribe" => "Stops listening to messages published to channels that match one or more patterns.",
+        "publish" => "Posts a message to a channel.",
+        "pubsub" => "A container for Pub/Sub commands.",
+        "replconf" => "An internal command for configuring the replication stream.",
+        "psync" => "An internal command used in replication.",
+        "wait" => "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        _ => "",
+    }
+}
+
+/// The command named `name`, in lowercase
+pub fn find(name: &str) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+impl CommandInfo {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    pub fn is_deny_oom(&self) -> bool {
+        self.has_flag(DENYOOM)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.has_flag(WRITE)
+    }
+
+    // ACL categories, which follow from the flags and the group
+    fn categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        if self.has_flag(WRITE) {
+            categories.push("@write");
+        }
+        if self.has_flag(READONLY) {
+            categories.push("@read");
+        }
+        let group = match self.group {
+            "generic" => Some("@keyspace"),
+            "string" => Some("@string"),
+            "bitmap" => Some("@bitmap"),
+            "geo" => Some("@geo"),
+            "hyperloglog" => Some("@hyperloglog"),
+            "hash" => Some("@hash"),
+            "set" => Some("@set"),
+            "sorted-set" => Some("@sortedset"),
+            "stream" => Some("@stream"),
+            "pubsub" => Some("@pubsub"),
+            "transactions" => Some("@transaction"),
+            "connection" => Some("@connection"),
+            _ => None,
+        };
+        categories.extend(group);
+        if self.has_flag(ADMIN) {
+            categories.extend(["@admin", "@dangerous"]);
+        }
+        categories.push(if self.has_flag(FAST) {
+            "@fast"
+        } else {
+            "@slow"
+        });
+        if self.has_flag(BLOCKING) {
+            categories.push("@blocking");
+        }
+        categories
+    }
+
+    // The reply of COMMAND INFO. Tips, key specifications and subcommands
+    // aren't tracked, so they're empty.
+    fn to_info(&self) -> Data {
+        let simple_strings = |strings: &[&str]| {
+            Data::Array(
+                strings
+                    .iter()
+                    .map(|s| Data::SimpleString((*s).into()))
+                    .collect(),
+            )
+        };
+        let (first_key, last_key, step) = self.keys;
+        Data::Array(vec![
+            Data::BulkString(self.name.into()),
+            Data::Integer(self.arity as i64),
+            simple_strings(self.flags),
+            Data::Integer(first_key as i64),
+            Data::Integer(last_key as i64),
+            Data::Integer(step as i64),
+            simple_strings(&self.categories()),
+            Data::Array(vec![]),
+            Data::Array(vec![]),
+            Data::Array(vec![]),
+        ])
+    }
+
+    // The documentation of COMMAND DOCS
+    fn to_docs(&self) -> Data {
+        Data::Array(vec![
+            Data::BulkString("summary".into()),
+            Data::BulkString(summary(self.name).into()),
+            Data::BulkString("group".into()),
+            Data::BulkString(self.group.into()),
+        ])
+    }
+}
+
+/// COMMAND [COUNT | INFO [<command> ...] | DOCS [<command> ...]]. Without
+/// names, INFO and DOCS are about every command.
+pub fn reply(args: &Args) -> Result<Data> {
+    if args.len() == 1 {
+        return Ok(Data::Array(
+            COMMANDS.iter().map(CommandInfo::to_info).collect(),
+        ));
+    }
+    let subcommand = args.string(1)?.to_ascii_lowercase();
+    let names = args
+        .strings(2)?
+        .into_iter()
+        .map(|name| name.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let reply = match subcommand.as_str() {
+        "count" if args.len() == 2 => Data::Integer(COMMANDS.len() as i64),
+        "info" if names.is_empty() => {
+            Data::Array(COMMANDS.iter().map(CommandInfo::to_info).collect())
+        }
+        // Unknown commands are nil
+        "info" => Data::Array(
+            names
+                .iter()
+                .map(|name| find(name).map_or(Data::NullBulkString, CommandInfo::to_info))
+                .collect(),
+        ),
+        // Unknown commands are left out
+        "docs" => {
+            let commands: Vec<_> = if names.is_empty() {
+                COMMANDS.iter().collect()
+            } else {
+                names.iter().filter_map(|name| find(name)).collect()
+            };
+            Data::Array(
+                commands
+                    .into_iter()
+                    .flat_map(|command| [Data::BulkString(command.name.into()), command.to_docs()])
+                    .collect(),
+            )
+        }
+        _ => bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try COMMAND HELP.",
+            subcommand
+        ),
+    };
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table() {
+        for (i, command) in COMMANDS.iter().enumerate() {
+            assert!(!summary(command.name).is_empty(), "{}", command.name);
+            assert!(
+                COMMANDS[i + 1..]
+                    .iter()
+                    .all(|other| other.name != command.name),
+                "{} twice",
+                command.name
+            );
+            assert!(
+                !(command.has_flag(WRITE) && command.has_flag(READONLY)),
+                "{}",
+                command.name
+            );
+        }
+        let deny_oom: Vec<_> = COMMANDS
+            .iter()
+            .filter(|command| command.is_deny_oom())
+            .map(|command| command.name)
+            .collect();
+        assert_eq!(
+            deny_oom,
+            [
+                "copy",
+                "set",
+                "setbit",
+                "bitop",
+                "bitfield",
+                "geoadd",
+                "pfadd",
+                "pfmerge",
+                "hset",
+                "hsetnx",
+                "hincrby",
+                "hincrbyfloat",
+                "sadd",
+                "sinterstore",
+                "sunionstore",
+                "sdiffstore",
+                "zadd",
+                "zincrby",
+                "zunionstore",
+                "zinterstore",
+                "zdiffstore",
+                "zrangestore",
+                "xadd",
+                "xsetid",
+            ]
+        );
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(
+            find("get").unwrap().categories(),
+            ["@read", "@string", "@fast"]
+        );
+        assert_eq!(
+            find("bzpopmin").unwrap().categories(),
+            ["@write", "@sortedset", "@fast", "@blocking"]
+        );
+        assert_eq!(
+            find("config").unwrap().categories(),
+            ["@admin", "@dangerous", "@slow"]
+        );
+        assert!(find("nosuchcommand").is_none());
+    }
+}
@@ -0,0 +1,393 @@
+//! Server configuration, read from the config file and the command line, and
+//! read or changed at runtime with CONFIG GET, CONFIG SET and CONFIG REWRITE.
+use crate::glob::glob_match;
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+
+/// Names of all parameters, in the order they are written by CONFIG REWRITE
+const PARAMETERS: &[&str] = &[
+    "bind",
+    "port",
+    "dir",
+    "dbfilename",
+    "maxmemory",
+    "maxmemory-policy",
+    "save",
+    "requirepass",
+    "proto-max-bulk-len",
+    "timeout",
+    "hash-max-listpack-entries",
+    "hash-max-listpack-value",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+];
+
+const MIN_PROTO_MAX_BULK_LEN: u64 = 1024 * 1024;
+
+const MAXMEMORY_POLICIES: &[&str] = &[
+    "noeviction",
+    "allkeys-lru",
+    "allkeys-lfu",
+    "allkeys-random",
+    "volatile-lru",
+    "volatile-lfu",
+    "volatile-random",
+    "volatile-ttl",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub dir: Option<PathBuf>,
+    pub dbfilename: Option<String>,
+    /// In bytes. 0 means no limit.
+    pub maxmemory: u64,
+    pub maxmemory_policy: String,
+    /// Snapshotting intervals as (seconds, changes) pairs
+    pub save: Vec<(u64, u64)>,
+    /// Password that clients must send with AUTH before running commands
+    pub requirepass: Option<String>,
+    /// Maximum size of a string in bytes, which limits the offsets of SETBIT
+    pub proto_max_bulk_len: u64,
+    /// Seconds after which idle clients are disconnected. 0 means never.
+    pub timeout: u64,
+    /// Limits of the collections reported as `listpack` by OBJECT ENCODING,
+    /// as (entries, value bytes) for hashes, sets and sorted sets
+    pub hash_max_listpack: (u64, u64),
+    pub set_max_listpack: (u64, u64),
+    pub zset_max_listpack: (u64, u64),
+    /// The config file the config was loaded from, which CONFIG REWRITE
+    /// writes to
+    pub file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 6379,
+            dir: None,
+            dbfilename: None,
+            maxmemory: 0,
+            maxmemory_policy: "noeviction".into(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            requirepass: None,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            timeout: 0,
+            hash_max_listpack: (128, 64),
+            set_max_listpack: (128, 64),
+            zset_max_listpack: (128, 64),
+            file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads a config file, which has one `parameter value` per line. Empty
+    /// lines and lines starting with `#` are ignored.
+    pub fn load(file: PathBuf) -> Result<Self> {
+        let mut config = Self::default();
+        let contents = fs::read_to_string(&file)?;
+        for (line, name, value) in parse_lines(&contents) {
+            let Some(name) = name else {
+                continue;
+            };
+            config
+                .set(&name, &value)
+                .map_err(|e| anyhow!("{} in config file: {}", e, line))?;
+        }
+        config.file = Some(file);
+        Ok(config)
+    }
+
+    /// The path of the RDB file, if both `dir` and `dbfilename` are set
+    pub fn rdb_path(&self) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(self.dbfilename.as_ref()?))
+    }
+
+    /// The value of a parameter, or None if it's unknown
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "bind" => self.bind.to_string(),
+            "port" => self.port.to_string(),
+            "dir" => self
+                .dir
+                .as_ref()
+                .map_or(String::new(), |dir| dir.to_string_lossy().into()),
+            "dbfilename" => self.dbfilename.clone().unwrap_or_default(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.clone(),
+            "save" => self
+                .save
+                .iter()
+                .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "requirepass" => self.requirepass.clone().unwrap_or_default(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "hash-max-listpack-entries" => self.hash_max_listpack.0.to_string(),
+            "hash-max-listpack-value" => self.hash_max_listpack.1.to_string(),
+            "set-max-listpack-entries" => self.set_max_listpack.0.to_string(),
+            "set-max-listpack-value" => self.set_max_listpack.1.to_string(),
+            "zset-max-listpack-entries" => self.zset_max_listpack.0.to_string(),
+            "zset-max-listpack-value" => self.zset_max_listpack.1.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Parameters matching the glob `pattern`, with their values
+    pub fn matching(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_ascii_lowercase();
+        PARAMETERS
+            .iter()
+            .filter(|name| glob_match(&pattern, name))
+            .map(|name| (*name, self.get(name).unwrap()))
+            .collect()
+    }
+
+    /// Sets a parameter from its string form, which is validated
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || anyhow!("argument couldn't be parsed into {}", name);
+        match name.to_ascii_lowercase().as_str() {
+            "bind" => self.bind = value.parse().map_err(|_| invalid())?,
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "dir" => self.dir = (!value.is_empty()).then(|| value.into()),
+            "dbfilename" => {
+                if value.contains('/') {
+                    bail!("dbfilename can't be a path, just a filename");
+                }
+                self.dbfilename = (!value.is_empty()).then(|| value.into())
+            }
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+            "maxmemory-policy" => {
+                let policy = value.to_ascii_lowercase();
+                if !MAXMEMORY_POLICIES.contains(&policy.as_str()) {
+                    bail!(
+                        "argument(s) must be one of the following: {}",
+                        MAXMEMORY_POLICIES.join(", ")
+                    );
+                }
+                self.maxmemory_policy = policy;
+            }
+            "save" => {
+                let numbers = value
+                    .split_ascii_whitespace()
+                    .map(|n| n.parse())
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|_| invalid())?;
+                if !numbers.len().is_multiple_of(2) {
+                    bail!("Invalid save parameters");
+                }
+                self.save = numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+            }
+            "requirepass" => self.requirepass = (!value.is_empty()).then(|| value.into()),
+            "proto-max-bulk-len" => {
+                let len = parse_memory(value).ok_or_else(invalid)?;
+                if len < MIN_PROTO_MAX_BULK_LEN {
+                    bail!("argument must be at least {}", MIN_PROTO_MAX_BULK_LEN);
+                }
+                self.proto_max_bulk_len = len;
+            }
+            "timeout" => self.timeout = value.parse().map_err(|_| invalid())?,
+            "hash-max-listpack-entries" => {
+                self.hash_max_listpack.0 = value.parse().map_err(|_| invalid())?
+            }
+            "hash-max-listpack-value" => {
+                self.hash_max_listpack.1 = parse_memory(value).ok_or_else(invalid)?
+            }
+            "set-max-listpack-entries" => {
+                self.set_max_listpack.0 = value.parse().map_err(|_| invalid())?
+            }
+            "set-max-listpack-value" => {
+                self.set_max_listpack.1 = parse_memory(value).ok_or_else(invalid)?
+            }
+            "zset-max-listpack-entries" => {
+                self.zset_max_listpack.0 = value.parse().map_err(|_| invalid())?
+            }
+            "zset-max-listpack-value" => {
+                self.zset_max_listpack.1 = parse_memory(value).ok_or_else(invalid)?
+            }
+            _ => bail!("Unknown option '{}'", name),
+        }
+        Ok(())
+    }
+
+    /// Whether a parameter can be changed with CONFIG SET
+    pub fn is_mutable(name: &str) -> bool {
+        !matches!(name, "bind" | "port")
+    }
+
+    pub fn is_known(name: &str) -> bool {
+        PARAMETERS.contains(&name)
+    }
+
+    /// Writes the config back to the file it was loaded from. Lines that set
+    /// a parameter are updated in place, other lines such as comments are
+    /// kept, and parameters missing from the file are appended.
+    pub fn rewrite(&self) -> Result<()> {
+        let Some(file) = &self.file else {
+            bail!("ERR The server is running without a config file");
+        };
+        let contents = fs::read_to_string(file).unwrap_or_default();
+
+        let mut lines = Vec::new();
+        let mut written = Vec::new();
+        for (line, name, _) in parse_lines(&contents) {
+            match name {
+                Some(name) if Self::is_known(&name) => {
+                    // Only keep the first line for each parameter
+                    if !written.contains(&name) {
+                        lines.push(self.line(&name));
+                        written.push(name);
+                    }
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+        for name in PARAMETERS {
+            if !written.iter().any(|w| w == name) {
+                lines.push(self.line(name));
+            }
+        }
+
+        fs::write(file, lines.join("\n") + "\n")
+            .map_err(|e| anyhow!("ERR Rewriting config file: {}", e))
+    }
+
+    // The config file line that sets `name`
+    fn line(&self, name: &str) -> String {
+        let value = self.get(name).unwrap();
+        if value.is_empty() || value.contains(' ') {
+            format!("{} \"{}\"", name, value)
+        } else {
+            format!("{} {}", name, value)
+        }
+    }
+}
+
+// Splits a config file into lines, with the lowercased parameter name and the
+// value of the lines that set a parameter. The value may be in double quotes.
+fn parse_lines(contents: &str) -> impl Iterator<Item = (&str, Option<String>, String)> {
+    contents.lines().map(|line| {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return (line, None, String::new());
+        }
+
+        let (name, value) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        (line, Some(name.to_ascii_lowercase()), value.to_string())
+    })
+}
+
+// Parses a memory amount such as `100`, `1k`, `10mb` or `1gb` into bytes
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let units: &[(&str, u64)] = &[
+        ("kb", 1024),
+        ("k", 1000),
+        ("mb", 1024 * 1024),
+        ("m", 1000 * 1000),
+        ("gb", 1024 * 1024 * 1024),
+        ("g", 1000 * 1000 * 1000),
+    ];
+    for (suffix, multiplier) in units {
+        if let Some(n) = value.strip_suffix(suffix) {
+            return n.parse::<u64>().ok()?.checked_mul(*multiplier);
+        }
+    }
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_and_set() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.matching("max*"),
+            vec![
+                ("maxmemory", "0".to_string()),
+                ("maxmemory-policy", "noeviction".to_string())
+            ]
+        );
+        assert_eq!(
+            config.matching("DBFILENAME"),
+            vec![("dbfilename", String::new())]
+        );
+        assert!(config.matching("nothing").is_empty());
+
+        config.set("maxmemory", "10mb").unwrap();
+        assert_eq!(config.maxmemory, 10 * 1024 * 1024);
+        config.set("MAXMEMORY-POLICY", "allkeys-LRU").unwrap();
+        assert_eq!(config.get("maxmemory-policy").unwrap(), "allkeys-lru");
+        config.set("save", "900 1 60 5").unwrap();
+        assert_eq!(config.save, vec![(900, 1), (60, 5)]);
+        config.set("save", "").unwrap();
+        assert!(config.save.is_empty());
+        config.set("proto-max-bulk-len", "1mb").unwrap();
+        assert_eq!(config.proto_max_bulk_len, 1024 * 1024);
+        config.set("zset-max-listpack-entries", "10").unwrap();
+        config.set("zset-max-listpack-value", "1k").unwrap();
+        assert_eq!(config.zset_max_listpack, (10, 1000));
+        assert_eq!(config.get("hash-max-listpack-value").unwrap(), "64");
+        config.set("timeout", "300").unwrap();
+        assert_eq!(config.timeout, 300);
+
+        assert!(config.set("maxmemory", "lots").is_err());
+        assert!(config.set("maxmemory-policy", "random").is_err());
+        assert!(config.set("save", "900").is_err());
+        assert!(config.set("dbfilename", "a/b.rdb").is_err());
+        assert!(config.set("proto-max-bulk-len", "1k").is_err());
+        assert!(config.set("set-max-listpack-entries", "-1").is_err());
+        assert!(config.set("timeout", "1s").is_err());
+        assert!(config.set("nothing", "1").is_err());
+    }
+
+    #[test]
+    fn load_and_rewrite() {
+        let file = std::env::temp_dir().join(format!("config-test-{}.conf", std::process::id()));
+        fs::write(
+            &file,
+            "# A comment\nport 7000\n\nsave \"60 1\"\nmaxmemory 1k\nport 7001\n",
+        )
+        .unwrap();
+
+        let mut config = Config::load(file.clone()).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.save, vec![(60, 1)]);
+        assert_eq!(config.maxmemory, 1000);
+
+        config.set("maxmemory", "2000").unwrap();
+        config.set("dbfilename", "dump.rdb").unwrap();
+        config.rewrite().unwrap();
+        let contents = fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            contents,
+            "# A comment\nport 7001\n\nsave \"60 1\"\nmaxmemory 2000\n\
+             bind 127.0.0.1\ndir \"\"\ndbfilename dump.rdb\nmaxmemory-policy noeviction\nrequirepass \"\"\n\
+             proto-max-bulk-len 536870912\ntimeout 0\nhash-max-listpack-entries 128\nhash-max-listpack-value 64\n\
+             set-max-listpack-entries 128\nset-max-listpack-value 64\nzset-max-listpack-entries 128\n\
+             zset-max-listpack-value 64\n"
+        );
+        assert_eq!(Config::load(file.clone()).unwrap(), config);
+
+        fs::remove_file(&file).unwrap();
+    }
+}
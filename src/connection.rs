@@ -1,27 +1,114 @@
 use crate::data::DecodeError;
-use crate::data::{decode_rdb_file, Data};
+use crate::data::{decode_rdb_file, Data, Protocol};
+use crate::log::trace;
 use anyhow::{anyhow, Result};
-use std::io::Write;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
-use std::{io::Read, net::TcpStream};
+use std::time::Duration;
+use thiserror::Error;
 
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A stream that a `Connection` can be built on. Like `TcpStream`, it can be
+/// read from and written to through a shared reference.
+pub trait ReadWrite: sealed::Sealed + Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+    /// Closes both directions, so that pending and later reads return 0 bytes
+    fn shutdown(&self) -> io::Result<()>;
+    fn peer_addr(&self) -> String;
+    /// Sets the read and write timeouts, or removes them if None
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+macro_rules! impl_read_write {
+    ($t:ty, $peer_addr:expr) => {
+        impl sealed::Sealed for $t {}
+
+        impl ReadWrite for $t {
+            fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+                Read::read(&mut &*self, buf)
+            }
+
+            fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+                Write::write_all(&mut &*self, buf)
+            }
+
+            fn shutdown(&self) -> io::Result<()> {
+                <$t>::shutdown(self, Shutdown::Both)
+            }
+
+            fn peer_addr(&self) -> String {
+                $peer_addr(self)
+            }
+
+            fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                self.set_read_timeout(timeout)?;
+                self.set_write_timeout(timeout)
+            }
+
+            fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+                <$t>::set_nonblocking(self, nonblocking)
+            }
+        }
+    };
+}
+
+impl_read_write!(TcpStream, |stream: &TcpStream| stream
+    .peer_addr()
+    .map(|addr| addr.to_string())
+    .unwrap_or_default());
+// Like Redis, the path of the socket followed by port 0
+impl_read_write!(UnixStream, |stream: &UnixStream| stream
+    .peer_addr()
+    .ok()
+    .and_then(|addr| Some(format!("{}:0", addr.as_pathname()?.display())))
+    .unwrap_or_default());
+
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    /// Reading or writing took longer than the timeout of the stream
+    #[error("timed out")]
+    Timeout,
+}
+
+// Turns the error of a read or write that timed out into
+// `ConnectionError::Timeout`
+fn io_error(err: io::Error) -> anyhow::Error {
+    match err.kind() {
+        // Which one is returned depends on the platform
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ConnectionError::Timeout.into(),
+        _ => err.into(),
+    }
+}
+
+/// Clones share the underlying stream and buffer
+#[derive(Clone)]
 pub struct Connection {
     buffer: Arc<Mutex<Vec<u8>>>,
-    stream: Arc<TcpStream>,
+    stream: Arc<dyn ReadWrite>,
+    /// The protocol `write_data` encodes with, RESP2 until set with HELLO
+    protocol: Arc<Mutex<Protocol>>,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: impl ReadWrite + 'static) -> Self {
         let buffer = Arc::new(Mutex::new(Vec::new()));
         Self {
             buffer,
             stream: Arc::new(stream),
+            protocol: Arc::new(Mutex::new(Protocol::Resp2)),
         }
     }
 
     fn load_more(&self) -> Result<()> {
         let mut buf = vec![0; 1024];
-        let num_bytes_read = self.stream.as_ref().read(&mut buf)?;
+        let num_bytes_read = self.stream.read(&mut buf).map_err(io_error)?;
         if num_bytes_read == 0 {
             // TcpStream::read returning 0 means the connection is closed
             Err(anyhow!("TcpStream closed"))
@@ -83,11 +170,147 @@ impl Connection {
 
     /// `write_data` is not thread-safe
     pub fn write_data(&self, data: Data) -> Result<()> {
-        Ok(self.stream.as_ref().write_all(&data.encode())?)
+        trace!("Sent: {}", data);
+        let protocol = *self.protocol.lock().unwrap();
+        self.stream
+            .write_all(&data.encode_as(protocol))
+            .map_err(io_error)
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        *self.protocol.lock().unwrap()
+    }
+
+    pub fn set_protocol(&self, protocol: Protocol) {
+        *self.protocol.lock().unwrap() = protocol;
     }
 
     /// `write` is not thread-safe
     pub fn write(&self, buf: Vec<u8>) -> Result<()> {
-        Ok(self.stream.as_ref().write_all(&buf)?)
+        self.stream.write_all(&buf).map_err(io_error)
+    }
+
+    /// Whether the other end closed the connection, checked without blocking.
+    /// Bytes it sent in the meantime are kept for `read_data`.
+    pub fn is_closed(&self) -> bool {
+        let mut buf = vec![0; 1024];
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let result = self.stream.read(&mut buf);
+        if self.stream.set_nonblocking(false).is_err() {
+            return true;
+        }
+        match result {
+            Ok(0) => true,
+            Ok(num_bytes_read) => {
+                self.buffer
+                    .lock()
+                    .unwrap()
+                    .extend_from_slice(&buf[..num_bytes_read]);
+                false
+            }
+            Err(err) => !matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+        }
+    }
+
+    /// The address of the other end, or an empty string if unknown
+    pub fn peer_addr(&self) -> String {
+        self.stream.peer_addr()
+    }
+
+    /// Closes the stream, including for clones. Reading from it fails from
+    /// then on.
+    pub fn close(&self) -> Result<()> {
+        Ok(self.stream.shutdown()?)
+    }
+
+    /// Makes reads and writes fail with `ConnectionError::Timeout` once they
+    /// take longer than `timeout`. None means they never time out.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.stream.set_timeout(timeout)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_stream() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = Connection::new(client);
+        let server = Connection::new(server);
+
+        let ping = Data::Array(vec![Data::BulkString("PING".into())]);
+        client.write_data(ping.clone()).unwrap();
+        assert_eq!(server.read_data().unwrap(), ping);
+
+        server
+            .write_data(Data::SimpleString("PONG".into()))
+            .unwrap();
+        assert_eq!(
+            client.read_data().unwrap(),
+            Data::SimpleString("PONG".into())
+        );
+
+        // Closing one end is seen as an error by the other
+        drop(server);
+        assert!(client.read_data().is_err());
+    }
+
+    #[test]
+    fn close_from_a_clone() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = Connection::new(client);
+        let server = Connection::new(server);
+
+        // Like CLIENT KILL, closing through a clone ends reads of the original
+        let reader = server.clone();
+        let handle = std::thread::spawn(move || reader.read_data().is_err());
+        server.close().unwrap();
+        assert!(handle.join().unwrap());
+        assert!(client.read_data().is_err());
+        assert_eq!(server.peer_addr(), "");
+    }
+
+    #[test]
+    fn timeout() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = Connection::new(client);
+        let server = Connection::new(server);
+
+        server.set_timeout(Some(Duration::from_millis(50))).unwrap();
+        let err = server.read_data().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConnectionError>(),
+            Some(ConnectionError::Timeout)
+        ));
+
+        // The connection is still usable
+        let ping = Data::Array(vec![Data::BulkString("PING".into())]);
+        client.write_data(ping.clone()).unwrap();
+        assert_eq!(server.read_data().unwrap(), ping);
+        server.set_timeout(None).unwrap();
+    }
+
+    #[test]
+    fn is_closed() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = Connection::new(client);
+        let server = Connection::new(server);
+        assert!(!server.is_closed());
+
+        // What was sent meanwhile can still be read
+        let ping = Data::Array(vec![Data::BulkString("PING".into())]);
+        client.write_data(ping.clone()).unwrap();
+        assert!(!server.is_closed());
+        assert_eq!(server.read_data().unwrap(), ping);
+
+        drop(client);
+        assert!(server.is_closed());
     }
 }
@@ -4,11 +4,25 @@ use core::fmt;
 use thiserror::Error;
 
 const NULL_BULK_STRING: &str = "$-1\r\n";
+const NULL_ARRAY: &str = "*-1\r\n";
 const SIMPLE_STRING_DATA_TYPE: char = '+';
 const BULK_STRING_DATA_TYPE: char = '$';
 const INTEGER_DATA_TYPE: char = ':';
 const ARRAY_DATA_TYPE: char = '*';
 const SIMPLE_ERROR_DATA_TYPE: char = '-';
+// RESP3 only
+const NULL: &str = "_\r\n";
+const NULL_DATA_TYPE: char = '_';
+const MAP_DATA_TYPE: char = '%';
+
+/// The protocol of a connection, chosen with HELLO. RESP3 has its own types
+/// for maps and nulls; with RESP2, maps are arrays of key-value pairs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Data {
@@ -17,7 +31,10 @@ pub enum Data {
     NullBulkString,
     Integer(i64),
     Array(Vec<Data>),
+    NullArray,
     SimpleError(String),
+    /// Keys and values, in order
+    Map(Vec<(Data, Data)>),
     Unknown(Vec<u8>),
 }
 
@@ -55,18 +72,44 @@ fn encode_integer(i: i64) -> Vec<u8> {
     res
 }
 
-fn encode_array(vs: Vec<Data>) -> Vec<u8> {
+fn encode_array(vs: Vec<Data>, protocol: Protocol) -> Vec<u8> {
     // *<number-of-elements>\r\n<element-1>...<element-n>
     let mut res = Vec::new();
     res.append(&mut vec![ARRAY_DATA_TYPE as u8]);
     res.append(&mut vs.len().to_string().as_bytes().to_vec());
     append_crlf(&mut res);
     for v in vs {
-        res.append(&mut v.encode());
+        res.append(&mut v.encode_as(protocol));
+    }
+    res
+}
+
+fn encode_map(pairs: &[(Data, Data)], protocol: Protocol) -> Vec<u8> {
+    if protocol == Protocol::Resp2 {
+        return encode_array(
+            pairs
+                .iter()
+                .flat_map(|(k, v)| [k.clone(), v.clone()])
+                .collect(),
+            protocol,
+        );
+    }
+    // %<number-of-pairs>\r\n<key-1><value-1>...<key-n><value-n>
+    let mut res = Vec::new();
+    res.push(MAP_DATA_TYPE as u8);
+    res.append(&mut pairs.len().to_string().as_bytes().to_vec());
+    append_crlf(&mut res);
+    for (k, v) in pairs {
+        res.append(&mut k.encode_as(protocol));
+        res.append(&mut v.encode_as(protocol));
     }
     res
 }
 
+fn encode_null_array() -> Vec<u8> {
+    NULL_ARRAY.into()
+}
+
 fn encode_simple_error(err: String) -> Vec<u8> {
     // -Error message\r\n
     let mut res = Vec::new();
@@ -234,6 +277,16 @@ fn decode_array(buf: &[u8]) -> Result<(Data, usize)> {
 
     assert_eq!(buf[0] as char, ARRAY_DATA_TYPE);
 
+    if buf[1] as char == '-' {
+        if buf.len() < 5 {
+            bail!(DecodeError::NeedMoreBytes)
+        }
+
+        // null array
+        assert_eq!(&buf[..5], NULL_ARRAY.as_bytes());
+        return Ok((Data::NullArray, 5));
+    }
+
     let mut curr = 1;
 
     let (length, num_bytes) = decode_unsigned_int(&buf[curr..]).unwrap();
@@ -258,6 +311,48 @@ fn decode_array(buf: &[u8]) -> Result<(Data, usize)> {
     Ok((Data::Array(values), curr))
 }
 
+fn decode_map(buf: &[u8]) -> Result<(Data, usize)> {
+    // Shortest map: %0\r\n. 4 bytes
+    if buf.len() < 4 {
+        bail!(DecodeError::NeedMoreBytes)
+    }
+
+    assert_eq!(buf[0] as char, MAP_DATA_TYPE);
+
+    let mut curr = 1;
+    let (length, num_bytes) = decode_unsigned_int(&buf[curr..])?;
+    curr += num_bytes;
+
+    // \r\n
+    if buf.len() < curr + 2 {
+        bail!(DecodeError::NeedMoreBytes)
+    }
+    assert_eq!(buf[curr] as char, '\r');
+    curr += 1;
+    assert_eq!(buf[curr] as char, '\n');
+    curr += 1;
+
+    let mut pairs = Vec::new();
+    for _ in 0..length {
+        let (key, num_bytes) = Data::decode(&buf[curr..])?;
+        curr += num_bytes;
+        let (value, num_bytes) = Data::decode(&buf[curr..])?;
+        curr += num_bytes;
+        pairs.push((key, value));
+    }
+
+    Ok((Data::Map(pairs), curr))
+}
+
+// RESP3 has a single null, decoded as a null bulk string
+fn decode_null(buf: &[u8]) -> Result<(Data, usize)> {
+    if buf.len() < 3 {
+        bail!(DecodeError::NeedMoreBytes)
+    }
+    assert_eq!(&buf[..3], NULL.as_bytes());
+    Ok((Data::NullBulkString, 3))
+}
+
 fn decode_simple_error(buf: &[u8]) -> Result<(Data, usize)> {
     // -<msg>\r\n
     if buf.len() < 3 {
@@ -311,14 +406,22 @@ pub fn decode_rdb_file(buf: &[u8]) -> Result<(Vec<u8>, usize)> {
 }
 
 impl Data {
+    /// Encodes with RESP2, as used between master and replicas
     pub fn encode(&self) -> Vec<u8> {
+        self.encode_as(Protocol::Resp2)
+    }
+
+    pub fn encode_as(&self, protocol: Protocol) -> Vec<u8> {
         match self {
             Data::SimpleString(s) => encode_simple_string(s.clone()),
             Data::BulkString(s) => encode_bulk_string(s.clone()),
+            Data::NullBulkString | Data::NullArray if protocol == Protocol::Resp3 => NULL.into(),
             Data::NullBulkString => encode_null_bulk_string(),
             Data::Integer(i) => encode_integer(*i),
-            Data::Array(arr) => encode_array(arr.to_vec()),
+            Data::Array(arr) => encode_array(arr.to_vec(), protocol),
+            Data::NullArray => encode_null_array(),
             Data::SimpleError(e) => encode_simple_error(e.clone()),
+            Data::Map(pairs) => encode_map(pairs, protocol),
             Data::Unknown(_) => panic!("encode Unknown?"),
         }
     }
@@ -334,6 +437,8 @@ impl Data {
             INTEGER_DATA_TYPE => decode_integer(buf),
             ARRAY_DATA_TYPE => decode_array(buf),
             SIMPLE_ERROR_DATA_TYPE => decode_simple_error(buf),
+            MAP_DATA_TYPE => decode_map(buf),
+            NULL_DATA_TYPE => decode_null(buf),
             c => Err(anyhow::anyhow!("Unrecognized data type: {}", c)),
         }
     }
@@ -346,7 +451,17 @@ impl Data {
             Data::Array(vs) => {
                 1 + vs.len().to_string().len() + 2 + vs.iter().map(|v| v.num_bytes()).sum::<usize>()
             }
+            Data::NullArray => 5,
             Data::SimpleError(e) => 1 + e.len() + 2,
+            // As an array of pairs, like `encode`
+            Data::Map(pairs) => {
+                1 + (2 * pairs.len()).to_string().len()
+                    + 2
+                    + pairs
+                        .iter()
+                        .map(|(k, v)| k.num_bytes() + v.num_bytes())
+                        .sum::<usize>()
+            }
             Data::Unknown(_) => usize::MAX,
             Data::Integer(i) => 1 + i.to_string().len() + 2,
         }
@@ -359,36 +474,41 @@ impl Data {
             _ => None,
         }
     }
+}
 
-    fn to_string(&self) -> String {
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Data::SimpleString(s) => {
-                format!("SimpleString('{}')", String::from_utf8(s.clone()).unwrap())
+                write!(f, "SimpleString('{}')", String::from_utf8_lossy(s))
             }
-            Data::BulkString(s) => {
-                format!("BulkString('{}')", String::from_utf8(s.clone()).unwrap())
-            }
-            Data::NullBulkString => "NullBulkString".into(),
-            Data::Array(vs) => format!(
+            Data::BulkString(s) => write!(f, "BulkString('{}')", String::from_utf8_lossy(s)),
+            Data::NullBulkString => write!(f, "NullBulkString"),
+            Data::Array(vs) => write!(
+                f,
                 "Array[{}]",
                 vs.iter()
                     .map(|v| v.to_string())
                     .collect::<Vec<String>>()
                     .join(", ")
             ),
-            Data::SimpleError(e) => format!("Error: '{}'", e),
-            Data::Unknown(_) => "Unknown".into(),
-            Data::Integer(_) => todo!(),
+            Data::NullArray => write!(f, "NullArray"),
+            Data::SimpleError(e) => write!(f, "Error: '{}'", e),
+            Data::Map(pairs) => write!(
+                f,
+                "Map{{{}}}",
+                pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Data::Unknown(_) => write!(f, "Unknown"),
+            Data::Integer(i) => write!(f, "Integer({})", i),
         }
     }
 }
 
-impl fmt::Display for Data {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -418,6 +538,7 @@ mod tests {
     #[test]
     fn array() {
         roundtrip(Data::Array(Vec::new()));
+        roundtrip(Data::NullArray);
         roundtrip(Data::Array(vec![Data::SimpleString("a".into())]));
         roundtrip(Data::Array(vec![
             Data::SimpleString("a".into()),
@@ -450,6 +571,24 @@ mod tests {
         roundtrip(Data::Integer(-42));
     }
 
+    #[test]
+    fn resp3() {
+        let map = Data::Map(vec![
+            (Data::BulkString("a".into()), Data::Integer(1)),
+            (Data::BulkString("b".into()), Data::NullBulkString),
+        ]);
+        // With RESP2, an array of pairs
+        let encoded = map.encode();
+        assert_eq!(encoded, b"*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n");
+        assert_eq!(map.num_bytes(), encoded.len());
+
+        let encoded = map.encode_as(Protocol::Resp3);
+        assert_eq!(encoded, b"%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n_\r\n");
+        assert_eq!(Data::decode(&encoded).unwrap(), (map, encoded.len()));
+        assert_eq!(Data::NullArray.encode_as(Protocol::Resp3), b"_\r\n");
+        assert!(Data::decode(b"%1\r\n+a\r\n").is_err());
+    }
+
     #[test]
     fn rdb_file() {
         assert!(decode_rdb_file("$2\r\nx".as_bytes()).is_err());
@@ -0,0 +1,179 @@
+//! Positions on the Earth as used by GEOADD, GEOPOS, GEODIST and GEOSEARCH.
+//! Like Redis, they are stored in sorted sets, with 52-bit geohashes as
+//! scores: the bits of the latitude and of the longitude, 26 each,
+//! interleaved.
+
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+// The limits of Web Mercator, beyond which the poles can't be indexed
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+
+/// Bits of each coordinate in a geohash
+const STEP: u32 = 26;
+
+/// The radius Redis uses, in meters
+const EARTH_RADIUS: f64 = 6372797.560856;
+
+pub fn is_valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+/// The geohash of a valid position
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    let cell = |value: f64, min: f64, max: f64| {
+        let cells = (1u64 << STEP) as f64;
+        // The maximum belongs to the last cell
+        (((value - min) / (max - min) * cells) as u64).min((1 << STEP) - 1)
+    };
+    interleave(cell(lat, LAT_MIN, LAT_MAX), cell(lon, LON_MIN, LON_MAX))
+}
+
+/// The center of the area of a geohash, as (longitude, latitude)
+pub fn decode(hash: u64) -> (f64, f64) {
+    let (lat, lon) = deinterleave(hash);
+    let center = |cell: u64, min: f64, max: f64| {
+        let cells = (1u64 << STEP) as f64;
+        let low = min + cell as f64 / cells * (max - min);
+        let high = min + (cell + 1) as f64 / cells * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (center(lon, LON_MIN, LON_MAX), center(lat, LAT_MIN, LAT_MAX))
+}
+
+// Puts the bits of `even` at the even positions, and those of `odd` at the
+// odd ones
+fn interleave(even: u64, odd: u64) -> u64 {
+    (0..STEP).fold(0, |hash, i| {
+        hash | ((even >> i) & 1) << (2 * i) | ((odd >> i) & 1) << (2 * i + 1)
+    })
+}
+
+fn deinterleave(hash: u64) -> (u64, u64) {
+    (0..STEP).fold((0, 0), |(even, odd), i| {
+        (
+            even | ((hash >> (2 * i)) & 1) << i,
+            odd | ((hash >> (2 * i + 1)) & 1) << i,
+        )
+    })
+}
+
+/// The distance in meters between two positions, with the haversine
+/// formula
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+// The distance in meters between two latitudes, in radians, on a meridian
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS * (lat2 - lat1).abs()
+}
+
+/// The area searched by GEOSEARCH around its center, with sizes in meters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// The distance from `center` to `position`, both (longitude, latitude),
+    /// if `position` is in the shape
+    pub fn distance_if_within(&self, center: (f64, f64), position: (f64, f64)) -> Option<f64> {
+        let ((lon1, lat1), (lon2, lat2)) = (center, position);
+        match *self {
+            Shape::Radius(radius) => {
+                let distance = distance(lon1, lat1, lon2, lat2);
+                (distance <= radius).then_some(distance)
+            }
+            Shape::Box { width, height } => {
+                // Like Redis, the width is measured at the latitude of the
+                // position
+                if lat_distance(lat1.to_radians(), lat2.to_radians()) > height / 2.0
+                    || distance(lon1, lat2, lon2, lat2) > width / 2.0
+                {
+                    return None;
+                }
+                Some(distance(lon1, lat1, lon2, lat2))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        for (lon, lat) in [
+            (13.361389, 38.115556),
+            (-122.27652, 37.805186),
+            (0.0, 0.0),
+            (LON_MIN, LAT_MIN),
+            (LON_MAX, LAT_MAX),
+        ] {
+            let hash = encode(lon, lat);
+            assert!(hash < 1 << 52);
+            let (decoded_lon, decoded_lat) = decode(hash);
+            // Cells are less than a meter wide
+            assert!((decoded_lon - lon).abs() < 1e-5, "{} {}", lon, decoded_lon);
+            assert!((decoded_lat - lat).abs() < 1e-5, "{} {}", lat, decoded_lat);
+        }
+        // The same hash as Redis
+        assert_eq!(encode(13.361389, 38.115556), 3479099956230698);
+    }
+
+    #[test]
+    fn interleaving() {
+        assert_eq!(interleave(0b11, 0b00), 0b0101);
+        assert_eq!(interleave(0b00, 0b11), 0b1010);
+        assert_eq!(deinterleave(0b1001), (0b01, 0b10));
+    }
+
+    #[test]
+    fn distances() {
+        // Palermo and Catania, as computed by Redis from their geohashes
+        let (lon1, lat1) = decode(encode(13.361389, 38.115556));
+        let (lon2, lat2) = decode(encode(15.087269, 37.502669));
+        let d = distance(lon1, lat1, lon2, lat2);
+        assert_eq!(format!("{:.4}", d), "166274.1516");
+        assert_eq!(distance(1.0, 2.0, 1.0, 2.0), 0.0);
+        // On a meridian
+        let d = distance(10.0, 0.0, 10.0, 1.0);
+        assert!((d - 111226.3).abs() < 0.1, "{}", d);
+    }
+
+    #[test]
+    fn shapes() {
+        let center = (15.0, 37.0);
+        let catania = (15.087269, 37.502669);
+        let d = Shape::Radius(60_000.0).distance_if_within(center, catania);
+        assert!((d.unwrap() - 56441.3).abs() < 0.1);
+        assert_eq!(
+            Shape::Radius(50_000.0).distance_if_within(center, catania),
+            None
+        );
+
+        let shape = |width, height| Shape::Box { width, height };
+        assert!(shape(20_000.0, 120_000.0)
+            .distance_if_within(center, catania)
+            .is_some());
+        // Too far north, or east
+        assert_eq!(
+            shape(20_000.0, 100_000.0).distance_if_within(center, catania),
+            None
+        );
+        assert_eq!(
+            shape(10_000.0, 120_000.0).distance_if_within(center, catania),
+            None
+        );
+    }
+}
@@ -0,0 +1,169 @@
+/// Matches `text` against a glob-style `pattern`, byte by byte:
+/// - `*` matches any sequence, including the empty one
+/// - `?` matches any single character
+/// - `[abc]` matches one of the characters in the brackets, `[a-z]` one in the range
+/// - `[^abc]` or `[!abc]` matches any character not in the brackets
+/// - `\x` matches `x`, so that special characters can be matched literally
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn match_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => {
+            // Consecutive stars match the same as a single one
+            let rest = &pattern[1..];
+            if rest.first() == Some(&b'*') {
+                return match_bytes(rest, text);
+            }
+
+            // Try every possible length for the sequence matched by `*`
+            (0..=text.len()).any(|i| match_bytes(rest, &text[i..]))
+        }
+        Some(b'?') => !text.is_empty() && match_bytes(&pattern[1..], &text[1..]),
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && match_bytes(&pattern[2..], &text[1..])
+        }
+        Some(b'[') => match class_end(pattern) {
+            Some(end) => {
+                !text.is_empty()
+                    && class_contains(&pattern[1..end], text[0])
+                    && match_bytes(&pattern[end + 1..], &text[1..])
+            }
+            // No closing bracket, treat '[' as a normal character
+            None => text.first() == Some(&b'[') && match_bytes(&pattern[1..], &text[1..]),
+        },
+        Some(c) => text.first() == Some(c) && match_bytes(&pattern[1..], &text[1..]),
+    }
+}
+
+// The position of the `]` closing the character class at the start of
+// `pattern`. An escaped `]` doesn't close it.
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'\\' => i += 2,
+            b']' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+// Whether `c` is in a character class, given without the brackets
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'^' | b'!') => (true, &class[1..]),
+        _ => (false, class),
+    };
+
+    // The characters of the class, with whether they were escaped. An
+    // escaped `-` doesn't make a range.
+    let mut chars = Vec::new();
+    let mut i = 0;
+    while i < class.len() {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            chars.push((class[i + 1], true));
+            i += 2;
+        } else {
+            chars.push((class[i], false));
+            i += 1;
+        }
+    }
+
+    let mut found = false;
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 2 < chars.len() && chars[i + 1] == (b'-', false) {
+            let (start, end) = (
+                chars[i].0.min(chars[i + 2].0),
+                chars[i].0.max(chars[i + 2].0),
+            );
+            found |= (start..=end).contains(&c);
+            i += 3;
+        } else {
+            found |= chars[i].0 == c;
+            i += 1;
+        }
+    }
+
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(glob_match("", ""));
+        assert!(glob_match("news", "news"));
+        assert!(!glob_match("news", "new"));
+        assert!(!glob_match("new", "news"));
+    }
+
+    #[test]
+    fn star() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("news.*", "news.sport"));
+        assert!(glob_match("news.*", "news."));
+        assert!(!glob_match("news.*", "news"));
+        assert!(glob_match("*.sport", "news.sport"));
+        assert!(glob_match("n*s*t", "news.sport"));
+        assert!(glob_match("n**t", "news.sport"));
+        assert!(!glob_match("n*x", "news.sport"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(!glob_match("h?llo", "heello"));
+    }
+
+    #[test]
+    fn character_class() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(!glob_match("h[ae]llo", "hllo"));
+
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(!glob_match("h[a-c]llo", "hdllo"));
+        assert!(glob_match("key[0-9]", "key7"));
+
+        // Unterminated class
+        assert!(glob_match("a[b", "a[b"));
+        assert!(!glob_match("a[b", "ab"));
+    }
+
+    #[test]
+    fn negated_class() {
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[!a-c]llo", "hello"));
+        assert!(!glob_match("h[!a-c]llo", "hbllo"));
+        // A negated class still matches exactly one character
+        assert!(!glob_match("h[^e]llo", "hllo"));
+        // `^` is only special at the start
+        assert!(glob_match("[a^]", "^"));
+    }
+
+    #[test]
+    fn escape() {
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("\\?\\[x\\]", "?[x]"));
+        assert!(glob_match("a\\\\b", "a\\b"));
+        // Inside a class
+        assert!(glob_match("[\\]a]", "]"));
+        assert!(glob_match("[a\\-c]", "-"));
+        assert!(!glob_match("[a\\-c]", "b"));
+        // A trailing backslash matches itself
+        assert!(glob_match("a\\", "a\\"));
+    }
+}
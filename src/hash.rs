@@ -0,0 +1,125 @@
+use crate::scan::ScanMap;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// A hash. Fields can have an expiration time, set with HEXPIRE and friends.
+/// Expired fields are only removed by `remove_expired`, so callers should call
+/// it before looking at the fields.
+#[derive(Clone, Debug, Default)]
+pub struct Hash {
+    fields: ScanMap<String>,
+    expirations: HashMap<String, SystemTime>,
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fields(&self) -> &ScanMap<String> {
+        &self.fields
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        self.fields.get(field)
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    /// Sets the value of a field, removing its expiration. Returns the old
+    /// value.
+    pub fn insert(&mut self, field: String, value: String) -> Option<String> {
+        self.expirations.remove(&field);
+        self.fields.insert(field, value)
+    }
+
+    /// Sets the value of a field, keeping its expiration
+    pub fn update(&mut self, field: String, value: String) {
+        self.fields.insert(field, value);
+    }
+
+    pub fn remove(&mut self, field: &str) -> Option<String> {
+        self.expirations.remove(field);
+        self.fields.remove(field)
+    }
+
+    pub fn expiration(&self, field: &str) -> Option<SystemTime> {
+        self.expirations.get(field).copied()
+    }
+
+    /// Sets or removes the expiration of an existing field. Returns false if
+    /// the field doesn't exist.
+    pub fn set_expiration(&mut self, field: &str, expiration: Option<SystemTime>) -> bool {
+        if !self.fields.contains_key(field) {
+            return false;
+        }
+
+        match expiration {
+            Some(expiration) => self.expirations.insert(field.into(), expiration),
+            None => self.expirations.remove(field),
+        };
+        true
+    }
+
+    /// Removes the fields that have expired by `now`
+    pub fn remove_expired(&mut self, now: SystemTime) {
+        let expired: Vec<String> = self
+            .expirations
+            .iter()
+            .filter(|(_, expiration)| **expiration <= now)
+            .map(|(field, _)| field.clone())
+            .collect();
+
+        for field in expired {
+            self.remove(&field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn remove_expired() {
+        let now = SystemTime::now();
+        let mut hash = Hash::new();
+        hash.insert("a".into(), "1".into());
+        hash.insert("b".into(), "2".into());
+        hash.insert("c".into(), "3".into());
+
+        assert!(hash.set_expiration("a", Some(now)));
+        assert!(hash.set_expiration("b", Some(now + Duration::from_secs(10))));
+        assert!(!hash.set_expiration("d", Some(now)));
+
+        hash.remove_expired(now);
+        assert_eq!(hash.get("a"), None);
+        assert_eq!(hash.expiration("a"), None);
+        assert_eq!(hash.get("b"), Some(&"2".to_string()));
+        assert_eq!(hash.get("c"), Some(&"3".to_string()));
+
+        hash.remove_expired(now + Duration::from_secs(10));
+        assert_eq!(hash.fields().len(), 1);
+    }
+
+    #[test]
+    fn insert_removes_expiration() {
+        let expiration = SystemTime::now() + Duration::from_secs(10);
+        let mut hash = Hash::new();
+        hash.insert("a".into(), "1".into());
+        hash.set_expiration("a", Some(expiration));
+
+        hash.update("a".into(), "2".into());
+        assert_eq!(hash.expiration("a"), Some(expiration));
+
+        hash.insert("a".into(), "3".into());
+        assert_eq!(hash.expiration("a"), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! HyperLogLogs as used by PFADD, PFCOUNT and PFMERGE. Like Redis, they are
+//! strings, in Redis' dense representation: a 16-byte header followed by
+//! 16384 registers of 6 bits, the first register in the low bits of the first
+//! byte. So they can be read with GET and restored with SET.
+
+/// Bits of the hash that select the register
+const P: u32 = 14;
+const REGISTERS: usize = 1 << P;
+/// Bits of the hash left to count the zeros of
+const Q: u32 = 64 - P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+
+const MAGIC: &[u8] = b"HYLL";
+const ENCODING_DENSE: u8 = 0;
+const HEADER_LEN: usize = 16;
+/// Size of the string of a HyperLogLog, about 12KB
+pub const LEN: usize = HEADER_LEN + (REGISTERS * REGISTER_BITS).div_ceil(8);
+
+// Redis caches the cardinality in the header, with the most significant bit
+// set when it's stale. It's never used here, only marked as stale so that
+// Redis doesn't trust it either.
+const CARDINALITY: usize = 8;
+const CARDINALITY_STALE: u8 = 1 << 7;
+
+const HASH_SEED: u64 = 0xadc83b19;
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+/// An empty HyperLogLog
+pub fn new() -> Vec<u8> {
+    let mut bytes = vec![0; LEN];
+    bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+    bytes[MAGIC.len()] = ENCODING_DENSE;
+    bytes
+}
+
+/// Whether `bytes` is a HyperLogLog. Redis' sparse representation isn't
+/// supported.
+pub fn is_valid(bytes: &[u8]) -> bool {
+    bytes.len() == LEN && bytes.starts_with(MAGIC) && bytes[MAGIC.len()] == ENCODING_DENSE
+}
+
+/// Adds `element`, and returns whether a register changed, which may change
+/// the estimated cardinality
+pub fn add(hll: &mut [u8], element: &[u8]) -> bool {
+    let (index, count) = pattern(element);
+    if get_register(hll, index) >= count {
+        return false;
+    }
+    set_register(hll, index, count);
+    mark_stale(hll);
+    true
+}
+
+/// Merges `other` into `hll`, so that it counts the union of both
+pub fn merge(hll: &mut [u8], other: &[u8]) {
+    for index in 0..REGISTERS {
+        let count = get_register(other, index);
+        if count > get_register(hll, index) {
+            set_register(hll, index, count);
+        }
+    }
+    mark_stale(hll);
+}
+
+fn mark_stale(hll: &mut [u8]) {
+    hll[CARDINALITY + 7] |= CARDINALITY_STALE;
+}
+
+/// The estimated cardinality, computed with the estimator of "New
+/// cardinality estimation algorithms for HyperLogLog sketches" (Ertl, 2017),
+/// as Redis does
+pub fn count(hll: &[u8]) -> u64 {
+    // How many registers have each value
+    let mut histogram = [0u32; Q as usize + 2];
+    for index in 0..REGISTERS {
+        histogram[get_register(hll, index) as usize] += 1;
+    }
+
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for j in (1..=Q as usize).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+// The register of `element`, and the number of trailing zeros of the rest of
+// its hash plus one
+fn pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash_64a(element, HASH_SEED);
+    let index = hash as usize & (REGISTERS - 1);
+    // The extra bit stops the count at Q + 1
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn get_register(hll: &[u8], index: usize) -> u8 {
+    let bit = index * REGISTER_BITS;
+    let byte = HEADER_LEN + bit / 8;
+    let shift = bit % 8;
+    let low = hll[byte] >> shift;
+    let high = hll.get(byte + 1).map_or(0, |b| (*b as u16) << (8 - shift)) as u8;
+    (low | high) & REGISTER_MAX
+}
+
+fn set_register(hll: &mut [u8], index: usize, value: u8) {
+    let bit = index * REGISTER_BITS;
+    let byte = HEADER_LEN + bit / 8;
+    let shift = bit % 8;
+    hll[byte] &= !(REGISTER_MAX << shift);
+    hll[byte] |= value << shift;
+    if shift + REGISTER_BITS > 8 {
+        hll[byte + 1] &= !(REGISTER_MAX >> (8 - shift));
+        hll[byte + 1] |= value >> (8 - shift);
+    }
+}
+
+// MurmurHash64A, the hash Redis uses, so that registers match Redis'
+fn murmur_hash_64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let chunks = key.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hll_of(elements: impl Iterator<Item = String>) -> Vec<u8> {
+        let mut hll = new();
+        for element in elements {
+            add(&mut hll, element.as_bytes());
+        }
+        hll
+    }
+
+    #[test]
+    fn registers() {
+        let mut hll = new();
+        for index in [0, 1, 2, 3, 4, REGISTERS - 1] {
+            set_register(&mut hll, index, REGISTER_MAX);
+            assert_eq!(get_register(&hll, index), REGISTER_MAX);
+            set_register(&mut hll, index, 5);
+            assert_eq!(get_register(&hll, index), 5);
+        }
+        // Neighbours are untouched
+        set_register(&mut hll, 5, 0);
+        assert_eq!(get_register(&hll, 4), 5);
+        assert_eq!(get_register(&hll, 5), 0);
+        assert!(is_valid(&hll));
+        assert!(!is_valid(b"HYLL"));
+    }
+
+    #[test]
+    fn add_and_count() {
+        let mut hll = new();
+        assert_eq!(count(&hll), 0);
+        assert!(add(&mut hll, b"a"));
+        assert!(!add(&mut hll, b"a"));
+        assert!(add(&mut hll, b"b"));
+        assert_eq!(count(&hll), 2);
+    }
+
+    #[test]
+    fn error_is_within_one_percent() {
+        // The standard error is 0.81%, so some sets are off by more than 1%,
+        // but the hash is deterministic so these ones never are
+        for n in [1_000, 10_000, 100_000, 500_000] {
+            let hll = hll_of((0..n).map(|i| format!("element:{}", i)));
+            let error = (count(&hll) as f64 - n as f64).abs() / n as f64;
+            assert!(error < 0.01, "{} elements, error {}", n, error);
+        }
+    }
+
+    #[test]
+    fn merged_counts_the_union() {
+        let mut a = hll_of((0..60_000).map(|i| i.to_string()));
+        let b = hll_of((40_000..100_000).map(|i| i.to_string()));
+        merge(&mut a, &b);
+        let error = (count(&a) as f64 - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.01, "error {}", error);
+    }
+}
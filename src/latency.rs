@@ -0,0 +1,144 @@
+//! Latency samples of every command, read with LATENCY HISTORY and LATENCY
+//! LATEST and cleared with LATENCY RESET. Unlike Redis, whose events are
+//! classes of slow operations, every command is an event, named after it.
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Number of samples kept for each event. Older ones are dropped.
+const MAX_SAMPLES: usize = 128;
+
+/// A sample, as (Unix time in seconds, latency in microseconds)
+pub type Sample = (u64, u64);
+
+#[derive(Debug, Default)]
+struct Event {
+    samples: VecDeque<Sample>,
+    /// The highest latency since the event was created or reset
+    max: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LatencyMonitor {
+    events: HashMap<String, Event>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `event` took `latency`. Like Redis, there's one sample per
+    /// second: the highest latency within a second is kept.
+    pub fn record(&mut self, event: &str, latency: Duration) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.record_at(event, now, latency.as_micros() as u64);
+    }
+
+    fn record_at(&mut self, event: &str, time: u64, latency: u64) {
+        if !self.events.contains_key(event) {
+            self.events.insert(event.to_string(), Event::default());
+        }
+        let event = self.events.get_mut(event).unwrap();
+        event.max = event.max.max(latency);
+        match event.samples.back_mut() {
+            Some((last_time, last_latency)) if *last_time == time => {
+                *last_latency = (*last_latency).max(latency);
+            }
+            _ => {
+                if event.samples.len() == MAX_SAMPLES {
+                    event.samples.pop_front();
+                }
+                event.samples.push_back((time, latency));
+            }
+        }
+    }
+
+    /// The samples of `event`, oldest first
+    pub fn history(&self, event: &str) -> Vec<Sample> {
+        self.events
+            .get(event)
+            .map(|event| event.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The latest sample and the highest latency of every event, sorted by
+    /// event name
+    pub fn latest(&self) -> Vec<(&str, Sample, u64)> {
+        let mut latest: Vec<_> = self
+            .events
+            .iter()
+            .filter_map(|(name, event)| Some((name.as_str(), *event.samples.back()?, event.max)))
+            .collect();
+        latest.sort_by_key(|(name, _, _)| *name);
+        latest
+    }
+
+    /// Removes the samples of `events`, or of every event if empty. Returns
+    /// how many events had samples.
+    pub fn reset(&mut self, events: &[String]) -> usize {
+        if events.is_empty() {
+            let num_events = self.events.len();
+            self.events.clear();
+            return num_events;
+        }
+        events
+            .iter()
+            .filter(|event| self.events.remove(event.as_str()).is_some())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples() {
+        let mut monitor = LatencyMonitor::new();
+        monitor.record_at("get", 10, 5);
+        // Within the same second, the highest latency is kept
+        monitor.record_at("get", 10, 3);
+        monitor.record_at("get", 10, 7);
+        monitor.record_at("get", 11, 2);
+        monitor.record_at("set", 11, 4);
+
+        assert_eq!(monitor.history("get"), vec![(10, 7), (11, 2)]);
+        assert!(monitor.history("missing").is_empty());
+        assert_eq!(
+            monitor.latest(),
+            vec![("get", (11, 2), 7), ("set", (11, 4), 4)]
+        );
+    }
+
+    #[test]
+    fn old_samples_are_dropped() {
+        let mut monitor = LatencyMonitor::new();
+        for time in 0..200 {
+            monitor.record_at("get", time, time);
+        }
+        let history = monitor.history("get");
+        assert_eq!(history.len(), MAX_SAMPLES);
+        assert_eq!(
+            history[0],
+            (200 - MAX_SAMPLES as u64, 200 - MAX_SAMPLES as u64)
+        );
+        assert_eq!(monitor.latest(), vec![("get", (199, 199), 199)]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut monitor = LatencyMonitor::new();
+        for event in ["a", "b", "c"] {
+            monitor.record_at(event, 1, 1);
+        }
+        assert_eq!(monitor.reset(&["a".into(), "missing".into()]), 1);
+        assert!(monitor.history("a").is_empty());
+        assert_eq!(monitor.reset(&[]), 2);
+        assert!(monitor.latest().is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+//! The longest common subsequence of two strings, as used by LCS, computed on
+//! bytes with the classic dynamic programming table.
+
+/// A run of consecutive bytes of the subsequence, found consecutively in both
+/// strings, as inclusive ranges of indices in each
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+
+impl Match {
+    pub fn len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+/// Bytes of the table `lcs` needs for strings of these lengths, to bound the
+/// memory it uses
+pub fn table_size(a_len: usize, b_len: usize) -> Option<usize> {
+    (a_len.checked_add(1)?)
+        .checked_mul(b_len.checked_add(1)?)?
+        .checked_mul(size_of::<u32>())
+}
+
+/// The longest common subsequence of `a` and `b`, and the runs it's made of.
+/// Like Redis, runs are listed from the end of the strings.
+pub fn lcs(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<Match>) {
+    // table[i][j] is the length of the LCS of a[..i] and b[..j]
+    let width = b.len() + 1;
+    let mut table = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    // Walk back from the end, collecting common bytes
+    let mut subsequence = Vec::with_capacity(table[table.len() - 1] as usize);
+    let mut matches = Vec::new();
+    let mut current: Option<Match> = None;
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            subsequence.push(a[i - 1]);
+            // Consecutive in both, since both indices moved back by one
+            match &mut current {
+                Some(m) => {
+                    m.a.0 -= 1;
+                    m.b.0 -= 1;
+                }
+                None => {
+                    current = Some(Match {
+                        a: (i - 1, i - 1),
+                        b: (j - 1, j - 1),
+                    })
+                }
+            }
+            i -= 1;
+            j -= 1;
+        } else {
+            matches.extend(current.take());
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+        }
+    }
+    matches.extend(current);
+    subsequence.reverse();
+    (subsequence, matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsequence_and_matches() {
+        // The example of the Redis documentation
+        let (subsequence, matches) = lcs(b"ohmytext", b"mynewtext");
+        assert_eq!(subsequence, b"mytext");
+        assert_eq!(
+            matches,
+            vec![
+                Match {
+                    a: (4, 7),
+                    b: (5, 8)
+                },
+                Match {
+                    a: (2, 3),
+                    b: (0, 1)
+                },
+            ]
+        );
+        assert_eq!(matches[0].len(), 4);
+    }
+
+    #[test]
+    fn nothing_in_common() {
+        assert_eq!(lcs(b"abc", b"xyz"), (vec![], vec![]));
+        assert_eq!(lcs(b"", b"xyz"), (vec![], vec![]));
+        assert_eq!(
+            lcs(b"a\x00b", b"\x00"),
+            (
+                vec![0],
+                vec![Match {
+                    a: (1, 1),
+                    b: (0, 0)
+                }]
+            )
+        );
+    }
+
+    #[test]
+    fn table_sizes() {
+        assert_eq!(table_size(0, 0), Some(4));
+        assert_eq!(table_size(1, 2), Some(24));
+        assert_eq!(table_size(usize::MAX, 1), None);
+    }
+}
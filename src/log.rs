@@ -0,0 +1,133 @@
+//! Leveled logging to stdout, with the `error!`, `warn!`, `info!`, `debug!`
+//! and `trace!` macros. The most verbose level logged is read from the
+//! `RUST_LOG` environment variable, such as `RUST_LOG=debug`, and is `info` by
+//! default.
+use std::{
+    env, fmt,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        };
+        f.pad(name)
+    }
+}
+
+static MAX_LEVEL: OnceLock<Level> = OnceLock::new();
+
+// The level of a `RUST_LOG` value. Directives for a module, such as
+// `redis=debug`, are taken as global ones, and the most verbose one wins.
+fn parse_filter(filter: &str) -> Option<Level> {
+    filter
+        .split(',')
+        .filter_map(|directive| Level::parse(directive.rsplit('=').next().unwrap_or_default()))
+        .max()
+}
+
+/// Reads the level from `RUST_LOG`. Logging before this uses the level of
+/// `RUST_LOG` too.
+pub fn init() {
+    max_level();
+}
+
+fn max_level() -> Level {
+    *MAX_LEVEL.get_or_init(|| {
+        env::var("RUST_LOG")
+            .ok()
+            .and_then(|filter| parse_filter(&filter))
+            .unwrap_or(Level::Info)
+    })
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+/// Prints a line with the time and level, if `level` is enabled. Use the
+/// macros rather than calling this directly.
+pub fn log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    println!(
+        "{}.{:03} {:<5} {}",
+        now.as_secs(),
+        now.subsec_millis(),
+        level,
+        args
+    );
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+// Named so that it doesn't clash with the `warn` attribute in this module
+macro_rules! warn_ {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Trace, format_args!($($arg)*)) };
+}
+
+pub(crate) use {debug, error, info, trace, warn_ as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        assert_eq!(parse_filter("debug"), Some(Level::Debug));
+        assert_eq!(parse_filter(" WARN "), Some(Level::Warn));
+        assert_eq!(
+            parse_filter("redis_starter_rust=trace,info"),
+            Some(Level::Trace)
+        );
+        assert_eq!(parse_filter("verbose"), None);
+        assert_eq!(parse_filter(""), None);
+        assert!(Level::Error < Level::Trace);
+    }
+}
@@ -3,14 +3,36 @@ pub mod data;
 pub mod rdb;
 pub mod value;
 pub mod stream;
+mod bitmap;
+mod command;
+mod command_info;
+mod config;
+mod geo;
+mod glob;
+mod hash;
+mod hyperloglog;
+mod latency;
+mod lcs;
+mod log;
 mod master;
 mod mode;
+mod pubsub;
 mod replica;
+mod rng;
+mod scan;
+mod sorted_set;
 mod store;
+mod watch;
 use clap::Parser;
+use config::Config;
+use connection::{Connection, ReadWrite};
+use log::{debug, error, info};
 use mode::Mode;
+use anyhow::Result;
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    os::unix::net::UnixListener,
     path::PathBuf,
     str::FromStr,
     sync::Arc,
@@ -22,73 +44,151 @@ use crate::mode::{MasterParams, SlaveParams};
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// Config file to load. Options given on the command line override it.
+    config_file: Option<PathBuf>,
     #[arg(long)]
     port: Option<u16>,
+    /// Address to listen on. Both IPv4 and IPv6 are accepted, e.g. `0.0.0.0` or `::`.
+    /// Defaults to `127.0.0.1`.
+    #[arg(long)]
+    bind: Option<IpAddr>,
     #[arg(long = "replicaof", value_names = &["MASTER_HOST", "MASTER_PORT"], num_args = 2)]
     replica_of: Option<Vec<String>>,
     #[arg(long)]
     dir: Option<PathBuf>,
     #[arg(long, value_name = "FILE")]
     dbfilename: Option<String>,
+    /// Also accept connections on a unix socket at this path
+    #[arg(long, value_name = "PATH")]
+    unixsocket: Option<PathBuf>,
+    /// Require clients to AUTH with this password
+    #[arg(long, value_name = "PASSWORD")]
+    requirepass: Option<String>,
+    /// Password to AUTH with when connecting to the master
+    #[arg(long, value_name = "PASSWORD")]
+    masterauth: Option<String>,
+    /// Close the connection of clients idle for this many seconds. 0 means never.
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+}
+
+// Parse the host part of `--replicaof`. IPv6 addresses may be given in
+// bracket notation, e.g. `[::1]`.
+fn parse_host(host: &str) -> Result<IpAddr> {
+    if host == "localhost" {
+        return Ok(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok(IpAddr::from_str(host)?)
+}
+
+// Accept connections, handling each one in its own thread
+fn serve<S, H>(incoming: impl Iterator<Item = io::Result<S>>, handler: H)
+where
+    S: ReadWrite + 'static,
+    H: Fn(Connection) + Clone + Send + 'static,
+{
+    for stream in incoming {
+        match stream {
+            Ok(stream) => {
+                let handler = handler.clone();
+                thread::spawn(move || handler(Connection::new(stream)));
+            }
+            Err(e) => {
+                error!("Error accepting a connection: {}", e);
+            }
+        }
+    }
+}
+
+// Accept connections on a unix socket at `path` in a background thread
+fn serve_unix<H>(path: PathBuf, handler: H)
+where
+    H: Fn(Connection) + Clone + Send + 'static,
+{
+    // A socket file left behind by a previous run would make bind fail
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || serve(listener.incoming(), handler));
+}
+
+// The config from the config file, if any, overridden by the command line
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = match &cli.config_file {
+        None => Config::default(),
+        Some(file) => Config::load(file.clone())?,
+    };
+    if let Some(port) = cli.port {
+        config.port = port;
+    }
+    if let Some(bind) = cli.bind {
+        config.bind = bind;
+    }
+    if cli.dir.is_some() {
+        config.dir = cli.dir.clone();
+    }
+    if cli.dbfilename.is_some() {
+        config.dbfilename = cli.dbfilename.clone();
+    }
+    if cli.requirepass.is_some() {
+        config.requirepass = cli.requirepass.clone();
+    }
+    if let Some(timeout) = cli.timeout {
+        config.timeout = timeout;
+    }
+    Ok(config)
 }
 
 fn main() {
     let cli = Cli::parse();
-    println!("{:?}", cli);
+    log::init();
+    debug!("{:?}", cli);
+    let config = load_config(&cli).unwrap();
+
+    let port = config.port;
+    let sockaddr = SocketAddr::new(config.bind, port);
 
     let mode = match &cli.replica_of {
-        None => Mode::Master(MasterParams {
-            dir: cli.dir,
-            dbfilename: cli.dbfilename,
-        }),
+        None => Mode::Master(MasterParams { config }),
         Some(args) => {
             assert_eq!(args.len(), 2);
-            let addr = if args.first().unwrap() == "localhost" {
-                IpAddr::from_str("127.0.0.1").unwrap()
-            } else {
-                IpAddr::from_str(args.first().unwrap()).unwrap()
-            };
+            let addr = parse_host(args.first().unwrap()).unwrap();
             let port: u16 = args.get(1).unwrap().clone().parse().unwrap();
             Mode::Slave(SlaveParams {
                 master_sockaddr: SocketAddr::new(addr, port),
+                masterauth: cli.masterauth.clone(),
             })
         }
     };
-    println!("mode: {:?}", mode);
-
-    let port = cli.port.unwrap_or(6379);
-    let sockaddr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
+    info!("Mode: {:?}", mode);
 
     match mode {
         Mode::Master(master_params) => {
             let master = Arc::new(master::Master::new(master_params).unwrap());
-            let listener = TcpListener::bind(sockaddr).unwrap();
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let master = master.clone();
-                        thread::spawn(move || master.handle_connection(stream));
-                    }
-                    Err(e) => {
-                        println!("error: {}", e);
-                    }
-                }
+            let handler = move |conn| {
+                let _ = master.handle_connection(conn);
+            };
+
+            if let Some(path) = cli.unixsocket {
+                serve_unix(path, handler.clone());
             }
+            serve(TcpListener::bind(sockaddr).unwrap().incoming(), handler);
         }
         Mode::Slave(slave_params) => {
             let listener = TcpListener::bind(sockaddr).unwrap();
-            let replica = replica::Replica::new(slave_params.master_sockaddr, port).unwrap();
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let replica = replica.clone();
-                        thread::spawn(move || replica.handle_connection(stream));
-                    }
-                    Err(e) => {
-                        println!("error: {}", e);
-                    }
-                }
+            let replica = replica::Replica::new(slave_params, port).unwrap();
+            let handler = move |conn| {
+                let _ = replica.handle_connection(conn);
+            };
+
+            if let Some(path) = cli.unixsocket {
+                serve_unix(path, handler.clone());
             }
+            serve(listener.incoming(), handler);
         }
     }
 }
@@ -1,24 +1,34 @@
-use crate::connection::Connection;
-use crate::data::{self, Data};
+use crate::command::{self, entries_to_array};
+use crate::config::Config;
+use crate::connection::{Connection, ConnectionError};
+use crate::data::{self, Data, Protocol};
+use crate::latency::{LatencyMonitor, Sample};
+use crate::log::{debug, info, trace, warn};
 use crate::mode::MasterParams;
+use crate::pubsub::{PubSub, Subscriber};
 use crate::rdb::Rdb;
-use crate::store::Store;
+use crate::store::{ActiveExpiry, EncodingLimits, ListpackLimits, Store, StoreDb};
 use crate::stream::{Entry, EntryId};
-use crate::value::Value;
-use anyhow::anyhow;
+use crate::watch::{DbKey, Watches};
 use anyhow::Result;
+use anyhow::{anyhow, bail};
 use base64::Engine;
-use crossbeam_channel::select;
-use std::collections::HashMap;
+use crossbeam_channel::{Select, SelectedOperation};
+use std::collections::{HashMap, HashSet};
 use std::ops::Bound::{Excluded, Included};
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::{
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+const NOAUTH_ERR_MSG: &str = "NOAUTH Authentication required.";
+
 struct ReplicaHandle {
     id: usize,
     conn: Connection,
@@ -27,89 +37,679 @@ struct ReplicaHandle {
 pub struct MasterInner {
     replication_id: String,
     replication_offset: usize,
-    store: Store,
+    dbs: StoreDb,
+    // The database that replicas apply commands to, changed by sending them
+    // SELECT
+    replication_db: usize,
     replicas: Vec<Arc<ReplicaHandle>>,
+    pubsub: PubSub,
+    watches: Watches,
+    next_connection_id: usize,
+    clients: HashMap<usize, ClientInfo>,
+}
+
+/// The state of a transaction started with MULTI
+#[derive(Default)]
+enum TransactionState {
+    #[default]
+    Idle,
+    /// Commands to run on EXEC
+    Queued(Vec<Data>),
+    /// A command failed to queue, so EXEC will fail
+    Error,
+}
+
+/// State that belongs to one client connection
+struct ConnectionState {
+    id: usize,
+    /// The selected database
+    db: usize,
+    transaction: TransactionState,
+    /// Keys watched with WATCH
+    watched: HashSet<DbKey>,
+    /// Whether the connection may run commands. Connections start
+    /// authenticated if no password is required.
+    authenticated: bool,
+    /// Set by CLIENT KILL, shared with the connection's `ClientInfo`
+    killed: Arc<AtomicBool>,
+}
+
+/// A connection as shown by CLIENT LIST. Unlike `ConnectionState`, it's
+/// shared, so that connections can see and kill each other.
+struct ClientInfo {
+    /// A clone of the connection, to close it with CLIENT KILL
+    conn: Connection,
+    killed: Arc<AtomicBool>,
+    addr: String,
+    /// Set with CLIENT SETNAME
+    name: Option<String>,
+    /// The selected database
+    db: usize,
+    created: Instant,
+}
+
+impl ConnectionState {
+    fn new(id: usize, authenticated: bool) -> Self {
+        Self {
+            id,
+            db: 0,
+            transaction: TransactionState::Idle,
+            watched: HashSet::new(),
+            authenticated,
+            killed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn unwatch(&mut self, watches: &mut Watches) {
+        watches.unwatch(self.id, self.watched.iter());
+        self.watched.clear();
+    }
+
+    fn in_transaction(&self) -> bool {
+        !matches!(self.transaction, TransactionState::Idle)
+    }
+
+    /// Puts the connection back as it was when it connected, for RESET. It
+    /// stays authenticated only if no password is required.
+    fn reset(&mut self, inner: &mut MasterInner, conn: &Connection, authenticated: bool) {
+        self.unwatch(&mut inner.watches);
+        conn.set_protocol(Protocol::Resp2);
+        self.transaction = TransactionState::Idle;
+        self.db = 0;
+        self.authenticated = authenticated;
+        if let Some(client) = inner.clients.get_mut(&self.id) {
+            client.db = 0;
+            client.name = None;
+        }
+    }
 }
 
 pub struct Master {
-    dir: Option<PathBuf>,
-    dbfilename: Option<String>,
-    rdb: Rdb,
+    config: Arc<RwLock<Config>>,
     inner: Arc<Mutex<MasterInner>>,
+    active_expiry: ActiveExpiry,
+    // Has its own lock, so that recording latencies doesn't wait for
+    // commands holding the lock of `inner`
+    latency: Mutex<LatencyMonitor>,
+}
+
+/// What to do with a connection after one of its commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HandleResult {
+    Continue,
+    /// The connection is from a replica, which completed its handshake
+    Replica,
+    /// QUIT: close the connection, the reply being written
+    Quit,
+}
+
+/// A command that leaves subscribe mode other than by unsubscribing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SubscribeExit {
+    Reset,
+    Quit,
+}
+
+// Handles a command from a connection in subscribe mode. Replies are sent
+// through the subscriber rather than written to the connection directly.
+// Returns Some for RESET and QUIT, which leave subscribe mode.
+fn handle_subscribed_data(
+    inner: &Mutex<MasterInner>,
+    subscriber: &mut Subscriber,
+    data: Data,
+) -> Result<Option<SubscribeExit>> {
+    let Data::Array(vs) = data else {
+        bail!("ERR Protocol error: expected array");
+    };
+    let args = command::Args::new(&vs);
+
+    match args.name()?.as_str() {
+        "subscribe" => {
+            args.check_arity(-2)?;
+            let channels = args.strings(1)?;
+            subscriber.subscribe(&mut inner.lock().unwrap().pubsub, channels);
+        }
+        "unsubscribe" => {
+            let channels = args.strings(1)?;
+            subscriber.unsubscribe(&mut inner.lock().unwrap().pubsub, channels);
+        }
+        "psubscribe" => {
+            args.check_arity(-2)?;
+            let patterns = args.strings(1)?;
+            subscriber.psubscribe(&mut inner.lock().unwrap().pubsub, patterns);
+        }
+        "punsubscribe" => {
+            let patterns = args.strings(1)?;
+            subscriber.punsubscribe(&mut inner.lock().unwrap().pubsub, patterns);
+        }
+        "ping" => {
+            let message = if args.len() > 1 {
+                args.string(1)?
+            } else {
+                String::new()
+            };
+            subscriber.send(Data::Array(vec![
+                Data::BulkString("pong".into()),
+                Data::BulkString(message.into()),
+            ]));
+        }
+        "reset" => {
+            // Unlike UNSUBSCRIBE, there's no message for each channel
+            subscriber.unsubscribe_all(&mut inner.lock().unwrap().pubsub);
+            subscriber.send(Data::SimpleString("RESET".into()));
+            return Ok(Some(SubscribeExit::Reset));
+        }
+        "quit" => {
+            subscriber.unsubscribe_all(&mut inner.lock().unwrap().pubsub);
+            subscriber.send(Data::SimpleString("OK".into()));
+            return Ok(Some(SubscribeExit::Quit));
+        }
+        command => bail!(
+            "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            command
+        ),
+    }
+
+    Ok(None)
+}
+
+struct XreadArgs {
+    // Set if blocking
+    timeout: Option<Duration>,
+    // The most entries to read from each stream
+    count: Option<usize>,
+    // Each stream with the id to read after
+    streams_and_start: Vec<(String, String)>,
+}
+
+// Parses `xread [count <count>] [block <timeout>] streams <stream1> <stream2>
+// <entryid1> <entryid2>`, where the options may come in any order
+fn parse_xread(vs: &[Data]) -> Result<XreadArgs> {
+    let args = command::Args::new(vs);
+
+    let (mut timeout, mut count) = (None, None);
+    let mut idx = 1;
+    let stream_start_idx = loop {
+        if idx >= args.len() {
+            bail!(command::SYNTAX_ERR_MSG);
+        }
+        match args.string(idx)?.to_ascii_lowercase().as_str() {
+            "streams" => break idx + 1,
+            "block" if idx + 1 < args.len() => {
+                let mill = match args.integer::<u64>(idx + 1)? {
+                    0 => u64::MAX,
+                    mill => mill,
+                };
+                timeout = Some(Duration::from_millis(mill));
+            }
+            "count" if idx + 1 < args.len() => {
+                // Like Redis, a count that isn't positive means no limit
+                count = Some(args.integer::<i64>(idx + 1)?)
+                    .filter(|&count| count > 0)
+                    .map(|count| count as usize);
+            }
+            _ => bail!(command::SYNTAX_ERR_MSG),
+        }
+        idx += 2;
+    };
+    if vs.len() < stream_start_idx + 2 || !(vs.len() - stream_start_idx).is_multiple_of(2) {
+        bail!("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.");
+    }
+    let num_streams = (vs.len() - stream_start_idx) / 2;
+
+    (stream_start_idx..stream_start_idx + num_streams)
+        .map(|i| Ok((args.string(i)?, args.string(i + num_streams)?)))
+        .collect::<Result<Vec<_>>>()
+        .map(|streams_and_start| XreadArgs {
+            timeout,
+            count,
+            streams_and_start,
+        })
+}
+
+/// How often a client blocked in XREAD, XREADGROUP or BZPOPMIN is checked for
+/// having disconnected
+const DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Waits for one of the operations of `select` until `deadline`, or forever if
+// None. Returns None once the deadline passes, or early if `conn` was closed,
+// so that a client that went away doesn't stay subscribed.
+fn select_while_connected<'a>(
+    select: &mut Select<'a>,
+    deadline: Option<Instant>,
+    conn: &Connection,
+) -> Option<SelectedOperation<'a>> {
+    loop {
+        let check = Instant::now() + DISCONNECT_CHECK_INTERVAL;
+        let until = deadline.map_or(check, |deadline| deadline.min(check));
+        if let Ok(operation) = select.select_deadline(until) {
+            return Some(operation);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) || conn.is_closed() {
+            return None;
+        }
+    }
+}
+
+type StreamEntries = Vec<(String, Vec<(EntryId, Vec<Entry>)>)>;
+
+// Reads the entries after the given id of each stream, at most `count` of
+// them, leaving out streams without any. `$` is the stream's id in
+// `max_entry_ids`, and the stream is left out if that's `None`.
+fn read_streams(
+    store: &Store,
+    streams_and_start: &[(String, String)],
+    count: Option<usize>,
+    max_entry_ids: Option<&HashMap<String, EntryId>>,
+) -> Result<StreamEntries> {
+    let mut stream_and_entries = Vec::new();
+    for (stream, start) in streams_and_start {
+        let start = match (start.as_str(), max_entry_ids) {
+            ("$", None) => continue,
+            ("$", Some(ids)) => ids[stream].clone(),
+            _ => EntryId::create_start(start.clone())?,
+        };
+
+        let entries = store.get_stream_range(
+            stream.clone(),
+            Excluded(start),
+            Included(EntryId::max()),
+            count,
+        )?;
+        if !entries.is_empty() {
+            stream_and_entries.push((stream.clone(), entries));
+        }
+    }
+
+    Ok(stream_and_entries)
 }
 
-fn entries_to_array(entries: Vec<(EntryId, Vec<Entry>)>) -> Data {
-    let data = entries
+fn streams_to_reply(stream_and_entries: StreamEntries) -> Data {
+    if stream_and_entries.is_empty() {
+        return Data::NullBulkString;
+    }
+
+    let as_arrays = stream_and_entries
         .into_iter()
-        .map(|(entryid, entries)| {
-            Data::Array(vec![
-                Data::BulkString(entryid.to_string().into()),
-                Data::Array(
-                    entries
-                        .into_iter()
-                        .flat_map(|entry| {
-                            vec![
-                                Data::BulkString(entry.key.into()),
-                                Data::BulkString(entry.value.into()),
-                            ]
-                        })
-                        .collect(),
-                ),
-            ])
+        .map(|(stream, entries)| {
+            let stream = Data::BulkString(stream.into());
+            let entries = entries_to_array(entries);
+            Data::Array(vec![stream, entries])
         })
         .collect();
+    Data::Array(as_arrays)
+}
 
-    Data::Array(data)
+impl Drop for Master {
+    fn drop(&mut self) {
+        self.active_expiry.stop();
+    }
 }
 
-impl Master {
-    pub fn new(params: MasterParams) -> Result<Self> {
-        let path = match (params.dir.clone(), params.dbfilename.clone()) {
-            (None, _) | (_, None) => None,
-            (Some(mut dir), Some(dbfilename)) => {
-                dir.push(dbfilename);
-                Some(dir)
-            }
-        };
-        let rdb = Rdb::read(path)?;
-        println!("Rdb: {:?}", rdb.store.data());
+impl MasterInner {
+    // Send a write command to all replicas, selecting database `db` first if
+    // needed
+    fn replicate(&mut self, db: usize, cmd: Data) -> Result<()> {
+        if db != self.replication_db {
+            self.replication_db = db;
+            let select = Data::Array(vec![
+                Data::BulkString("SELECT".into()),
+                Data::BulkString(db.to_string().into()),
+            ]);
+            self.replicate(db, select)?;
+        }
+
+        for replica in self.replicas.iter() {
+            replica.conn.write_data(cmd.clone())?;
+        }
+
+        self.replication_offset += cmd.num_bytes();
+        debug!("Replication offset: {}", self.replication_offset);
+        Ok(())
+    }
+
+    // Replicates the writes of a command executed on the store, and marks the
+    // keys it modified for WATCH. Returns the reply.
+    fn apply(&mut self, db: usize, outcome: command::Outcome) -> Result<Data> {
+        for cmd in outcome.replicate {
+            self.replicate(db, cmd)?;
+        }
+        for key in outcome.modified.iter() {
+            self.watches.touch(db, key);
+        }
+        Ok(outcome.reply)
+    }
+}
+
+// Kills the connections that `filter` matches, returning how many. Connection
+// `id` is the one killing them.
+fn kill_clients(
+    inner: &MasterInner,
+    id: usize,
+    filter: impl Fn(usize, &ClientInfo) -> bool,
+) -> usize {
+    let mut killed = 0;
+    for (&client_id, client) in inner.clients.iter() {
+        if !filter(client_id, client) {
+            continue;
+        }
+        // The connection stops before its next command. Others may be
+        // blocked reading, so their stream is closed too, which fails if
+        // they're already disconnecting. The killing connection still gets
+        // its reply.
+        client.killed.store(true, Ordering::Relaxed);
+        if client_id != id {
+            let _ = client.conn.close();
+        }
+        killed += 1;
+    }
+    killed
+}
 
-        let store = Store::new();
-        for (k, v) in rdb.store.data().iter() {
-            store.set(k.clone(), v.clone(), None);
+// Names connection `id`, as with CLIENT SETNAME. An empty name removes it.
+fn set_client_name(inner: &mut MasterInner, id: usize, name: String) -> Result<()> {
+    if name.chars().any(|c| !c.is_ascii_graphic()) {
+        bail!("ERR Client names cannot contain spaces, newlines or special characters.");
+    }
+    if let Some(client) = inner.clients.get_mut(&id) {
+        client.name = (!name.is_empty()).then_some(name);
+    }
+    Ok(())
+}
+
+// CLIENT ID | GETNAME | SETNAME <name> | LIST | KILL <addr> |
+// KILL [ID <id>] [ADDR <addr>] [SKIPME yes|no], for connection `id`
+fn handle_client(inner: &mut MasterInner, id: usize, vs: &[Data]) -> Result<Data> {
+    let args = command::Args::new(vs);
+    let subcommand = args.string(1)?.to_ascii_lowercase();
+    let reply = match subcommand.as_str() {
+        "id" if args.len() == 2 => Data::Integer(id as i64),
+        "getname" if args.len() == 2 => match inner.clients.get(&id).and_then(|c| c.name.clone()) {
+            Some(name) => Data::BulkString(name.into()),
+            None => Data::NullBulkString,
+        },
+        "setname" if args.len() == 3 => {
+            set_client_name(inner, id, args.string(2)?)?;
+            Data::SimpleString("OK".into())
+        }
+        "list" if args.len() == 2 => {
+            let mut ids: Vec<_> = inner.clients.keys().collect();
+            ids.sort();
+            let list: String = ids
+                .into_iter()
+                .map(|id| {
+                    let client = &inner.clients[id];
+                    format!(
+                        "id={} addr={} name={} age={} db={}\n",
+                        id,
+                        client.addr,
+                        client.name.as_deref().unwrap_or_default(),
+                        client.created.elapsed().as_secs(),
+                        client.db
+                    )
+                })
+                .collect();
+            Data::BulkString(list.into())
+        }
+        "kill" if args.len() == 3 => {
+            // The old form, CLIENT KILL <addr>
+            let addr = args.string(2)?;
+            if kill_clients(inner, id, |_, client| client.addr == addr) == 0 {
+                bail!("ERR No such client");
+            }
+            Data::SimpleString("OK".into())
+        }
+        "kill" if args.len().is_multiple_of(2) => {
+            // CLIENT KILL <filter> <value> [<filter> <value> ...], killing
+            // the connections that match all filters
+            let (mut target, mut addr, mut skip_me) = (None, None, true);
+            for i in (2..args.len()).step_by(2) {
+                match args.string(i)?.to_ascii_lowercase().as_str() {
+                    "id" => match args.string(i + 1)?.parse::<usize>() {
+                        Ok(target_id) if target_id > 0 => target = Some(target_id),
+                        _ => bail!("ERR client-id should be greater than 0"),
+                    },
+                    "addr" => addr = Some(args.string(i + 1)?),
+                    "skipme" => match args.string(i + 1)?.to_ascii_lowercase().as_str() {
+                        "yes" => skip_me = true,
+                        "no" => skip_me = false,
+                        _ => bail!(command::SYNTAX_ERR_MSG),
+                    },
+                    _ => bail!(command::SYNTAX_ERR_MSG),
+                }
+            }
+            let killed = kill_clients(inner, id, |client_id, client| {
+                target.is_none_or(|target| target == client_id)
+                    && addr.as_ref().is_none_or(|addr| *addr == client.addr)
+                    && !(skip_me && client_id == id)
+            });
+            Data::Integer(killed as i64)
         }
+        _ => bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try CLIENT HELP.",
+            subcommand
+        ),
+    };
+    Ok(reply)
+}
+
+// Passes on the configs that the databases use
+fn configure_dbs(dbs: &StoreDb, config: &Config) {
+    dbs.set_max_string_len(config.proto_max_bulk_len as usize);
+    let limits = |(max_entries, max_value): (u64, u64)| ListpackLimits {
+        max_entries: max_entries as usize,
+        max_value: max_value as usize,
+    };
+    dbs.set_encoding_limits(EncodingLimits {
+        hash: limits(config.hash_max_listpack),
+        set: limits(config.set_max_listpack),
+        zset: limits(config.zset_max_listpack),
+    });
+}
+
+// Loads the keys of the RDB file at `path`, if there's one, into database 0.
+// Returns how many keys were loaded.
+fn load_rdb(dbs: &StoreDb, path: Option<PathBuf>) -> Result<usize> {
+    let data = Rdb::read(path)?.store.data();
+    for (k, v) in data.iter() {
+        dbs.get(0).set(k.clone(), v.clone(), None);
+    }
+    Ok(data.len())
+}
+
+/// A subcommand of DEBUG
+struct DebugSubcommand {
+    name: &'static str,
+    /// Number of arguments, including DEBUG and the subcommand
+    arity: usize,
+    /// Lines describing the subcommand in DEBUG HELP
+    help: &'static [&'static str],
+    run: fn(&Master, &command::Args) -> Result<Data>,
+}
+
+const DEBUG_SUBCOMMANDS: &[DebugSubcommand] = &[
+    DebugSubcommand {
+        name: "sleep",
+        arity: 3,
+        help: &[
+            "SLEEP <seconds>",
+            "    Stop the server for <seconds>. Decimals allowed.",
+        ],
+        // Only the connection sleeps, since the lock isn't held
+        run: |_, args| {
+            let duration = Duration::try_from_secs_f64(args.float(2)?)
+                .map_err(|_| anyhow!("ERR invalid sleep time"))?;
+            thread::sleep(duration);
+            Ok(Data::SimpleString("OK".into()))
+        },
+    },
+    DebugSubcommand {
+        name: "set-active-expire",
+        arity: 3,
+        help: &[
+            "SET-ACTIVE-EXPIRE <0|1>",
+            "    Setting it to 0 disables expiring keys in background when they are not",
+            "    accessed (otherwise the Redis behavior). Setting it to 1 reenables back the",
+            "    default.",
+        ],
+        run: |master, args| {
+            master
+                .active_expiry
+                .set_enabled(args.integer::<i64>(2)? != 0);
+            Ok(Data::SimpleString("OK".into()))
+        },
+    },
+    DebugSubcommand {
+        name: "jmap",
+        arity: 2,
+        help: &[
+            "JMAP",
+            "    Does nothing. There's no allocator to report about.",
+        ],
+        run: |_, _| Ok(Data::SimpleString("OK".into())),
+    },
+    DebugSubcommand {
+        name: "reload",
+        arity: 2,
+        help: &[
+            "RELOAD",
+            "    Flush the databases and load the RDB file again. Keys aren't saved first,",
+            "    since there's no way to write RDB files yet.",
+        ],
+        // Like Redis, this isn't replicated
+        run: |master, _| {
+            let path = master.config.read().unwrap().rdb_path();
+            let mut inner = master.inner.lock().unwrap();
+            let inner = &mut *inner;
+            for (idx, store) in inner.dbs.iter().enumerate() {
+                for key in store.flush(false) {
+                    inner.watches.touch(idx, &key);
+                }
+            }
+            let num_keys = load_rdb(&inner.dbs, path)?;
+            for key in inner.dbs.get(0).keys() {
+                inner.watches.touch(0, &key);
+            }
+            info!("Reloaded {} keys from the RDB file", num_keys);
+            Ok(Data::SimpleString("OK".into()))
+        },
+    },
+    DebugSubcommand {
+        name: "help",
+        arity: 2,
+        help: &["HELP", "    Print this help."],
+        run: |_, _| {
+            let mut lines = vec!["DEBUG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:"];
+            for subcommand in DEBUG_SUBCOMMANDS {
+                lines.extend(subcommand.help);
+            }
+            Ok(Data::Array(
+                lines
+                    .into_iter()
+                    .map(|line| Data::SimpleString(line.into()))
+                    .collect(),
+            ))
+        },
+    },
+];
+
+fn handle_debug(master: &Master, args: &command::Args) -> Result<Data> {
+    args.check_arity(-2)?;
+    let name = args.string(1)?.to_ascii_lowercase();
+    match DEBUG_SUBCOMMANDS
+        .iter()
+        .find(|subcommand| subcommand.name == name && subcommand.arity == args.len())
+    {
+        Some(subcommand) => (subcommand.run)(master, args),
+        None => bail!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try DEBUG HELP.",
+            name
+        ),
+    }
+}
+
+impl Master {
+    pub fn new(params: MasterParams) -> Result<Self> {
+        let dbs = StoreDb::new();
+        configure_dbs(&dbs, &params.config);
+        let num_keys = load_rdb(&dbs, params.config.rdb_path())?;
+        info!("Loaded {} keys from the RDB file", num_keys);
+        let active_expiry = dbs.start_active_expiry();
 
         let inner = MasterInner {
             replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".into(),
             replication_offset: 0,
-            store,
+            dbs,
+            replication_db: 0,
             replicas: Vec::new(),
+            pubsub: PubSub::new(),
+            watches: Watches::new(),
+            next_connection_id: 0,
+            clients: HashMap::new(),
         };
 
         let master = Self {
-            dir: params.dir,
-            dbfilename: params.dbfilename,
-            rdb,
+            config: Arc::new(RwLock::new(params.config)),
             inner: Arc::new(Mutex::new(inner)),
+            active_expiry,
+            latency: Mutex::new(LatencyMonitor::new()),
         };
 
         Ok(master)
     }
 
-    pub fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        let mut conn = Connection::new(stream);
+    pub fn handle_connection(&self, mut conn: Connection) -> Result<()> {
+        let mut state = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_connection_id += 1;
+            let authenticated = self.config.read().unwrap().requirepass.is_none();
+            let state = ConnectionState::new(inner.next_connection_id, authenticated);
+            inner.clients.insert(
+                state.id,
+                ClientInfo {
+                    conn: conn.clone(),
+                    killed: state.killed.clone(),
+                    addr: conn.peer_addr(),
+                    name: None,
+                    db: 0,
+                    created: Instant::now(),
+                },
+            );
+            state
+        };
+        info!("Accepted connection {}", state.id);
+        conn.set_timeout(self.client_timeout())?;
 
-        loop {
+        let result = loop {
+            // Checked before each command, so that commands already read
+            // into the buffer aren't run once killed
+            if state.killed.load(Ordering::Relaxed) {
+                debug!("Closing connection {}: killed", state.id);
+                break Ok(());
+            }
             let result = conn.read_data();
 
             match result {
+                Err(error) if matches!(error.downcast_ref(), Some(ConnectionError::Timeout)) => {
+                    info!("Closing connection {}: idle for too long", state.id);
+                    break Ok(());
+                }
                 Err(error) => {
-                    println!("Error: {:?}, will close connection", error);
-                    break;
+                    debug!("Closing connection {}: {}", state.id, error);
+                    break Ok(());
                 }
-                Ok(data) => {
-                    let is_replica = self.handle_data(&mut conn, data)?;
-                    if is_replica {
+                Ok(data) => match self.handle_data_timed(&mut conn, &mut state, data) {
+                    Err(err) => break Err(err),
+                    Ok(HandleResult::Continue) => {}
+                    Ok(HandleResult::Quit) => {
+                        debug!("Closing connection {}: quit", state.id);
+                        // Shut down rather than dropped, since `ClientInfo`
+                        // holds a clone
+                        break conn.close();
+                    }
+                    Ok(HandleResult::Replica) => {
+                        // Like Redis, replicas are never timed out
+                        conn.set_timeout(None)?;
                         let mut inner = self.inner.lock().unwrap();
 
                         let handle = ReplicaHandle {
@@ -119,337 +719,864 @@ impl Master {
                         let handle = Arc::new(handle);
 
                         inner.replicas.push(handle.clone());
-                        break;
+                        break Ok(());
                     }
-                }
+                },
             }
-        }
+        };
 
-        Ok(())
+        let mut inner = self.inner.lock().unwrap();
+        state.unwatch(&mut inner.watches);
+        inner.clients.remove(&state.id);
+        result
     }
 
-    // Return true if this connection is from a replica (b/c we just completed a handshake)
-    fn handle_data(&self, conn: &mut Connection, data: Data) -> Result<bool> {
-        println!("Recv: {}", data);
-        let num_bytes = data.num_bytes();
-        match data {
-            Data::Array(vs) => {
-                let string_at = |idx: usize| -> Result<String> {
-                    vs[idx].get_string().ok_or(anyhow!("fail to get string"))
-                };
+    // Like `handle_data`, recording the time it takes as a sample of the
+    // command for LATENCY
+    fn handle_data_timed(
+        &self,
+        conn: &mut Connection,
+        state: &mut ConnectionState,
+        data: Data,
+    ) -> Result<HandleResult> {
+        let name = match &data {
+            Data::Array(vs) => command::Args::new(vs).name().ok(),
+            _ => None,
+        };
+        let start = Instant::now();
+        let result = self.handle_data(conn, state, data);
+        if let Some(name) = name {
+            self.latency.lock().unwrap().record(&name, start.elapsed());
+        }
+        result
+    }
 
-                match string_at(0)?.to_ascii_lowercase().as_str() {
-                    "ping" => conn.write_data(Data::SimpleString("PONG".into()))?,
-                    "echo" => {
-                        assert_eq!(vs.len(), 2);
-                        let string = string_at(1)?;
-                        conn.write_data(Data::BulkString(string.into()))?
-                    }
-                    "keys" => {
-                        assert_eq!(vs.len(), 2);
-                        assert_eq!(string_at(1)?, "*");
-
-                        let keys = self
-                            .rdb
-                            .store
-                            .data()
-                            .keys()
-                            .map(|k| Data::BulkString(k.as_str().into()))
-                            .collect();
-                        conn.write_data(Data::Array(keys))?
-                    }
+    fn handle_data(
+        &self,
+        conn: &mut Connection,
+        state: &mut ConnectionState,
+        data: Data,
+    ) -> Result<HandleResult> {
+        debug!("Received from connection {}: {}", state.id, data);
+        let vs = match data {
+            Data::Array(vs) => vs,
+            v => {
+                warn!("Ignoring data that isn't a command: {:?}", v);
+                return Ok(HandleResult::Continue);
+            }
+        };
+
+        let args = command::Args::new(&vs);
+        let name = args.name()?;
+        if !state.authenticated
+            && !matches!(name.as_str(), "auth" | "hello" | "quit" | "reset")
+            && self.config.read().unwrap().requirepass.is_some()
+        {
+            if state.in_transaction() {
+                state.transaction = TransactionState::Error;
+            }
+            conn.write_data(Data::SimpleError(NOAUTH_ERR_MSG.into()))?;
+            return Ok(HandleResult::Continue);
+        }
+        if let Err(err) = command::check(&args) {
+            if state.in_transaction() {
+                state.transaction = TransactionState::Error;
+            }
+            conn.write_data(Data::SimpleError(err.to_string()))?;
+            return Ok(HandleResult::Continue);
+        }
 
-                    "get" => {
-                        let inner = self.inner.lock().unwrap();
+        let string_at = |idx: usize| args.string(idx);
 
-                        assert_eq!(vs.len(), 2);
-                        let key = string_at(1)?;
-                        match inner.store.get(&key) {
-                            None => conn.write_data(Data::NullBulkString)?,
-                            Some(value) => {
-                                conn.write_data(Data::BulkString(value.to_string().into()))?
-                            }
-                        }
+        match name.as_str() {
+            "auth" => {
+                let reply = match self.auth(&args) {
+                    Ok(()) => {
+                        state.authenticated = true;
+                        Data::SimpleString("OK".into())
                     }
-                    "type" => {
-                        let inner = self.inner.lock().unwrap();
-
-                        assert_eq!(vs.len(), 2);
-                        let key = string_at(1)?;
-                        let t = inner.store.get_type(key);
-                        conn.write_data(Data::SimpleString(t.into()))?
+                    Err(err) => Data::SimpleError(err.to_string()),
+                };
+                conn.write_data(reply)?
+            }
+            "hello" => {
+                let reply = self
+                    .hello(conn, state, &vs)
+                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+            "multi" => {
+                if state.in_transaction() {
+                    conn.write_data(Data::SimpleError(
+                        "ERR MULTI calls can not be nested".into(),
+                    ))?
+                } else {
+                    state.transaction = TransactionState::Queued(Vec::new());
+                    conn.write_data(Data::SimpleString("OK".into()))?
+                }
+            }
+            "watch" => {
+                if state.in_transaction() {
+                    conn.write_data(Data::SimpleError(
+                        "ERR WATCH inside MULTI is not allowed".into(),
+                    ))?
+                } else {
+                    let mut inner = self.inner.lock().unwrap();
+                    for key in args.strings(1)? {
+                        let key = (state.db, key);
+                        inner.watches.watch(state.id, key.clone());
+                        state.watched.insert(key);
+                    }
+                    conn.write_data(Data::SimpleString("OK".into()))?
+                }
+            }
+            "unwatch" if !state.in_transaction() => {
+                state.unwatch(&mut self.inner.lock().unwrap().watches);
+                conn.write_data(Data::SimpleString("OK".into()))?
+            }
+            "exec" => {
+                let reply = match std::mem::take(&mut state.transaction) {
+                    TransactionState::Idle => Data::SimpleError("ERR EXEC without MULTI".into()),
+                    TransactionState::Error => {
+                        state.unwatch(&mut self.inner.lock().unwrap().watches);
+                        Data::SimpleError(
+                            "EXECABORT Transaction discarded because of previous errors.".into(),
+                        )
                     }
-                    "set" => {
+                    TransactionState::Queued(cmds) => {
+                        // Holding the lock for the whole transaction, so that
+                        // no other client sees or modifies the store halfway
                         let mut inner = self.inner.lock().unwrap();
+                        let dirty = inner.watches.is_dirty(state.id);
+                        state.unwatch(&mut inner.watches);
+                        if dirty {
+                            conn.write_data(Data::NullArray)?;
+                            return Ok(HandleResult::Continue);
+                        }
 
-                        assert!(vs.len() == 3 || vs.len() == 5);
-                        let key = string_at(1)?;
-                        let value = string_at(2)?;
-
-                        let expire_in = if vs.len() == 5 {
-                            let px = string_at(3)?;
-                            assert_eq!(px.to_ascii_lowercase(), "px");
-                            let expire_in: u64 = string_at(4)?.parse()?;
-                            Some(Duration::from_millis(expire_in))
-                        } else {
-                            None
-                        };
-
-                        inner.store.set(key, Value::String(value), expire_in);
-                        conn.write_data(Data::SimpleString("OK".into()))?;
-
-                        // Replications
-                        inner
-                            .replicas
-                            .iter_mut()
-                            .map(|replica| replica.conn.write_data(Data::Array(vs.clone())))
-                            .collect::<Result<Vec<()>>>()?;
-
-                        inner.replication_offset += num_bytes;
-                        println!("replication offset: +{}", inner.replication_offset);
-                    }
-                    "xadd" => {
-                        // xadd <stream> <entry-id> <e1 key> <e1 value>
-                        assert!(vs.len() >= 5);
-                        assert!(vs.len() % 2 == 1);
-
-                        let stream = string_at(1)?;
-                        let entry_id = string_at(2)?;
-
-                        let kvs = vs[3..]
-                            .chunks_exact(2)
-                            .map(|data| {
-                                let k = data[0].get_string().unwrap();
-                                let v = data[1].get_string().unwrap();
-                                (k, v)
+                        let replies = cmds
+                            .iter()
+                            .map(|cmd| {
+                                let Data::Array(vs) = cmd else {
+                                    unreachable!("only arrays are queued")
+                                };
+                                self.execute(&mut inner, state.id, &mut state.db, vs)
+                                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()))
                             })
                             .collect();
-
-                        let res = self.inner.lock().unwrap().store.stream_set(
-                            stream.clone(),
-                            entry_id.clone(),
-                            kvs,
-                        );
-
-                        match res {
-                            Ok(entry_id) => {
-                                conn.write_data(Data::BulkString(entry_id.to_string().into()))?
-                            }
-                            Err(err) => {
-                                conn.write_data(Data::SimpleError(err.to_string()))?;
-                                return Ok(false);
-                            }
-                        }
+                        Data::Array(replies)
+                    }
+                };
+                conn.write_data(reply)?
+            }
+            "discard" => {
+                if state.in_transaction() {
+                    state.transaction = TransactionState::Idle;
+                    state.unwatch(&mut self.inner.lock().unwrap().watches);
+                    conn.write_data(Data::SimpleString("OK".into()))?
+                } else {
+                    conn.write_data(Data::SimpleError("ERR DISCARD without MULTI".into()))?
+                }
+            }
+            // Also ends a transaction rather than being queued
+            "reset" => {
+                let authenticated = self.config.read().unwrap().requirepass.is_none();
+                state.reset(&mut self.inner.lock().unwrap(), conn, authenticated);
+                conn.write_data(Data::SimpleString("RESET".into()))?
+            }
+            "quit" => {
+                conn.write_data(Data::SimpleString("OK".into()))?;
+                return Ok(HandleResult::Quit);
+            }
+            "subscribe" | "psubscribe" | "psync" | "replconf" | "wait" | "debug"
+                if state.in_transaction() =>
+            {
+                state.transaction = TransactionState::Error;
+                conn.write_data(Data::SimpleError(
+                    "ERR Command not allowed inside a transaction".into(),
+                ))?
+            }
+            _ if state.in_transaction() => {
+                if let TransactionState::Queued(cmds) = &mut state.transaction {
+                    cmds.push(Data::Array(vs.clone()));
+                }
+                conn.write_data(Data::SimpleString("QUEUED".into()))?
+            }
+            "xread" => {
+                let reply = self
+                    .xread(conn, state.db, &vs)
+                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+            "xreadgroup" => {
+                let reply = self
+                    .xreadgroup(conn, state.db, &vs)
+                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+            "bzpopmin" | "bzpopmax" => {
+                let reply = self
+                    .bzpop(conn, state.db, &vs)
+                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+            "subscribe" | "psubscribe" => {
+                match self.subscribe_mode(conn, Data::Array(vs.clone()))? {
+                    Some(SubscribeExit::Reset) => {
+                        let authenticated = self.config.read().unwrap().requirepass.is_none();
+                        state.reset(&mut self.inner.lock().unwrap(), conn, authenticated);
                     }
-                    "xrange" => {
-                        // xrange <stream> <start> <end>
-                        assert_eq!(vs.len(), 4);
+                    Some(SubscribeExit::Quit) => return Ok(HandleResult::Quit),
+                    None => {}
+                }
+            }
+            "debug" => {
+                // Not under the lock, so that DEBUG SLEEP doesn't block other
+                // connections
+                let reply = handle_debug(self, &args)
+                    .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+            kind @ ("unsubscribe" | "punsubscribe") => {
+                // Not in subscribe mode, so there's nothing to unsubscribe from
+                let channels = if vs.len() < 2 {
+                    vec![Data::NullBulkString]
+                } else {
+                    (1..vs.len())
+                        .map(|idx| Ok(Data::BulkString(string_at(idx)?.into())))
+                        .collect::<Result<Vec<_>>>()?
+                };
+                for channel in channels {
+                    conn.write_data(Data::Array(vec![
+                        Data::BulkString(kind.into()),
+                        channel,
+                        Data::Integer(0),
+                    ]))?;
+                }
+            }
+            "replconf" => conn.write_data(Data::SimpleString("OK".into()))?,
+            "psync" => {
+                let slave_replication_id = string_at(1)?;
+                let slave_replication_offset: isize = string_at(2)?.parse()?;
 
-                        let stream = string_at(1)?;
+                if slave_replication_id == "?" {
+                    assert_eq!(slave_replication_offset, -1);
+                    conn.write_data(Data::SimpleString(
+                        format!("FULLRESYNC {} 0", self.inner.lock().unwrap().replication_id)
+                            .into(),
+                    ))?;
 
-                        let entries = self.inner.lock().unwrap().store.get_stream_range(
-                            stream,
-                            Included(EntryId::create_start(string_at(2)?)?),
-                            Included(EntryId::create_end(string_at(3)?)?),
-                        )?;
+                    // Send RDB file. Assume empty for this challenge
+                    // Format: $<length_of_file>\r\n<contents_of_file>
+                    // Like bulk string, but without trailing \r\n
+                    let empty_rdb_base64 = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
+                    let empty_rdb =
+                        base64::engine::general_purpose::STANDARD.decode(empty_rdb_base64)?;
+                    conn.write(data::encode_rdb_file(empty_rdb))?;
 
-                        conn.write_data(entries_to_array(entries))?
-                    }
-                    "xread" => {
-                        // xread [blocks <timeout>] streams <stream1> <entryid1> <stream2> <entryid2>
-                        assert_eq!(vs.len() % 2, 0);
-
-                        let (timeout, stream_start_idx) = if string_at(1)? == "block" {
-                            let mill = match string_at(2)?.parse::<u64>()? {
-                                0 => u64::MAX,
-                                mill => mill,
-                            };
+                    info!("Finished handshaking with a replica");
+                    return Ok(HandleResult::Replica);
+                } else {
+                    todo!()
+                }
+            }
+            "wait" => {
+                let num_replicas_to_wait = string_at(1)?.parse::<usize>()?;
+                let timeout = Duration::from_millis(string_at(2)?.parse()?);
+                self.handle_wait(conn, num_replicas_to_wait, timeout)?
+            }
+            _ => {
+                let reply = {
+                    let mut inner = self.inner.lock().unwrap();
+                    self.execute(&mut inner, state.id, &mut state.db, &vs)
+                }
+                .unwrap_or_else(|err| Data::SimpleError(err.to_string()));
+                conn.write_data(reply)?
+            }
+        }
 
-                            (Some(Duration::from_millis(mill)), 4)
-                        } else {
-                            (None, 2)
-                        };
-                        let num_streams = (vs.len() - stream_start_idx) / 2;
+        Ok(HandleResult::Continue)
+    }
 
-                        let mut streams_and_start = Vec::new();
-                        for i in stream_start_idx..stream_start_idx + num_streams {
-                            let stream = string_at(i)?;
-                            let start = string_at(i + num_streams)?;
-                            streams_and_start.push((stream, start));
-                        }
+    // Executes a command that doesn't need the connection on database `db`,
+    // for connection `id`, returning the reply. SELECT changes `db`. Writes
+    // are replicated. The caller holds the lock, so that EXEC can run a whole
+    // transaction under it.
+    fn execute(
+        &self,
+        inner: &mut MasterInner,
+        id: usize,
+        db: &mut usize,
+        vs: &[Data],
+    ) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let string_at = |idx: usize| args.string(idx);
+        let name = args.name()?;
+        self.free_memory(inner, &name)?;
 
-                        let mut curr_max_entry_ids = HashMap::new();
-                        {
-                            let inner = self.inner.lock().unwrap();
-                            streams_and_start.iter().for_each(|(stream, _)| {
-                                let curr_max = inner.store.get_stream_curr_max_id(stream.clone());
-                                curr_max_entry_ids.insert(stream.clone(), curr_max);
-                            });
-                        }
+        let reply = match name.as_str() {
+            // Inside a transaction BZPOPMIN and BZPOPMAX never block
+            "bzpopmin" | "bzpopmax" => match command::bzpop(inner.dbs.get(*db), &args)? {
+                Some(outcome) => inner.apply(*db, outcome)?,
+                None => Data::NullArray,
+            },
+            "xread" => {
+                // Inside a transaction XREAD never blocks, and `$` never has
+                // entries after it
+                let xread = parse_xread(vs)?;
+                let stream_and_entries = read_streams(
+                    inner.dbs.get(*db),
+                    &xread.streams_and_start,
+                    xread.count,
+                    None,
+                )?;
+                streams_to_reply(stream_and_entries)
+            }
+            "config" => self.handle_config(&inner.dbs, vs)?,
+            "latency" => self.handle_latency(vs)?,
+            "client" => handle_client(inner, id, vs)?,
+            "info" => match string_at(1)?.to_ascii_lowercase().as_str() {
+                "replication" => {
+                    let role = String::from("role:master");
+                    let replication_id = format!("master_replid:{}", inner.replication_id);
+                    let replication_offset =
+                        format!("master_repl_offset:{}", inner.replication_offset);
 
-                        let get_stream_and_entries = |convert_wildcard: bool| {
-                            let inner = self.inner.lock().unwrap();
-                            streams_and_start
-                                .iter()
-                                .filter_map(|(stream, start)| {
-                                    let start = if start == "$" {
-                                        if convert_wildcard {
-                                            curr_max_entry_ids.get(stream).unwrap().clone()
-                                        } else {
-                                            return None;
-                                        }
-                                    } else {
-                                        EntryId::create_start(start.clone()).unwrap()
-                                    };
+                    Data::BulkString([role, replication_id, replication_offset].join("\n").into())
+                }
+                "memory" => {
+                    let config = self.config.read().unwrap();
+                    let lines = [
+                        format!("used_memory:{}", inner.dbs.used_memory()),
+                        format!("maxmemory:{}", config.maxmemory),
+                        format!("maxmemory_policy:{}", config.maxmemory_policy),
+                    ];
+                    Data::BulkString(lines.join("\n").into())
+                }
+                info_type => bail!("ERR unknown info type: {}", info_type),
+            },
+            "publish" => {
+                let num_receivers = inner.pubsub.publish(&string_at(1)?, &string_at(2)?);
+                Data::Integer(num_receivers as i64)
+            }
+            "pubsub" => self.handle_pubsub(inner, vs)?,
+            // Only gets here inside a transaction, where EXEC unwatches anyway
+            "unwatch" => Data::SimpleString("OK".into()),
+            "select" => {
+                *db = command::select(&args)?;
+                if let Some(client) = inner.clients.get_mut(&id) {
+                    client.db = *db;
+                }
+                Data::SimpleString("OK".into())
+            }
+            "copy" => {
+                let copied = command::copy(&inner.dbs, *db, &args)?;
+                for key in copied.outcome.modified.iter() {
+                    inner.watches.touch(copied.destination_db, key);
+                }
+                for cmd in copied.outcome.replicate {
+                    inner.replicate(copied.replication_db, cmd)?;
+                }
+                copied.outcome.reply
+            }
+            "flushall" => {
+                // flushall [async|sync]
+                let lazy = command::flush_async(&args)?;
+                for (idx, store) in inner.dbs.iter().enumerate() {
+                    for key in store.flush(lazy) {
+                        inner.watches.touch(idx, &key);
+                    }
+                }
+                inner.replicate(*db, Data::Array(vs.to_vec()))?;
+                Data::SimpleString("OK".into())
+            }
+            _ => {
+                let outcome = command::execute(inner.dbs.get(*db), vs);
+                inner.apply(*db, outcome)?
+            }
+        };
 
-                                    let entries = inner
-                                        .store
-                                        .get_stream_range(
-                                            stream.clone(),
-                                            Excluded(start),
-                                            Included(EntryId::max()),
-                                        )
-                                        .unwrap();
-
-                                    if entries.is_empty() {
-                                        None
-                                    } else {
-                                        Some((stream.clone(), entries))
-                                    }
-                                })
-                                .collect::<Vec<_>>()
-                        };
+        Ok(reply)
+    }
 
-                        let mut stream_and_entries = get_stream_and_entries(false);
-                        println!("Streams and entries: {:?}", stream_and_entries);
+    // xread [block <timeout>] streams <stream1> <entryid1> <stream2> <entryid2>
+    fn xread(&self, conn: &Connection, db: usize, vs: &[Data]) -> Result<Data> {
+        let XreadArgs {
+            timeout,
+            count,
+            streams_and_start,
+        } = parse_xread(vs)?;
 
-                        if stream_and_entries.is_empty() && timeout.is_some() {
-                            // Blocks waiting
+        // Reading and subscribing happen under the lock that writes also
+        // take, so that no entry added in between is missed
+        let (curr_max_entry_ids, subscriptions, timeout) = {
+            let inner = self.inner.lock().unwrap();
+            let store = inner.dbs.get(db);
+            let mut curr_max_entry_ids = HashMap::new();
+            for (stream, _) in streams_and_start.iter() {
+                let curr_max = store.get_stream_curr_max_id(stream.clone())?;
+                curr_max_entry_ids.insert(stream.clone(), curr_max);
+            }
 
-                            // TODO: Handle more than one
-                            let (stream, entry_id) = streams_and_start[0].clone();
-                            let update_chan = {
-                                let mut inner = self.inner.lock().unwrap();
-                                let entry_id = if entry_id == "$" {
-                                    inner.store.get_stream_curr_max_id(stream.clone())
-                                } else {
-                                    EntryId::create_start(entry_id.clone()).unwrap()
-                                };
-                                inner
-                                    .store
-                                    .stream_subscribe(stream.clone(), entry_id.clone())
-                            };
+            let stream_and_entries = read_streams(store, &streams_and_start, count, None)?;
+            trace!("Streams and entries: {:?}", stream_and_entries);
+            let Some(timeout) = timeout.filter(|_| stream_and_entries.is_empty()) else {
+                return Ok(streams_to_reply(stream_and_entries));
+            };
 
-                            println!("Blocking for updates for {}, {}", stream, entry_id);
-                            select! {
-                                recv(update_chan) -> msg => match msg {
-                                    Err(err) =>  println!("Error receiving update: {}", err),
-                                    Ok(()) => {
-                                        println!("Received update, will query again...");
-                                        stream_and_entries = get_stream_and_entries(true);
-                                    }
-                                },
-                                default(timeout.unwrap()) => println!("Timeout!"),
-                            }
-                        }
+            let mut subscriptions = Vec::new();
+            for (stream, entry_id) in streams_and_start.iter() {
+                let entry_id = if entry_id == "$" {
+                    curr_max_entry_ids[stream].clone()
+                } else {
+                    EntryId::create_start(entry_id.clone())?
+                };
+                debug!("Blocking for entries of {} after {}", stream, entry_id);
+                let (subscriber, update_chan) = store.stream_subscribe(stream.clone(), entry_id)?;
+                subscriptions.push((stream.clone(), subscriber, update_chan));
+            }
+            (curr_max_entry_ids, subscriptions, timeout)
+        };
 
-                        if stream_and_entries.is_empty() {
-                            conn.write_data(Data::NullBulkString)?
-                        } else {
-                            let as_arrays = stream_and_entries
-                                .into_iter()
-                                .map(|(stream, entries)| {
-                                    let stream = Data::BulkString(stream.into());
-                                    let entries = entries_to_array(entries);
-                                    Data::Array(vec![stream, entries])
-                                })
-                                .collect::<Vec<_>>();
-                            conn.write_data(Data::Array(as_arrays))?
-                        }
-                    }
-                    "config" => {
-                        assert_eq!(vs.len(), 3);
-                        assert_eq!(vs[1].get_string().unwrap().to_ascii_lowercase(), "get");
-                        match string_at(2)?.to_ascii_lowercase().as_str() {
-                            "dir" => {
-                                let dir = self
-                                    .dir
-                                    .as_ref()
-                                    .map(|p| p.clone().into_os_string().into_string())
-                                    .unwrap()
-                                    .unwrap();
-                                conn.write_data(Data::Array(vec![
-                                    Data::BulkString("dir".into()),
-                                    Data::BulkString(dir.into()),
-                                ]))?
-                            }
-                            "dbfilename" => {
-                                let dbfilename = self.dbfilename.as_ref().unwrap().to_string();
-                                conn.write_data(Data::Array(vec![
-                                    Data::BulkString("dbfilename".into()),
-                                    Data::BulkString(dbfilename.into()),
-                                ]))?
-                            }
-                            _ => unreachable!(),
-                        };
-                    }
-                    "info" => match string_at(1)?.to_ascii_lowercase().as_str() {
-                        "replication" => {
-                            let inner = self.inner.lock().unwrap();
-                            let role = String::from("role:master");
-                            let replication_id = format!("master_replid:{}", inner.replication_id);
-                            let replication_offset =
-                                format!("master_repl_offset:{}", inner.replication_offset);
-
-                            conn.write_data(Data::BulkString(
-                                [role, replication_id, replication_offset].join("\n").into(),
-                            ))?
-                        }
-                        info_type => panic!("unknown info type: {}", info_type),
-                    },
-                    "replconf" => conn.write_data(Data::SimpleString("OK".into()))?,
-                    "psync" => {
-                        let slave_replication_id = string_at(1)?;
-                        let slave_replication_offset: isize = string_at(2)?.parse()?;
-
-                        if slave_replication_id == "?" {
-                            assert_eq!(slave_replication_offset, -1);
-                            conn.write_data(Data::SimpleString(
-                                format!(
-                                    "FULLRESYNC {} 0",
-                                    self.inner.lock().unwrap().replication_id
-                                )
-                                .into(),
-                            ))?;
-
-                            // Send RDB file. Assume empty for this challenge
-                            // Format: $<length_of_file>\r\n<contents_of_file>
-                            // Like bulk string, but without trailing \r\n
-                            let empty_rdb_base64 = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
-                            let empty_rdb = base64::engine::general_purpose::STANDARD
-                                .decode(empty_rdb_base64)?;
-                            conn.write(data::encode_rdb_file(empty_rdb))?;
-
-                            println!("Finished handshaking with replica");
-                            return Ok(true);
-                        } else {
-                            todo!()
-                        }
+        // Waits for any of the streams, for `timeout` in all
+        let mut select = Select::new();
+        for (_, _, update_chan) in subscriptions.iter() {
+            select.recv(update_chan);
+        }
+        let deadline = Instant::now().checked_add(timeout);
+        let notified = match select_while_connected(&mut select, deadline, conn) {
+            None => {
+                debug!("Timed out waiting for entries, or disconnected");
+                false
+            }
+            Some(operation) => {
+                let (stream, _, update_chan) = &subscriptions[operation.index()];
+                match operation.recv(update_chan) {
+                    Err(err) => {
+                        warn!("Error receiving a stream update: {}", err);
+                        false
                     }
-                    "wait" => {
-                        assert_eq!(vs.len(), 3);
-                        let num_replicas_to_wait = string_at(1)?.parse::<usize>()?;
-                        let timeout = Duration::from_millis(string_at(2)?.parse()?);
-                        self.handle_wait(conn, num_replicas_to_wait, timeout)?
+                    Ok(()) => {
+                        debug!("Notified of new entries in {}", stream);
+                        true
                     }
-                    command => panic!("unknown command: {}", command),
                 }
             }
-            v => println!("Unkonwn: {:?}", v),
         };
 
-        Ok(false)
-    }
+        let inner = self.inner.lock().unwrap();
+        let store = inner.dbs.get(db);
+        // The streams that weren't written to would still notify
+        for (stream, subscriber, _) in subscriptions.iter() {
+            store.stream_unsubscribe(stream, subscriber);
+        }
+        if !notified {
+            return Ok(streams_to_reply(Vec::new()));
+        }
+        let stream_and_entries =
+            read_streams(store, &streams_and_start, count, Some(&curr_max_entry_ids))?;
+        Ok(streams_to_reply(stream_and_entries))
+    }
+
+    // XREADGROUP: with BLOCK and only `>` ids, blocks until a stream has new
+    // entries or the timeout expires. Like XREAD, reading and subscribing
+    // happen under the lock that writes also take.
+    fn xreadgroup(&self, conn: &Connection, db: usize, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let xreadgroup = command::parse_xreadgroup(&args)?;
+        let deadline = match xreadgroup.block {
+            Some(Some(timeout)) => Instant::now().checked_add(timeout),
+            _ => None,
+        };
+
+        loop {
+            let subscriptions = {
+                let mut inner = self.inner.lock().unwrap();
+                let outcome = command::xreadgroup(inner.dbs.get(db), &args)?;
+                if outcome.reply != Data::NullArray || !xreadgroup.may_block() {
+                    return inner.apply(db, outcome);
+                }
+                inner.apply(db, outcome)?;
+
+                let store = inner.dbs.get(db);
+                let mut subscriptions = Vec::new();
+                for stream in xreadgroup.streams.iter() {
+                    let entry_id = store.get_stream_curr_max_id(stream.clone())?;
+                    let (subscriber, update_chan) =
+                        store.stream_subscribe(stream.clone(), entry_id)?;
+                    subscriptions.push((stream, subscriber, update_chan));
+                }
+                subscriptions
+            };
+
+            let mut select = Select::new();
+            for (_, _, update_chan) in subscriptions.iter() {
+                select.recv(update_chan);
+            }
+            let notified =
+                select_while_connected(&mut select, deadline, conn).is_some_and(|operation| {
+                    let (_, _, update_chan) = &subscriptions[operation.index()];
+                    operation.recv(update_chan).is_ok()
+                });
+
+            let inner = self.inner.lock().unwrap();
+            for (stream, subscriber, _) in subscriptions.iter() {
+                inner.dbs.get(db).stream_unsubscribe(stream, subscriber);
+            }
+            if !notified {
+                return Ok(Data::NullArray);
+            }
+            // Another consumer may have read the new entries first, and then
+            // this one waits again
+        }
+    }
+
+    // BZPOPMIN and BZPOPMAX: pops from the first of the keys that has
+    // members, blocking until one has or the timeout expires. Checking the
+    // keys and starting to wait happen under the lock that writes also take,
+    // so that no write is missed. Nothing is popped for a client that
+    // disconnected while waiting.
+    fn bzpop(&self, conn: &Connection, db: usize, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let (keys, timeout) = command::parse_bzpop(&args)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let (token, ready) = {
+                let mut inner = self.inner.lock().unwrap();
+                match command::bzpop(inner.dbs.get(db), &args)? {
+                    Some(outcome) => return inner.apply(db, outcome),
+                    None => inner.dbs.get(db).wait_for_keys(&keys),
+                }
+            };
+
+            let mut select = Select::new();
+            select.recv(&ready);
+            let woken = select_while_connected(&mut select, deadline, conn)
+                .is_some_and(|operation| operation.recv(&ready).is_ok());
+            // The keys that weren't written to would still notify
+            self.inner.lock().unwrap().dbs.get(db).unwait(&keys, token);
+            if !woken {
+                return Ok(Data::NullArray);
+            }
+        }
+    }
+
+    // Evicts keys if the memory used is over `maxmemory`. Evictions are
+    // replicated as DEL. Fails for commands that may use more memory if not
+    // enough could be freed.
+    fn free_memory(&self, inner: &mut MasterInner, name: &str) -> Result<()> {
+        let (maxmemory, policy) = {
+            let config = self.config.read().unwrap();
+            (config.maxmemory, config.maxmemory_policy.clone())
+        };
+        if maxmemory == 0 {
+            return Ok(());
+        }
+
+        let (evicted, freed) = {
+            let mut rng = inner.dbs.get(0).rng();
+            inner.dbs.evict(maxmemory as usize, &policy, &mut rng)
+        };
+        for (db, key) in evicted {
+            inner.watches.touch(db, &key);
+            let del = Data::Array(vec![
+                Data::BulkString("DEL".into()),
+                Data::BulkString(key.into()),
+            ]);
+            inner.replicate(db, del)?;
+        }
+
+        if !freed && command::is_deny_oom(name) {
+            bail!(command::OOM_ERR_MSG);
+        }
+        Ok(())
+    }
+
+    // AUTH [<username>] <password>. Only the default user exists.
+    fn auth(&self, args: &command::Args) -> Result<()> {
+        let (username, password) = match args.len() {
+            2 => ("default".to_string(), args.string(1)?),
+            3 => (args.string(1)?, args.string(2)?),
+            _ => bail!(command::SYNTAX_ERR_MSG),
+        };
+
+        let config = self.config.read().unwrap();
+        let Some(requirepass) = &config.requirepass else {
+            bail!("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?");
+        };
+        if username != "default" || password != *requirepass {
+            bail!("WRONGPASS invalid username-password pair or user is disabled.");
+        }
+        Ok(())
+    }
+
+    // HELLO [<protover> [AUTH <username> <password>] [SETNAME <name>]].
+    // Switches the connection to the protocol and replies with information
+    // about the server, as a map.
+    fn hello(&self, conn: &Connection, state: &mut ConnectionState, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let mut protocol = conn.protocol();
+        if args.len() > 1 {
+            protocol = match args.string(1)?.parse::<i64>() {
+                Ok(2) => Protocol::Resp2,
+                Ok(3) => Protocol::Resp3,
+                Ok(_) => bail!("NOPROTO unsupported protocol version"),
+                Err(_) => bail!("ERR Protocol version is not an integer or out of range"),
+            };
+        }
+        // AUTH is the index of its arguments, to authenticate once all
+        // options are parsed
+        let (mut auth, mut name) = (None, None);
+        let mut idx = 2;
+        while idx < args.len() {
+            match args.string(idx)?.to_ascii_lowercase().as_str() {
+                "auth" if idx + 2 < args.len() => {
+                    auth = Some(idx);
+                    idx += 3;
+                }
+                "setname" if idx + 1 < args.len() => {
+                    name = Some(args.string(idx + 1)?);
+                    idx += 2;
+                }
+                _ => bail!("ERR Syntax error in HELLO option '{}'", args.string(idx)?),
+            }
+        }
+
+        if let Some(idx) = auth {
+            self.auth(&command::Args::new(&vs[idx..idx + 3]))?;
+            state.authenticated = true;
+        }
+        if !state.authenticated && self.config.read().unwrap().requirepass.is_some() {
+            bail!("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time");
+        }
+        if let Some(name) = name {
+            set_client_name(&mut self.inner.lock().unwrap(), state.id, name)?;
+        }
+
+        conn.set_protocol(protocol);
+        let string = |s: &str| Data::BulkString(s.into());
+        let proto = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        Ok(Data::Map(vec![
+            (string("server"), string("redis")),
+            (string("version"), string(env!("CARGO_PKG_VERSION"))),
+            (string("proto"), Data::Integer(proto)),
+            (string("id"), Data::Integer(state.id as i64)),
+            (string("mode"), string("standalone")),
+            (string("role"), string("master")),
+            (string("modules"), Data::Array(Vec::new())),
+        ]))
+    }
+
+    // LATENCY HISTORY <event> | LATEST | RESET [<event> ...]. Latencies are
+    // in microseconds.
+    fn handle_latency(&self, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let subcommand = args.string(1)?.to_ascii_lowercase();
+        let mut latency = self.latency.lock().unwrap();
+        let sample = |(time, latency): Sample| {
+            vec![Data::Integer(time as i64), Data::Integer(latency as i64)]
+        };
+        match subcommand.as_str() {
+            "history" if args.len() == 3 => Ok(Data::Array(
+                latency
+                    .history(&args.string(2)?.to_ascii_lowercase())
+                    .into_iter()
+                    .map(|s| Data::Array(sample(s)))
+                    .collect(),
+            )),
+            "latest" if args.len() == 2 => Ok(Data::Array(
+                latency
+                    .latest()
+                    .into_iter()
+                    .map(|(event, latest, max)| {
+                        let mut item = vec![Data::BulkString(event.into())];
+                        item.extend(sample(latest));
+                        item.push(Data::Integer(max as i64));
+                        Data::Array(item)
+                    })
+                    .collect(),
+            )),
+            "reset" => {
+                let events = args
+                    .strings(2)?
+                    .iter()
+                    .map(|event| event.to_ascii_lowercase())
+                    .collect::<Vec<_>>();
+                Ok(Data::Integer(latency.reset(&events) as i64))
+            }
+            _ => bail!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try LATENCY HELP.",
+                subcommand
+            ),
+        }
+    }
+
+    // CONFIG GET <pattern> [<pattern> ...] | SET <parameter> <value>
+    // [<parameter> <value> ...] | REWRITE
+    fn handle_config(&self, dbs: &StoreDb, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let subcommand = args.string(1)?.to_ascii_lowercase();
+        match subcommand.as_str() {
+            "get" if args.len() >= 3 => {
+                let config = self.config.read().unwrap();
+                let mut parameters = Vec::new();
+                for pattern in args.strings(2)? {
+                    for parameter in config.matching(&pattern) {
+                        if !parameters.contains(&parameter) {
+                            parameters.push(parameter);
+                        }
+                    }
+                }
+                Ok(Data::Array(
+                    parameters
+                        .into_iter()
+                        .flat_map(|(name, value)| {
+                            [
+                                Data::BulkString(name.into()),
+                                Data::BulkString(value.into()),
+                            ]
+                        })
+                        .collect(),
+                ))
+            }
+            "set" if args.len() >= 4 && args.len().is_multiple_of(2) => {
+                // Set all parameters or none: apply them to a copy, which
+                // replaces the config once they are all valid
+                let mut config = self.config.write().unwrap();
+                let mut updated = config.clone();
+                for pair in args.strings(2)?.chunks(2) {
+                    let name = pair[0].to_ascii_lowercase();
+                    if !Config::is_known(&name) {
+                        bail!(
+                            "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                            name
+                        );
+                    }
+                    if !Config::is_mutable(&name) {
+                        bail!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                            name
+                        );
+                    }
+                    updated.set(&name, &pair[1]).map_err(|e| {
+                        anyhow!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                            name,
+                            e
+                        )
+                    })?;
+                }
+                *config = updated;
+                configure_dbs(dbs, &config);
+                Ok(Data::SimpleString("OK".into()))
+            }
+            "rewrite" if args.len() == 2 => {
+                self.config.read().unwrap().rewrite()?;
+                Ok(Data::SimpleString("OK".into()))
+            }
+            _ => bail!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try CONFIG HELP.",
+                subcommand
+            ),
+        }
+    }
+
+    // PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+    fn handle_pubsub(&self, inner: &MasterInner, vs: &[Data]) -> Result<Data> {
+        let args = command::Args::new(vs);
+        let subcommand = args.string(1)?.to_ascii_lowercase();
+        match subcommand.as_str() {
+            "channels" if args.len() <= 3 => {
+                let pattern = if args.len() == 3 {
+                    Some(args.string(2)?)
+                } else {
+                    None
+                };
+                let channels = inner.pubsub.channels(pattern.as_deref());
+                Ok(Data::Array(
+                    channels
+                        .into_iter()
+                        .map(|c| Data::BulkString(c.into()))
+                        .collect(),
+                ))
+            }
+            "numsub" => {
+                let mut reply = Vec::new();
+                for channel in args.strings(2)? {
+                    let num_subscribers = inner.pubsub.num_subscribers(&channel);
+                    reply.push(Data::BulkString(channel.into()));
+                    reply.push(Data::Integer(num_subscribers as i64));
+                }
+                Ok(Data::Array(reply))
+            }
+            "numpat" if args.len() == 2 => Ok(Data::Integer(inner.pubsub.num_patterns() as i64)),
+            _ => bail!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+                subcommand
+            ),
+        }
+    }
+
+    // Serves a connection in subscribe mode until it has unsubscribed from all
+    // channels and patterns. `data` is the (P)SUBSCRIBE command that entered
+    // subscribe mode. Commands from the client are read and handled in another
+    // thread, so that published messages can be written in the meantime.
+    // Everything written to the client goes through one channel. Returns how
+    // subscribe mode was left, if not by unsubscribing.
+    fn subscribe_mode(&self, conn: &Connection, data: Data) -> Result<Option<SubscribeExit>> {
+        // Like Redis, subscribers are never timed out, since they're
+        // expected to wait for messages
+        conn.set_timeout(None)?;
+        let (tx, rx) = mpsc::channel();
+        let mut subscriber = Subscriber::new(&mut self.inner.lock().unwrap().pubsub, tx);
+        // If this fails the connection never subscribed, and the thread below
+        // exits right away
+        if let Err(err) = handle_subscribed_data(&self.inner, &mut subscriber, data) {
+            subscriber.send(Data::SimpleError(err.to_string()));
+        }
+
+        let inner = self.inner.clone();
+        let reader = conn.clone();
+        let handle = thread::spawn(move || {
+            let mut exit = None;
+            while subscriber.is_subscribed() {
+                match reader.read_data() {
+                    Ok(data) => match handle_subscribed_data(&inner, &mut subscriber, data) {
+                        Ok(command_exit) => exit = command_exit,
+                        Err(err) => subscriber.send(Data::SimpleError(err.to_string())),
+                    },
+                    Err(error) => {
+                        debug!("Unsubscribing from everything: {}", error);
+                        subscriber.unsubscribe_all(&mut inner.lock().unwrap().pubsub);
+                    }
+                }
+            }
+            // Dropping `subscriber` drops the last sender, which ends the
+            // loop below
+            exit
+        });
+
+        for data in rx {
+            conn.write_data(data)?;
+        }
+        let exit = handle.join().unwrap_or(None);
+
+        debug!("Left subscribe mode");
+        conn.set_timeout(self.client_timeout())?;
+        Ok(exit)
+    }
+
+    // The timeout of idle clients, from the timeout config
+    fn client_timeout(&self) -> Option<Duration> {
+        match self.config.read().unwrap().timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
 
     fn handle_wait(
         &self,
@@ -459,110 +1586,1805 @@ impl Master {
     ) -> Result<()> {
         let mut inner = self.inner.lock().unwrap();
 
-        if num_replicas_to_wait > 0 && inner.replication_offset > 0 {
-            println!("Sending getack to replicas...");
-            let getack = Data::Array(vec![
-                Data::BulkString("REPLCONF".into()),
-                Data::BulkString("GETACK".into()),
-                Data::BulkString("*".into()),
-            ]);
-            for r in inner.replicas.iter() {
-                r.conn.write_data(getack.clone())?;
-            }
-
-            println!("Waiting acks from replicas...");
-
-            let cnt = {
-                // Implement timeout: https://stackoverflow.com/a/42720480/9057530
-                let (tx, rx) = mpsc::channel();
-                let replication_offset = inner.replication_offset;
-                let cnt = Arc::new(Mutex::new(0));
-
-                let replicas = inner.replicas.clone();
-
-                {
-                    let cnt = cnt.clone();
-
-                    // The idea is to query replicas for replicated offsets.
-                    //
-                    // Two possible ways to implement this:
-                    // 1. Query all replicas in order, in one thread.
-                    // 2. Spawn one thread for each replica and query offsets in parallel.
-                    //
-                    // The 1st approach is simpler and passes the tests. The 2nd approach
-                    // is more correct but doesn't pass the tests.
-                    //
-                    // The following events happen in the test:
-                    //
-                    // Start 3 replicas and 1 master
-                    // to master: Set foo 123 (which gets replicated to all 3 replicas)
-                    // to master: WAIT 1 500
-                    // Only replica-1 responds REPLCONF ACK
-                    //
-                    // to master: SET bar 456 (which gets replicated to all 3 replicas)
-                    // to master: WAIT 3 500
-                    // Only replica-1 and replica-2 reponds REPLCONF ACK
-                    //
-                    // If we implement the 2nd approach, when the master is querying replica-2
-                    // for offset after "SET bar", a thread is still blocked waiting
-                    // for REPLCONF ACK from replica-2 for "SET foo". In other words,
-                    // two threads are waiting for REPLCONF ACK from replica-2, but
-                    // only one is sent.
-                    // This is not a problem for the 1st approach because we wouldn't
-                    // try to query replica-2's offset.
-                    std::thread::spawn(move || -> Result<()> {
-                        for r in replicas.iter() {
-                            let r = r.clone();
-                            println!("Waiting replica {} response", r.id);
-                            let data = r.conn.read_data()?;
-                            if let Data::Array(vs) = data {
-                                let string_at = |idx: usize| -> Result<String> {
-                                    vs[idx].get_string().ok_or(anyhow!("fail to get string"))
-                                };
+        // Without replicas there's nothing to wait for
+        if inner.replicas.is_empty() {
+            return conn.write_data(Data::Integer(0));
+        }
+        // Without writes, nothing was sent to replicas yet, so they are all
+        // up to date
+        if num_replicas_to_wait == 0 || inner.replication_offset == 0 {
+            return conn.write_data(Data::Integer(inner.replicas.len() as i64));
+        }
 
-                                match string_at(0)?.to_ascii_uppercase().as_str() {
-                                    "REPLCONF" => {
-                                        assert_eq!(vs.len(), 3);
-                                        assert_eq!(string_at(1)?, "ACK");
-                                        let offset = string_at(2)?.parse::<usize>()?;
-                                        println!(
-                                            "replica {}: {}. Replication offset: {}",
-                                            r.id, offset, replication_offset
-                                        );
-                                        if offset >= replication_offset {
-                                            let mut cnt = cnt.lock().unwrap();
-                                            *cnt += 1;
-
-                                            if *cnt == num_replicas_to_wait {
-                                                tx.send(()).unwrap();
-                                                break;
-                                            }
-                                        };
-                                    }
-                                    _ => unreachable!(),
+        debug!("Sending GETACK to replicas");
+        let getack = Data::Array(vec![
+            Data::BulkString("REPLCONF".into()),
+            Data::BulkString("GETACK".into()),
+            Data::BulkString("*".into()),
+        ]);
+        for r in inner.replicas.iter() {
+            r.conn.write_data(getack.clone())?;
+        }
+
+        debug!("Waiting for ACKs from replicas");
+
+        let cnt = {
+            // Implement timeout: https://stackoverflow.com/a/42720480/9057530
+            let (tx, rx) = mpsc::channel();
+            let replication_offset = inner.replication_offset;
+            let cnt = Arc::new(Mutex::new(0));
+
+            let replicas = inner.replicas.clone();
+
+            {
+                let cnt = cnt.clone();
+
+                // The idea is to query replicas for replicated offsets.
+                //
+                // Two possible ways to implement this:
+                // 1. Query all replicas in order, in one thread.
+                // 2. Spawn one thread for each replica and query offsets in parallel.
+                //
+                // The 1st approach is simpler and passes the tests. The 2nd approach
+                // is more correct but doesn't pass the tests.
+                //
+                // The following events happen in the test:
+                //
+                // Start 3 replicas and 1 master
+                // to master: Set foo 123 (which gets replicated to all 3 replicas)
+                // to master: WAIT 1 500
+                // Only replica-1 responds REPLCONF ACK
+                //
+                // to master: SET bar 456 (which gets replicated to all 3 replicas)
+                // to master: WAIT 3 500
+                // Only replica-1 and replica-2 reponds REPLCONF ACK
+                //
+                // If we implement the 2nd approach, when the master is querying replica-2
+                // for offset after "SET bar", a thread is still blocked waiting
+                // for REPLCONF ACK from replica-2 for "SET foo". In other words,
+                // two threads are waiting for REPLCONF ACK from replica-2, but
+                // only one is sent.
+                // This is not a problem for the 1st approach because we wouldn't
+                // try to query replica-2's offset.
+                std::thread::spawn(move || -> Result<()> {
+                    for r in replicas.iter() {
+                        let r = r.clone();
+                        trace!("Waiting for replica {}", r.id);
+                        let data = r.conn.read_data()?;
+                        if let Data::Array(vs) = data {
+                            let string_at = |idx: usize| -> Result<String> {
+                                vs[idx].get_string().ok_or(anyhow!("fail to get string"))
+                            };
+
+                            match string_at(0)?.to_ascii_uppercase().as_str() {
+                                "REPLCONF" => {
+                                    assert_eq!(vs.len(), 3);
+                                    assert_eq!(string_at(1)?, "ACK");
+                                    let offset = string_at(2)?.parse::<usize>()?;
+                                    debug!(
+                                        "Replica {} is at offset {}, the master at {}",
+                                        r.id, offset, replication_offset
+                                    );
+                                    if offset >= replication_offset {
+                                        let mut cnt = cnt.lock().unwrap();
+                                        *cnt += 1;
+
+                                        if *cnt == num_replicas_to_wait {
+                                            tx.send(()).unwrap();
+                                            break;
+                                        }
+                                    };
                                 }
-                            } else {
-                                unreachable!()
+                                _ => unreachable!(),
                             }
+                        } else {
+                            unreachable!()
                         }
-                        Ok(())
-                    });
-                }
+                    }
+                    Ok(())
+                });
+            }
 
-                if let Err(err) = rx.recv_timeout(timeout) {
-                    println!("Timeout: {}", err);
-                };
+            if let Err(err) = rx.recv_timeout(timeout) {
+                debug!("Timed out waiting for ACKs: {}", err);
+            };
+
+            let cnt = *cnt.lock().unwrap();
+            cnt
+        };
+        debug!("{} replicas acknowledged", cnt);
+
+        inner.replication_offset += getack.num_bytes();
+        debug!("Replication offset: {}", inner.replication_offset);
+        conn.write_data(Data::Integer(cnt as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    // Starts a master on a random port, returning the address to connect to
+    fn start_master() -> SocketAddr {
+        start_master_with(Config::default())
+    }
+
+    fn start_master_with(config: Config) -> SocketAddr {
+        serve(Arc::new(Master::new(MasterParams { config }).unwrap()))
+    }
+
+    // Serves `master` on a new port, for tests that look into it
+    fn serve(master: Arc<Master>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let master = master.clone();
+                let conn = Connection::new(stream.unwrap());
+                thread::spawn(move || master.handle_connection(conn));
+            }
+        });
+
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> Connection {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Connection::new(stream)
+    }
+
+    fn command(cmd: &str) -> Data {
+        Data::Array(
+            cmd.split_ascii_whitespace()
+                .map(|s| Data::BulkString(s.into()))
+                .collect(),
+        )
+    }
+
+    // Sends a command and reads one reply
+    fn send(conn: &Connection, cmd: &str) -> Data {
+        conn.write_data(command(cmd)).unwrap();
+        conn.read_data().unwrap()
+    }
+
+    fn array(vs: &[&str]) -> Data {
+        Data::Array(vs.iter().map(|s| Data::BulkString((*s).into())).collect())
+    }
+
+    fn push(kind: &str, channel: &str, count: i64) -> Data {
+        Data::Array(vec![
+            Data::BulkString(kind.into()),
+            Data::BulkString(channel.into()),
+            Data::Integer(count),
+        ])
+    }
+
+    #[test]
+    fn publish_and_subscribe() {
+        let addr = start_master();
+        let subscriber = connect(addr);
+        let publisher = connect(addr);
+
+        assert_eq!(
+            send(&subscriber, "SUBSCRIBE a b"),
+            push("subscribe", "a", 1)
+        );
+        assert_eq!(subscriber.read_data().unwrap(), push("subscribe", "b", 2));
+
+        assert_eq!(send(&publisher, "PUBLISH a hello"), Data::Integer(1));
+        assert_eq!(send(&publisher, "PUBLISH c hello"), Data::Integer(0));
+        assert_eq!(
+            subscriber.read_data().unwrap(),
+            array(&["message", "a", "hello"])
+        );
+
+        // Only subscribe-mode commands are allowed
+        assert!(matches!(send(&subscriber, "GET a"), Data::SimpleError(_)));
+        assert_eq!(send(&subscriber, "PING"), array(&["pong", ""]));
+
+        // Leaving subscribe mode
+        assert_eq!(
+            send(&subscriber, "UNSUBSCRIBE a"),
+            push("unsubscribe", "a", 1)
+        );
+        assert_eq!(send(&publisher, "PUBLISH a hello"), Data::Integer(0));
+        assert_eq!(
+            send(&subscriber, "UNSUBSCRIBE"),
+            push("unsubscribe", "b", 0)
+        );
+        assert_eq!(send(&subscriber, "PING"), Data::SimpleString("PONG".into()));
+        assert_eq!(send(&publisher, "PUBLISH b hello"), Data::Integer(0));
+    }
+
+    #[test]
+    fn subscriber_disconnects() {
+        let addr = start_master();
+        let publisher = connect(addr);
+
+        {
+            let subscriber = connect(addr);
+            send(&subscriber, "SUBSCRIBE a");
+            assert_eq!(send(&publisher, "PUBLISH a hello"), Data::Integer(1));
+        }
+
+        // The subscription goes away with the connection
+        for _ in 0..50 {
+            if send(&publisher, "PUBLISH a hello") == Data::Integer(0) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("subscription outlived the connection");
+    }
+
+    #[test]
+    fn pattern_subscribe() {
+        let addr = start_master();
+        let subscriber = connect(addr);
+        let publisher = connect(addr);
+
+        assert_eq!(
+            send(&subscriber, "PSUBSCRIBE news.*"),
+            push("psubscribe", "news.*", 1)
+        );
+        assert_eq!(
+            send(&subscriber, "SUBSCRIBE weather"),
+            push("subscribe", "weather", 2)
+        );
+
+        assert_eq!(
+            send(&publisher, "PUBLISH news.sport goal"),
+            Data::Integer(1)
+        );
+        assert_eq!(
+            subscriber.read_data().unwrap(),
+            array(&["pmessage", "news.*", "news.sport", "goal"])
+        );
+        assert_eq!(send(&publisher, "PUBLISH sport goal"), Data::Integer(0));
+
+        assert_eq!(send(&publisher, "PUBSUB CHANNELS"), array(&["weather"]));
+        assert_eq!(
+            send(&publisher, "PUBSUB CHANNELS w?ather"),
+            array(&["weather"])
+        );
+        assert_eq!(send(&publisher, "PUBSUB CHANNELS news.*"), array(&[]));
+        assert_eq!(
+            send(&publisher, "PUBSUB NUMSUB weather news.sport"),
+            Data::Array(vec![
+                Data::BulkString("weather".into()),
+                Data::Integer(1),
+                Data::BulkString("news.sport".into()),
+                Data::Integer(0),
+            ])
+        );
+        assert_eq!(send(&publisher, "PUBSUB NUMPAT"), Data::Integer(1));
+
+        assert_eq!(
+            send(&subscriber, "PUNSUBSCRIBE"),
+            push("punsubscribe", "news.*", 1)
+        );
+        assert_eq!(send(&publisher, "PUBSUB NUMPAT"), Data::Integer(0));
+        assert_eq!(
+            send(&publisher, "PUBLISH news.sport goal"),
+            Data::Integer(0)
+        );
+    }
+
+    fn ok() -> Data {
+        Data::SimpleString("OK".into())
+    }
+
+    fn queued() -> Data {
+        Data::SimpleString("QUEUED".into())
+    }
+
+    #[test]
+    fn multi_exec() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert_eq!(send(&client, "HSET h f v"), queued());
+        assert_eq!(send(&client, "GET a"), queued());
+        assert_eq!(send(&client, "HGET a f"), queued());
+
+        // Nothing runs before EXEC
+        assert_eq!(send(&other, "GET a"), Data::NullBulkString);
+
+        assert_eq!(
+            send(&client, "EXEC"),
+            Data::Array(vec![
+                ok(),
+                Data::Integer(1),
+                Data::BulkString("1".into()),
+                Data::SimpleError(crate::value::WRONG_TYPE_ERR_MSG.into()),
+            ])
+        );
+        assert_eq!(send(&other, "GET a"), Data::BulkString("1".into()));
+
+        // Back to normal after EXEC
+        assert_eq!(send(&client, "GET a"), Data::BulkString("1".into()));
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![]));
+    }
+
+    #[test]
+    fn discard() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert_eq!(send(&client, "DISCARD"), ok());
+        assert_eq!(send(&client, "GET a"), Data::NullBulkString);
+
+        assert!(matches!(send(&client, "DISCARD"), Data::SimpleError(_)));
+        assert!(matches!(send(&client, "EXEC"), Data::SimpleError(_)));
+    }
+
+    #[test]
+    fn queueing_error_aborts_exec() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert!(matches!(send(&client, "GET"), Data::SimpleError(_)));
+        assert!(matches!(
+            send(&client, "NOSUCHCOMMAND"),
+            Data::SimpleError(_)
+        ));
+        assert!(matches!(send(&client, "MULTI"), Data::SimpleError(_)));
+        assert_eq!(send(&client, "SET b 2"), queued());
 
-                let cnt = *cnt.lock().unwrap();
-                cnt
+        let Data::SimpleError(err) = send(&client, "EXEC") else {
+            panic!("expected error");
+        };
+        assert!(err.starts_with("EXECABORT"));
+        assert_eq!(send(&client, "GET a"), Data::NullBulkString);
+        assert_eq!(send(&client, "GET b"), Data::NullBulkString);
+    }
+
+    #[test]
+    fn watch_aborts_exec_if_key_modified() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "WATCH a b"), ok());
+        assert_eq!(send(&other, "HSET b f v"), Data::Integer(1));
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert_eq!(send(&client, "EXEC"), Data::NullArray);
+        assert_eq!(send(&client, "GET a"), Data::NullBulkString);
+
+        // EXEC unwatched everything
+        assert_eq!(send(&other, "SET b x"), ok());
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![ok()]));
+    }
+
+    #[test]
+    fn watch_untouched_keys() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "WATCH a"), ok());
+        // Reads and writes to other keys don't count
+        assert_eq!(send(&other, "GET a"), Data::NullBulkString);
+        assert_eq!(send(&other, "SET b 1"), ok());
+        assert_eq!(send(&other, "HSETNX h f v"), Data::Integer(1));
+
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert!(matches!(send(&client, "WATCH a"), Data::SimpleError(_)));
+        assert_eq!(send(&client, "SET a 1"), queued());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![ok()]));
+    }
+
+    #[test]
+    fn unwatch() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "WATCH a"), ok());
+        assert_eq!(send(&client, "UNWATCH"), ok());
+        assert_eq!(send(&other, "SET a 1"), ok());
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "GET a"), queued());
+        assert_eq!(
+            send(&client, "EXEC"),
+            Data::Array(vec![Data::BulkString("1".into())])
+        );
+
+        // DISCARD unwatches too
+        assert_eq!(send(&client, "WATCH a"), ok());
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "DISCARD"), ok());
+        assert_eq!(send(&other, "SET a 2"), ok());
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![]));
+    }
+
+    #[test]
+    fn select() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "SET a 0"), ok());
+        assert_eq!(send(&client, "SELECT 1"), ok());
+        assert_eq!(send(&client, "GET a"), Data::NullBulkString);
+        assert_eq!(send(&client, "SET a 1"), ok());
+        assert_eq!(send(&client, "GET a"), Data::BulkString("1".into()));
+
+        // The selected database belongs to the connection
+        assert_eq!(send(&other, "GET a"), Data::BulkString("0".into()));
+        assert_eq!(send(&client, "SELECT 0"), ok());
+        assert_eq!(send(&client, "GET a"), Data::BulkString("0".into()));
+
+        for index in ["16", "-1", "x"] {
+            assert!(matches!(
+                send(&client, &format!("SELECT {}", index)),
+                Data::SimpleError(_)
+            ));
+        }
+
+        // SELECT in a transaction applies to the commands after it
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SELECT 1"), queued());
+        assert_eq!(send(&client, "GET a"), queued());
+        assert_eq!(
+            send(&client, "EXEC"),
+            Data::Array(vec![ok(), Data::BulkString("1".into())])
+        );
+        assert_eq!(send(&client, "GET a"), Data::BulkString("1".into()));
+    }
+
+    #[test]
+    fn dbsize_and_flush() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        send(&client, "SET a 0");
+        send(&client, "SET b 0");
+        send(&client, "SELECT 1");
+        send(&client, "SET a 1");
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(1));
+
+        assert_eq!(send(&client, "FLUSHDB"), ok());
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(0));
+        send(&client, "SELECT 0");
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(2));
+
+        send(&client, "SELECT 2");
+        send(&client, "SET c 2");
+        assert_eq!(send(&client, "FLUSHALL"), ok());
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(0));
+        send(&client, "SELECT 0");
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(0));
+
+        send(&client, "SET a 0");
+        assert_eq!(
+            send(&client, "FLUSHDB LATER"),
+            Data::SimpleError(command::SYNTAX_ERR_MSG.into())
+        );
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(1));
+        assert_eq!(send(&client, "FLUSHDB ASYNC"), ok());
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(0));
+        send(&client, "SET a 0");
+        assert_eq!(send(&client, "FLUSHALL sync"), ok());
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(0));
+    }
+
+    #[test]
+    fn watch_is_per_database() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        assert_eq!(send(&client, "WATCH a"), ok());
+        send(&other, "SELECT 1");
+        send(&other, "SET a 1");
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![]));
+
+        // Flushing a database touches the watched keys that existed
+        send(&other, "SELECT 0");
+        send(&other, "SET a 0");
+        assert_eq!(send(&client, "WATCH a"), ok());
+        send(&other, "FLUSHALL");
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "EXEC"), Data::NullArray);
+    }
+
+    // Connects to the master as a replica, up to the end of the handshake
+    fn connect_replica(addr: SocketAddr) -> Connection {
+        let replica = connect(addr);
+        let Data::SimpleString(reply) = send(&replica, "PSYNC ? -1") else {
+            panic!("expected FULLRESYNC");
+        };
+        assert!(reply.starts_with(b"FULLRESYNC"));
+        replica.read_rdb_file().unwrap();
+        // The master registers the replica once the RDB is sent
+        thread::sleep(Duration::from_millis(50));
+        replica
+    }
+
+    #[test]
+    fn wait_returns_right_away() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        // Without replicas, even after a write
+        let start = Instant::now();
+        assert_eq!(send(&client, "WAIT 1 5000"), Data::Integer(0));
+        send(&client, "SET a 1");
+        assert_eq!(send(&client, "WAIT 1 5000"), Data::Integer(0));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Before any write, replicas are up to date without being asked
+        let addr = start_master();
+        let client = connect(addr);
+        let replica = connect_replica(addr);
+        let start = Instant::now();
+        assert_eq!(send(&client, "WAIT 1 5000"), Data::Integer(1));
+        assert_eq!(send(&client, "WAIT 2 5000"), Data::Integer(1));
+        // Waiting for no replica doesn't ask either
+        send(&client, "SET a 1");
+        assert_eq!(send(&client, "WAIT 0 5000"), Data::Integer(1));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The replica only got the write, and no GETACK
+        assert_eq!(replica.read_data().unwrap(), command("SET a 1"));
+        replica
+            .set_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(replica.read_data().is_err());
+    }
+
+    #[test]
+    fn replication_selects_database() {
+        let master = Master::new(MasterParams {
+            config: Config::default(),
+        })
+        .unwrap();
+        let (replica, conn) = std::os::unix::net::UnixStream::pair().unwrap();
+        let replica = Connection::new(replica);
+        master
+            .inner
+            .lock()
+            .unwrap()
+            .replicas
+            .push(Arc::new(ReplicaHandle {
+                id: 0,
+                conn: Connection::new(conn),
+            }));
+
+        let mut state = ConnectionState::new(1, true);
+        let mut execute = |cmd: &str| {
+            let Data::Array(vs) = command(cmd) else {
+                unreachable!()
+            };
+            let mut inner = master.inner.lock().unwrap();
+            master.execute(&mut inner, state.id, &mut state.db, &vs)
+        };
+        execute("SET a 0").unwrap();
+        execute("SELECT 3").unwrap();
+        execute("SET a 3").unwrap();
+        execute("SET b 3").unwrap();
+        execute("SELECT 0").unwrap();
+        execute("SET c 0").unwrap();
+
+        for cmd in [
+            "SET a 0", "SELECT 3", "SET a 3", "SET b 3", "SELECT 0", "SET c 0",
+        ] {
+            assert_eq!(replica.read_data().unwrap(), command(cmd));
+        }
+    }
+
+    #[test]
+    fn config_get_and_set() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        assert_eq!(
+            send(&client, "CONFIG GET maxmemory*"),
+            array(&["maxmemory", "0", "maxmemory-policy", "noeviction"])
+        );
+        assert_eq!(
+            send(
+                &client,
+                "CONFIG SET maxmemory 1mb maxmemory-policy allkeys-lru"
+            ),
+            ok()
+        );
+        assert_eq!(
+            send(&client, "CONFIG GET maxmemory port"),
+            array(&["maxmemory", "1048576", "port", "6379"])
+        );
+
+        // Nothing is set if one of the parameters is invalid
+        let Data::SimpleError(_) = send(&client, "CONFIG SET maxmemory 5 maxmemory-policy foo")
+        else {
+            panic!("expected error");
+        };
+        let Data::SimpleError(_) = send(&client, "CONFIG SET port 7000") else {
+            panic!("expected error");
+        };
+        assert_eq!(
+            send(&client, "CONFIG GET maxmemory"),
+            array(&["maxmemory", "1048576"])
+        );
+
+        assert_eq!(
+            send(&client, "CONFIG REWRITE"),
+            Data::SimpleError("ERR The server is running without a config file".into())
+        );
+    }
+
+    #[test]
+    fn config_set_encoding_limits() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        send(&client, "HSET h a 1 b 2");
+        assert_eq!(
+            send(&client, "OBJECT ENCODING h"),
+            Data::BulkString("listpack".into())
+        );
+        assert_eq!(
+            send(&client, "CONFIG SET hash-max-listpack-entries 1"),
+            ok()
+        );
+        assert_eq!(
+            send(&client, "OBJECT ENCODING h"),
+            Data::BulkString("hashtable".into())
+        );
+        // Other databases use the new limits too
+        send(&client, "SELECT 1");
+        send(&client, "HSET h a 1 b 2");
+        assert_eq!(
+            send(&client, "OBJECT ENCODING h"),
+            Data::BulkString("hashtable".into())
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let addr = start_master();
+        let client = connect(addr);
+        assert_eq!(send(&client, "SET a 1"), ok());
+        assert_eq!(send(&client, "GET a"), Data::BulkString("1".into()));
+
+        // A sample is recorded once the reply is sent, so these two are in
+        let Data::Array(latest) = send(&client, "LATENCY LATEST") else {
+            panic!("expected array");
+        };
+        let events: Vec<_> = latest
+            .iter()
+            .map(|event| match event {
+                Data::Array(fields) if fields.len() == 4 => fields[0].clone(),
+                _ => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                Data::BulkString("get".into()),
+                Data::BulkString("set".into())
+            ]
+        );
+
+        let Data::Array(history) = send(&client, "LATENCY HISTORY set") else {
+            panic!("expected array");
+        };
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            send(&client, "LATENCY HISTORY nosuchevent"),
+            Data::Array(vec![])
+        );
+
+        assert_eq!(
+            send(&client, "LATENCY RESET set nosuchevent"),
+            Data::Integer(1)
+        );
+        assert_eq!(send(&client, "LATENCY HISTORY set"), Data::Array(vec![]));
+        let Data::SimpleError(_) = send(&client, "LATENCY DOCTOR") else {
+            panic!("expected error");
+        };
+    }
+
+    #[test]
+    fn idle_clients_time_out() {
+        let addr = start_master_with(Config {
+            timeout: 1,
+            ..Config::default()
+        });
+        let idle = connect(addr);
+        let subscriber = connect(addr);
+        assert_eq!(send(&idle, "PING"), Data::SimpleString("PONG".into()));
+        send(&subscriber, "SUBSCRIBE a");
+
+        thread::sleep(Duration::from_millis(1500));
+        // The server closed the connection
+        assert!(idle
+            .write_data(command("PING"))
+            .and_then(|_| idle.read_data())
+            .is_err());
+
+        // Subscribers are kept
+        let publisher = connect(addr);
+        assert_eq!(send(&publisher, "PUBLISH a hello"), Data::Integer(1));
+        assert_eq!(
+            subscriber.read_data().unwrap(),
+            array(&["message", "a", "hello"])
+        );
+    }
+
+    #[test]
+    fn debug_sleep() {
+        let addr = start_master();
+        let sleeper = connect(addr);
+        let other = connect(addr);
+
+        let start = Instant::now();
+        sleeper.write_data(command("DEBUG SLEEP 0.5")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // Other connections are still served while it sleeps
+        assert_eq!(send(&other, "PING"), Data::SimpleString("PONG".into()));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(sleeper.read_data().unwrap(), ok());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        assert_eq!(send(&sleeper, "DEBUG SLEEP 0"), ok());
+        for cmd in ["DEBUG SLEEP -1", "DEBUG SLEEP abc", "DEBUG SLEEP"] {
+            let Data::SimpleError(_) = send(&sleeper, cmd) else {
+                panic!("expected error for {}", cmd);
+            };
+        }
+    }
+
+    #[test]
+    fn debug_sleep_times_out_clients() {
+        let addr = start_master();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let client = Connection::new(stream.try_clone().unwrap());
+
+        client.write_data(command("DEBUG SLEEP 0.3")).unwrap();
+        assert!(client.read_data().is_err());
+
+        // The reply is still sent once the server wakes up
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read_data().unwrap(), ok());
+    }
+
+    #[test]
+    fn debug() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        assert_eq!(send(&client, "DEBUG SET-ACTIVE-EXPIRE 0"), ok());
+        assert_eq!(send(&client, "DEBUG SET-ACTIVE-EXPIRE 1"), ok());
+        let Data::SimpleError(_) = send(&client, "DEBUG SET-ACTIVE-EXPIRE yes") else {
+            panic!("expected error");
+        };
+        assert_eq!(send(&client, "DEBUG JMAP"), ok());
+
+        // There's no RDB file, so reloading leaves the databases empty
+        assert_eq!(send(&client, "SET a 1"), ok());
+        assert_eq!(send(&client, "DEBUG RELOAD"), ok());
+        assert_eq!(send(&client, "GET a"), Data::NullBulkString);
+
+        let Data::Array(help) = send(&client, "DEBUG HELP") else {
+            panic!("expected array");
+        };
+        assert_eq!(help.len(), 14);
+        assert_eq!(
+            send(&client, "DEBUG NOSUCH"),
+            Data::SimpleError(
+                "ERR unknown subcommand or wrong number of arguments for 'nosuch'. Try DEBUG HELP."
+                    .into()
+            )
+        );
+
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(
+            send(&client, "DEBUG SLEEP 0"),
+            Data::SimpleError("ERR Command not allowed inside a transaction".into())
+        );
+    }
+
+    #[test]
+    fn client() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        let Data::Integer(id) = send(&client, "CLIENT ID") else {
+            panic!("expected integer");
+        };
+        let Data::Integer(other_id) = send(&other, "CLIENT ID") else {
+            panic!("expected integer");
+        };
+        assert_ne!(id, other_id);
+
+        assert_eq!(send(&client, "CLIENT GETNAME"), Data::NullBulkString);
+        assert_eq!(send(&client, "CLIENT SETNAME worker"), ok());
+        assert_eq!(
+            send(&client, "CLIENT GETNAME"),
+            Data::BulkString("worker".into())
+        );
+        let Data::SimpleError(_) = send(&client, "CLIENT SETNAME caf\u{e9}") else {
+            panic!("expected error");
+        };
+        assert_eq!(send(&other, "SELECT 2"), ok());
+
+        let Data::BulkString(list) = send(&client, "CLIENT LIST") else {
+            panic!("expected bulk string");
+        };
+        let list = String::from_utf8(list.to_vec()).unwrap();
+        let lines: Vec<_> = list.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", list);
+        let line = |id: i64| {
+            *lines
+                .iter()
+                .find(|line| line.starts_with(&format!("id={} ", id)))
+                .unwrap()
+        };
+        assert!(line(id).contains(" name=worker "), "{}", list);
+        assert!(line(id).ends_with(" db=0"), "{}", list);
+        assert!(line(other_id).contains(" name= "), "{}", list);
+        assert!(line(other_id).ends_with(" db=2"), "{}", list);
+        assert!(line(id).contains(" addr=127.0.0.1:"), "{}", list);
+
+        assert_eq!(
+            send(&client, &format!("CLIENT KILL ID {}", other_id)),
+            Data::Integer(1)
+        );
+        assert!(other.read_data().is_err());
+        assert_eq!(
+            send(&client, &format!("CLIENT KILL ID {}", id + other_id)),
+            Data::Integer(0)
+        );
+        let Data::SimpleError(_) = send(&client, "CLIENT NOSUCHSUBCOMMAND") else {
+            panic!("expected error");
+        };
+    }
+
+    #[test]
+    fn client_kill() {
+        let addr = start_master();
+        let client = connect(addr);
+        let id = |conn: &Connection| match send(conn, "CLIENT ID") {
+            Data::Integer(id) => id,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        // The address of a connection, as the server sees it
+        let peer_addr = |conn: &Connection| {
+            // Once it has replied, the connection is registered
+            let prefix = format!("id={} addr=", id(conn));
+            let Data::BulkString(list) = send(&client, "CLIENT LIST") else {
+                panic!("expected bulk string");
+            };
+            let list = String::from_utf8(list.to_vec()).unwrap();
+            let line = list.lines().find(|line| line.starts_with(&prefix)).unwrap();
+            line[prefix.len()..].split(' ').next().unwrap().to_string()
+        };
+
+        let other = connect(addr);
+        let other_addr = peer_addr(&other);
+        assert_eq!(
+            send(
+                &client,
+                &format!("CLIENT KILL ADDR {} ID {}", other_addr, id(&client))
+            ),
+            Data::Integer(0)
+        );
+        assert_eq!(
+            send(&client, &format!("CLIENT KILL ADDR {}", other_addr)),
+            Data::Integer(1)
+        );
+        assert!(other.read_data().is_err());
+
+        let other = connect(addr);
+        assert_eq!(
+            send(&client, &format!("CLIENT KILL {}", peer_addr(&other))),
+            ok()
+        );
+        assert!(other.read_data().is_err());
+        assert_eq!(
+            send(&client, "CLIENT KILL 127.0.0.1:1"),
+            Data::SimpleError("ERR No such client".into())
+        );
+        let Data::SimpleError(_) = send(&client, "CLIENT KILL ID 0") else {
+            panic!("expected error");
+        };
+
+        // Connections skip themselves unless told otherwise. Then they get
+        // the reply, but don't run the commands after it.
+        let own_id = id(&client);
+        assert_eq!(
+            send(&client, &format!("CLIENT KILL ID {}", own_id)),
+            Data::Integer(0)
+        );
+        client
+            .write_data(command(&format!("CLIENT KILL ID {} SKIPME no", own_id)))
+            .unwrap();
+        client.write_data(command("PING")).unwrap();
+        assert_eq!(client.read_data().unwrap(), Data::Integer(1));
+        assert!(client.read_data().is_err());
+    }
+
+    #[test]
+    fn auth() {
+        let addr = start_master_with(Config {
+            requirepass: Some("secret".into()),
+            ..Config::default()
+        });
+        let client = connect(addr);
+        let noauth = Data::SimpleError(NOAUTH_ERR_MSG.into());
+
+        assert_eq!(send(&client, "GET a"), noauth);
+        assert_eq!(send(&client, "NOSUCHCOMMAND"), noauth);
+        let Data::SimpleError(err) = send(&client, "AUTH wrong") else {
+            panic!("expected error");
+        };
+        assert!(err.starts_with("WRONGPASS"));
+        assert_eq!(send(&client, "SET a 1"), noauth);
+
+        assert_eq!(send(&client, "AUTH default secret"), ok());
+        assert_eq!(send(&client, "SET a 1"), ok());
+
+        // Other connections still need to authenticate
+        let other = connect(addr);
+        assert_eq!(send(&other, "GET a"), noauth);
+        assert_eq!(send(&other, "AUTH secret"), ok());
+        assert_eq!(send(&other, "GET a"), Data::BulkString("1".into()));
+    }
+
+    #[test]
+    fn reset() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        let reset = Data::SimpleString("RESET".into());
+
+        send(&client, "SELECT 3");
+        send(&client, "CLIENT SETNAME conn");
+        send(&client, "WATCH a");
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "SET a 3"), queued());
+        // Not queued
+        assert_eq!(send(&client, "RESET"), reset);
+        assert_eq!(
+            send(&client, "EXEC"),
+            Data::SimpleError("ERR EXEC without MULTI".into())
+        );
+        assert_eq!(send(&client, "CLIENT GETNAME"), Data::NullBulkString);
+        // Back in database 0
+        send(&client, "SET a 0");
+        assert_eq!(send(&other, "GET a"), Data::BulkString("0".into()));
+        // and no longer watching
+        assert_eq!(send(&client, "MULTI"), ok());
+        send(&client, "SET b 1");
+        send(&other, "SET a 1");
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![ok()]));
+
+        // Leaves subscribe mode, without unsubscribe messages
+        send(&client, "SUBSCRIBE ch1 ch2");
+        client.read_data().unwrap();
+        assert_eq!(send(&client, "RESET"), reset);
+        assert_eq!(send(&other, "PUBLISH ch1 m"), Data::Integer(0));
+        assert_eq!(send(&client, "GET a"), Data::BulkString("1".into()));
+    }
+
+    #[test]
+    fn malformed_stream_ids() {
+        let addr = start_master();
+        let client = connect(addr);
+        let invalid =
+            Data::SimpleError("ERR Invalid stream ID specified as stream command argument".into());
+        send(&client, "XADD s 1-1 f v");
+
+        for cmd in [
+            "XADD s notanid f v",
+            "XADD s 2-x f v",
+            "XRANGE s notanid +",
+            "XREVRANGE s + 1-x",
+            "XREAD STREAMS s notanid",
+            "XREAD BLOCK 10 STREAMS s notanid",
+        ] {
+            assert_eq!(send(&client, cmd), invalid, "{}", cmd);
+            // The connection is still usable
+            assert_eq!(send(&client, "PING"), Data::SimpleString("PONG".into()));
+        }
+        assert_eq!(send(&client, "XLEN s"), Data::Integer(1));
+    }
+
+    #[test]
+    fn quit() {
+        let addr = start_master_with(Config {
+            requirepass: Some("secret".into()),
+            ..Config::default()
+        });
+        let other = connect(addr);
+        send(&other, "AUTH secret");
+
+        // Even unauthenticated, and inside a transaction
+        for setup in [&[][..], &["AUTH secret", "MULTI", "SET a 1"]] {
+            let client = connect(addr);
+            for cmd in setup {
+                send(&client, cmd);
+            }
+            assert_eq!(send(&client, "QUIT"), ok());
+            assert!(client.read_data().is_err());
+            assert!(client
+                .write_data(command("PING"))
+                .and_then(|_| client.read_data())
+                .is_err());
+        }
+        // Not executed
+        assert_eq!(send(&other, "GET a"), Data::NullBulkString);
+        // Removed right after closing
+        thread::sleep(Duration::from_millis(50));
+        let Data::BulkString(list) = send(&other, "CLIENT LIST") else {
+            panic!("expected bulk string");
+        };
+        assert_eq!(
+            list.split(|&b| b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn quit_while_subscribed() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        send(&client, "SUBSCRIBE ch");
+        assert_eq!(send(&client, "QUIT"), ok());
+        assert!(client.read_data().is_err());
+        assert_eq!(send(&other, "PUBLISH ch hi"), Data::Integer(0));
+    }
+
+    #[test]
+    fn hello() {
+        let addr = start_master();
+        let client = connect(addr);
+        let string = |s: &str| Data::BulkString(s.into());
+
+        // With RESP2, an array of pairs
+        let Data::Array(info) = send(&client, "HELLO") else {
+            panic!("expected array");
+        };
+        assert_eq!(info.len(), 14);
+        assert_eq!(info[0], string("server"));
+        assert_eq!(&info[4..6], [string("proto"), Data::Integer(2)]);
+
+        let Data::Map(info) = send(&client, "HELLO 3 SETNAME conn") else {
+            panic!("expected map");
+        };
+        let keys: Vec<_> = info.iter().map(|(k, _)| k.get_string().unwrap()).collect();
+        assert_eq!(
+            keys,
+            ["server", "version", "proto", "id", "mode", "role", "modules"]
+        );
+        assert_eq!(info[2].1, Data::Integer(3));
+        assert_eq!(info[3].1, send(&client, "CLIENT ID"));
+        assert_eq!(info[5].1, string("master"));
+        assert_eq!(send(&client, "CLIENT GETNAME"), string("conn"));
+        // Stays on RESP3 without a version
+        assert!(matches!(send(&client, "HELLO"), Data::Map(_)));
+
+        for (cmd, err) in [
+            ("HELLO 4", "NOPROTO unsupported protocol version"),
+            (
+                "HELLO two",
+                "ERR Protocol version is not an integer or out of range",
+            ),
+            ("HELLO 3 FOO", "ERR Syntax error in HELLO option 'FOO'"),
+            (
+                "HELLO 3 AUTH default",
+                "ERR Syntax error in HELLO option 'AUTH'",
+            ),
+        ] {
+            assert_eq!(send(&client, cmd), Data::SimpleError(err.into()));
+        }
+
+        // RESET goes back to RESP2
+        send(&client, "RESET");
+        assert!(matches!(send(&client, "HELLO"), Data::Array(_)));
+    }
+
+    #[test]
+    fn hello_auth() {
+        let addr = start_master_with(Config {
+            requirepass: Some("secret".into()),
+            ..Config::default()
+        });
+        let client = connect(addr);
+        let Data::SimpleError(err) = send(&client, "HELLO 3") else {
+            panic!("expected error");
+        };
+        assert!(err.starts_with("NOAUTH HELLO"));
+        let Data::SimpleError(err) = send(&client, "HELLO 3 AUTH default wrong") else {
+            panic!("expected error");
+        };
+        assert!(err.starts_with("WRONGPASS"));
+        assert_eq!(
+            send(&client, "GET a"),
+            Data::SimpleError(NOAUTH_ERR_MSG.into())
+        );
+
+        assert!(matches!(
+            send(&client, "HELLO 3 AUTH default secret"),
+            Data::Map(_)
+        ));
+        assert_eq!(send(&client, "SET a 1"), ok());
+    }
+
+    #[test]
+    fn reset_requires_auth_again() {
+        let addr = start_master_with(Config {
+            requirepass: Some("secret".into()),
+            ..Config::default()
+        });
+        let client = connect(addr);
+        assert_eq!(send(&client, "RESET"), Data::SimpleString("RESET".into()));
+        assert_eq!(send(&client, "AUTH secret"), ok());
+        assert_eq!(send(&client, "SET a 1"), ok());
+        assert_eq!(send(&client, "RESET"), Data::SimpleString("RESET".into()));
+        assert_eq!(
+            send(&client, "GET a"),
+            Data::SimpleError(NOAUTH_ERR_MSG.into())
+        );
+    }
+
+    #[test]
+    fn auth_without_password() {
+        let client = connect(start_master());
+        let Data::SimpleError(err) = send(&client, "AUTH secret") else {
+            panic!("expected error");
+        };
+        assert!(err.starts_with("ERR AUTH <password> called without any password"));
+        assert_eq!(send(&client, "PING"), Data::SimpleString("PONG".into()));
+    }
+
+    #[test]
+    fn maxmemory() {
+        let addr = start_master();
+        let client = connect(addr);
+        let oom = Data::SimpleError(command::OOM_ERR_MSG.into());
+
+        for i in 0..10 {
+            send(&client, &format!("SET key:{} value", i));
+        }
+        assert_eq!(send(&client, "CONFIG SET maxmemory 500"), ok());
+        assert_eq!(send(&client, "SET another value"), oom);
+        assert_eq!(send(&client, "GET key:0"), Data::BulkString("value".into()));
+        assert_eq!(send(&client, "DBSIZE"), Data::Integer(10));
+
+        assert_eq!(
+            send(&client, "CONFIG SET maxmemory-policy allkeys-lru"),
+            ok()
+        );
+        for i in 0..20 {
+            assert_eq!(send(&client, &format!("SET new:{} value", i)), ok());
+        }
+        let Data::Integer(dbsize) = send(&client, "DBSIZE") else {
+            panic!("expected integer");
+        };
+        assert!(dbsize < 10);
+        // The most recently written key survives
+        assert_eq!(
+            send(&client, "GET new:19"),
+            Data::BulkString("value".into())
+        );
+    }
+
+    #[test]
+    fn bzpopmin_blocks_until_zadd() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+
+        let waiter = thread::spawn(move || send(&client, "BZPOPMIN empty z 5"));
+        thread::sleep(Duration::from_millis(100));
+        // Adding to a key it isn't waiting for doesn't wake it up
+        assert_eq!(send(&other, "ZADD other 1 x"), Data::Integer(1));
+        assert_eq!(send(&other, "ZADD z 2 b 1 a"), Data::Integer(2));
+
+        assert_eq!(waiter.join().unwrap(), array(&["z", "a", "1"]));
+        assert_eq!(send(&other, "ZRANGE z 0 -1"), array(&["b"]));
+    }
+
+    #[test]
+    fn bzpopmin_stops_waiting_on_disconnect_and_timeout() {
+        let master = Arc::new(
+            Master::new(MasterParams {
+                config: Config::default(),
+            })
+            .unwrap(),
+        );
+        let addr = serve(master.clone());
+        let num_waiters = |key: &str| master.inner.lock().unwrap().dbs.get(0).num_waiters(key);
+        let other = connect(addr);
+
+        let client = connect(addr);
+        client.write_data(command("BZPOPMIN a z 0")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!((num_waiters("a"), num_waiters("z")), (1, 1));
+        drop(client);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!((num_waiters("a"), num_waiters("z")), (0, 0));
+        // Nothing is popped for the client that left
+        assert_eq!(send(&other, "ZADD z 1 a"), Data::Integer(1));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(send(&other, "ZCARD z"), Data::Integer(1));
+
+        assert_eq!(send(&other, "BZPOPMIN b y 0.05"), Data::NullArray);
+        assert_eq!((num_waiters("b"), num_waiters("y")), (0, 0));
+    }
+
+    #[test]
+    fn bzpopmax() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        send(&client, "ZADD z 1 a 2 b");
+        assert_eq!(
+            send(&client, "BZPOPMAX missing z 0"),
+            array(&["z", "b", "2"])
+        );
+        assert_eq!(send(&client, "BZPOPMAX z 0.05"), array(&["z", "a", "1"]));
+        // Times out
+        assert_eq!(send(&client, "BZPOPMAX z 0.05"), Data::NullArray);
+        assert_eq!(
+            send(&client, "BZPOPMAX z -1"),
+            Data::SimpleError("ERR timeout is negative".into())
+        );
+
+        // Doesn't block inside a transaction
+        assert_eq!(send(&client, "MULTI"), ok());
+        assert_eq!(send(&client, "BZPOPMIN z 0"), queued());
+        assert_eq!(send(&client, "EXEC"), Data::Array(vec![Data::NullArray]));
+    }
+
+    #[test]
+    fn xreadgroup_blocks_until_xadd() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        send(&other, "XGROUP CREATE a g $ MKSTREAM");
+        send(&other, "XGROUP CREATE b g $ MKSTREAM");
+
+        let waiter = thread::spawn(move || {
+            let reply = send(&client, "XREADGROUP GROUP g alice BLOCK 0 STREAMS a b > >");
+            (client, reply)
+        });
+        thread::sleep(Duration::from_millis(100));
+        send(&other, "XADD b 1-0 k v");
+        let (client, reply) = waiter.join().unwrap();
+        assert_eq!(
+            reply,
+            Data::Array(vec![Data::Array(vec![
+                Data::BulkString("b".into()),
+                Data::Array(vec![Data::Array(vec![
+                    Data::BulkString("1-0".into()),
+                    array(&["k", "v"])
+                ])])
+            ])])
+        );
+        // The entry is pending for alice
+        let Data::Array(pending) = send(&other, "XPENDING b g") else {
+            panic!("expected array");
+        };
+        assert_eq!(pending[0], Data::Integer(1));
+
+        // Times out, and history reads never block
+        assert_eq!(
+            send(&client, "XREADGROUP GROUP g alice BLOCK 50 STREAMS a b > >"),
+            Data::NullArray
+        );
+        assert!(matches!(
+            send(&client, "XREADGROUP GROUP g alice BLOCK 0 STREAMS a 0"),
+            Data::Array(_)
+        ));
+        send(&other, "XADD a 1-0 k v");
+        assert_eq!(send(&other, "XLEN a"), Data::Integer(1));
+    }
+
+    #[test]
+    fn xread_count() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        for i in 1..=5 {
+            send(&client, &format!("XADD s {}-0 k v", i));
+        }
+
+        let stream_ids = |reply: Data| match reply {
+            Data::Array(mut streams) if streams.len() == 1 => match streams.remove(0) {
+                Data::Array(mut stream) => entry_ids(stream.remove(1)),
+                _ => panic!("expected stream"),
+            },
+            reply => panic!("expected one stream, got {:?}", reply),
+        };
+        assert_eq!(
+            stream_ids(send(&client, "XREAD COUNT 2 STREAMS s 0")),
+            ["1-0", "2-0"]
+        );
+        assert_eq!(
+            stream_ids(send(&client, "XREAD COUNT 2 STREAMS s 2-0")),
+            ["3-0", "4-0"]
+        );
+        // BLOCK may come before COUNT, and doesn't block if there are entries
+        assert_eq!(
+            stream_ids(send(&client, "XREAD BLOCK 0 count 2 STREAMS s 4-0")),
+            ["5-0"]
+        );
+        assert_eq!(
+            stream_ids(send(&client, "XREAD COUNT 0 STREAMS s 0")).len(),
+            5
+        );
+
+        // The count applies to the entries read after waking up too
+        let waiter = thread::spawn(move || send(&client, "XREAD BLOCK 0 COUNT 2 STREAMS s 5-0"));
+        thread::sleep(Duration::from_millis(100));
+        send(&other, "MULTI");
+        for i in 6..=8 {
+            send(&other, &format!("XADD s {}-0 k v", i));
+        }
+        send(&other, "EXEC");
+        assert_eq!(stream_ids(waiter.join().unwrap()), ["6-0", "7-0"]);
+
+        for command in [
+            "XREAD COUNT 2 s 0",
+            "XREAD COUNT STREAMS s 0",
+            "XREAD FOO 1 STREAMS s 0",
+        ] {
+            assert!(
+                matches!(send(&other, command), Data::SimpleError(_)),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn xread_blocks_on_multiple_streams() {
+        let addr = start_master();
+        let client = connect(addr);
+        let other = connect(addr);
+        send(&other, "XADD a 1-0 k v");
+
+        let waiter = thread::spawn(move || {
+            let reply = send(&client, "XREAD BLOCK 0 STREAMS a b $ $");
+            (client, reply)
+        });
+        thread::sleep(Duration::from_millis(100));
+        send(&other, "XADD b 1-0 k v");
+        let (client, reply) = waiter.join().unwrap();
+        // Only the stream with new entries
+        assert_eq!(
+            reply,
+            Data::Array(vec![Data::Array(vec![
+                Data::BulkString("b".into()),
+                Data::Array(vec![Data::Array(vec![
+                    Data::BulkString("1-0".into()),
+                    array(&["k", "v"])
+                ])])
+            ])])
+        );
+        // The subscription to `a` is gone, so writing to it is fine
+        send(&other, "XADD a 2-0 k v");
+        assert_eq!(send(&other, "XLEN a"), Data::Integer(2));
+
+        // The timeout is for the whole call
+        let start = Instant::now();
+        assert_eq!(
+            send(&client, "XREAD BLOCK 200 STREAMS a b c $ $ $"),
+            Data::NullBulkString
+        );
+        let elapsed = start.elapsed();
+        assert!(
+            (Duration::from_millis(200)..Duration::from_millis(500)).contains(&elapsed),
+            "{:?}",
+            elapsed
+        );
+        send(&other, "XADD c 1-0 k v");
+        assert_eq!(send(&other, "XLEN c"), Data::Integer(1));
+    }
+
+    #[test]
+    fn nomkstream_after_blocking_xread() {
+        let addr = start_master();
+        let client = connect(addr);
+        assert_eq!(
+            send(&client, "XREAD BLOCK 50 STREAMS s $"),
+            Data::NullBulkString
+        );
+        assert_eq!(
+            send(&client, "XADD s NOMKSTREAM * a 1"),
+            Data::NullBulkString
+        );
+        assert_eq!(send(&client, "TYPE s"), Data::SimpleString("none".into()));
+    }
+
+    #[test]
+    fn xread_waiters_on_the_same_id() {
+        let master = Arc::new(
+            Master::new(MasterParams {
+                config: Config::default(),
+            })
+            .unwrap(),
+        );
+        let addr = serve(master.clone());
+        let num_subscribers = |stream: &str| {
+            let inner = master.inner.lock().unwrap();
+            inner.dbs.get(0).num_stream_subscribers(stream)
+        };
+        let other = connect(addr);
+        send(&other, "XADD s 1-0 k v");
+
+        // One times out, which doesn't unsubscribe the others
+        let timed_out = connect(addr);
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let client = connect(addr);
+                thread::spawn(move || send(&client, "XREAD BLOCK 0 STREAMS s 1-0"))
+            })
+            .collect();
+        assert_eq!(
+            send(&timed_out, "XREAD BLOCK 50 STREAMS s 1-0"),
+            Data::NullBulkString
+        );
+        thread::sleep(Duration::from_millis(50));
+
+        send(&other, "XADD s 2-0 k v");
+        let entries = Data::Array(vec![Data::Array(vec![
+            Data::BulkString("s".into()),
+            Data::Array(vec![Data::Array(vec![
+                Data::BulkString("2-0".into()),
+                array(&["k", "v"]),
+            ])]),
+        ])]);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), entries);
+        }
+        assert_eq!(send(&timed_out, "PING"), Data::SimpleString("PONG".into()));
+        assert_eq!(num_subscribers("s"), 0);
+
+        // Waiting for a missing stream doesn't create it
+        assert_eq!(
+            send(&other, "XREAD BLOCK 50 STREAMS missing 0"),
+            Data::NullBulkString
+        );
+        assert_eq!(num_subscribers("missing"), 0);
+        assert_eq!(
+            send(&other, "TYPE missing"),
+            Data::SimpleString("none".into())
+        );
+        assert_eq!(send(&other, "DBSIZE"), Data::Integer(1));
+    }
+
+    #[test]
+    fn xread_unsubscribes_on_disconnect() {
+        let master = Arc::new(
+            Master::new(MasterParams {
+                config: Config::default(),
+            })
+            .unwrap(),
+        );
+        let addr = serve(master.clone());
+        let num_subscribers = |stream: &str| {
+            let inner = master.inner.lock().unwrap();
+            inner.dbs.get(0).num_stream_subscribers(stream)
+        };
+        let other = connect(addr);
+
+        for cmd in [
+            "XREAD BLOCK 0 STREAMS s $",
+            "XREADGROUP GROUP g c BLOCK 0 STREAMS s >",
+        ] {
+            send(&other, "DEL s");
+            send(&other, "XGROUP CREATE s g $ MKSTREAM");
+            let client = connect(addr);
+            client.write_data(command(cmd)).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(num_subscribers("s"), 1, "{}", cmd);
+
+            drop(client);
+            thread::sleep(Duration::from_millis(300));
+            assert_eq!(num_subscribers("s"), 0, "{}", cmd);
+            let Data::BulkString(list) = send(&other, "CLIENT LIST") else {
+                panic!("expected bulk string");
             };
-            println!("cnt: {}", cnt);
+            assert_eq!(
+                list.split(|&b| b == b'\n')
+                    .filter(|l| !l.is_empty())
+                    .count(),
+                1
+            );
+        }
+    }
+
+    // The ids of the entries in an XRANGE-like reply
+    fn entry_ids(reply: Data) -> Vec<String> {
+        let Data::Array(entries) = reply else {
+            panic!("expected array");
+        };
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                Data::Array(entry) => match &entry[0] {
+                    Data::BulkString(id) => String::from_utf8_lossy(id).to_string(),
+                    _ => panic!("expected id"),
+                },
+                _ => panic!("expected entry"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stream_commands() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        for id in 1..=5 {
+            send(&client, &format!("XADD s {id}-0 k v{id}"));
+        }
+        assert_eq!(send(&client, "XLEN s"), Data::Integer(5));
+        assert_eq!(send(&client, "XLEN missing"), Data::Integer(0));
+
+        assert_eq!(
+            entry_ids(send(&client, "XREVRANGE s + 2")),
+            ["5-0", "4-0", "3-0", "2-0"]
+        );
+        assert_eq!(
+            entry_ids(send(&client, "XREVRANGE s 4 - COUNT 2")),
+            ["4-0", "3-0"]
+        );
+
+        assert_eq!(send(&client, "XDEL s 2-0 3 9-0"), Data::Integer(2));
+        assert_eq!(
+            entry_ids(send(&client, "XRANGE s - +")),
+            ["1-0", "4-0", "5-0"]
+        );
+
+        assert_eq!(send(&client, "XTRIM s MAXLEN ~ 1"), Data::Integer(2));
+        assert_eq!(send(&client, "XTRIM s MAXLEN 5"), Data::Integer(0));
+        assert_eq!(entry_ids(send(&client, "XRANGE s - +")), ["5-0"]);
+        assert_eq!(
+            send(&client, "XTRIM s MAXLEN -1"),
+            Data::SimpleError(command::MAXLEN_ERR_MSG.into())
+        );
+
+        // Ids of deleted entries aren't reused
+        send(&client, "XDEL s 5-0");
+        assert_eq!(send(&client, "XLEN s"), Data::Integer(0));
+        assert!(matches!(
+            send(&client, "XADD s 5-0 k v"),
+            Data::SimpleError(_)
+        ));
+        let Data::BulkString(id) = send(&client, "XADD s * k v") else {
+            panic!("expected id");
+        };
+        let id = EntryId::create_from_complete(String::from_utf8(id).unwrap()).unwrap();
+        assert!(id > EntryId::create_from_complete("5-0".into()).unwrap());
+        assert_eq!(send(&client, "XDEL missing 1-0"), Data::Integer(0));
+    }
+
+    #[test]
+    fn xinfo() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        send(&client, "XADD s 1-1 a 1");
+        send(&client, "XADD s 2-1 b 2");
+        send(&client, "XADD s 3-1 c 3");
+        send(&client, "XDEL s 1-1");
+
+        let Data::Array(reply) = send(&client, "XINFO STREAM s") else {
+            panic!("expected array");
+        };
+        let fields: HashMap<String, Data> = reply
+            .chunks_exact(2)
+            .map(|pair| match &pair[0] {
+                Data::BulkString(name) => {
+                    (String::from_utf8_lossy(name).to_string(), pair[1].clone())
+                }
+                _ => panic!("expected field name"),
+            })
+            .collect();
+        assert_eq!(fields["length"], Data::Integer(2));
+        assert_eq!(fields["last-generated-id"], Data::BulkString("3-1".into()));
+        assert_eq!(
+            fields["max-deleted-entry-id"],
+            Data::BulkString("1-1".into())
+        );
+        assert_eq!(fields["entries-added"], Data::Integer(3));
+        assert_eq!(fields["groups"], Data::Integer(0));
+        assert_eq!(
+            fields["first-entry"],
+            Data::Array(vec![Data::BulkString("2-1".into()), array(&["b", "2"])])
+        );
+        assert_eq!(
+            fields["last-entry"],
+            Data::Array(vec![Data::BulkString("3-1".into()), array(&["c", "3"])])
+        );
+
+        assert_eq!(send(&client, "XINFO GROUPS s"), Data::Array(Vec::new()));
+        assert_eq!(
+            send(&client, "XINFO STREAM missing"),
+            Data::SimpleError("ERR no such key".into())
+        );
+        send(&client, "SET k v");
+        assert!(
+            matches!(send(&client, "XINFO STREAM k"), Data::SimpleError(e) if e.starts_with("WRONGTYPE"))
+        );
+    }
+
+    #[test]
+    fn consumer_groups() {
+        let addr = start_master();
+        let client = connect(addr);
 
-            inner.replication_offset += getack.num_bytes();
-            println!("replication offset: +{}", getack.num_bytes());
-            conn.write_data(Data::Integer(cnt as i64))
-        } else {
-            conn.write_data(Data::Integer(inner.replicas.len() as i64))
+        assert!(matches!(
+            send(&client, "XGROUP CREATE s g $"),
+            Data::SimpleError(e) if e.starts_with("ERR The XGROUP subcommand requires the key to exist")
+        ));
+        assert_eq!(send(&client, "XGROUP CREATE s g $ MKSTREAM"), ok());
+        assert_eq!(
+            send(&client, "XGROUP CREATE s g 0"),
+            Data::SimpleError("BUSYGROUP Consumer Group name already exists".into())
+        );
+        for id in 1..=3 {
+            send(&client, &format!("XADD s {id}-0 k v{id}"));
         }
+
+        // Each new entry is delivered once
+        let reply = send(&client, "XREADGROUP GROUP g alice COUNT 2 STREAMS s >");
+        let Data::Array(streams) = reply else {
+            panic!("expected array");
+        };
+        let Data::Array(stream) = &streams[0] else {
+            panic!("expected stream");
+        };
+        assert_eq!(stream[0], Data::BulkString("s".into()));
+        assert_eq!(entry_ids(stream[1].clone()), ["1-0", "2-0"]);
+        let reply = send(&client, "XREADGROUP GROUP g bob STREAMS s >");
+        assert_eq!(
+            reply,
+            Data::Array(vec![Data::Array(vec![
+                Data::BulkString("s".into()),
+                Data::Array(vec![Data::Array(vec![
+                    Data::BulkString("3-0".into()),
+                    array(&["k", "v3"]),
+                ])]),
+            ])])
+        );
+        assert_eq!(
+            send(&client, "XREADGROUP GROUP g bob STREAMS s >"),
+            Data::NullArray
+        );
+
+        // The PEL
+        assert_eq!(
+            send(&client, "XPENDING s g"),
+            Data::Array(vec![
+                Data::Integer(3),
+                Data::BulkString("1-0".into()),
+                Data::BulkString("3-0".into()),
+                Data::Array(vec![array(&["alice", "2"]), array(&["bob", "1"])]),
+            ])
+        );
+        let Data::Array(pending) = send(&client, "XPENDING s g - + 10 alice") else {
+            panic!("expected array");
+        };
+        assert_eq!(pending.len(), 2);
+        let Data::Array(entry) = &pending[0] else {
+            panic!("expected pending entry");
+        };
+        assert_eq!(entry[0], Data::BulkString("1-0".into()));
+        assert_eq!(entry[1], Data::BulkString("alice".into()));
+        assert_eq!(entry[3], Data::Integer(1));
+        assert_eq!(
+            send(&client, "XPENDING s g IDLE 60000 - + 10"),
+            Data::Array(Vec::new())
+        );
+
+        // Pending entries can be read again, until acknowledged
+        assert_eq!(send(&client, "XACK s g 1-0 3-0 9-0"), Data::Integer(2));
+        let Data::Array(streams) = send(&client, "XREADGROUP GROUP g alice STREAMS s 0") else {
+            panic!("expected array");
+        };
+        let Data::Array(stream) = &streams[0] else {
+            panic!("expected stream");
+        };
+        assert_eq!(entry_ids(stream[1].clone()), ["2-0"]);
+        assert_eq!(
+            send(&client, "XPENDING s g"),
+            Data::Array(vec![
+                Data::Integer(1),
+                Data::BulkString("2-0".into()),
+                Data::BulkString("2-0".into()),
+                Data::Array(vec![array(&["alice", "1"])]),
+            ])
+        );
+
+        let Data::Array(info) = send(&client, "XINFO GROUPS s") else {
+            panic!("expected array");
+        };
+        assert_eq!(
+            info[0],
+            Data::Array(vec![
+                Data::BulkString("name".into()),
+                Data::BulkString("g".into()),
+                Data::BulkString("consumers".into()),
+                Data::Integer(2),
+                Data::BulkString("pending".into()),
+                Data::Integer(1),
+                Data::BulkString("last-delivered-id".into()),
+                Data::BulkString("3-0".into()),
+            ])
+        );
+
+        assert_eq!(
+            send(&client, "XREADGROUP GROUP missing c STREAMS s >"),
+            Data::SimpleError(
+                "NOGROUP No such key 's' or consumer group 'missing' in XREADGROUP with GROUP option"
+                    .into()
+            )
+        );
+        assert_eq!(
+            send(&client, "XPENDING s missing"),
+            Data::SimpleError("NOGROUP No such key 's' or consumer group 'missing'".into())
+        );
+        assert_eq!(
+            send(&client, "XREADGROUP GROUP g c STREAMS s t >"),
+            Data::SimpleError(
+                "ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn xreadgroup_noack() {
+        let addr = start_master();
+        let client = connect(addr);
+
+        send(&client, "XADD s 1-0 k v");
+        assert_eq!(send(&client, "XGROUP CREATE s g 0"), ok());
+        assert!(matches!(
+            send(&client, "XREADGROUP GROUP g c NOACK STREAMS s >"),
+            Data::Array(_)
+        ));
+        assert_eq!(
+            send(&client, "XPENDING s g"),
+            Data::Array(vec![
+                Data::Integer(0),
+                Data::NullBulkString,
+                Data::NullBulkString,
+                Data::NullArray,
+            ])
+        );
     }
 }
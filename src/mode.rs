@@ -1,14 +1,16 @@
-use std::{net::SocketAddr, path::PathBuf};
+use crate::config::Config;
+use std::net::SocketAddr;
 
 #[derive(Clone, Debug)]
 pub struct MasterParams {
-    pub dir: Option<PathBuf>,
-    pub dbfilename: Option<String>,
+    pub config: Config,
 }
 
 #[derive(Clone, Debug)]
 pub struct SlaveParams {
     pub master_sockaddr : SocketAddr,
+    /// Password to AUTH with if the master requires one
+    pub masterauth: Option<String>,
 }
 
 #[derive(Clone, Debug)]
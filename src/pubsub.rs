@@ -0,0 +1,379 @@
+use crate::data::Data;
+use crate::glob::glob_match;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::mpsc::Sender;
+
+type Subscribers = HashMap<usize, Sender<Data>>;
+
+/// Channels and patterns, and their subscribers. Each subscriber is a
+/// connection in subscribe mode, identified by an id, that writes whatever it
+/// receives on its channel to the client.
+#[derive(Default)]
+pub struct PubSub {
+    next_subscriber_id: usize,
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+}
+
+fn add(map: &mut HashMap<String, Subscribers>, name: String, id: usize, tx: Sender<Data>) {
+    map.entry(name).or_default().insert(id, tx);
+}
+
+fn remove(map: &mut HashMap<String, Subscribers>, name: &str, id: usize) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of subscribers that received the message. A
+    /// subscriber receives it once for the channel and once for each
+    /// matching pattern it's subscribed to.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        // A send only fails if the subscriber's connection is going away, in
+        // which case it's about to unsubscribe anyway
+        let send = |subscribers: &Subscribers, data: Data| {
+            subscribers
+                .values()
+                .filter(|tx| tx.send(data.clone()).is_ok())
+                .count()
+        };
+
+        let mut num_receivers = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            let data = Data::Array(vec![
+                Data::BulkString("message".into()),
+                Data::BulkString(channel.into()),
+                Data::BulkString(message.into()),
+            ]);
+            num_receivers += send(subscribers, data);
+        }
+
+        for (pattern, subscribers) in self.patterns.iter() {
+            if glob_match(pattern, channel) {
+                let data = Data::Array(vec![
+                    Data::BulkString("pmessage".into()),
+                    Data::BulkString(pattern.as_str().into()),
+                    Data::BulkString(channel.into()),
+                    Data::BulkString(message.into()),
+                ]);
+                num_receivers += send(subscribers, data);
+            }
+        }
+
+        num_receivers
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a pattern
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    /// Number of subscribers of a channel, not counting pattern subscribers
+    pub fn num_subscribers(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, |s| s.len())
+    }
+
+    /// Number of patterns subscribed to by any subscriber
+    pub fn num_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+/// The subscriptions of one connection. Replies to subscribe-mode commands
+/// are sent through the same channel as published messages so that the
+/// client sees them in order.
+pub struct Subscriber {
+    id: usize,
+    tx: Sender<Data>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscriber {
+    pub fn new(pubsub: &mut PubSub, tx: Sender<Data>) -> Self {
+        let id = pubsub.next_subscriber_id;
+        pubsub.next_subscriber_id += 1;
+
+        Self {
+            id,
+            tx,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    pub fn send(&self, data: Data) {
+        // Fails only if the connection is closed, nothing to do then
+        let _ = self.tx.send(data);
+    }
+
+    // The count in the reply is the number of channels and patterns the
+    // connection is still subscribed to
+    fn send_reply(&self, kind: &str, name: Option<&str>) {
+        self.send(Data::Array(vec![
+            Data::BulkString(kind.into()),
+            name.map_or(Data::NullBulkString, |c| Data::BulkString(c.into())),
+            Data::Integer((self.channels.len() + self.patterns.len()) as i64),
+        ]));
+    }
+
+    pub fn subscribe(&mut self, pubsub: &mut PubSub, channels: Vec<String>) {
+        for channel in channels {
+            if self.channels.insert(channel.clone()) {
+                add(
+                    &mut pubsub.channels,
+                    channel.clone(),
+                    self.id,
+                    self.tx.clone(),
+                );
+            }
+            self.send_reply("subscribe", Some(&channel));
+        }
+    }
+
+    pub fn psubscribe(&mut self, pubsub: &mut PubSub, patterns: Vec<String>) {
+        for pattern in patterns {
+            if self.patterns.insert(pattern.clone()) {
+                add(
+                    &mut pubsub.patterns,
+                    pattern.clone(),
+                    self.id,
+                    self.tx.clone(),
+                );
+            }
+            self.send_reply("psubscribe", Some(&pattern));
+        }
+    }
+
+    /// Unsubscribes from all channels if `channels` is empty
+    pub fn unsubscribe(&mut self, pubsub: &mut PubSub, channels: Vec<String>) {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+
+        if channels.is_empty() {
+            self.send_reply("unsubscribe", None);
+        }
+
+        for channel in channels {
+            if self.channels.remove(&channel) {
+                remove(&mut pubsub.channels, &channel, self.id);
+            }
+            self.send_reply("unsubscribe", Some(&channel));
+        }
+    }
+
+    /// Unsubscribes from all patterns if `patterns` is empty
+    pub fn punsubscribe(&mut self, pubsub: &mut PubSub, patterns: Vec<String>) {
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+
+        if patterns.is_empty() {
+            self.send_reply("punsubscribe", None);
+        }
+
+        for pattern in patterns {
+            if self.patterns.remove(&pattern) {
+                remove(&mut pubsub.patterns, &pattern, self.id);
+            }
+            self.send_reply("punsubscribe", Some(&pattern));
+        }
+    }
+
+    /// Removes all subscriptions, without replying. Used when the connection
+    /// goes away.
+    pub fn unsubscribe_all(&mut self, pubsub: &mut PubSub) {
+        for channel in std::mem::take(&mut self.channels) {
+            remove(&mut pubsub.channels, &channel, self.id);
+        }
+        for pattern in std::mem::take(&mut self.patterns) {
+            remove(&mut pubsub.patterns, &pattern, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+
+    fn bulk(s: &str) -> Data {
+        Data::BulkString(s.into())
+    }
+
+    fn drain(rx: &Receiver<Data>) -> Vec<Data> {
+        rx.try_iter().collect()
+    }
+
+    #[test]
+    fn subscribe_and_publish() {
+        let mut pubsub = PubSub::new();
+
+        let (tx1, rx1) = mpsc::channel();
+        let mut s1 = Subscriber::new(&mut pubsub, tx1);
+        s1.subscribe(&mut pubsub, vec!["a".into(), "b".into()]);
+        assert_eq!(
+            drain(&rx1),
+            vec![
+                Data::Array(vec![bulk("subscribe"), bulk("a"), Data::Integer(1)]),
+                Data::Array(vec![bulk("subscribe"), bulk("b"), Data::Integer(2)]),
+            ]
+        );
+
+        let (tx2, rx2) = mpsc::channel();
+        let mut s2 = Subscriber::new(&mut pubsub, tx2);
+        s2.subscribe(&mut pubsub, vec!["a".into()]);
+        drain(&rx2);
+
+        assert_eq!(pubsub.publish("a", "hello"), 2);
+        assert_eq!(pubsub.publish("b", "world"), 1);
+        assert_eq!(pubsub.publish("c", "nobody"), 0);
+
+        let message = |channel, msg| Data::Array(vec![bulk("message"), bulk(channel), bulk(msg)]);
+        assert_eq!(
+            drain(&rx1),
+            vec![message("a", "hello"), message("b", "world")]
+        );
+        assert_eq!(drain(&rx2), vec![message("a", "hello")]);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let mut pubsub = PubSub::new();
+        let (tx, rx) = mpsc::channel();
+        let mut s = Subscriber::new(&mut pubsub, tx);
+
+        // Not subscribed to anything
+        s.unsubscribe(&mut pubsub, Vec::new());
+        assert_eq!(
+            drain(&rx),
+            vec![Data::Array(vec![
+                bulk("unsubscribe"),
+                Data::NullBulkString,
+                Data::Integer(0)
+            ])]
+        );
+
+        s.subscribe(&mut pubsub, vec!["a".into(), "b".into(), "c".into()]);
+        drain(&rx);
+
+        s.unsubscribe(&mut pubsub, vec!["b".into()]);
+        assert_eq!(
+            drain(&rx),
+            vec![Data::Array(vec![
+                bulk("unsubscribe"),
+                bulk("b"),
+                Data::Integer(2)
+            ])]
+        );
+        assert_eq!(pubsub.publish("b", "x"), 0);
+        assert!(s.is_subscribed());
+
+        // Unsubscribe from everything that's left
+        s.unsubscribe(&mut pubsub, Vec::new());
+        assert_eq!(drain(&rx).len(), 2);
+        assert!(!s.is_subscribed());
+        assert_eq!(pubsub.publish("a", "x"), 0);
+        assert!(pubsub.channels.is_empty());
+    }
+
+    #[test]
+    fn patterns() {
+        let mut pubsub = PubSub::new();
+        let (tx, rx) = mpsc::channel();
+        let mut s = Subscriber::new(&mut pubsub, tx);
+
+        s.subscribe(&mut pubsub, vec!["news.sport".into()]);
+        s.psubscribe(&mut pubsub, vec!["news.*".into(), "*.sport".into()]);
+        assert_eq!(
+            drain(&rx).last().unwrap(),
+            &Data::Array(vec![bulk("psubscribe"), bulk("*.sport"), Data::Integer(3)])
+        );
+        assert_eq!(pubsub.num_patterns(), 2);
+
+        // Delivered once for the channel and once per matching pattern
+        assert_eq!(pubsub.publish("news.sport", "goal"), 3);
+        let mut received = drain(&rx);
+        assert_eq!(
+            received.remove(0),
+            Data::Array(vec![bulk("message"), bulk("news.sport"), bulk("goal")])
+        );
+        received.sort_by_key(|d| d.to_string());
+        assert_eq!(
+            received,
+            vec![
+                Data::Array(vec![
+                    bulk("pmessage"),
+                    bulk("*.sport"),
+                    bulk("news.sport"),
+                    bulk("goal")
+                ]),
+                Data::Array(vec![
+                    bulk("pmessage"),
+                    bulk("news.*"),
+                    bulk("news.sport"),
+                    bulk("goal")
+                ]),
+            ]
+        );
+        assert_eq!(pubsub.publish("news.weather", "rain"), 1);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+
+        drain(&rx);
+        s.punsubscribe(&mut pubsub, Vec::new());
+        assert_eq!(drain(&rx).len(), 2);
+        assert!(s.is_subscribed());
+        assert_eq!(pubsub.num_patterns(), 0);
+        assert_eq!(pubsub.publish("news.weather", "rain"), 0);
+    }
+
+    #[test]
+    fn introspection() {
+        let mut pubsub = PubSub::new();
+        let (tx1, _rx1) = mpsc::channel();
+        let (tx2, _rx2) = mpsc::channel();
+        let mut s1 = Subscriber::new(&mut pubsub, tx1);
+        let mut s2 = Subscriber::new(&mut pubsub, tx2);
+
+        s1.subscribe(&mut pubsub, vec!["a1".into(), "b1".into()]);
+        s2.subscribe(&mut pubsub, vec!["a1".into(), "a2".into()]);
+        s2.psubscribe(&mut pubsub, vec!["a*".into()]);
+
+        let mut channels = pubsub.channels(None);
+        channels.sort();
+        assert_eq!(channels, vec!["a1", "a2", "b1"]);
+        let mut channels = pubsub.channels(Some("a*"));
+        channels.sort();
+        assert_eq!(channels, vec!["a1", "a2"]);
+
+        assert_eq!(pubsub.num_subscribers("a1"), 2);
+        assert_eq!(pubsub.num_subscribers("b1"), 1);
+        assert_eq!(pubsub.num_subscribers("c"), 0);
+        assert_eq!(pubsub.num_patterns(), 1);
+
+        s2.unsubscribe_all(&mut pubsub);
+        assert_eq!(pubsub.num_subscribers("a1"), 1);
+        assert_eq!(pubsub.num_patterns(), 0);
+    }
+}
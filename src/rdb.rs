@@ -1,3 +1,4 @@
+use crate::log::{debug, trace, warn};
 use crate::value::Value;
 use anyhow::Result;
 use std::{
@@ -99,22 +100,27 @@ fn decode_length<R: Read>(reader: &mut BufReader<R>) -> Result<Length> {
     }
 }
 
-fn decode_string<R: Read>(reader: &mut BufReader<R>) -> Result<String> {
+// Strings are binary-safe, only keys and aux fields need to be UTF-8
+fn decode_bytes<R: Read>(reader: &mut BufReader<R>) -> Result<Vec<u8>> {
     let length = decode_length(reader)?;
 
     match length {
         Length::EncodedAsInt(length) => {
             let mut buf = vec![0; length];
             reader.read_exact(&mut buf)?;
-            Ok(String::from_utf8(buf)?)
+            Ok(buf)
         }
-        Length::EncodedAsString(length_str) => Ok(length_str.to_string()),
+        Length::EncodedAsString(length_str) => Ok(length_str.to_string().into_bytes()),
     }
 }
 
+fn decode_string<R: Read>(reader: &mut BufReader<R>) -> Result<String> {
+    Ok(String::from_utf8(decode_bytes(reader)?)?)
+}
+
 fn decode_value<R: Read>(value_code: u8, reader: &mut BufReader<R>) -> Result<Value> {
     match value_code {
-        value_code::STRING => Ok(Value::String(decode_string(reader)?)),
+        value_code::STRING => Ok(Value::String(decode_bytes(reader)?)),
         _ => unimplemented!(),
     }
 }
@@ -140,7 +146,7 @@ impl Rdb {
         let magic = read_exact_as_string(5)?;
         let version = read_exact_as_string(4)?;
         assert_eq!(magic, "REDIS");
-        println!("Rdb version: {}", version);
+        debug!("RDB version: {}", version);
 
         // Parts
         let mut op_code = [0; 1];
@@ -148,27 +154,24 @@ impl Rdb {
         while f.read_exact(&mut op_code).is_ok() {
             match op_code[0] {
                 AUX => {
-                    println!("AUX");
                     let k = decode_string(&mut f)?;
                     let v = decode_string(&mut f)?;
-                    println!("Setting: {}:{}", k, v);
+                    trace!("AUX {}: {}", k, v);
                 }
                 SELECTDB => {
-                    println!("SELECTDB");
                     let db = decode_length(&mut f)?.to_usize();
-                    println!("Select db: {}", db);
+                    trace!("SELECTDB {}", db);
                 }
                 RESIZEDB => {
-                    println!("RESIZEDB");
                     let data_hashtbl_size = decode_length(&mut f)?.to_usize();
                     let expiry_hashtbl_size = decode_length(&mut f)?.to_usize();
-                    println!(
-                        "Data table size: {}. Expiry table size: {}",
-                        data_hashtbl_size, expiry_hashtbl_size
+                    trace!(
+                        "RESIZEDB: data table size: {}, expiry table size: {}",
+                        data_hashtbl_size,
+                        expiry_hashtbl_size
                     );
                 }
                 EXP_MS => {
-                    println!("EXP_MS");
                     let mut buf = [0; 8];
                     f.read_exact(&mut buf)?;
                     let exp = UNIX_EPOCH + Duration::from_millis(u64::from_le_bytes(buf));
@@ -176,7 +179,7 @@ impl Rdb {
 
                     f.read_exact(&mut op_code)?;
                     let (key, value) = decode_key_value(op_code[0], &mut f)?;
-                    println!("KV: {}, {:?}, exp={:?}", key, value, exp);
+                    trace!("Key {}: {:?}, expires at {:?}", key, value, exp);
 
                     if exp > curr {
                         let exp_in = exp.duration_since(curr)?;
@@ -184,16 +187,13 @@ impl Rdb {
                     }
                 }
                 EOF => {
-                    println!("EOF");
                     let mut buf = Vec::new();
                     f.read_to_end(&mut buf)?;
-                    println!("Checksum: {:?}", buf);
+                    trace!("EOF, checksum: {:?}", buf);
                 }
                 value_code => {
-                    println!("VALUE");
-
                     let (key, value) = decode_key_value(value_code, &mut f)?;
-                    println!("KV: {}, {:?}", key, value);
+                    trace!("Key {}: {:?}", key, value);
 
                     store.set(key, value, None);
                 }
@@ -215,7 +215,7 @@ impl Rdb {
                     Self::read_from_buf(f)
                 }
                 Err(err) => {
-                    println!("Error opening file: {}", err);
+                    warn!("Error opening the RDB file: {}", err);
                     Ok(empty)
                 }
             },
@@ -279,6 +279,13 @@ mod tests {
         assert_eq!(d(&[0b1000_0000, 0xff, 0xff, 0xff, 0xff, 0xff]), 4294967295);
     }
 
+    #[test]
+    fn test_decode_binary_string() {
+        let bytes = decode_bytes(&mut BufReader::new(&[0x02, 0xff, 0x00][..])).unwrap();
+        assert_eq!(bytes, [0xff, 0x00]);
+        assert!(decode_string(&mut BufReader::new(&[0x02, 0xff, 0x00][..])).is_err());
+    }
+
     #[test]
     fn test_read() {
         let rdb = Rdb::read_from_buf(BufReader::new(&single_key_rdb()[..])).unwrap();
@@ -1,27 +1,72 @@
+use crate::command;
 use crate::connection::Connection;
 use crate::data::Data;
-use crate::store::Store;
-use crate::value::Value;
-use anyhow::{anyhow, Result};
+use crate::log::{debug, info, trace, warn};
+use crate::mode::SlaveParams;
+use crate::store::{ActiveExpiry, StoreDb};
+use anyhow::{anyhow, bail, Result};
 use std::{
-    net::{SocketAddr, TcpStream},
+    net::TcpStream,
     sync::{Arc, Mutex},
     thread,
-    time::Duration,
 };
 
 pub struct Replica {
     master_replication_id: String,
     replication_offset: Arc<Mutex<usize>>,
-    store: Arc<Mutex<Store>>,
+    dbs: Arc<Mutex<StoreDb>>,
+    active_expiry: ActiveExpiry,
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.active_expiry.stop();
+    }
+}
+
+// Executes a command on database `db`, returning the reply. SELECT changes
+// `db`. FLUSHALL and COPY are handled here since they're not about a single
+// database.
+fn execute(dbs: &StoreDb, db: &mut usize, vs: &[Data]) -> Data {
+    let args = command::Args::new(vs);
+    let result = match args.name() {
+        Ok(name) if name == "select" => command::select(&args).map(|selected| {
+            *db = selected;
+            Data::SimpleString("OK".into())
+        }),
+        Ok(name) if name == "flushall" => command::flush_async(&args).map(|lazy| {
+            dbs.iter().for_each(|store| {
+                store.flush(lazy);
+            });
+            Data::SimpleString("OK".into())
+        }),
+        Ok(name) if name == "copy" => {
+            command::copy(dbs, *db, &args).map(|copied| copied.outcome.reply)
+        }
+        _ => Ok(command::execute(dbs.get(*db), vs).reply),
+    };
+
+    result.unwrap_or_else(|err| Data::SimpleError(err.to_string()))
 }
 
 impl Replica {
-    pub fn new(master_sockaddr: SocketAddr, port: u16) -> Result<Arc<Self>> {
+    pub fn new(params: SlaveParams, port: u16) -> Result<Arc<Self>> {
         // If it's a slave, handshake with master
-        let master_stream = TcpStream::connect(master_sockaddr)?;
+        let master_stream = TcpStream::connect(params.master_sockaddr)?;
         let conn = Connection::new(master_stream);
 
+        // AUTH
+        if let Some(password) = params.masterauth {
+            conn.write_data(Data::Array(vec![
+                Data::BulkString("AUTH".into()),
+                Data::BulkString(password.into()),
+            ]))?;
+            let resp = conn.read_data()?;
+            if resp != Data::SimpleString("OK".into()) {
+                bail!("Failed to authenticate with master: {}", resp);
+            }
+        }
+
         // PING
         conn.write_data(Data::Array(vec![Data::BulkString("PING".into())]))?;
         assert_eq!(conn.read_data()?, Data::SimpleString("PONG".into()));
@@ -57,15 +102,20 @@ impl Replica {
         } else {
             panic!("Expect FULLRESYNC");
         };
-        println!("Master replication id: {}", master_replication_id);
+        info!("Master replication id: {}", master_replication_id);
         let rdb_file = conn.read_rdb_file()?;
-        println!("Rdb file is {} bytes long", rdb_file.len());
+        info!("Received an RDB file of {} bytes", rdb_file.len());
 
-        println!("Finished handshaking!");
+        info!("Finished handshaking with the master");
+        // Expirations aren't replicated, so replicas remove expired keys
+        // themselves
+        let dbs = StoreDb::new();
+        let active_expiry = dbs.start_active_expiry();
         let replica = Arc::new(Self {
             master_replication_id,
             replication_offset: Arc::new(Mutex::new(0)),
-            store: Arc::new(Mutex::new(Store::new())),
+            dbs: Arc::new(Mutex::new(dbs)),
+            active_expiry,
         });
 
         let replica_clone = replica.clone();
@@ -75,14 +125,16 @@ impl Replica {
     }
 
     fn handle_replication(self: Arc<Self>, conn: Connection) -> Result<()> {
-        println!("Start handling replication cmds...");
+        debug!("Start handling replicated commands");
         let conn = Arc::new(conn);
+        // The database the master selected
+        let mut db = 0;
 
         loop {
             let res = conn.read_data();
 
             if let Ok(data) = res {
-                println!("Replication : {}", data);
+                debug!("Replicated command: {}", data);
                 let cmd_len = data.num_bytes();
                 match data {
                     Data::Array(vs) => {
@@ -91,25 +143,7 @@ impl Replica {
                         };
 
                         match string_at(0)?.to_ascii_uppercase().as_str() {
-                            "PING" => println!("Received PING from master"),
-                            "SET" => {
-                                let store = self.store.lock().unwrap();
-
-                                assert!(vs.len() == 3 || vs.len() == 5);
-                                let key = string_at(1)?;
-                                let value = string_at(2)?;
-
-                                let expire_in = if vs.len() == 5 {
-                                    let px = string_at(3)?;
-                                    assert_eq!(px.to_ascii_lowercase(), "px");
-                                    let expire_in: u64 = string_at(4)?.parse()?;
-                                    Some(Duration::from_millis(expire_in))
-                                } else {
-                                    None
-                                };
-
-                                store.set(key, Value::String(value), expire_in);
-                            }
+                            "PING" => trace!("Received PING from master"),
                             "REPLCONF" => {
                                 assert_eq!(vs.len(), 3);
                                 assert_eq!(string_at(1)?, "GETACK");
@@ -123,12 +157,17 @@ impl Replica {
                                     ),
                                 ]))?
                             }
-                            command => panic!("unknown command: {}", command),
+                            _ => {
+                                let dbs = self.dbs.lock().unwrap();
+                                if let Data::SimpleError(err) = execute(&dbs, &mut db, &vs) {
+                                    warn!("Error applying replicated command: {}", err);
+                                }
+                            }
                         };
 
                         let mut offset = self.replication_offset.lock().unwrap();
                         *offset += cmd_len;
-                        println!("Replication offset: {}", offset);
+                        debug!("Replication offset: {}", offset);
                     }
                     _ => panic!("Unknown replicaiton cmd: {}", data),
                 }
@@ -140,17 +179,24 @@ impl Replica {
         Ok(())
     }
 
-    pub fn handle_connection(&self, stream: TcpStream) -> Result<()> {
-        println!("Start handing queries...");
+    pub fn handle_connection(&self, mut conn: Connection) -> Result<()> {
+        info!("Accepted connection");
+        // The database selected by the client
+        let mut db = 0;
 
-        let mut conn = Connection::new(stream);
         loop {
             let res = conn.read_data();
 
             match res {
-                Ok(data) => self.handle_data(&mut conn, data)?,
+                Ok(data) => {
+                    if self.handle_data(&mut conn, &mut db, data)? {
+                        debug!("Closing connection: quit");
+                        conn.close()?;
+                        break;
+                    }
+                }
                 Err(error) => {
-                    println!("Error: {}, will close connection", error);
+                    debug!("Closing connection: {}", error);
                     break;
                 }
             }
@@ -159,8 +205,9 @@ impl Replica {
         Ok(())
     }
 
-    fn handle_data(&self, conn: &mut Connection, data: Data) -> Result<()> {
-        println!("Recv: {}", data);
+    // Returns true for QUIT, after which the connection is closed
+    fn handle_data(&self, conn: &mut Connection, db: &mut usize, data: Data) -> Result<bool> {
+        debug!("Received: {}", data);
         match data {
             Data::Array(vs) => {
                 let string_at = |idx: usize| -> Result<String> {
@@ -168,43 +215,6 @@ impl Replica {
                 };
 
                 match string_at(0)?.to_ascii_lowercase().as_str() {
-                    "ping" => conn.write_data(Data::SimpleString("PONG".into()))?,
-                    "echo" => {
-                        assert_eq!(vs.len(), 2);
-                        let string = string_at(1)?;
-                        conn.write_data(Data::BulkString(string.into()))?
-                    }
-                    "get" => {
-                        let store = self.store.lock().unwrap();
-
-                        assert_eq!(vs.len(), 2);
-                        let key = string_at(1)?;
-                        match store.get(&key) {
-                            None => conn.write_data(Data::NullBulkString)?,
-                            Some(value) => {
-                                conn.write_data(Data::BulkString(value.to_string().into()))?
-                            }
-                        }
-                    }
-                    "set" => {
-                        let store = self.store.lock().unwrap();
-
-                        assert!(vs.len() == 3 || vs.len() == 5);
-                        let key = string_at(1)?;
-                        let value = string_at(2)?;
-
-                        let expire_in = if vs.len() == 5 {
-                            let px = string_at(3)?;
-                            assert_eq!(px.to_ascii_lowercase(), "px");
-                            let expire_in: u64 = string_at(4)?.parse()?;
-                            Some(Duration::from_millis(expire_in))
-                        } else {
-                            None
-                        };
-
-                        store.set(key, Value::String(value), expire_in);
-                        conn.write_data(Data::SimpleString("OK".into()))?
-                    }
                     "info" => match string_at(1)?.to_ascii_lowercase().as_str() {
                         "replication" => {
                             let role = String::from("role:slave");
@@ -221,12 +231,22 @@ impl Replica {
                         }
                         info_type => panic!("unknown info type: {}", info_type),
                     },
-                    command => println!("unknown command: {}", command),
+                    "quit" => {
+                        conn.write_data(Data::SimpleString("OK".into()))?;
+                        return Ok(true);
+                    }
+                    name if command::is_write(name) => {
+                        conn.write_data(Data::SimpleError(command::READONLY_ERR_MSG.into()))?
+                    }
+                    _ => {
+                        let dbs = self.dbs.lock().unwrap();
+                        conn.write_data(execute(&dbs, db, &vs))?
+                    }
                 }
             }
             _ => panic!("Unknown: {}", data),
         };
 
-        Ok(())
+        Ok(false)
     }
 }
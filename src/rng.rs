@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small seedable pseudo-random number generator (SplitMix64). Commands
+/// that pick random elements take one of these, so that tests can seed it and
+/// get deterministic results.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeded from the current time
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a float in `0.0..1.0`
+    pub fn next_f64(&mut self) -> f64 {
+        // The top 53 bits fit exactly in the mantissa
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns an index in `0..len`. `len` must be positive.
+    pub fn index(&mut self, len: usize) -> usize {
+        assert!(len > 0);
+        // Maps the 64 random bits onto the range by multiplication, which
+        // avoids the bias of taking the remainder
+        ((self.next_u64() as u128 * len as u128) >> 64) as usize
+    }
+
+    /// Picks random items, as HRANDFIELD and SRANDMEMBER do. With a
+    /// non-negative `count` the items are distinct and there are at most
+    /// `count` of them. With a negative `count` there are exactly `-count` of
+    /// them and items may repeat. `items` has `len` items, which must not be
+    /// 0. Only the picked items are cloned, and `items` is walked once, up to
+    /// the last picked one.
+    pub fn sample<I>(&mut self, items: I, len: usize, count: i64) -> Vec<I::Item>
+    where
+        I: Iterator,
+        I::Item: Clone,
+    {
+        let positions = self.sample_positions(len, count);
+
+        // Visit the positions in order, then put the items back in the
+        // order they were picked
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_key(|&i| positions[i]);
+        let mut picked: Vec<Option<I::Item>> = (0..positions.len()).map(|_| None).collect();
+        let mut items = items.enumerate();
+        let mut current: Option<(usize, I::Item)> = None;
+        for i in order {
+            let position = positions[i];
+            if current.as_ref().is_none_or(|(at, _)| *at != position) {
+                current = items.by_ref().find(|(at, _)| *at == position);
+            }
+            picked[i] = current.as_ref().map(|(_, item)| item.clone());
+        }
+        picked.into_iter().map(|item| item.unwrap()).collect()
+    }
+
+    // The positions in `0..len` picked by `sample`
+    fn sample_positions(&mut self, len: usize, count: i64) -> Vec<usize> {
+        assert!(len > 0);
+        if count < 0 {
+            return (0..count.unsigned_abs()).map(|_| self.index(len)).collect();
+        }
+
+        // Partial Fisher-Yates shuffle of `0..len`, where only the positions
+        // that were swapped are stored
+        let count = (count as usize).min(len);
+        let mut swapped: HashMap<usize, usize> = HashMap::new();
+        (0..count)
+            .map(|i| {
+                let j = i + self.index(len - i);
+                let picked = swapped.get(&j).copied().unwrap_or(j);
+                let at_i = swapped.get(&i).copied().unwrap_or(i);
+                swapped.insert(j, at_i);
+                picked
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..10 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+    }
+
+    #[test]
+    fn index_in_range() {
+        let mut rng = Rng::new(7);
+        let mut seen = [false; 5];
+        for _ in 0..1000 {
+            seen[rng.index(5)] = true;
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(rng.index(1), 0);
+    }
+
+    #[test]
+    fn sample() {
+        let mut rng = Rng::new(7);
+        let items: Vec<i32> = (0..10).collect();
+
+        let mut distinct = rng.sample(items.iter(), 10, 20);
+        distinct.sort();
+        assert_eq!(distinct, items.iter().collect::<Vec<_>>());
+        let some = rng.sample(items.iter(), 10, 3);
+        assert_eq!(some.len(), 3);
+        assert!(some
+            .iter()
+            .all(|item| some.iter().filter(|i| *i == item).count() == 1));
+
+        let repeated = rng.sample([1, 2].iter(), 2, -50);
+        assert_eq!(repeated.len(), 50);
+        assert!(repeated.contains(&&1) && repeated.contains(&&2));
+    }
+
+    #[test]
+    fn sample_positions_are_uniform() {
+        let mut rng = Rng::new(7);
+        let mut counts = [0; 10];
+        for _ in 0..10_000 {
+            for position in rng.sample_positions(10, 3) {
+                counts[position] += 1;
+            }
+        }
+        // Each position is picked 3 times in 10
+        assert!(
+            counts.iter().all(|&c| (2700..3300).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+}
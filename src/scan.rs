@@ -0,0 +1,312 @@
+//! Cursor-based iteration for SCAN and friends.
+//!
+//! Items are visited in the order of a stable hash of their name, and the
+//! cursor is the hash of the next item to return. So a scan needs no state on
+//! the server, and it returns every item that exists for the whole scan, no
+//! matter what is added or removed in between. Items added or removed during
+//! the scan may or may not be returned.
+//!
+//! Scanned collections keep their names in that order in an `Index`, so that a
+//! batch starts from the cursor without going through the whole collection.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Deref;
+
+/// 64-bit FNV-1a hash
+pub fn hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Names ordered by their hash
+#[derive(Clone, Debug, Default)]
+pub struct Index(BTreeSet<(u64, String)>);
+
+impl Index {
+    pub fn insert(&mut self, name: &str) {
+        self.0.insert((hash(name), name.to_string()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.remove(&(hash(name), name.to_string()));
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// All names, starting from the first one whose hash is at least
+    /// `position` and wrapping around. Finding the start takes O(log n), so
+    /// it's a cheap way to pick names from a random place.
+    pub fn names_from(&self, position: u64) -> impl Iterator<Item = &String> {
+        let start = (position, String::new());
+        self.0
+            .range(start.clone()..)
+            .chain(self.0.range(..start))
+            .map(|(_, name)| name)
+    }
+
+    /// Returns about `count` names, starting from `cursor`, and the cursor to
+    /// continue from, which is 0 once all names have been returned. Names
+    /// with the same hash are always returned together, so a batch may have
+    /// more than `count` names.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&String>) {
+        let mut names = self.0.range((cursor, String::new())..);
+        let mut batch: Vec<&(u64, String)> = names.by_ref().take(count).collect();
+        for next in names {
+            if batch.last().is_some_and(|(hash, _)| *hash == next.0) {
+                batch.push(next);
+            } else {
+                let batch = batch.into_iter().map(|(_, name)| name).collect();
+                return (next.0, batch);
+            }
+        }
+        (0, batch.into_iter().map(|(_, name)| name).collect())
+    }
+}
+
+/// A map from names that can be scanned. It derefs to the map to read it,
+/// while writes go through its own methods, which keep the index up to date.
+#[derive(Clone, Debug)]
+pub struct ScanMap<V> {
+    map: HashMap<String, V>,
+    index: Index,
+}
+
+impl<V> Default for ScanMap<V> {
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+            index: Index::default(),
+        }
+    }
+}
+
+impl<V> Deref for ScanMap<V> {
+    type Target = HashMap<String, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<V> ScanMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut V> {
+        self.map.get_mut(name)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.map.values_mut()
+    }
+
+    pub fn insert(&mut self, name: String, value: V) -> Option<V> {
+        if !self.map.contains_key(&name) {
+            self.index.insert(&name);
+        }
+        self.map.insert(name, value)
+    }
+
+    /// The value of `name`, inserting the one built by `default` first if
+    /// it's missing
+    pub fn get_or_insert_with(&mut self, name: String, default: impl FnOnce(&str) -> V) -> &mut V {
+        if !self.map.contains_key(&name) {
+            self.index.insert(&name);
+        }
+        self.map
+            .entry(name)
+            .or_insert_with_key(|name| default(name))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<V> {
+        let value = self.map.remove(name)?;
+        self.index.remove(name);
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&String, &mut V) -> bool) {
+        let index = &mut self.index;
+        self.map.retain(|name, value| {
+            let keep = f(name, value);
+            if !keep {
+                index.remove(name);
+            }
+            keep
+        });
+    }
+
+    /// Like `Index::names_from`
+    pub fn names_from(&self, position: u64) -> impl Iterator<Item = &String> {
+        self.index.names_from(position)
+    }
+
+    /// Like `Index::scan`, with the values
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, &V)>) {
+        let (cursor, names) = self.index.scan(cursor, count);
+        let batch = names
+            .into_iter()
+            .map(|name| (name, &self.map[name]))
+            .collect();
+        (cursor, batch)
+    }
+}
+
+/// A set of names that can be scanned, like `ScanMap`
+#[derive(Clone, Debug, Default)]
+pub struct ScanSet {
+    set: HashSet<String>,
+    index: Index,
+}
+
+impl Deref for ScanSet {
+    type Target = HashSet<String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.set
+    }
+}
+
+impl FromIterator<String> for ScanSet {
+    fn from_iter<I: IntoIterator<Item = String>>(names: I) -> Self {
+        let mut set = Self::default();
+        set.extend(names);
+        set
+    }
+}
+
+impl Extend<String> for ScanSet {
+    fn extend<I: IntoIterator<Item = String>>(&mut self, names: I) {
+        for name in names {
+            self.insert(name);
+        }
+    }
+}
+
+impl ScanSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `name` wasn't in the set yet
+    pub fn insert(&mut self, name: String) -> bool {
+        if self.set.contains(&name) {
+            return false;
+        }
+        self.index.insert(&name);
+        self.set.insert(name)
+    }
+
+    /// Returns whether `name` was in the set
+    pub fn remove(&mut self, name: &str) -> bool {
+        if !self.set.remove(name) {
+            return false;
+        }
+        self.index.remove(name);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.set.clear();
+        self.index.clear();
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&String>) {
+        self.index.scan(cursor, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn fnv1a() {
+        assert_eq!(hash(""), 0xcbf29ce484222325);
+        assert_eq!(hash("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn covers_all_items() {
+        let set: ScanSet = (0..100).map(|i| format!("item:{}", i)).collect();
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut num_batches = 0;
+        loop {
+            let (next, batch) = set.scan(cursor, 7);
+            assert!(!batch.is_empty());
+            for name in batch {
+                assert!(seen.insert(name.clone()), "{} returned twice", name);
+            }
+            num_batches += 1;
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(seen.len(), 100);
+        assert_eq!(num_batches, 15);
+    }
+
+    #[test]
+    fn items_removed_during_scan() {
+        let mut set: ScanSet = (0..20).map(|i| i.to_string()).collect();
+
+        let (cursor, first) = set.scan(0, 5);
+        let first: Vec<String> = first.into_iter().cloned().collect();
+
+        // Remove some of the items that haven't been returned yet
+        let removed: Vec<String> = set
+            .iter()
+            .filter(|n| !first.contains(n))
+            .take(3)
+            .cloned()
+            .collect();
+        for name in removed.iter() {
+            assert!(set.remove(name));
+        }
+
+        let (cursor, rest) = set.scan(cursor, 100);
+        assert_eq!(cursor, 0);
+        assert_eq!(first.len() + rest.len(), 17);
+    }
+
+    #[test]
+    fn names_from_wraps_around() {
+        let set: ScanSet = (0..10).map(|i| i.to_string()).collect();
+        let (_, all) = set.scan(0, 100);
+
+        for (i, name) in all.iter().enumerate() {
+            let names: Vec<_> = set.index.names_from(hash(name)).collect();
+            let expected: Vec<_> = all[i..].iter().chain(&all[..i]).copied().collect();
+            assert_eq!(names, expected);
+        }
+        let names: Vec<_> = set.index.names_from(u64::MAX).collect();
+        assert_eq!(names.len(), 10);
+    }
+
+    #[test]
+    fn map_keeps_index_up_to_date() {
+        let mut map = ScanMap::new();
+        map.insert("a".to_string(), 1);
+        map.insert("a".to_string(), 2);
+        *map.get_or_insert_with("b".into(), |_| 0) += 1;
+        *map.get_or_insert_with("b".into(), |_| 0) += 1;
+        map.insert("c".to_string(), 3);
+        assert_eq!(map.remove("c"), Some(3));
+        assert_eq!(map.remove("c"), None);
+        map.insert("d".to_string(), 4);
+        map.retain(|name, _| name != "d");
+
+        let (cursor, mut batch) = map.scan(0, 10);
+        batch.sort();
+        assert_eq!(cursor, 0);
+        assert_eq!(batch, [(&"a".to_string(), &2), (&"b".to_string(), &2)]);
+    }
+}
@@ -0,0 +1,441 @@
+use crate::scan::ScanMap;
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+const NAN_SCORE_ERR_MSG: &str = "ERR resulting score is not a number (NaN)";
+const SCORE_BOUND_ERR_MSG: &str = "ERR min or max is not a float";
+const LEX_BOUND_ERR_MSG: &str = "ERR min or max not valid string range item";
+
+/// A sorted set: members with a score, ordered by score and then by member.
+/// Scores are never NaN.
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: ScanMap<f64>,
+    // Every (score, member) pair, in order
+    ordered: BTreeSet<(Score, String)>,
+}
+
+// A score, which can be ordered since it's never NaN
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Flags of ZADD that restrict which members are added or updated
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddOptions {
+    /// NX: only add new members
+    pub nx: bool,
+    /// XX: only update existing members
+    pub xx: bool,
+    /// GT: only update existing members if the new score is greater
+    pub gt: bool,
+    /// LT: only update existing members if the new score is less
+    pub lt: bool,
+    /// INCR: add the score to the member's score instead of replacing it
+    pub incr: bool,
+}
+
+/// How ZUNIONSTORE and ZINTERSTORE combine the scores of a member found in
+/// several sets
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Aggregate {
+    #[default]
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which Redis turns into 0
+            Self::Sum => zero_if_nan(a + b),
+            Self::Min => a.min(b),
+            Self::Max => a.max(b),
+        }
+    }
+}
+
+/// Scores can't be NaN, so results like inf * 0 are 0 instead
+pub fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// A range of members, as given to ZRANGE
+#[derive(Clone, Debug, PartialEq)]
+pub enum Range {
+    /// Positions, which count from the end when negative. Both are
+    /// inclusive.
+    Index(i64, i64),
+    /// Scores between a min and a max
+    Score(Bound<f64>, Bound<f64>),
+    /// Members between a min and a max, compared as bytes. Only meaningful
+    /// when all the members have the same score.
+    Lex(Bound<String>, Bound<String>),
+}
+
+/// What ZRANGE and ZRANGESTORE select: the members in `range`, in reverse
+/// order if `rev`, skipping `offset` of them and taking up to `count`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeQuery {
+    pub range: Range,
+    pub rev: bool,
+    pub offset: usize,
+    pub count: usize,
+}
+
+/// Parses a min or max score: a float, which is exclusive if it starts with
+/// `(`. `-inf` and `+inf` are allowed.
+pub fn parse_score_bound(s: &str) -> Result<Bound<f64>> {
+    let (s, exclusive) = match s.strip_prefix('(') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    let Some(score) = s.parse::<f64>().ok().filter(|score| !score.is_nan()) else {
+        bail!(SCORE_BOUND_ERR_MSG);
+    };
+    Ok(if exclusive {
+        Bound::Excluded(score)
+    } else {
+        Bound::Included(score)
+    })
+}
+
+/// Parses a min or max member: `[member` is inclusive, `(member` is
+/// exclusive, and `-` and `+` are lower and higher than any member
+pub fn parse_lex_bound(s: &str) -> Result<Bound<String>> {
+    match s {
+        "-" | "+" => Ok(Bound::Unbounded),
+        _ if s.starts_with('[') => Ok(Bound::Included(s[1..].into())),
+        _ if s.starts_with('(') => Ok(Bound::Excluded(s[1..].into())),
+        _ => bail!(LEX_BOUND_ERR_MSG),
+    }
+}
+
+// Tells whether a member with its score is in some range
+type MemberFilter<'a> = Box<dyn Fn(&String, f64) -> bool + 'a>;
+
+// Whether `x` is above the lower bound `min`
+fn above<T: PartialOrd + ?Sized>(min: Bound<&T>, x: &T) -> bool {
+    match min {
+        Bound::Included(min) => x >= min,
+        Bound::Excluded(min) => x > min,
+        Bound::Unbounded => true,
+    }
+}
+
+// Whether `x` is below the upper bound `max`
+fn below<T: PartialOrd + ?Sized>(max: Bound<&T>, x: &T) -> bool {
+    match max {
+        Bound::Included(max) => x <= max,
+        Bound::Excluded(max) => x < max,
+        Bound::Unbounded => true,
+    }
+}
+
+/// What `SortedSet::add` did with a member, with its score afterwards
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Added {
+    New(f64),
+    Updated(f64),
+    /// The member already had that score
+    Unchanged(f64),
+    /// The flags didn't allow adding or updating the member
+    Skipped,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Removes `member`, returning its score
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.ordered.remove(&(Score(score), member.to_string()));
+        Some(score)
+    }
+
+    /// The position of `member` in order, or in reverse order if `rev`.
+    /// Members with the same score are ordered by member. Counting the
+    /// members before it takes linear time.
+    pub fn rank(&self, member: &str, rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let before = self
+            .ordered
+            .range(..(Score(score), member.to_string()))
+            .count();
+        Some(if rev { self.len() - 1 - before } else { before })
+    }
+
+    /// One batch of ZSCAN: about `count` members with their scores, starting
+    /// from `cursor`, and the next cursor
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&String, f64)>) {
+        let (cursor, batch) = self.scores.scan(cursor, count);
+        let batch = batch
+            .into_iter()
+            .map(|(member, score)| (member, *score))
+            .collect();
+        (cursor, batch)
+    }
+
+    /// Members with their scores, in order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&String, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// Members in `range` with their scores, in order, or in reverse order
+    /// if `rev`. With `rev`, indexes count from the last member.
+    ///
+    /// Score and lex ranges are found by walking the members from the
+    /// start, which takes linear time.
+    pub fn range<'a>(
+        &'a self,
+        range: &'a Range,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&'a String, f64)> + 'a> {
+        let members: Box<dyn Iterator<Item = (&String, f64)>> = if rev {
+            Box::new(self.iter().rev())
+        } else {
+            Box::new(self.iter())
+        };
+
+        // Whether a member is above the min and below the max
+        let (above_min, below_max): (MemberFilter, MemberFilter) = match range {
+            Range::Index(start, stop) => {
+                return match self.index_range(*start, *stop) {
+                    None => Box::new(std::iter::empty()),
+                    Some((start, stop)) => Box::new(members.skip(start).take(stop - start + 1)),
+                };
+            }
+            Range::Score(min, max) => (
+                Box::new(|_, score| above(min.as_ref(), &score)),
+                Box::new(|_, score| below(max.as_ref(), &score)),
+            ),
+            Range::Lex(min, max) => (
+                Box::new(|member, _| above(min.as_ref(), member)),
+                Box::new(|member, _| below(max.as_ref(), member)),
+            ),
+        };
+
+        if rev {
+            Box::new(
+                members
+                    .skip_while(move |(member, score)| !below_max(member, *score))
+                    .take_while(move |(member, score)| above_min(member, *score)),
+            )
+        } else {
+            Box::new(
+                members
+                    .skip_while(move |(member, score)| !above_min(member, *score))
+                    .take_while(move |(member, score)| below_max(member, *score)),
+            )
+        }
+    }
+
+    /// The members selected by `query`, with their scores
+    pub fn query<'a>(&'a self, query: &'a RangeQuery) -> impl Iterator<Item = (&'a String, f64)> {
+        self.range(&query.range, query.rev)
+            .skip(query.offset)
+            .take(query.count)
+    }
+
+    // Turns `start` and `stop`, which may be negative, into positions of
+    // members. Returns None if there's no member between them.
+    fn index_range(&self, start: i64, stop: i64) -> Option<(usize, usize)> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return None;
+        }
+        Some((start as usize, stop as usize))
+    }
+
+    /// Adds `member` or updates its score, as ZADD does. Fails if INCR would
+    /// make the score NaN.
+    pub fn add(&mut self, member: String, score: f64, options: &AddOptions) -> Result<Added> {
+        let old = self.score(&member);
+        let new = match old {
+            Some(old) if options.incr => old + score,
+            _ => score,
+        };
+        if new.is_nan() {
+            bail!(NAN_SCORE_ERR_MSG);
+        }
+        // -0 and 0 are the same score
+        let new = if new == 0.0 { 0.0 } else { new };
+
+        let added = match old {
+            None if options.xx => return Ok(Added::Skipped),
+            None => Added::New(new),
+            Some(_) if options.nx => return Ok(Added::Skipped),
+            Some(old) if (options.gt && new <= old) || (options.lt && new >= old) => {
+                return Ok(Added::Skipped)
+            }
+            Some(old) if old == new => return Ok(Added::Unchanged(old)),
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.clone()));
+                Added::Updated(new)
+            }
+        };
+        self.ordered.insert((Score(new), member.clone()));
+        self.scores.insert(member, new);
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        let options = AddOptions::default();
+        for (member, score) in [("c", 1.0), ("a", 2.0), ("b", 1.0), ("d", -0.0)] {
+            zset.add(member.into(), score, &options).unwrap();
+        }
+        assert_eq!(
+            zset.add("a".into(), 0.5, &options).unwrap(),
+            Added::Updated(0.5)
+        );
+
+        let members: Vec<_> = zset.iter().map(|(m, s)| (m.as_str(), s)).collect();
+        assert_eq!(
+            members,
+            vec![("d", 0.0), ("a", 0.5), ("b", 1.0), ("c", 1.0)]
+        );
+        assert_eq!(zset.len(), 4);
+    }
+
+    #[test]
+    fn parse_bounds() {
+        assert_eq!(parse_score_bound("1.5").unwrap(), Bound::Included(1.5));
+        assert_eq!(parse_score_bound("(2").unwrap(), Bound::Excluded(2.0));
+        assert_eq!(
+            parse_score_bound("-inf").unwrap(),
+            Bound::Included(f64::NEG_INFINITY)
+        );
+        assert_eq!(
+            parse_score_bound("(+inf").unwrap(),
+            Bound::Excluded(f64::INFINITY)
+        );
+        for s in ["", "(", "[1", "nan", "1x"] {
+            assert!(parse_score_bound(s).is_err(), "{}", s);
+        }
+
+        assert_eq!(parse_lex_bound("-").unwrap(), Bound::Unbounded);
+        assert_eq!(parse_lex_bound("+").unwrap(), Bound::Unbounded);
+        assert_eq!(parse_lex_bound("[a").unwrap(), Bound::Included("a".into()));
+        assert_eq!(parse_lex_bound("(").unwrap(), Bound::Excluded("".into()));
+        for s in ["", "a", "-a"] {
+            assert!(parse_lex_bound(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn index_range() {
+        let mut zset = SortedSet::new();
+        for (i, member) in ["a", "b", "c", "d"].iter().enumerate() {
+            zset.add(member.to_string(), i as f64, &AddOptions::default())
+                .unwrap();
+        }
+        let members = |range: Range, rev: bool| -> Vec<String> {
+            zset.range(&range, rev).map(|(m, _)| m.clone()).collect()
+        };
+        assert_eq!(members(Range::Index(1, 2), false), vec!["b", "c"]);
+        assert_eq!(members(Range::Index(-2, -1), false), vec!["c", "d"]);
+        assert_eq!(members(Range::Index(-10, 10), false).len(), 4);
+        assert_eq!(members(Range::Index(0, 0), true), vec!["d"]);
+        assert!(members(Range::Index(2, 1), false).is_empty());
+        assert!(members(Range::Index(4, 10), false).is_empty());
+        assert!(members(Range::Index(0, -5), false).is_empty());
+    }
+
+    #[test]
+    fn add_options() {
+        let mut zset = SortedSet::new();
+        zset.add("a".into(), 5.0, &AddOptions::default()).unwrap();
+
+        let nx = AddOptions {
+            nx: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.add("a".into(), 1.0, &nx).unwrap(), Added::Skipped);
+        let xx = AddOptions {
+            xx: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.add("b".into(), 1.0, &xx).unwrap(), Added::Skipped);
+        assert_eq!(zset.score("b"), None);
+
+        let gt = AddOptions {
+            gt: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.add("a".into(), 3.0, &gt).unwrap(), Added::Skipped);
+        assert_eq!(zset.add("a".into(), 7.0, &gt).unwrap(), Added::Updated(7.0));
+        // GT doesn't stop new members from being added
+        assert_eq!(zset.add("c".into(), 1.0, &gt).unwrap(), Added::New(1.0));
+        let lt = AddOptions {
+            lt: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.add("a".into(), 9.0, &lt).unwrap(), Added::Skipped);
+
+        let incr = AddOptions {
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            zset.add("a".into(), 0.0, &incr).unwrap(),
+            Added::Unchanged(7.0)
+        );
+        assert_eq!(
+            zset.add("a".into(), f64::INFINITY, &incr).unwrap(),
+            Added::Updated(f64::INFINITY)
+        );
+        assert!(zset.add("a".into(), f64::NEG_INFINITY, &incr).is_err());
+        assert_eq!(zset.score("a"), Some(f64::INFINITY));
+    }
+}
@@ -1,79 +1,1949 @@
-use crate::stream::{Entry, EntryId, Stream};
-use crate::value::Value;
-use anyhow::Result;
-use crossbeam_channel::Receiver;
+use crate::bitmap::{self, BitOp, BitfieldOp, Unit};
+use crate::hash::Hash;
+use crate::hyperloglog;
+use crate::rng::Rng;
+use crate::scan::{ScanMap, ScanSet};
+use crate::sorted_set::{self, AddOptions, Added, Aggregate, Range, RangeQuery, SortedSet};
+use crate::stream::{Entry, EntryId, GroupEntry, Stream, StreamInfo, Trim};
+use crate::value::{Value, WRONG_TYPE_ERR_MSG};
+use anyhow::{anyhow, bail, Result};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
     ops::Bound,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Clone, Debug)]
+const HASH_VALUE_NOT_INTEGER_ERR_MSG: &str = "ERR hash value is not an integer";
+const HASH_VALUE_NOT_FLOAT_ERR_MSG: &str = "ERR hash value is not a float";
+const OVERFLOW_ERR_MSG: &str = "ERR increment or decrement would overflow";
+const NAN_OR_INFINITY_ERR_MSG: &str = "ERR increment would produce NaN or Infinity";
+const NO_SUCH_KEY_ERR_MSG: &str = "ERR no such key";
+const XGROUP_NO_KEY_ERR_MSG: &str = "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+pub const INVALID_HLL_ERR_MSG: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+const BUSYGROUP_ERR_MSG: &str = "BUSYGROUP Consumer Group name already exists";
+
+/// Formats a sorted set score the way `%.17g` picks a notation, but with the
+/// shortest digits that read back as the same float: `1.5`, `1e+308`,
+/// `1e-05`, and `inf`/`-inf` for infinities
+pub fn format_score(f: f64) -> String {
+    if !f.is_finite() {
+        return f.to_string();
+    }
+    // `{:e}` gives the shortest digits as `<mantissa>e<exponent>`
+    let sci = format!("{:e}", f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if (-4..17).contains(&exp) {
+        f.to_string()
+    } else {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    }
+}
+
+/// Formats a float in fixed point with at most 17 decimals and no trailing
+/// zeros, like the `%.17Lf` Redis uses for HINCRBYFLOAT and GEOPOS
+pub fn format_fixed(f: f64) -> String {
+    let shortest = f.to_string();
+    match shortest.split_once('.') {
+        Some((_, decimals)) if decimals.len() > 17 => {
+            let rounded = format!("{:.17}", f);
+            let trimmed = rounded.trim_end_matches('0').trim_end_matches('.');
+            match trimmed {
+                "-0" => "0".to_string(),
+                _ => trimmed.to_string(),
+            }
+        }
+        _ => shortest,
+    }
+}
+
+// Approximate memory used by every key, plus every element of a collection
+const KEY_OVERHEAD: usize = 64;
+const ELEMENT_OVERHEAD: usize = 48;
+
+// Approximate memory used by a key and its value. Collections are estimated
+// from their number of elements, so that measuring them is cheap.
+fn approx_size(key: &str, value: &Value) -> usize {
+    let value_size = match value {
+        Value::String(s) => s.len(),
+        Value::Hash(hash) => hash.fields().len() * ELEMENT_OVERHEAD,
+        Value::Set(set) => set.len() * ELEMENT_OVERHEAD,
+        Value::SortedSet(zset) => zset.len() * ELEMENT_OVERHEAD,
+        Value::Stream(stream) => stream.len() * ELEMENT_OVERHEAD,
+    };
+    KEY_OVERHEAD + key.len() + value_size
+}
+
+// LFU counters start at `LFU_INIT_VAL`, so that new keys aren't evicted
+// right away. Above it, an access increments the counter with probability
+// 1 / ((counter - LFU_INIT_VAL) * LFU_LOG_FACTOR + 1), which makes the
+// counter logarithmic in the number of accesses. The counter decays by one
+// for every `LFU_DECAY_PERIOD` without access. These are Redis's defaults.
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_PERIOD: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // Decides whether accesses increment LFU counters. It's thread-local
+    // since accesses happen wherever a key is read.
+    static LFU_RNG: RefCell<Rng> = RefCell::new(Rng::from_time());
+}
+
+#[derive(Debug)]
 struct ValueWrapper {
     value: Value,
     expiration: Option<SystemTime>,
+    // When the key was last read or written, for LRU eviction
+    last_access: Instant,
+    // Logarithmic access counter, for LFU eviction and OBJECT FREQ. It's
+    // decayed when it's read, by the time since `lfu_time`.
+    lfu_counter: u8,
+    lfu_time: Instant,
+    // The approximate size of the key, which is counted in `used_memory`
+    // for as long as the key exists
+    size: usize,
+    used_memory: Arc<AtomicUsize>,
+    // Whether the collection outgrew the listpack encoding after a change.
+    // Like Redis, it never goes back, even if it shrinks.
+    converted: bool,
+}
+
+impl Drop for ValueWrapper {
+    fn drop(&mut self) {
+        self.used_memory.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+impl ValueWrapper {
+    fn new(
+        key: &str,
+        value: Value,
+        expiration: Option<SystemTime>,
+        used_memory: &Arc<AtomicUsize>,
+    ) -> Self {
+        let size = approx_size(key, &value);
+        used_memory.fetch_add(size, Ordering::Relaxed);
+        Self {
+            value,
+            expiration,
+            last_access: Instant::now(),
+            lfu_counter: LFU_INIT_VAL,
+            lfu_time: Instant::now(),
+            size,
+            used_memory: used_memory.clone(),
+            converted: false,
+        }
+    }
+
+    // Records an access, for LRU and LFU
+    fn touch(&mut self) {
+        let counter = self.lfu();
+        self.lfu_counter = if counter == u8::MAX {
+            counter
+        } else {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+            let r = LFU_RNG.with(|rng| rng.borrow_mut().next_f64());
+            if r < p {
+                counter + 1
+            } else {
+                counter
+            }
+        };
+        self.lfu_time = Instant::now();
+        self.last_access = Instant::now();
+    }
+
+    // The LFU counter, decayed by one for every period since the last access
+    fn lfu(&self) -> u8 {
+        let periods = self.lfu_time.elapsed().as_secs() / LFU_DECAY_PERIOD.as_secs();
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // Measures the size again, after the value has changed in place
+    fn resize(&mut self, key: &str) {
+        let size = approx_size(key, &self.value);
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        self.used_memory.fetch_sub(self.size, Ordering::Relaxed);
+        self.size = size;
+    }
+
+    // Removes the expired fields of a hash. Returns true if the whole value
+    // has expired, or if it's a hash whose fields have all expired.
+    fn remove_expired(&mut self, now: SystemTime) -> bool {
+        if self.expiration.is_some_and(|expiration| expiration <= now) {
+            return true;
+        }
+
+        match &mut self.value {
+            Value::Hash(hash) => {
+                hash.remove_expired(now);
+                hash.is_empty()
+            }
+            _ => false,
+        }
+    }
+}
+
+// Returns the value stored at `key`, removing it first if it has expired.
+// This counts as an access to the key.
+fn get_live<'a>(map: &'a mut ScanMap<ValueWrapper>, key: &str) -> Option<&'a mut ValueWrapper> {
+    let v = peek_live(map, key)?;
+    v.touch();
+    Some(v)
+}
+
+// Like `get_live`, but doesn't count as an access, for commands that inspect
+// keys such as OBJECT
+fn peek_live<'a>(map: &'a mut ScanMap<ValueWrapper>, key: &str) -> Option<&'a mut ValueWrapper> {
+    if map
+        .get_mut(key)
+        .is_some_and(|v| v.remove_expired(SystemTime::now()))
+    {
+        map.remove(key);
+    }
+    map.get_mut(key)
+}
+
+// Like `get_live`, but inserts the value built by `default` if the key doesn't exist
+fn get_live_or_insert_with<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: String,
+    used_memory: &Arc<AtomicUsize>,
+    default: impl FnOnce() -> Value,
+) -> &'a mut ValueWrapper {
+    get_live(map, &key);
+    map.get_or_insert_with(key, |key| {
+        ValueWrapper::new(key, default(), None, used_memory)
+    })
+}
+
+// Checks that a string is a HyperLogLog
+fn valid_hll(bytes: &mut Vec<u8>) -> Result<&mut Vec<u8>> {
+    if !hyperloglog::is_valid(bytes) {
+        bail!(INVALID_HLL_ERR_MSG);
+    }
+    Ok(bytes)
+}
+
+// Merges the HyperLogLogs at `keys` into a new one. Missing keys are empty.
+fn merge_hlls(map: &mut ScanMap<ValueWrapper>, keys: &[String]) -> Result<Vec<u8>> {
+    let mut merged = hyperloglog::new();
+    for key in keys {
+        if let Some(v) = get_live(map, key) {
+            hyperloglog::merge(&mut merged, valid_hll(v.value.as_string_mut()?)?);
+        }
+    }
+    Ok(merged)
+}
+
+// Returns the hash stored at `key`, with its expired fields removed
+fn get_live_hash<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: &str,
+) -> Result<Option<&'a mut Hash>> {
+    match get_live(map, key) {
+        None => Ok(None),
+        Some(v) => Ok(Some(v.value.as_hash_mut()?)),
+    }
+}
+
+// Like `get_live_hash`, but creates an empty hash if the key doesn't exist
+fn get_live_hash_or_insert<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: String,
+    used_memory: &Arc<AtomicUsize>,
+) -> Result<&'a mut Hash> {
+    get_live_or_insert_with(map, key, used_memory, || Value::Hash(Hash::new()))
+        .value
+        .as_hash_mut()
+}
+
+// Returns the set stored at `key`
+fn get_live_set<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: &str,
+) -> Result<Option<&'a mut ScanSet>> {
+    match get_live(map, key) {
+        None => Ok(None),
+        Some(v) => Ok(Some(v.value.as_set_mut()?)),
+    }
+}
+
+// Like `get_live_set`, but creates an empty set if the key doesn't exist
+fn get_live_set_or_insert<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: String,
+    used_memory: &Arc<AtomicUsize>,
+) -> Result<&'a mut ScanSet> {
+    get_live_or_insert_with(map, key, used_memory, || Value::Set(ScanSet::new()))
+        .value
+        .as_set_mut()
+}
+
+// Returns the sorted set stored at `key`
+fn get_live_sorted_set<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: &str,
+) -> Result<Option<&'a mut SortedSet>> {
+    match get_live(map, key) {
+        None => Ok(None),
+        Some(v) => Ok(Some(v.value.as_sorted_set_mut()?)),
+    }
+}
+
+// Like `get_live_sorted_set`, but creates an empty sorted set if the key
+// doesn't exist
+fn get_live_sorted_set_or_insert<'a>(
+    map: &'a mut ScanMap<ValueWrapper>,
+    key: String,
+    used_memory: &Arc<AtomicUsize>,
+) -> Result<&'a mut SortedSet> {
+    get_live_or_insert_with(map, key, used_memory, || Value::SortedSet(SortedSet::new()))
+        .value
+        .as_sorted_set_mut()
+}
+
+// Moves the value at `from` to `to`. If `to` exists, it's replaced if
+// `replace` is true, otherwise nothing is done and false is returned.
+fn rename_key(
+    map: &mut ScanMap<ValueWrapper>,
+    from: &str,
+    to: &str,
+    replace: bool,
+) -> Result<bool> {
+    // Renaming isn't an access to either key
+    if peek_live(map, from).is_none() {
+        bail!(NO_SUCH_KEY_ERR_MSG);
+    }
+    if !replace && peek_live(map, to).is_some() {
+        return Ok(false);
+    }
+
+    if from != to {
+        let mut v = map.remove(from).unwrap();
+        v.resize(to);
+        map.insert(to.to_string(), v);
+    }
+    Ok(true)
+}
+
+/// How SINTER, SUNION and SDIFF combine their sets
+#[derive(Clone, Copy, Debug)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Members of a sorted set with their scores
+pub type ScoredMembers = Vec<(String, f64)>;
+
+// Removes and returns up to `count` members of the sorted set at `key`, as
+// `Store::zpop` does
+fn pop_sorted_set(
+    map: &mut ScanMap<ValueWrapper>,
+    key: &str,
+    count: usize,
+    max: bool,
+) -> Result<Vec<(String, f64)>> {
+    let Some(zset) = get_live_sorted_set(map, key)? else {
+        return Ok(Vec::new());
+    };
+
+    let popped: Vec<(String, f64)> = zset
+        .range(&Range::Index(0, -1), max)
+        .take(count)
+        .map(|(member, score)| (member.clone(), score))
+        .collect();
+    for (member, _) in popped.iter() {
+        zset.remove(member);
+    }
+    if zset.is_empty() {
+        map.remove(key);
+    }
+    Ok(popped)
+}
+
+// Combines the sets stored at `keys`. Missing keys are empty sets.
+fn combine_sets(
+    map: &mut ScanMap<ValueWrapper>,
+    op: SetOp,
+    keys: &[String],
+) -> Result<HashSet<String>> {
+    let empty = HashSet::new();
+    let mut result: Option<HashSet<String>> = None;
+    for key in keys {
+        let set = get_live_set(map, key)?.map_or(&empty, |set| &*set);
+        result = Some(match (result, op) {
+            (None, _) => set.clone(),
+            (Some(mut result), SetOp::Inter) => {
+                result.retain(|member| set.contains(member));
+                result
+            }
+            (Some(mut result), SetOp::Union) => {
+                result.extend(set.iter().cloned());
+                result
+            }
+            (Some(mut result), SetOp::Diff) => {
+                result.retain(|member| !set.contains(member));
+                result
+            }
+        });
+    }
+    Ok(result.unwrap_or_default())
+}
+
+// The members of the set or sorted set stored at `key`, with their scores
+// multiplied by `weight`. Members of plain sets have a score of 1.
+fn weighted_scores(
+    map: &mut ScanMap<ValueWrapper>,
+    key: &str,
+    weight: f64,
+) -> Result<HashMap<String, f64>> {
+    let weighted = |score: f64| sorted_set::zero_if_nan(score * weight);
+    Ok(match get_live(map, key).map(|v| &v.value) {
+        None => HashMap::new(),
+        Some(Value::Set(set)) => set
+            .iter()
+            .map(|member| (member.clone(), weighted(1.0)))
+            .collect(),
+        Some(Value::SortedSet(zset)) => zset
+            .iter()
+            .map(|(member, score)| (member.clone(), weighted(score)))
+            .collect(),
+        Some(_) => bail!(WRONG_TYPE_ERR_MSG),
+    })
+}
+
+// Combines the sets or sorted sets stored at `keys` like `combine_sets`, with
+// the scores of each multiplied by its weight. The scores of a member found in
+// several of them are combined with `aggregate`.
+fn combine_sorted_sets(
+    map: &mut ScanMap<ValueWrapper>,
+    op: SetOp,
+    keys: &[String],
+    weights: &[f64],
+    aggregate: Aggregate,
+) -> Result<SortedSet> {
+    let mut result: Option<HashMap<String, f64>> = None;
+    for (key, weight) in keys.iter().zip(weights) {
+        let scores = weighted_scores(map, key, *weight)?;
+        result = Some(match (result, op) {
+            (None, _) => scores,
+            (Some(mut result), SetOp::Inter) => {
+                result.retain(|member, score| match scores.get(member) {
+                    Some(other) => {
+                        *score = aggregate.apply(*score, *other);
+                        true
+                    }
+                    None => false,
+                });
+                result
+            }
+            (Some(mut result), SetOp::Union) => {
+                for (member, other) in scores {
+                    result
+                        .entry(member)
+                        .and_modify(|score| *score = aggregate.apply(*score, other))
+                        .or_insert(other);
+                }
+                result
+            }
+            (Some(mut result), SetOp::Diff) => {
+                result.retain(|member, _| !scores.contains_key(member));
+                result
+            }
+        });
+    }
+
+    let mut zset = SortedSet::new();
+    for (member, score) in result.unwrap_or_default() {
+        zset.add(member, score, &AddOptions::default())?;
+    }
+    Ok(zset)
+}
+
+// Active expiration: every `ACTIVE_EXPIRE_INTERVAL`, sample
+// `ACTIVE_EXPIRE_SAMPLE` keys of each database and remove the expired ones,
+// repeating while more than a quarter of the sample had expired, for at most
+// `ACTIVE_EXPIRE_TIME_LIMIT` per database
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+// One round of active expiration on a database
+fn active_expire(map: &Mutex<ScanMap<ValueWrapper>>, rng: &mut Rng) {
+    let start = Instant::now();
+    while start.elapsed() < ACTIVE_EXPIRE_TIME_LIMIT {
+        let mut map = map.lock().unwrap();
+        if map.is_empty() {
+            return;
+        }
+
+        // A HashMap has no random access, so sample the keys that follow a
+        // random position in the scan index, wrapping around
+        let now = SystemTime::now();
+        let sample_size = ACTIVE_EXPIRE_SAMPLE.min(map.len());
+        let sample: Vec<String> = map
+            .names_from(rng.next_u64())
+            .take(sample_size)
+            .cloned()
+            .collect();
+
+        let mut num_expired = 0;
+        for key in sample {
+            if map.get_mut(&key).is_some_and(|v| v.remove_expired(now)) {
+                map.remove(&key);
+                num_expired += 1;
+            }
+        }
+
+        if num_expired * 4 <= sample_size {
+            return;
+        }
+    }
+}
+
+// Strings up to this many bytes are reported as `embstr` by OBJECT ENCODING
+const EMBSTR_MAX_LEN: usize = 44;
+
+/// Whether SET requires the key to be missing (NX) or to exist (XX)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    IfMissing,
+    IfExists,
+}
+
+/// The expiration SET gives the key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SetExpiry {
+    /// No expiration, removing any previous one
+    #[default]
+    Persist,
+    At(SystemTime),
+    /// The expiration of the previous value, for KEEPTTL
+    Keep,
+}
+
+/// The options of SET
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// Return the previous value, for GET
+    pub get: bool,
+    pub expiry: SetExpiry,
+}
+
+/// What a SET with options did
+#[derive(Debug, PartialEq)]
+pub struct SetResult {
+    /// False if the condition wasn't met
+    pub done: bool,
+    /// The previous value, with the GET option only
+    pub previous: Option<Vec<u8>>,
+    /// The expiration of the key, once set
+    pub expiration: Option<SystemTime>,
+}
+
+/// Collections with up to `max_entries` elements of up to `max_value` bytes
+/// are reported as `listpack` by OBJECT ENCODING, like the
+/// `*-max-listpack-entries` and `*-max-listpack-value` configs of Redis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListpackLimits {
+    pub max_entries: usize,
+    pub max_value: usize,
+}
+
+impl Default for ListpackLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 128,
+            max_value: 64,
+        }
+    }
+}
+
+/// The listpack limits of each type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodingLimits {
+    pub hash: ListpackLimits,
+    pub set: ListpackLimits,
+    pub zset: ListpackLimits,
+}
+
+// Whether a collection is within its listpack limits, or `None` for values
+// that are never listpacks
+fn fits_listpack(value: &Value, limits: EncodingLimits) -> Option<bool> {
+    let fits = |limits: ListpackLimits,
+                len: usize,
+                mut elements: Box<dyn Iterator<Item = &String> + '_>| {
+        len <= limits.max_entries && elements.all(|e| e.len() <= limits.max_value)
+    };
+    match value {
+        Value::Hash(hash) => {
+            let fields = hash.fields();
+            let elements = fields.iter().flat_map(|(field, value)| [field, value]);
+            Some(fits(limits.hash, fields.len(), Box::new(elements)))
+        }
+        Value::Set(set) => Some(fits(limits.set, set.len(), Box::new(set.iter()))),
+        Value::SortedSet(zset) => Some(fits(
+            limits.zset,
+            zset.len(),
+            Box::new(zset.iter().map(|(member, _)| member)),
+        )),
+        Value::String(_) | Value::Stream(_) => None,
+    }
 }
 
-impl ValueWrapper {
-    fn has_expired(&self) -> bool {
-        match self.expiration {
-            None => false,
-            Some(expiration) => expiration <= SystemTime::now(),
+// Default of the proto-max-bulk-len config, which limits the size of strings
+const DEFAULT_MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+// Number of keys sampled in each database to pick a key to evict
+const EVICTION_SAMPLE: usize = 5;
+
+/// The number of databases, which are selected with SELECT
+pub const NUM_DBS: usize = 16;
+
+/// All databases, indexed from 0 to `NUM_DBS - 1`
+pub struct StoreDb(Vec<Store>);
+
+impl StoreDb {
+    pub fn new() -> Self {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        Self(
+            (0..NUM_DBS)
+                .map(|_| Store::with_used_memory(used_memory.clone()))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, db: usize) -> &Store {
+        &self.0[db]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Store> {
+        self.0.iter()
+    }
+
+    pub fn set_max_string_len(&self, len: usize) {
+        for store in self.0.iter() {
+            store.set_max_string_len(len);
+        }
+    }
+
+    pub fn set_encoding_limits(&self, limits: EncodingLimits) {
+        for store in self.0.iter() {
+            store.set_encoding_limits(limits);
+        }
+    }
+
+    /// Approximate memory used by the keys of all databases, in bytes
+    pub fn used_memory(&self) -> usize {
+        self.0[0].used_memory.load(Ordering::Relaxed)
+    }
+
+    /// Evicts keys with `policy` until the memory used is at most
+    /// `maxmemory`. Returns the evicted keys with their database, and whether
+    /// enough memory could be freed.
+    pub fn evict(
+        &self,
+        maxmemory: usize,
+        policy: &str,
+        rng: &mut Rng,
+    ) -> (Vec<(usize, String)>, bool) {
+        let mut evicted = Vec::new();
+        while self.used_memory() > maxmemory {
+            if policy == "noeviction" {
+                return (evicted, false);
+            }
+
+            // Sample a few keys of each database and evict the best candidate
+            let mut best: Option<(u64, usize, String)> = None;
+            for (db, store) in self.0.iter().enumerate() {
+                for (score, key) in store.eviction_candidates(policy, rng) {
+                    if best.as_ref().is_none_or(|(best, _, _)| score > *best) {
+                        best = Some((score, db, key));
+                    }
+                }
+            }
+            let Some((_, db, key)) = best else {
+                return (evicted, false);
+            };
+            self.0[db].del(std::slice::from_ref(&key));
+            evicted.push((db, key));
+        }
+        (evicted, true)
+    }
+
+    /// Starts a background thread that regularly removes expired keys from
+    /// all databases, so that keys nobody reads again don't stay around
+    /// forever. It's controlled with the returned `ActiveExpiry`.
+    pub fn start_active_expiry(&self) -> ActiveExpiry {
+        let active_expiry = ActiveExpiry {
+            stop: Arc::new(AtomicBool::new(false)),
+            enabled: Arc::new(AtomicBool::new(true)),
+        };
+        let maps: Vec<_> = self.0.iter().map(|store| store.map.clone()).collect();
+
+        let (stop, enabled) = (active_expiry.stop.clone(), active_expiry.enabled.clone());
+        thread::spawn(move || {
+            let mut rng = Rng::from_time();
+            while !stop.load(Ordering::Relaxed) {
+                if enabled.load(Ordering::Relaxed) {
+                    for map in maps.iter() {
+                        active_expire(map, &mut rng);
+                    }
+                }
+                thread::sleep(ACTIVE_EXPIRE_INTERVAL);
+            }
+        });
+
+        active_expiry
+    }
+}
+
+/// Controls the thread started by `StoreDb::start_active_expiry`
+pub struct ActiveExpiry {
+    stop: Arc<AtomicBool>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl ActiveExpiry {
+    /// Stops the thread for good
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Pauses or resumes removing expired keys, for DEBUG SET-ACTIVE-EXPIRE.
+    /// Expired keys are still removed when they're accessed.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// A client blocked in XREAD or XREADGROUP until an entry after `entry_id`
+/// is added to a stream, returned by `Store::stream_subscribe` to unsubscribe
+/// with. Many subscribers may wait for the same id.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SubscriberId {
+    entry_id: EntryId,
+    token: u64,
+}
+
+pub struct Store {
+    map: Arc<Mutex<ScanMap<ValueWrapper>>>,
+    rng: Mutex<Rng>,
+    // Approximate memory used by the keys, shared by all databases
+    used_memory: Arc<AtomicUsize>,
+    // Clients blocked until something is added to a key, such as BZPOPMIN,
+    // by token. They're notified once, then removed.
+    waiters: Mutex<HashMap<String, HashMap<u64, Sender<()>>>>,
+    // Like `waiters`, for entries added to streams. Kept out of the streams so
+    // that waiting for a stream that doesn't exist doesn't create it.
+    stream_subscribers: Mutex<HashMap<String, BTreeMap<SubscriberId, Sender<()>>>>,
+    // Tells apart the clients in `waiters` and `stream_subscribers`
+    next_token: AtomicU64,
+    // The proto-max-bulk-len config, in bytes
+    max_string_len: AtomicUsize,
+    encoding_limits: Mutex<EncodingLimits>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self::with_used_memory(Arc::new(AtomicUsize::new(0)))
+    }
+
+    fn with_used_memory(used_memory: Arc<AtomicUsize>) -> Self {
+        Store {
+            map: Arc::new(Mutex::new(ScanMap::new())),
+            rng: Mutex::new(Rng::from_time()),
+            used_memory,
+            waiters: Mutex::new(HashMap::new()),
+            stream_subscribers: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+            max_string_len: AtomicUsize::new(DEFAULT_MAX_STRING_LEN),
+            encoding_limits: Mutex::new(EncodingLimits::default()),
+        }
+    }
+
+    /// Maximum size of a string in bytes
+    pub fn max_string_len(&self) -> usize {
+        self.max_string_len.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_string_len(&self, len: usize) {
+        self.max_string_len.store(len, Ordering::Relaxed);
+    }
+
+    pub fn set_encoding_limits(&self, limits: EncodingLimits) {
+        *self.encoding_limits.lock().unwrap() = limits;
+    }
+
+    /// Returns a channel that gets a message the next time something is
+    /// added to one of `keys`. The caller should check the keys again then,
+    /// since another client may have been first. Once done waiting, for any
+    /// reason, the returned token should be passed to `unwait`.
+    pub fn wait_for_keys(&self, keys: &[String]) -> (u64, Receiver<()>) {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = unbounded();
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters
+                .entry(key.clone())
+                .or_default()
+                .insert(token, tx.clone());
+        }
+        (token, rx)
+    }
+
+    /// Undoes `wait_for_keys`, for the keys that weren't written to yet
+    pub fn unwait(&self, keys: &[String], token: u64) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            if let Some(key_waiters) = waiters.get_mut(key) {
+                key_waiters.remove(&token);
+                if key_waiters.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+
+    // Notifies the clients waiting for something to be added to `key`
+    fn wake_waiters(&self, key: &str) {
+        let waiters = self.waiters.lock().unwrap().remove(key).unwrap_or_default();
+        for tx in waiters.into_values() {
+            // The client may have stopped waiting
+            let _ = tx.send(());
+        }
+    }
+
+    /// Number of clients waiting for something to be added to `key`
+    #[cfg(test)]
+    pub fn num_waiters(&self, key: &str) -> usize {
+        self.waiters
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, HashMap::len)
+    }
+
+    /// The random number generator used by commands that pick random elements
+    pub fn rng(&self) -> MutexGuard<'_, Rng> {
+        self.rng.lock().unwrap()
+    }
+
+    /// Number of keys that haven't expired. Expired keys are removed on the
+    /// way, and nothing is cloned.
+    pub fn count(&self) -> usize {
+        let mut map = self.map.lock().unwrap();
+
+        let now = SystemTime::now();
+        map.retain(|_, v| !v.remove_expired(now));
+        map.len()
+    }
+
+    /// Keys that haven't expired
+    pub fn keys(&self) -> Vec<String> {
+        let mut map = self.map.lock().unwrap();
+
+        let now = SystemTime::now();
+        map.retain(|_, v| !v.remove_expired(now));
+        map.keys().cloned().collect()
+    }
+
+    /// A random key that hasn't expired, or None if there are no keys. The
+    /// random position is picked between two short locks, rather than while
+    /// holding one.
+    pub fn random_key(&self, rng: &mut Rng) -> Option<String> {
+        loop {
+            let len = self.count();
+            if len == 0 {
+                return None;
+            }
+            let idx = rng.index(len);
+
+            // Keys may have been removed or have expired in the meantime, then
+            // pick again
+            let mut map = self.map.lock().unwrap();
+            let Some(key) = map.keys().nth(idx).cloned() else {
+                continue;
+            };
+            if peek_live(&mut map, &key).is_some() {
+                return Some(key);
+            }
+        }
+    }
+
+    /// One batch of SCAN: about `count` keys that haven't expired, starting
+    /// from `cursor`, with their types. Also returns the next cursor.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(String, String)>) {
+        let mut map = self.map.lock().unwrap();
+
+        let (cursor, batch) = map.scan(cursor, count);
+        let batch: Vec<String> = batch.into_iter().map(|(key, _)| key.clone()).collect();
+        // Expired keys are removed on the way
+        let keys = batch
+            .into_iter()
+            .filter_map(|key| {
+                let type_name = peek_live(&mut map, &key)?.value.type_string();
+                Some((key, type_name))
+            })
+            .collect();
+        (cursor, keys)
+    }
+
+    /// Removes all keys, returning them. With `lazy` the values are freed in
+    /// a background thread, so that flushing a large database returns
+    /// quickly.
+    pub fn flush(&self, lazy: bool) -> Vec<String> {
+        let map = std::mem::take(&mut *self.map.lock().unwrap());
+        let keys = map.keys().cloned().collect();
+        if lazy {
+            thread::spawn(move || drop(map));
+        }
+        keys
+    }
+
+    /// The encoding Redis would use for the value at `key`, for OBJECT
+    /// ENCODING. Small collections of short elements are `listpack`, unless
+    /// they were converted when they grew.
+    pub fn object_encoding(&self, key: &str) -> Option<&'static str> {
+        let limits = *self.encoding_limits.lock().unwrap();
+        let mut map = self.map.lock().unwrap();
+        let v = peek_live(&mut map, key)?;
+
+        let is_listpack = !v.converted && fits_listpack(&v.value, limits) == Some(true);
+        let encoding = match &v.value {
+            Value::String(s)
+                if s.len() <= 20
+                    && std::str::from_utf8(s).is_ok_and(|s| s.parse::<i64>().is_ok()) =>
+            {
+                "int"
+            }
+            Value::String(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::String(_) => "raw",
+            Value::Hash(_) | Value::Set(_) | Value::SortedSet(_) if is_listpack => "listpack",
+            Value::Hash(_) | Value::Set(_) => "hashtable",
+            Value::SortedSet(_) => "skiplist",
+            Value::Stream(_) => "stream",
+        };
+        Some(encoding)
+    }
+
+    /// Whether `key` exists. This doesn't count as an access.
+    pub fn contains(&self, key: &str) -> bool {
+        let mut map = self.map.lock().unwrap();
+        peek_live(&mut map, key).is_some()
+    }
+
+    /// When `key` expires, in milliseconds since the Unix epoch, or None if
+    /// it doesn't exist or has no expiration. This doesn't count as an
+    /// access.
+    pub fn expiretime_ms(&self, key: &str) -> Option<i64> {
+        let mut map = self.map.lock().unwrap();
+        let expiration = peek_live(&mut map, key)?.expiration?;
+        let since_epoch = expiration.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some(since_epoch.as_millis() as i64)
+    }
+
+    /// Makes `key` expire at `unix_ms`, in milliseconds since the Unix epoch.
+    /// A time that has passed deletes the key. Returns false if the key
+    /// doesn't exist.
+    pub fn set_expiry_at_ms(&self, key: &str, unix_ms: u64) -> bool {
+        let mut map = self.map.lock().unwrap();
+        let Some(v) = get_live(&mut map, key) else {
+            return false;
+        };
+        match UNIX_EPOCH.checked_add(Duration::from_millis(unix_ms)) {
+            Some(expiration) if expiration > SystemTime::now() => {
+                v.expiration = Some(expiration);
+            }
+            _ => {
+                map.remove(key);
+            }
+        }
+        true
+    }
+
+    /// Records an access to each of `keys`, for TOUCH. Returns how many of
+    /// them exist.
+    pub fn touch(&self, keys: &[String]) -> usize {
+        let mut map = self.map.lock().unwrap();
+        keys.iter()
+            .filter(|key| get_live(&mut map, key).is_some())
+            .count()
+    }
+
+    /// The logarithmic access counter of `key`, for OBJECT FREQ
+    pub fn access_frequency(&self, key: &str) -> Option<u8> {
+        let mut map = self.map.lock().unwrap();
+        peek_live(&mut map, key).map(|v| v.lfu())
+    }
+
+    /// How long since `key` was last read or written, for OBJECT IDLETIME
+    pub fn idle_time(&self, key: &str) -> Option<Duration> {
+        let mut map = self.map.lock().unwrap();
+        peek_live(&mut map, key).map(|v| v.last_access.elapsed())
+    }
+
+    pub fn get_type(&self, key: String) -> String {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, &key) {
+            Some(v) => v.value.type_string(),
+            None => "none".into(),
+        }
+    }
+
+    pub fn set(&self, key: String, value: Value, expire_in: Option<Duration>) {
+        let expiration = expire_in.and_then(|expire_in| SystemTime::now().checked_add(expire_in));
+
+        let value = ValueWrapper::new(&key, value, expiration, &self.used_memory);
+        self.map.lock().unwrap().insert(key, value);
+    }
+
+    /// Sets a string with the options of SET, all under one lock. With the
+    /// GET option the previous value must be a string.
+    pub fn set_string(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> Result<SetResult> {
+        let mut map = self.map.lock().unwrap();
+
+        let (exists, previous, previous_expiration) = match get_live(&mut map, &key) {
+            None => (false, None, None),
+            Some(v) if options.get => (true, Some(v.value.as_string()?.clone()), v.expiration),
+            Some(v) => (true, None, v.expiration),
+        };
+        let done = match options.condition {
+            None => true,
+            Some(SetCondition::IfMissing) => !exists,
+            Some(SetCondition::IfExists) => exists,
+        };
+        if !done {
+            return Ok(SetResult {
+                done,
+                previous,
+                expiration: None,
+            });
+        }
+
+        let expiration = match options.expiry {
+            SetExpiry::Persist => None,
+            SetExpiry::At(expiration) => Some(expiration),
+            SetExpiry::Keep => previous_expiration,
+        };
+        let wrapper = ValueWrapper::new(&key, Value::String(value), expiration, &self.used_memory);
+        map.insert(key, wrapper);
+        Ok(SetResult {
+            done,
+            previous,
+            expiration,
+        })
+    }
+
+    // A sample of the keys that `policy` may evict, with a score that is
+    // higher for better candidates: the idle time for LRU, the rareness of
+    // accesses for LFU, the closeness of the expiration for TTL, and a random
+    // number for random.
+    fn eviction_candidates(&self, policy: &str, rng: &mut Rng) -> Vec<(u64, String)> {
+        let map = self.map.lock().unwrap();
+        if map.is_empty() {
+            return Vec::new();
+        }
+
+        let volatile = policy.starts_with("volatile-");
+        let now = SystemTime::now();
+        let offset = rng.index(map.len());
+        map.iter()
+            .skip(offset)
+            .chain(map.iter().take(offset))
+            .filter(|(_, v)| !volatile || v.expiration.is_some())
+            .take(EVICTION_SAMPLE)
+            .map(|(key, v)| {
+                let score = if policy.ends_with("-random") {
+                    rng.next_u64()
+                } else if policy.ends_with("-lfu") {
+                    (u8::MAX - v.lfu()) as u64
+                } else if policy == "volatile-ttl" {
+                    let ttl = v.expiration.map_or(Duration::ZERO, |expiration| {
+                        expiration.duration_since(now).unwrap_or_default()
+                    });
+                    u64::MAX - ttl.as_millis() as u64
+                } else {
+                    v.last_access.elapsed().as_micros() as u64
+                };
+                (score, key.clone())
+            })
+            .collect()
+    }
+
+    /// Removes the keys that exist. Returns how many there were.
+    pub fn del(&self, keys: &[String]) -> usize {
+        let mut map = self.map.lock().unwrap();
+        keys.iter()
+            .filter(|key| get_live(&mut map, key).is_some() && map.remove(key).is_some())
+            .count()
+    }
+
+    /// Renames `from` to `to`, replacing any value at `to`. The expiration
+    /// of `from` moves with its value.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+        rename_key(&mut map, from, to, true).map(|_| ())
+    }
+
+    /// Like `rename`, but only if `to` doesn't exist. Returns whether the key
+    /// was renamed.
+    pub fn rename_if_absent(&self, from: &str, to: &str) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+        rename_key(&mut map, from, to, false)
+    }
+
+    /// Copies the value at `from`, with its expiration, to `to` in the
+    /// database `destination_db`, or in this one if None. Returns false if
+    /// `from` doesn't exist, or if `to` exists and `replace` is false.
+    pub fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        replace: bool,
+        destination_db: Option<&Store>,
+    ) -> Result<bool> {
+        let destination = destination_db.unwrap_or(self);
+        if from == to && std::ptr::eq(self, destination) {
+            bail!("ERR source and destination objects are the same");
+        }
+
+        // The source is unlocked before locking the destination, which may be
+        // the same store
+        let (value, expiration) = {
+            let mut map = self.map.lock().unwrap();
+            match get_live(&mut map, from) {
+                None => return Ok(false),
+                Some(v) => (v.value.clone(), v.expiration),
+            }
+        };
+
+        let mut map = destination.map.lock().unwrap();
+        if !replace && peek_live(&mut map, to).is_some() {
+            return Ok(false);
+        }
+        let v = ValueWrapper::new(to, value, expiration, &destination.used_memory);
+        map.insert(to.to_string(), v);
+        Ok(true)
+    }
+
+    /// Measures the size of `keys` again after they have been modified.
+    /// Collections change in place, so their size is only known afterwards.
+    /// Those that outgrew the listpack encoding are converted, for good.
+    pub fn update_sizes(&self, keys: &[String]) {
+        let limits = *self.encoding_limits.lock().unwrap();
+        let mut map = self.map.lock().unwrap();
+        for key in keys {
+            if let Some(v) = map.get_mut(key) {
+                v.resize(key);
+                if !v.converted && fits_listpack(&v.value, limits) == Some(false) {
+                    v.converted = true;
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let mut map = self.map.lock().unwrap();
+        get_live(&mut map, key).map(|v| v.value.clone())
+    }
+
+    /// Returns WRONGTYPE error if the key holds a non-string value
+    pub fn get_string(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, key) {
+            None => Ok(None),
+            Some(v) => Ok(Some(v.value.as_string()?.clone())),
+        }
+    }
+
+    /// Runs `f` on the string stored at `key` while holding the lock, or
+    /// returns `None` if the key doesn't exist
+    pub fn with_string<T>(&self, key: &str, f: impl FnOnce(&[u8]) -> T) -> Result<Option<T>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, key) {
+            None => Ok(None),
+            Some(v) => Ok(Some(f(v.value.as_string()?))),
+        }
+    }
+
+    /// Sets a bit of the string at `key`, creating or growing it as needed.
+    /// Returns the previous value of the bit.
+    pub fn setbit(&self, key: String, offset: usize, value: bool) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+
+        let v = get_live_or_insert_with(&mut map, key.clone(), &self.used_memory, || {
+            Value::String(Vec::new())
+        });
+        let previous = bitmap::set_bit(v.value.as_string_mut()?, offset, value);
+        v.resize(&key);
+        Ok(previous)
+    }
+
+    /// Runs the ops of BITFIELD on the string at `key`, returning their
+    /// replies. If any op writes, the key is created if missing and, like
+    /// Redis, grown to fit all the integers written, even if they overflow
+    /// with `Overflow::Fail`.
+    pub fn bitfield(&self, key: String, ops: &[BitfieldOp]) -> Result<Vec<Option<i64>>> {
+        let writes = ops.iter().filter(|op| op.is_write());
+        let Some(end) = writes.map(BitfieldOp::end).max() else {
+            let replies = self.with_string(&key, |s| {
+                ops.iter()
+                    .map(|op| Some(bitmap::get_int(s, op.offset, op.ty)))
+                    .collect()
+            })?;
+            return Ok(replies.unwrap_or_else(|| vec![Some(0); ops.len()]));
+        };
+
+        let mut map = self.map.lock().unwrap();
+        let v = get_live_or_insert_with(&mut map, key.clone(), &self.used_memory, || {
+            Value::String(Vec::new())
+        });
+        let bytes = v.value.as_string_mut()?;
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        let replies = ops.iter().map(|op| op.apply(bytes)).collect();
+        v.resize(&key);
+        Ok(replies)
+    }
+
+    /// Adds `elements` to the HyperLogLog at `key`, creating it if needed.
+    /// Returns whether the key was created or a register changed.
+    pub fn pfadd(&self, key: String, elements: &[Vec<u8>]) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+
+        let created = get_live(&mut map, &key).is_none();
+        let v = get_live_or_insert_with(&mut map, key.clone(), &self.used_memory, || {
+            Value::String(hyperloglog::new())
+        });
+        let hll = valid_hll(v.value.as_string_mut()?)?;
+        let mut changed = false;
+        for element in elements {
+            changed |= hyperloglog::add(hll, element);
+        }
+        Ok(created || changed)
+    }
+
+    /// The estimated cardinality of the union of the HyperLogLogs at `keys`.
+    /// Missing keys are empty.
+    pub fn pfcount(&self, keys: &[String]) -> Result<u64> {
+        let mut map = self.map.lock().unwrap();
+        Ok(hyperloglog::count(&merge_hlls(&mut map, keys)?))
+    }
+
+    /// Stores the union of the HyperLogLogs at `destination` and `sources` at
+    /// `destination`, creating it if needed
+    pub fn pfmerge(&self, destination: String, sources: &[String]) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+
+        let mut keys = vec![destination.clone()];
+        keys.extend_from_slice(sources);
+        let merged = merge_hlls(&mut map, &keys)?;
+        let v = get_live_or_insert_with(&mut map, destination.clone(), &self.used_memory, || {
+            Value::String(Vec::new())
+        });
+        *v.value.as_string_mut()? = merged;
+        v.resize(&destination);
+        Ok(())
+    }
+
+    /// The offset of the first bit equal to `bit` in the string at `key`, as
+    /// `bitmap::position` finds it. A missing key is an empty string.
+    pub fn bitpos(
+        &self,
+        key: &str,
+        bit: bool,
+        start: Option<i64>,
+        end: Option<i64>,
+        unit: Unit,
+    ) -> Result<i64> {
+        let start = start.unwrap_or(0);
+        let position = self.with_string(key, |s| bitmap::position(s, bit, start, end, unit))?;
+        Ok(position.unwrap_or_else(|| bitmap::position(&[], bit, start, end, unit)))
+    }
+
+    /// Stores the result of BITOP over the strings at `keys` at
+    /// `destination`, replacing any value there, or removes `destination` if
+    /// the result is empty. Missing keys are empty strings. Returns the
+    /// length of the result.
+    pub fn bitop(&self, op: BitOp, destination: String, keys: &[String]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        // Check the types and remove expired keys first, so that the strings
+        // can then be borrowed all at once
+        for key in keys {
+            if let Some(v) = get_live(&mut map, key) {
+                v.value.as_string()?;
+            }
+        }
+        let sources = keys
+            .iter()
+            .map(|key| match map.get(key) {
+                None => Ok(&[][..]),
+                Some(v) => v.value.as_string().map(|s| s.as_slice()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let result = bitmap::bitop(op, &sources);
+        let len = result.len();
+        if result.is_empty() {
+            map.remove(&destination);
+        } else {
+            let value =
+                ValueWrapper::new(&destination, Value::String(result), None, &self.used_memory);
+            map.insert(destination, value);
+        }
+        Ok(len)
+    }
+
+    /// Returns the number of fields that were newly added
+    pub fn hset(&self, key: String, kvs: Vec<(String, String)>) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+        let hash = get_live_hash_or_insert(&mut map, key, &self.used_memory)?;
+
+        let mut num_added = 0;
+        for (field, value) in kvs {
+            if hash.insert(field, value).is_none() {
+                num_added += 1;
+            }
+        }
+
+        Ok(num_added)
+    }
+
+    /// Sets `field` only if it doesn't exist yet, creating the hash if
+    /// needed. Returns whether the field was set.
+    pub fn hsetnx(&self, key: String, field: String, value: String) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+        let hash = get_live_hash_or_insert(&mut map, key, &self.used_memory)?;
+
+        if hash.contains_key(&field) {
+            return Ok(false);
+        }
+        hash.insert(field, value);
+        Ok(true)
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live_hash(&mut map, key)? {
+            None => Ok(None),
+            Some(hash) => Ok(hash.get(field).cloned()),
+        }
+    }
+
+    /// Runs `f` on the fields of the hash stored at `key` while holding the
+    /// lock, so that callers can build replies without cloning the whole hash.
+    /// A missing key is treated as an empty hash.
+    pub fn with_hash<T>(&self, key: &str, f: impl FnOnce(&ScanMap<String>) -> T) -> Result<T> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live_hash(&mut map, key)? {
+            None => Ok(f(&ScanMap::new())),
+            Some(hash) => Ok(f(hash.fields())),
+        }
+    }
+
+    /// Returns the number of fields that were removed. The key is removed
+    /// when the hash becomes empty.
+    pub fn hdel(&self, key: &str, fields: &[String]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let hash = match get_live_hash(&mut map, key)? {
+            None => return Ok(0),
+            Some(hash) => hash,
+        };
+
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(field).is_some())
+            .count();
+
+        if hash.is_empty() {
+            map.remove(key);
+        }
+
+        Ok(removed)
+    }
+
+    /// Adds `incr` to the integer stored in `field`, which is created at 0
+    /// if missing. Returns the new value.
+    pub fn hincrby(&self, key: String, field: String, incr: i64) -> Result<i64> {
+        let mut map = self.map.lock().unwrap();
+        let hash = get_live_hash_or_insert(&mut map, key, &self.used_memory)?;
+
+        let curr = match hash.get(&field) {
+            None => 0,
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| anyhow!(HASH_VALUE_NOT_INTEGER_ERR_MSG))?,
+        };
+        let new = curr.checked_add(incr).ok_or(anyhow!(OVERFLOW_ERR_MSG))?;
+
+        hash.update(field, new.to_string());
+        Ok(new)
+    }
+
+    /// Adds `incr` to the float stored in `field`, which is created at 0 if
+    /// missing. Returns the new value, formatted as it's stored.
+    pub fn hincrbyfloat(&self, key: String, field: String, incr: f64) -> Result<String> {
+        // Checked before the hash is created, so that a missing key stays
+        // missing
+        if !incr.is_finite() {
+            bail!(NAN_OR_INFINITY_ERR_MSG);
+        }
+        let mut map = self.map.lock().unwrap();
+        let hash = get_live_hash_or_insert(&mut map, key, &self.used_memory)?;
+
+        let curr = match hash.get(&field) {
+            None => 0.0,
+            Some(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|v| !v.is_nan())
+                .ok_or(anyhow!(HASH_VALUE_NOT_FLOAT_ERR_MSG))?,
+        };
+        let new = curr + incr;
+        if !new.is_finite() {
+            bail!(NAN_OR_INFINITY_ERR_MSG);
+        }
+
+        let new = format_fixed(new);
+        hash.update(field, new.clone());
+        Ok(new)
+    }
+
+    pub fn hexists(&self, key: &str, field: &str) -> Result<bool> {
+        Ok(self.hget(key, field)?.is_some())
+    }
+
+    /// Sets the expiration of each of `fields`. Returns for each field:
+    /// -2 if it doesn't exist, 2 if it was deleted because `expiration` has
+    /// passed, and 1 if the expiration was set.
+    pub fn hexpire(
+        &self,
+        key: &str,
+        fields: &[String],
+        expiration: SystemTime,
+    ) -> Result<Vec<i64>> {
+        let mut map = self.map.lock().unwrap();
+
+        let hash = match get_live_hash(&mut map, key)? {
+            None => return Ok(vec![-2; fields.len()]),
+            Some(hash) => hash,
+        };
+
+        let expired = expiration <= SystemTime::now();
+        let results = fields
+            .iter()
+            .map(|field| {
+                if !hash.contains_key(field) {
+                    -2
+                } else if expired {
+                    hash.remove(field);
+                    2
+                } else {
+                    hash.set_expiration(field, Some(expiration));
+                    1
+                }
+            })
+            .collect();
+
+        if hash.is_empty() {
+            map.remove(key);
+        }
+
+        Ok(results)
+    }
+
+    /// Returns the remaining time to live in milliseconds of each of
+    /// `fields`, or -2 if the field doesn't exist and -1 if it has no
+    /// expiration
+    pub fn hpttl(&self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        let mut map = self.map.lock().unwrap();
+
+        let hash = match get_live_hash(&mut map, key)? {
+            None => return Ok(vec![-2; fields.len()]),
+            Some(hash) => hash,
+        };
+
+        let now = SystemTime::now();
+        Ok(fields
+            .iter()
+            .map(
+                |field| match (hash.contains_key(field), hash.expiration(field)) {
+                    (false, _) => -2,
+                    (true, None) => -1,
+                    (true, Some(expiration)) => expiration
+                        .duration_since(now)
+                        .map_or(0, |ttl| ttl.as_millis() as i64),
+                },
+            )
+            .collect())
+    }
+
+    /// Removes the expiration of each of `fields`. Returns for each field:
+    /// -2 if it doesn't exist, -1 if it has no expiration, and 1 if the
+    /// expiration was removed.
+    pub fn hpersist(&self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        let mut map = self.map.lock().unwrap();
+
+        let hash = match get_live_hash(&mut map, key)? {
+            None => return Ok(vec![-2; fields.len()]),
+            Some(hash) => hash,
+        };
+
+        Ok(fields
+            .iter()
+            .map(
+                |field| match (hash.contains_key(field), hash.expiration(field)) {
+                    (false, _) => -2,
+                    (true, None) => -1,
+                    (true, Some(_)) => {
+                        hash.set_expiration(field, None);
+                        1
+                    }
+                },
+            )
+            .collect())
+    }
+
+    /// Picks random fields of the hash at `key`, together with their values,
+    /// as described in `Rng::sample`. Returns `None` if the key doesn't exist.
+    pub fn hrandfield(
+        &self,
+        key: &str,
+        count: i64,
+        rng: &mut Rng,
+    ) -> Result<Option<Vec<(String, String)>>> {
+        let mut map = self.map.lock().unwrap();
+
+        let hash = match get_live_hash(&mut map, key)? {
+            None => return Ok(None),
+            Some(hash) => hash,
+        };
+
+        let fields = hash.fields();
+        let picked = rng.sample(fields.iter(), fields.len(), count);
+
+        Ok(Some(
+            picked
+                .into_iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Returns the number of members that were newly added
+    pub fn sadd(&self, key: String, members: Vec<String>) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+        let set = get_live_set_or_insert(&mut map, key, &self.used_memory)?;
+
+        Ok(members
+            .into_iter()
+            .filter(|member| set.insert(member.clone()))
+            .count())
+    }
+
+    /// Returns the number of members that were removed. The key is removed
+    /// when the set becomes empty.
+    pub fn srem(&self, key: &str, members: &[String]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let set = match get_live_set(&mut map, key)? {
+            None => return Ok(0),
+            Some(set) => set,
+        };
+
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+
+        if set.is_empty() {
+            map.remove(key);
+        }
+
+        Ok(removed)
+    }
+
+    /// Moves `member` from the set at `source` to the set at `destination`.
+    /// Returns whether `member` was in `source`. The source key is removed
+    /// when its set becomes empty.
+    pub fn smove(&self, source: &str, destination: String, member: String) -> Result<bool> {
+        let mut map = self.map.lock().unwrap();
+
+        // Check both types before changing anything
+        get_live_set(&mut map, &destination)?;
+        let set = match get_live_set(&mut map, source)? {
+            None => return Ok(false),
+            Some(set) => set,
+        };
+
+        if source == destination {
+            return Ok(set.contains(&member));
+        }
+        if !set.remove(&member) {
+            return Ok(false);
+        }
+        if set.is_empty() {
+            map.remove(source);
+        }
+
+        get_live_set_or_insert(&mut map, destination, &self.used_memory)?.insert(member);
+        Ok(true)
+    }
+
+    /// Runs `f` on the set stored at `key` while holding the lock. A missing
+    /// key is treated as an empty set.
+    pub fn with_set<T>(&self, key: &str, f: impl FnOnce(&ScanSet) -> T) -> Result<T> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live_set(&mut map, key)? {
+            None => Ok(f(&ScanSet::new())),
+            Some(set) => Ok(f(set)),
+        }
+    }
+
+    /// Picks random members of the set at `key`, like `hrandfield`. Returns
+    /// `None` if the key doesn't exist.
+    pub fn srandmember(&self, key: &str, count: i64, rng: &mut Rng) -> Result<Option<Vec<String>>> {
+        let mut map = self.map.lock().unwrap();
+
+        let set = match get_live_set(&mut map, key)? {
+            None => return Ok(None),
+            Some(set) => set,
+        };
+
+        let picked = rng.sample(set.iter(), set.len(), count);
+        Ok(Some(picked.into_iter().cloned().collect()))
+    }
+
+    /// Picks random members of the sorted set at `key` with their scores, as
+    /// `Rng::sample` does. Returns `None` if the key doesn't exist.
+    pub fn zrandmember(
+        &self,
+        key: &str,
+        count: i64,
+        rng: &mut Rng,
+    ) -> Result<Option<Vec<(String, f64)>>> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(zset) = get_live_sorted_set(&mut map, key)? else {
+            return Ok(None);
+        };
+        // Ranks are picked, and the members at those ranks found in one walk
+        let picked = rng.sample(zset.iter(), zset.len(), count);
+        Ok(Some(
+            picked
+                .into_iter()
+                .map(|(member, score)| (member.clone(), score))
+                .collect(),
+        ))
+    }
+
+    /// Removes and returns up to `count` random members of the set at `key`.
+    /// The key is removed when the set becomes empty.
+    pub fn spop(&self, key: &str, count: usize, rng: &mut Rng) -> Result<Vec<String>> {
+        let mut map = self.map.lock().unwrap();
+
+        let set = match get_live_set(&mut map, key)? {
+            None => return Ok(Vec::new()),
+            Some(set) => set,
+        };
+
+        let popped: Vec<String> = rng
+            .sample(set.iter(), set.len(), count as i64)
+            .into_iter()
+            .cloned()
+            .collect();
+        for member in popped.iter() {
+            set.remove(member);
+        }
+
+        if set.is_empty() {
+            map.remove(key);
         }
+
+        Ok(popped)
     }
-}
 
-pub struct Store {
-    map: Arc<Mutex<HashMap<String, ValueWrapper>>>,
-    streams: Arc<Mutex<HashMap<String, Stream>>>,
-}
+    /// The result of SINTER, SUNION or SDIFF over the sets stored at `keys`
+    pub fn set_op(&self, op: SetOp, keys: &[String]) -> Result<HashSet<String>> {
+        let mut map = self.map.lock().unwrap();
+        combine_sets(&mut map, op, keys)
+    }
 
-impl Store {
-    pub fn new() -> Self {
-        Store {
-            map: Arc::new(Mutex::new(HashMap::new())),
-            streams: Arc::new(Mutex::new(HashMap::new())),
+    /// The size of the intersection of the sets stored at `keys`, without
+    /// building it. Counting stops at `limit` if it's not 0.
+    pub fn sintercard(&self, keys: &[String], limit: usize) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        // Check the types and remove expired keys first, so that the sets can
+        // then be borrowed all at once
+        for key in keys {
+            get_live_set(&mut map, key)?;
+        }
+        let mut sets = Vec::new();
+        for key in keys {
+            match map.get(key) {
+                None => return Ok(0),
+                Some(v) => sets.push(v.value.as_set()?),
+            }
+        }
+
+        // Iterate the smallest set and probe the others
+        sets.sort_by_key(|set| set.len());
+        let Some((smallest, others)) = sets.split_first() else {
+            return Ok(0);
+        };
+        let mut count = 0;
+        for member in smallest.iter() {
+            if others.iter().all(|set| set.contains(member)) {
+                count += 1;
+                if count == limit {
+                    break;
+                }
+            }
         }
+        Ok(count)
     }
 
-    pub fn get_type(&self, key: String) -> String {
-        match self.get(key.as_str()) {
-            Some(v) => return v.type_string(),
-            None => {
-                let streams = self.streams.lock().unwrap();
+    /// Stores the result of `set_op` at `destination`, replacing any value
+    /// there, or removes `destination` if the result is empty. Returns the
+    /// size of the result.
+    pub fn set_op_store(&self, op: SetOp, destination: String, keys: &[String]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
 
-                if streams.contains_key(key.as_str()) {
-                    return "stream".into();
-                }
+        // The sets are combined before anything is written, so `destination`
+        // may also be one of `keys`
+        let result = combine_sets(&mut map, op, keys)?;
+        let len = result.len();
+        if result.is_empty() {
+            map.remove(&destination);
+        } else {
+            let value = ValueWrapper::new(
+                &destination,
+                Value::Set(result.into_iter().collect()),
+                None,
+                &self.used_memory,
+            );
+            map.insert(destination, value);
+        }
+        Ok(len)
+    }
+
+    /// The result of ZUNION, ZINTER or ZDIFF over the sets or sorted sets
+    /// stored at `keys`. `weights` has a weight for each of `keys`.
+    pub fn zset_op(
+        &self,
+        op: SetOp,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<SortedSet> {
+        let mut map = self.map.lock().unwrap();
+        combine_sorted_sets(&mut map, op, keys, weights, aggregate)
+    }
+
+    /// Stores the result of `combine_sorted_sets` at `destination`, like
+    /// `set_op_store`. `weights` has a weight for each of `keys`.
+    pub fn zset_op_store(
+        &self,
+        op: SetOp,
+        destination: String,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let result = combine_sorted_sets(&mut map, op, keys, weights, aggregate)?;
+        let len = result.len();
+        if result.is_empty() {
+            map.remove(&destination);
+        } else {
+            let value = ValueWrapper::new(
+                &destination,
+                Value::SortedSet(result),
+                None,
+                &self.used_memory,
+            );
+            map.insert(destination.clone(), value);
+            self.wake_waiters(&destination);
+        }
+        Ok(len)
+    }
+
+    /// Stores the members of the sorted set at `source` selected by `query`
+    /// at `destination`, like `zset_op_store`
+    pub fn zrangestore(
+        &self,
+        destination: String,
+        source: &str,
+        query: &RangeQuery,
+    ) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let mut result = SortedSet::new();
+        if let Some(zset) = get_live_sorted_set(&mut map, source)? {
+            for (member, score) in zset.query(query) {
+                result.add(member.clone(), score, &AddOptions::default())?;
             }
         }
+        let len = result.len();
+        if result.is_empty() {
+            map.remove(&destination);
+        } else {
+            let value = ValueWrapper::new(
+                &destination,
+                Value::SortedSet(result),
+                None,
+                &self.used_memory,
+            );
+            map.insert(destination.clone(), value);
+            self.wake_waiters(&destination);
+        }
+        Ok(len)
+    }
+
+    /// Adds members with their scores, or updates their scores, as ZADD
+    /// does. Returns what was done with each member. The key isn't created if
+    /// no member is added.
+    pub fn zadd(
+        &self,
+        key: String,
+        members: Vec<(f64, String)>,
+        options: &AddOptions,
+    ) -> Result<Vec<Added>> {
+        let mut map = self.map.lock().unwrap();
+        let zset = get_live_sorted_set_or_insert(&mut map, key.clone(), &self.used_memory)?;
 
-        "none".into()
+        let added = members
+            .into_iter()
+            .map(|(score, member)| zset.add(member, score, options))
+            .collect::<Result<Vec<_>>>();
+        if zset.is_empty() {
+            map.remove(&key);
+        }
+        if let Ok(added) = &added {
+            if added.iter().any(|a| matches!(a, Added::New(_))) {
+                self.wake_waiters(&key);
+            }
+        }
+        added
     }
 
-    pub fn set(&self, key: String, value: Value, expire_in: Option<Duration>) {
-        let expiration = expire_in.and_then(|expire_in| SystemTime::now().checked_add(expire_in));
+    /// Removes and returns up to `count` members with the lowest scores, or
+    /// the highest if `max`. The key is removed when the sorted set becomes
+    /// empty.
+    pub fn zpop(&self, key: &str, count: usize, max: bool) -> Result<Vec<(String, f64)>> {
+        let mut map = self.map.lock().unwrap();
+        pop_sorted_set(&mut map, key, count, max)
+    }
 
-        self.map
-            .lock()
-            .unwrap()
-            .insert(key, ValueWrapper { value, expiration });
+    /// Like `zpop`, on the first of `keys` that has members. Returns that key
+    /// with the popped members, or None if none of them has members.
+    pub fn sorted_set_multi_pop(
+        &self,
+        keys: &[String],
+        count: usize,
+        max: bool,
+    ) -> Result<Option<(String, ScoredMembers)>> {
+        let mut map = self.map.lock().unwrap();
+        for key in keys {
+            let popped = pop_sorted_set(&mut map, key, count, max)?;
+            if !popped.is_empty() {
+                return Ok(Some((key.clone(), popped)));
+            }
+        }
+        Ok(None)
     }
 
-    pub fn get(&self, key: &str) -> Option<Value> {
+    /// Removes `members` from the sorted set at `key`. Returns the number of
+    /// members that were removed. The key is removed when the sorted set
+    /// becomes empty.
+    pub fn zrem(&self, key: &str, members: &[String]) -> Result<usize> {
         let mut map = self.map.lock().unwrap();
+        let Some(zset) = get_live_sorted_set(&mut map, key)? else {
+            return Ok(0);
+        };
 
-        match map.get(key).cloned() {
-            None => None,
-            Some(value) => {
-                if value.has_expired() {
-                    map.remove(key);
-                    None
-                } else {
-                    Some(value.value)
-                }
+        let removed = members
+            .iter()
+            .filter(|member| zset.remove(member).is_some())
+            .count();
+        if zset.is_empty() {
+            map.remove(key);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the members in `range` from the sorted set at `key`, like
+    /// `zrem`. Only the members in the range are cloned.
+    pub fn zremrange(&self, key: &str, range: &Range) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+        let Some(zset) = get_live_sorted_set(&mut map, key)? else {
+            return Ok(0);
+        };
+
+        let members: Vec<String> = zset
+            .range(range, false)
+            .map(|(member, _)| member.clone())
+            .collect();
+        for member in members.iter() {
+            zset.remove(member);
+        }
+        if zset.is_empty() {
+            map.remove(key);
+        }
+        Ok(members.len())
+    }
+
+    /// Runs `f` on the sorted set stored at `key` while holding the lock, or
+    /// returns `None` if the key doesn't exist
+    pub fn with_sorted_set<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&SortedSet) -> T,
+    ) -> Result<Option<T>> {
+        let mut map = self.map.lock().unwrap();
+        Ok(get_live_sorted_set(&mut map, key)?.map(|zset| f(zset)))
+    }
+
+    /// The elements that SORT sorts: the members of a set, or those of a
+    /// sorted set by score. A missing key has none.
+    pub fn sort_elements(&self, key: &str) -> Result<Vec<String>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, key).map(|v| &v.value) {
+            None => Ok(Vec::new()),
+            Some(Value::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(Value::SortedSet(zset)) => {
+                Ok(zset.iter().map(|(member, _)| member.clone()).collect())
             }
+            Some(_) => bail!(WRONG_TYPE_ERR_MSG),
         }
     }
 
@@ -82,63 +1952,648 @@ impl Store {
         stream: String,
         start: Bound<EntryId>,
         end: Bound<EntryId>,
+        count: Option<usize>,
+    ) -> Result<Vec<(EntryId, Vec<Entry>)>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, &stream) {
+            None => Ok(Vec::new()),
+            Some(v) => v.value.as_stream()?.range(start, end, count),
+        }
+    }
+
+    /// Number of entries in the stream at `stream`, 0 if it doesn't exist
+    pub fn stream_len(&self, stream: &str) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, stream) {
+            None => Ok(0),
+            Some(v) => Ok(v.value.as_stream()?.len()),
+        }
+    }
+
+    pub fn get_stream_rev_range(
+        &self,
+        stream: &str,
+        start: Bound<EntryId>,
+        end: Bound<EntryId>,
+        count: Option<usize>,
     ) -> Result<Vec<(EntryId, Vec<Entry>)>> {
-        let streams = self.streams.lock().unwrap();
-        let stream = streams.get(&stream);
+        let mut map = self.map.lock().unwrap();
 
-        match stream {
+        match get_live(&mut map, stream) {
             None => Ok(Vec::new()),
-            Some(stream) => stream.range(start, end),
+            Some(v) => Ok(v.value.as_stream()?.rev_range(start, end, count)),
+        }
+    }
+
+    /// Removes the given entries from the stream, returning how many existed.
+    /// Like in Redis, a stream left empty is kept, along with its last id.
+    pub fn stream_remove(&self, stream: &str, entry_ids: &[EntryId]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            return Ok(0);
+        };
+        let removed = v.value.as_stream_mut()?.remove(entry_ids);
+        v.resize(stream);
+        Ok(removed)
+    }
+
+    /// Sets the last generated id of the stream and its counters, for XSETID
+    pub fn stream_set_id(
+        &self,
+        stream: &str,
+        last_id: EntryId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<EntryId>,
+    ) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            bail!(NO_SUCH_KEY_ERR_MSG);
+        };
+        v.value
+            .as_stream_mut()?
+            .set_id(last_id, entries_added, max_deleted_id)
+    }
+
+    /// Trims the stream as `trim` says, evicting at most `limit` entries if
+    /// given. Returns how many were evicted.
+    pub fn stream_trim(&self, stream: &str, trim: &Trim, limit: Option<usize>) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            return Ok(0);
+        };
+        let evicted = v.value.as_stream_mut()?.trim(trim, limit);
+        v.resize(stream);
+        Ok(evicted)
+    }
+
+    /// Metadata of the stream at `stream`, or `None` if it doesn't exist
+    pub fn stream_info(&self, stream: &str) -> Result<Option<StreamInfo>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, stream) {
+            None => Ok(None),
+            Some(v) => Ok(Some(v.value.as_stream()?.info())),
+        }
+    }
+
+    /// Runs `f` on the stream stored at `stream` while holding the lock, or
+    /// returns `None` if the key doesn't exist
+    pub fn with_stream<T>(&self, stream: &str, f: impl FnOnce(&Stream) -> T) -> Result<Option<T>> {
+        let mut map = self.map.lock().unwrap();
+
+        match get_live(&mut map, stream) {
+            None => Ok(None),
+            Some(v) => Ok(Some(f(v.value.as_stream()?))),
+        }
+    }
+
+    /// Like `with_stream`, for changing the stream
+    pub fn with_stream_mut<T>(
+        &self,
+        stream: &str,
+        f: impl FnOnce(&mut Stream) -> T,
+    ) -> Result<Option<T>> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            return Ok(None);
+        };
+        let result = f(v.value.as_stream_mut()?);
+        v.resize(stream);
+        Ok(Some(result))
+    }
+
+    /// Like `with_stream`, for changing the stream. Fails if the key doesn't
+    /// exist, as the XGROUP subcommands do.
+    pub fn with_existing_stream_mut<T>(
+        &self,
+        stream: &str,
+        f: impl FnOnce(&mut Stream) -> T,
+    ) -> Result<T> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            bail!(XGROUP_NO_KEY_ERR_MSG);
+        };
+        let result = f(v.value.as_stream_mut()?);
+        v.resize(stream);
+        Ok(result)
+    }
+
+    /// Creates a consumer group of the stream, delivering the entries after
+    /// `entry_id`, or the entries added from now on if it's `None`. With
+    /// `mkstream`, an empty stream is created if the key doesn't exist.
+    /// Returns the id the group starts after.
+    pub fn stream_create_group(
+        &self,
+        stream: &str,
+        group: String,
+        entry_id: Option<EntryId>,
+        mkstream: bool,
+    ) -> Result<EntryId> {
+        let mut map = self.map.lock().unwrap();
+
+        if get_live(&mut map, stream).is_none() && !mkstream {
+            bail!(XGROUP_NO_KEY_ERR_MSG);
+        }
+        let v = get_live_or_insert_with(&mut map, stream.to_string(), &self.used_memory, || {
+            Value::Stream(Stream::new())
+        });
+        let stream_value = v.value.as_stream_mut()?;
+        let entry_id = entry_id.unwrap_or_else(|| stream_value.max_entry_id());
+        if !stream_value.create_group(group, entry_id.clone()) {
+            bail!(BUSYGROUP_ERR_MSG);
         }
+        v.resize(stream);
+        Ok(entry_id)
+    }
+
+    /// See `Stream::read_group`. Returns `None` if the stream or the group
+    /// doesn't exist.
+    pub fn stream_read_group(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        after: Option<EntryId>,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<Option<Vec<GroupEntry>>> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            return Ok(None);
+        };
+        let read = v
+            .value
+            .as_stream_mut()?
+            .read_group(group, consumer, after, count, noack);
+        v.resize(stream);
+        Ok(read)
+    }
+
+    /// Acknowledges entries delivered to `group`, returning how many were
+    /// pending
+    pub fn stream_ack(&self, stream: &str, group: &str, entry_ids: &[EntryId]) -> Result<usize> {
+        let mut map = self.map.lock().unwrap();
+
+        let Some(v) = get_live(&mut map, stream) else {
+            return Ok(0);
+        };
+        let acked = v.value.as_stream_mut()?.ack(group, entry_ids);
+        v.resize(stream);
+        Ok(acked)
     }
 
-    pub fn get_stream_curr_max_id(&self, stream: String) -> EntryId {
-        let mut streams = self.streams.lock().unwrap();
+    pub fn get_stream_curr_max_id(&self, stream: String) -> Result<EntryId> {
+        let mut map = self.map.lock().unwrap();
 
-        let stream = streams.entry(stream).or_insert(Stream::new());
-        stream.max_entry_id()
+        match get_live(&mut map, &stream) {
+            None => Ok(Stream::new().max_entry_id()),
+            Some(v) => Ok(v.value.as_stream()?.max_entry_id()),
+        }
     }
 
     /// The `entry_id` arg might be wildcard. The returned `EntryId` is the
     /// actually inserted id.
     pub fn stream_set(
-        &mut self,
+        &self,
         stream: String,
         entry_id: String,
         kvs: Vec<(String, String)>,
     ) -> Result<EntryId> {
-        let mut streams = self.streams.lock().unwrap();
+        let entry_id = self.stream_append(stream, entry_id, kvs, true)?;
+        Ok(entry_id.expect("the stream is created"))
+    }
+
+    /// Like `stream_set`, but returns `None` rather than create the stream if
+    /// it doesn't exist, for XADD NOMKSTREAM
+    pub fn stream_set_existing(
+        &self,
+        stream: String,
+        entry_id: String,
+        kvs: Vec<(String, String)>,
+    ) -> Result<Option<EntryId>> {
+        self.stream_append(stream, entry_id, kvs, false)
+    }
 
-        let stream = streams.entry(stream).or_insert(Stream::new());
-        let entry_id = EntryId::create(entry_id, &stream.max_entry_id())?;
+    fn stream_append(
+        &self,
+        stream: String,
+        entry_id: String,
+        kvs: Vec<(String, String)>,
+        mkstream: bool,
+    ) -> Result<Option<EntryId>> {
+        let mut map = self.map.lock().unwrap();
+
+        let v = if mkstream {
+            get_live_or_insert_with(&mut map, stream.clone(), &self.used_memory, || {
+                Value::Stream(Stream::new())
+            })
+        } else {
+            match get_live(&mut map, &stream) {
+                None => return Ok(None),
+                Some(v) => v,
+            }
+        };
+        let stream_value = v.value.as_stream_mut()?;
+        let entry_id = EntryId::create(entry_id, &stream_value.max_entry_id())?;
 
         let entries = kvs
             .into_iter()
             .map(|(key, value)| Entry { key, value })
             .collect();
 
-        stream.append(entry_id.clone(), entries)?;
+        stream_value.append(entry_id.clone(), entries)?;
+        v.resize(&stream);
+        self.notify_stream_subscribers(&stream, &entry_id);
 
-        Ok(entry_id)
+        Ok(Some(entry_id))
+    }
+
+    /// Returns a channel that's sent to once an entry after `entry_id` is
+    /// added to `stream`, after which the subscriber is removed. The stream
+    /// doesn't need to exist.
+    pub fn stream_subscribe(
+        &self,
+        stream: String,
+        entry_id: EntryId,
+    ) -> Result<(SubscriberId, Receiver<()>)> {
+        if let Some(v) = get_live(&mut self.map.lock().unwrap(), &stream) {
+            v.value.as_stream()?;
+        }
+
+        let id = SubscriberId {
+            entry_id,
+            token: self.next_token.fetch_add(1, Ordering::Relaxed),
+        };
+        let (tx, rx) = unbounded();
+        self.stream_subscribers
+            .lock()
+            .unwrap()
+            .entry(stream)
+            .or_default()
+            .insert(id.clone(), tx);
+        Ok((id, rx))
     }
 
-    pub fn stream_subscribe(&mut self, stream: String, entry_id: EntryId) -> Receiver<()> {
-        let mut streams = self.streams.lock().unwrap();
-        let stream = streams.entry(stream).or_insert(Stream::new());
-        stream.subscribe_entries_after(entry_id)
+    /// Undoes `stream_subscribe`, if the subscriber wasn't notified yet
+    pub fn stream_unsubscribe(&self, stream: &str, id: &SubscriberId) {
+        let mut subscribers = self.stream_subscribers.lock().unwrap();
+        if let Some(stream_subscribers) = subscribers.get_mut(stream) {
+            stream_subscribers.remove(id);
+            if stream_subscribers.is_empty() {
+                subscribers.remove(stream);
+            }
+        }
+    }
+
+    // Notifies the subscribers waiting for entries before `entry_id`, just
+    // added to `stream`. Those waiting for it exactly keep waiting.
+    fn notify_stream_subscribers(&self, stream: &str, entry_id: &EntryId) {
+        let mut subscribers = self.stream_subscribers.lock().unwrap();
+        let Some(stream_subscribers) = subscribers.get_mut(stream) else {
+            return;
+        };
+        let waiting = stream_subscribers.split_off(&SubscriberId {
+            entry_id: entry_id.clone(),
+            token: 0,
+        });
+        for tx in std::mem::replace(stream_subscribers, waiting).into_values() {
+            // The client may have stopped waiting
+            let _ = tx.send(());
+        }
+        if stream_subscribers.is_empty() {
+            subscribers.remove(stream);
+        }
+    }
+
+    /// Number of clients waiting for entries of `stream`
+    #[cfg(test)]
+    pub fn num_stream_subscribers(&self, stream: &str) -> usize {
+        self.stream_subscribers
+            .lock()
+            .unwrap()
+            .get(stream)
+            .map_or(0, |subscribers| subscribers.len())
     }
 
     pub fn data(&self) -> HashMap<String, Value> {
         let mut map = self.map.lock().unwrap();
 
-        *map = map
-            .iter()
-            .filter(|&(_, v)| !v.has_expired())
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
+        let now = SystemTime::now();
+        map.retain(|_, v| !v.remove_expired(now));
 
         map.iter()
             .map(|(k, v)| (k.clone(), v.value.clone()))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_expiry() {
+        let dbs = StoreDb::new();
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            let value = Value::String(i.to_string().into_bytes());
+            dbs.get(3).set(key, value, Some(Duration::from_millis(1)));
+        }
+        dbs.get(3)
+            .set("persistent".into(), Value::String("x".into()), None);
+
+        let active_expiry = dbs.start_active_expiry();
+        thread::sleep(Duration::from_millis(200));
+        active_expiry.stop();
+
+        // Look at the map directly, since reading through the store would
+        // remove expired keys too
+        let map = dbs.get(3).map.lock().unwrap();
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["persistent"]);
+    }
+
+    #[test]
+    fn paused_active_expiry() {
+        let dbs = StoreDb::new();
+        let active_expiry = dbs.start_active_expiry();
+        active_expiry.set_enabled(false);
+        set(dbs.get(0), "a", Some(Duration::from_millis(1)));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(dbs.get(0).map.lock().unwrap().len(), 1);
+
+        active_expiry.set_enabled(true);
+        thread::sleep(Duration::from_millis(200));
+        active_expiry.stop();
+        assert!(dbs.get(0).map.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn score_formatting() {
+        assert_eq!(format_score(1.5), "1.5");
+        assert_eq!(format_score(-3.0), "-3");
+        assert_eq!(format_score(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_score(1e16), "10000000000000000");
+        assert_eq!(format_score(1e17), "1e+17");
+        assert_eq!(format_score(1e308), "1e+308");
+        assert_eq!(format_score(-1.25e20), "-1.25e+20");
+        assert_eq!(format_score(0.0001), "0.0001");
+        assert_eq!(format_score(0.00001), "1e-05");
+        assert_eq!(format_score(5e-324), "5e-324");
+        assert_eq!(format_score(f64::INFINITY), "inf");
+        assert_eq!(format_score(f64::NEG_INFINITY), "-inf");
+    }
+
+    #[test]
+    fn fixed_formatting() {
+        assert_eq!(format_fixed(10.5), "10.5");
+        assert_eq!(format_fixed(205.0), "205");
+        assert_eq!(format_fixed(1e20), "100000000000000000000");
+        assert_eq!(format_fixed(1e-5), "0.00001");
+        assert_eq!(format_fixed(1.5e-10), "0.00000000015");
+        assert_eq!(format_fixed(1e-20), "0");
+        assert_eq!(format_fixed(-1e-20), "0");
+        assert_eq!(format_fixed(1.2345678901234567e-10), "0.00000000012345679");
+    }
+
+    fn set(store: &Store, key: &str, expire_in: Option<Duration>) {
+        store.set(key.into(), Value::String("value".into()), expire_in);
+    }
+
+    #[test]
+    fn used_memory() {
+        let dbs = StoreDb::new();
+        assert_eq!(dbs.used_memory(), 0);
+
+        set(dbs.get(0), "a", None);
+        let size = dbs.used_memory();
+        assert_eq!(size, approx_size("a", &Value::String("value".into())));
+        set(dbs.get(1), "a", None);
+        assert_eq!(dbs.used_memory(), 2 * size);
+
+        // Overwriting doesn't count twice
+        set(dbs.get(1), "a", None);
+        assert_eq!(dbs.used_memory(), 2 * size);
+
+        dbs.get(0)
+            .sadd("s".into(), vec!["x".into(), "y".into()])
+            .unwrap();
+        dbs.get(0).update_sizes(&["s".into()]);
+        let with_set = dbs.used_memory();
+        dbs.get(0).srem("s", &["x".into()]).unwrap();
+        dbs.get(0).update_sizes(&["s".into()]);
+        assert_eq!(dbs.used_memory(), with_set - ELEMENT_OVERHEAD);
+
+        dbs.get(0).del(&["a".into(), "s".into()]);
+        dbs.get(1).flush(false);
+        assert_eq!(dbs.used_memory(), 0);
+    }
+
+    #[test]
+    fn evict_lru() {
+        let dbs = StoreDb::new();
+        let mut rng = Rng::new(1);
+        // Few enough keys that the sample covers all of them
+        for key in ["a", "b", "c", "d"] {
+            set(dbs.get(0), key, None);
+            thread::sleep(Duration::from_millis(2));
+        }
+        let size = dbs.used_memory() / 4;
+
+        // Reading a key makes it recently used
+        dbs.get(0).get("a");
+        let (evicted, freed) = dbs.evict(3 * size, "allkeys-lru", &mut rng);
+        assert!(freed);
+        assert_eq!(evicted, vec![(0, "b".to_string())]);
+
+        let (evicted, freed) = dbs.evict(3 * size, "allkeys-lru", &mut rng);
+        assert!(freed && evicted.is_empty());
+
+        let (evicted, freed) = dbs.evict(size, "noeviction", &mut rng);
+        assert!(!freed && evicted.is_empty());
+    }
+
+    #[test]
+    fn evict_volatile() {
+        let dbs = StoreDb::new();
+        let mut rng = Rng::new(1);
+        set(dbs.get(0), "a", None);
+        set(dbs.get(2), "b", Some(Duration::from_secs(100)));
+        set(dbs.get(2), "c", Some(Duration::from_secs(10)));
+
+        let (evicted, freed) = dbs.evict(0, "volatile-ttl", &mut rng);
+        assert!(!freed);
+        assert_eq!(evicted, vec![(2, "c".to_string()), (2, "b".to_string())]);
+        assert_eq!(dbs.get(0).count(), 1);
+    }
+
+    #[test]
+    fn access_frequency() {
+        let store = Store::new();
+        set(&store, "a", None);
+        assert_eq!(store.access_frequency("a"), Some(LFU_INIT_VAL));
+
+        // Logarithmic: many accesses only add a few
+        for _ in 0..1000 {
+            store.get("a");
+        }
+        let freq = store.access_frequency("a").unwrap();
+        assert!(
+            freq > LFU_INIT_VAL + 3 && freq < LFU_INIT_VAL + 20,
+            "{}",
+            freq
+        );
+
+        // Decays by one per period without access
+        store.map.lock().unwrap().get_mut("a").unwrap().lfu_time -= 3 * LFU_DECAY_PERIOD;
+        assert_eq!(store.access_frequency("a"), Some(freq - 3));
+        assert_eq!(store.access_frequency("b"), None);
+    }
+
+    #[test]
+    fn evict_lfu() {
+        let dbs = StoreDb::new();
+        let mut rng = Rng::new(1);
+        for key in ["a", "b", "c"] {
+            set(dbs.get(0), key, None);
+        }
+        for _ in 0..10 {
+            dbs.get(0).get("a");
+            dbs.get(0).get("c");
+        }
+        let size = dbs.used_memory() / 3;
+
+        let (evicted, freed) = dbs.evict(2 * size, "allkeys-lfu", &mut rng);
+        assert!(freed);
+        assert_eq!(evicted, vec![(0, "b".to_string())]);
+    }
+
+    #[test]
+    fn idle_time() {
+        let store = Store::new();
+        set(&store, "a", None);
+        store.map.lock().unwrap().get_mut("a").unwrap().last_access -= Duration::from_secs(10);
+
+        // Inspecting the key isn't an access
+        assert_eq!(store.object_encoding("a"), Some("embstr"));
+        assert_eq!(store.idle_time("a").unwrap().as_secs(), 10);
+        assert_eq!(store.idle_time("a").unwrap().as_secs(), 10);
+
+        store.get("a");
+        assert_eq!(store.idle_time("a").unwrap().as_secs(), 0);
+        assert_eq!(store.idle_time("b"), None);
+    }
+
+    #[test]
+    fn touch() {
+        let store = Store::new();
+        for key in ["a", "b", "expired"] {
+            set(&store, key, None);
+            store.map.lock().unwrap().get_mut(key).unwrap().last_access -= Duration::from_secs(10);
+        }
+        store
+            .map
+            .lock()
+            .unwrap()
+            .get_mut("expired")
+            .unwrap()
+            .expiration = Some(SystemTime::now() - Duration::from_secs(1));
+
+        let keys = ["a", "missing", "expired", "a"].map(String::from);
+        assert_eq!(store.touch(&keys), 2);
+        assert_eq!(store.idle_time("a").unwrap().as_secs(), 0);
+        assert_eq!(store.idle_time("b").unwrap().as_secs(), 10);
+        assert!(!store.contains("expired"));
+    }
+
+    #[test]
+    fn scan_keeps_up_with_the_keyspace() {
+        let store = Store::new();
+        for i in 0..50 {
+            set(&store, &format!("key:{}", i), None);
+        }
+        set(&store, "expired", Some(Duration::from_millis(1)));
+        store.del(&["key:0".to_string(), "key:1".to_string()]);
+        store.rename("key:2", "renamed").unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        let mut scanned = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = store.scan(cursor, 10);
+            scanned.extend(batch.into_iter().map(|(key, _)| key));
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        scanned.sort();
+        let mut keys = store.keys();
+        keys.sort();
+        assert_eq!(scanned, keys);
+        assert_eq!(scanned.len(), 48);
+
+        store.flush(false);
+        assert_eq!(store.scan(0, 10), (0, Vec::new()));
+    }
+
+    #[test]
+    fn waiters() {
+        let store = Store::new();
+        let keys = ["a".to_string(), "b".to_string()];
+        let (first, first_ready) = store.wait_for_keys(&keys);
+        let (second, second_ready) = store.wait_for_keys(&keys[1..]);
+        assert_eq!((store.num_waiters("a"), store.num_waiters("b")), (1, 2));
+
+        store.wake_waiters("b");
+        assert!(first_ready.try_recv().is_ok());
+        assert!(second_ready.try_recv().is_ok());
+        assert_eq!((store.num_waiters("a"), store.num_waiters("b")), (1, 0));
+
+        store.unwait(&keys, first);
+        store.unwait(&keys[1..], second);
+        assert!(store.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stream_subscribers() {
+        let store = Store::new();
+        let id = |s: &str| EntryId::create_from_complete(s.into()).unwrap();
+        let (_, first) = store.stream_subscribe("s".into(), id("1-0")).unwrap();
+        let (_, second) = store.stream_subscribe("s".into(), id("1-0")).unwrap();
+        let (after_two, third) = store.stream_subscribe("s".into(), id("2-0")).unwrap();
+        // Stopped waiting without unsubscribing
+        let (_, gone) = store.stream_subscribe("s".into(), id("1-0")).unwrap();
+        drop(gone);
+        // Waiting doesn't create the stream
+        assert_eq!(store.get_type("s".into()), "none");
+        assert_eq!(store.num_stream_subscribers("s"), 4);
+
+        store
+            .stream_set("s".into(), "2-0".into(), Vec::new())
+            .unwrap();
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+        // Still waiting for entries after 2-0
+        assert!(third.try_recv().is_err());
+        assert_eq!(store.num_stream_subscribers("s"), 1);
+
+        store.stream_unsubscribe("s", &after_two);
+        assert_eq!(store.num_stream_subscribers("s"), 0);
+        assert!(store.stream_subscribers.lock().unwrap().is_empty());
+        store
+            .stream_set("s".into(), "3-0".into(), Vec::new())
+            .unwrap();
+        assert!(third.try_recv().is_err());
+
+        store.set("str".into(), Value::String("x".into()), None);
+        assert!(store.stream_subscribe("str".into(), id("1-0")).is_err());
+    }
+}
@@ -1,11 +1,10 @@
 use anyhow::{bail, Result};
-use crossbeam_channel::{unbounded, Receiver, Sender};
 use std::ops::Bound;
-use std::ops::Bound::{Excluded, Unbounded};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::Display,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 const NOT_INCREASING_ERR_MSG: &str =
@@ -13,8 +12,32 @@ const NOT_INCREASING_ERR_MSG: &str =
 
 const MIN_ID_ERR_MSG: &str = "ERR The ID specified in XADD must be greater than 0-0";
 
+const SETID_SMALLER_ERR_MSG: &str =
+    "ERR The ID specified in XSETID is smaller than the target stream top item";
+
+const SETID_ENTRIES_ADDED_ERR_MSG: &str =
+    "ERR The entries_added specified in XSETID is smaller than the target stream length";
+
+const SETID_MAX_DELETED_ERR_MSG: &str =
+    "ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id";
+
+const INVALID_ID_ERR_MSG: &str = "ERR Invalid stream ID specified as stream command argument";
+
+// Redis stores a stream as a radix tree of listpacks holding up to this many
+// entries each, which XINFO reports on
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+/// How XADD and XTRIM trim a stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trim {
+    /// Keeps the newest entries, up to this many
+    MaxLen(usize),
+    /// Evicts the entries with smaller ids
+    MinId(EntryId),
+}
+
 // Derived PartialEq and Eq is exactly what we want: compare `ms` and then `seq`
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct EntryId {
     ms: u64,
     seq: u64,
@@ -26,16 +49,24 @@ impl Display for EntryId {
     }
 }
 
+// Parses the `<ms>` or `<seq>` part of an id
+fn parse_id_part(s: &str) -> Result<u64> {
+    match s.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!(INVALID_ID_ERR_MSG),
+    }
+}
+
 impl EntryId {
     // Create from "<ms>-<seq>", without any wildcards
     pub fn create_from_complete(s: String) -> Result<Self> {
         let vs = s.split('-').collect::<Vec<_>>();
         if vs.len() != 2 {
-            bail!("Expect 'ms-seq' format");
+            bail!(INVALID_ID_ERR_MSG);
         }
 
-        let ms = vs[0].to_string().parse()?;
-        let seq = vs[1].to_string().parse()?;
+        let ms = parse_id_part(vs[0])?;
+        let seq = parse_id_part(vs[1])?;
         Ok(Self { ms, seq })
     }
 
@@ -44,24 +75,41 @@ impl EntryId {
     /// <ms>-*
     /// *
     pub fn create(s: String, curr_max: &Self) -> Result<Self> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        Self::create_at(s, curr_max, now_ms)
+    }
+
+    // `create`, with `*` resolved at `now_ms`
+    fn create_at(s: String, curr_max: &Self, now_ms: u64) -> Result<Self> {
         if s == "*" {
-            let ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
-            let seq = if ms == curr_max.ms { 1 } else { 0 };
-            Ok(Self { ms, seq })
+            // Within the millisecond of `curr_max`, or if the clock went
+            // back, the sequence number goes on from it
+            if now_ms > curr_max.ms {
+                return Ok(Self { ms: now_ms, seq: 0 });
+            }
+            let id = match curr_max.seq.checked_add(1) {
+                Some(seq) => Self {
+                    ms: curr_max.ms,
+                    seq,
+                },
+                None => Self {
+                    ms: curr_max.ms.saturating_add(1),
+                    seq: 0,
+                },
+            };
+            Ok(id)
         } else {
             let vs = s.split('-').collect::<Vec<_>>();
             if vs.len() != 2 {
-                bail!("Expect 'ms-seq' format");
+                bail!(INVALID_ID_ERR_MSG);
             }
 
-            let ms = vs[0].to_string().parse()?;
-            let seq = match vs[1].to_string().parse::<u64>() {
-                Ok(ms) => ms,
-                Err(_) => {
-                    assert_eq!(vs[1], "*");
-
+            let ms = parse_id_part(vs[0])?;
+            let seq = match vs[1] {
+                "*" => {
                     if ms == curr_max.ms {
-                        curr_max.seq + 1
+                        // Fails to append once the sequence is exhausted
+                        curr_max.seq.saturating_add(1)
                     } else if ms == 0 {
                         // 0-0 is not allowed
                         1
@@ -69,6 +117,7 @@ impl EntryId {
                         0
                     }
                 }
+                seq => parse_id_part(seq)?,
             };
             Ok(Self { ms, seq })
         }
@@ -77,14 +126,16 @@ impl EntryId {
     // Create a start entry-id, handles:
     // <ms>-<seq>
     // <ms>
-    // -
+    // - and +, which may be given at either end
     pub fn create_start(s: String) -> Result<Self> {
         if s == "-" {
             Ok(Self { ms: 0, seq: 0 })
+        } else if s == "+" {
+            Ok(Self::max())
         } else if s.contains('-') {
             Self::create_from_complete(s)
         } else {
-            let ms: u64 = s.parse()?;
+            let ms = parse_id_part(&s)?;
             Ok(Self { ms, seq: 0 })
         }
     }
@@ -92,21 +143,39 @@ impl EntryId {
     // Create an end entry-id, handles:
     // <ms>-<seq>
     // <ms>
-    // +
+    // + and -, which may be given at either end
     pub fn create_end(s: String) -> Result<Self> {
         if s == "+" {
-            Ok(Self {
-                ms: u64::MAX,
-                seq: u64::MAX,
-            })
+            Ok(Self::max())
+        } else if s == "-" {
+            Ok(Self { ms: 0, seq: 0 })
         } else if s.contains('-') {
             Self::create_from_complete(s)
         } else {
-            let ms: u64 = s.parse()?;
+            let ms = parse_id_part(&s)?;
             Ok(Self { ms, seq: u64::MAX })
         }
     }
 
+    /// The start of a range as given to XRANGE: an id as taken by
+    /// `create_start`, excluded if prefixed by '('
+    pub fn start_bound(s: String) -> Result<Bound<Self>> {
+        match s.strip_prefix('(') {
+            Some("-" | "+") => bail!(INVALID_ID_ERR_MSG),
+            Some(id) => Ok(Excluded(Self::create_start(id.to_string())?)),
+            None => Ok(Included(Self::create_start(s)?)),
+        }
+    }
+
+    /// Like `start_bound`, for the end of a range
+    pub fn end_bound(s: String) -> Result<Bound<Self>> {
+        match s.strip_prefix('(') {
+            Some("-" | "+") => bail!(INVALID_ID_ERR_MSG),
+            Some(id) => Ok(Excluded(Self::create_end(id.to_string())?)),
+            None => Ok(Included(Self::create_end(s)?)),
+        }
+    }
+
     pub fn max() -> Self {
         Self {
             ms: u64::MAX,
@@ -115,24 +184,175 @@ impl EntryId {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Entry {
     pub key: String,
     pub value: String,
 }
 
-#[derive(Debug)]
+// Whether there's no id between `start` and `end`. `BTreeMap::range` panics
+// for such bounds instead of returning nothing.
+fn is_empty_range(start: &Bound<EntryId>, end: &Bound<EntryId>) -> bool {
+    match (start, end) {
+        (Included(start), Included(end)) => start > end,
+        (Included(start) | Excluded(start), Included(end) | Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// What XINFO STREAM reports about a stream
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    pub length: usize,
+    pub radix_tree_keys: usize,
+    pub radix_tree_nodes: usize,
+    pub last_generated_id: EntryId,
+    pub max_deleted_entry_id: EntryId,
+    pub entries_added: u64,
+    pub groups: usize,
+    pub first_entry: Option<(EntryId, Vec<Entry>)>,
+    pub last_entry: Option<(EntryId, Vec<Entry>)>,
+}
+
+/// An entry delivered to a consumer of a group but not acknowledged yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// When it was last delivered
+    pub delivery_time: SystemTime,
+    pub delivery_count: u64,
+}
+
+/// A consumer group, which delivers each entry of the stream to one of its
+/// consumers
+#[derive(Clone, Debug)]
+pub struct ConsumerGroup {
+    last_delivered_id: EntryId,
+    consumers: BTreeSet<String>,
+    // The Pending Entries List (PEL)
+    pending: BTreeMap<EntryId, PendingEntry>,
+}
+
+impl ConsumerGroup {
+    pub fn last_delivered_id(&self) -> &EntryId {
+        &self.last_delivered_id
+    }
+
+    pub fn num_consumers(&self) -> usize {
+        self.consumers.len()
+    }
+
+    pub fn pending(&self) -> &BTreeMap<EntryId, PendingEntry> {
+        &self.pending
+    }
+
+    /// The pending entries with ids between `start` and `end`
+    pub fn pending_range(
+        &self,
+        start: Bound<EntryId>,
+        end: Bound<EntryId>,
+    ) -> impl Iterator<Item = (&EntryId, &PendingEntry)> {
+        let range = (!is_empty_range(&start, &end)).then(|| self.pending.range((start, end)));
+        range.into_iter().flatten()
+    }
+}
+
+/// How XCLAIM and XAUTOCLAIM change the pending entries they claim
+#[derive(Clone, Debug)]
+pub struct ClaimOptions {
+    /// Entries delivered more recently than this aren't claimed
+    pub min_idle: Duration,
+    pub delivery_time: SystemTime,
+    /// The new delivery count, rather than one more
+    pub retry_count: Option<u64>,
+    /// Whether entries of the stream that aren't pending are claimed too
+    pub force: bool,
+    /// Whether the delivery count is left as is
+    pub justid: bool,
+}
+
+/// A pending entry claimed by XCLAIM or XAUTOCLAIM, with its fields. The
+/// fields are `None` if the entry was deleted, and then it was removed from
+/// the PEL rather than claimed.
+pub type Claimed = (EntryId, PendingEntry, Option<Vec<Entry>>);
+
+// Claims `entryid` of `group` for `consumer`, if it's pending and idle for
+// long enough
+fn claim_entry(
+    group: &mut ConsumerGroup,
+    entries: &BTreeMap<EntryId, Vec<Entry>>,
+    entryid: &EntryId,
+    consumer: &str,
+    options: &ClaimOptions,
+    now: SystemTime,
+) -> Option<Claimed> {
+    let fields = entries.get(entryid).cloned();
+    match group.pending.get(entryid) {
+        Some(pending)
+            if now
+                .duration_since(pending.delivery_time)
+                .unwrap_or_default()
+                < options.min_idle =>
+        {
+            return None
+        }
+        Some(_) if fields.is_none() => {
+            let pending = group.pending.remove(entryid)?;
+            return Some((entryid.clone(), pending, None));
+        }
+        Some(_) => {}
+        // Entries forced into the PEL are claimed whatever the idle time
+        None if options.force && fields.is_some() => {
+            let pending = PendingEntry {
+                consumer: String::new(),
+                delivery_time: now,
+                delivery_count: 0,
+            };
+            group.pending.insert(entryid.clone(), pending);
+        }
+        None => return None,
+    }
+
+    let pending = group.pending.get_mut(entryid)?;
+    pending.consumer = consumer.to_string();
+    pending.delivery_time = options.delivery_time;
+    match options.retry_count {
+        Some(retry_count) => pending.delivery_count = retry_count,
+        None if !options.justid => pending.delivery_count += 1,
+        None => {}
+    }
+    group.consumers.insert(consumer.to_string());
+    Some((entryid.clone(), pending.clone(), fields))
+}
+
+/// An entry read by a consumer group. The fields are `None` if the entry was
+/// deleted after being delivered.
+pub type GroupEntry = (EntryId, Option<Vec<Entry>>);
+
+#[derive(Clone, Debug, Default)]
 pub struct Stream {
     entries: BTreeMap<EntryId, Vec<Entry>>,
-    subscribers: BTreeMap<EntryId, Sender<()>>,
+    // The largest id ever appended. Deleting entries doesn't lower it, so ids
+    // are never reused.
+    last_id: EntryId,
+    max_deleted_id: EntryId,
+    // Number of entries ever appended
+    entries_added: u64,
+    groups: BTreeMap<String, ConsumerGroup>,
 }
 
 impl Stream {
     pub fn new() -> Self {
-        Self {
-            entries: BTreeMap::new(),
-            subscribers: BTreeMap::new(),
-        }
+        Self::default()
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
     pub fn append(&mut self, entry_id: EntryId, entries: Vec<Entry>) -> Result<()> {
@@ -146,22 +366,8 @@ impl Stream {
         }
 
         self.entries.insert(entry_id.clone(), entries);
-
-        // Notify subscribers, if any
-        let subscribers = self
-            .subscribers
-            .range((Unbounded, Excluded(entry_id.clone())))
-            .map(|(e, tx)| {
-                tx.send(()).unwrap();
-                e.clone()
-            })
-            .collect::<Vec<_>>();
-
-        for entryid in subscribers {
-            self.subscribers.remove(&entryid);
-        }
-
-        self.subscribers.remove(&entry_id);
+        self.last_id = entry_id.clone();
+        self.entries_added += 1;
 
         Ok(())
     }
@@ -170,26 +376,304 @@ impl Stream {
         &self,
         start: Bound<EntryId>,
         end: Bound<EntryId>,
+        count: Option<usize>,
     ) -> Result<Vec<(EntryId, Vec<Entry>)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
         Ok(self
             .entries
             .range((start, end))
+            .take(count.unwrap_or(usize::MAX))
             .map(|(entryid, entry)| (entryid.clone(), entry.clone()))
             .collect())
     }
 
-    pub fn max_entry_id(&self) -> EntryId {
+    /// Like `range`, but from `end` down to `start`, returning at most `count`
+    /// entries
+    pub fn rev_range(
+        &self,
+        start: Bound<EntryId>,
+        end: Bound<EntryId>,
+        count: Option<usize>,
+    ) -> Vec<(EntryId, Vec<Entry>)> {
+        if is_empty_range(&start, &end) {
+            return Vec::new();
+        }
         self.entries
+            .range((start, end))
+            .rev()
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(entryid, entry)| (entryid.clone(), entry.clone()))
+            .collect()
+    }
+
+    /// Removes the entries with the given ids, returning how many existed
+    pub fn remove(&mut self, entry_ids: &[EntryId]) -> usize {
+        let mut removed = 0;
+        for entryid in entry_ids {
+            if self.entries.remove(entryid).is_some() {
+                self.max_deleted_id = self.max_deleted_id.clone().max(entryid.clone());
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Evicts the oldest entries as `trim` says, but no more than `limit` if
+    /// given. Returns how many were evicted.
+    pub fn trim(&mut self, trim: &Trim, limit: Option<usize>) -> usize {
+        let mut evicted = 0;
+        while limit.is_none_or(|limit| evicted < limit) {
+            let should_evict = match (trim, self.entries.first_key_value()) {
+                (_, None) => false,
+                (Trim::MaxLen(maxlen), _) => self.entries.len() > *maxlen,
+                (Trim::MinId(min_id), Some((entryid, _))) => entryid < min_id,
+            };
+            if !should_evict {
+                break;
+            }
+            let (entryid, _) = self.entries.pop_first().unwrap();
+            self.max_deleted_id = self.max_deleted_id.clone().max(entryid);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Sets the last generated id, and optionally the counters XINFO reports,
+    /// for XSETID. The id can't be below the last entry.
+    pub fn set_id(
+        &mut self,
+        last_id: EntryId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<EntryId>,
+    ) -> Result<()> {
+        if self
+            .entries
+            .last_key_value()
+            .is_some_and(|(entryid, _)| *entryid > last_id)
+        {
+            bail!(SETID_SMALLER_ERR_MSG);
+        }
+        if entries_added.is_some_and(|entries_added| entries_added < self.entries.len() as u64) {
+            bail!(SETID_ENTRIES_ADDED_ERR_MSG);
+        }
+        if max_deleted_id
+            .as_ref()
+            .is_some_and(|max_deleted_id| *max_deleted_id > last_id)
+        {
+            bail!(SETID_MAX_DELETED_ERR_MSG);
+        }
+
+        self.last_id = last_id;
+        if let Some(entries_added) = entries_added {
+            self.entries_added = entries_added;
+        }
+        if let Some(max_deleted_id) = max_deleted_id {
+            self.max_deleted_id = max_deleted_id;
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> StreamInfo {
+        let radix_tree_keys = self.entries.len().div_ceil(STREAM_NODE_MAX_ENTRIES);
+        let entry =
+            |(entryid, entries): (&EntryId, &Vec<Entry>)| (entryid.clone(), entries.clone());
+        StreamInfo {
+            length: self.entries.len(),
+            radix_tree_keys,
+            // Approximated as a root with a child per key
+            radix_tree_nodes: radix_tree_keys + 1,
+            last_generated_id: self.last_id.clone(),
+            max_deleted_entry_id: self.max_deleted_id.clone(),
+            entries_added: self.entries_added,
+            groups: self.groups.len(),
+            first_entry: self.entries.first_key_value().map(entry),
+            last_entry: self.entries.last_key_value().map(entry),
+        }
+    }
+
+    /// Creates a group that delivers the entries after `last_delivered_id`.
+    /// Returns false if the group already exists.
+    pub fn create_group(&mut self, name: String, last_delivered_id: EntryId) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let group = ConsumerGroup {
+            last_delivered_id,
+            consumers: BTreeSet::new(),
+            pending: BTreeMap::new(),
+        };
+        self.groups.insert(name, group);
+        true
+    }
+
+    /// Removes a group, returning whether it existed
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Adds `consumer` to `group`. Returns whether it was added, or `None` if
+    /// there's no such group.
+    pub fn create_consumer(&mut self, group: &str, consumer: &str) -> Option<bool> {
+        let group = self.groups.get_mut(group)?;
+        Some(group.consumers.insert(consumer.to_string()))
+    }
+
+    /// Removes `consumer` from `group` along with its pending entries.
+    /// Returns how many entries were pending, if the consumer existed, or
+    /// `None` if there's no such group.
+    pub fn delete_consumer(&mut self, group: &str, consumer: &str) -> Option<Option<usize>> {
+        let group = self.groups.get_mut(group)?;
+        if !group.consumers.remove(consumer) {
+            return Some(None);
+        }
+        let num_pending = group.pending.len();
+        group
+            .pending
+            .retain(|_, pending| pending.consumer != consumer);
+        Some(Some(num_pending - group.pending.len()))
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&String, &ConsumerGroup)> {
+        self.groups.iter()
+    }
+
+    /// Reads up to `count` entries for `consumer` of `group`, or returns
+    /// `None` if there's no such group. Without `after`, the entries never
+    /// delivered to the group are read and, unless `noack`, added to the
+    /// PEL. With `after`, the entries after it that are pending for the
+    /// consumer are delivered again, which counts as another delivery.
+    pub fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: Option<EntryId>,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Option<Vec<GroupEntry>> {
+        let group = self.groups.get_mut(group)?;
+        group.consumers.insert(consumer.to_string());
+        let count = count.unwrap_or(usize::MAX);
+        let now = SystemTime::now();
+
+        let Some(after) = after else {
+            let read: Vec<GroupEntry> = self
+                .entries
+                .range((Excluded(group.last_delivered_id.clone()), Unbounded))
+                .take(count)
+                .map(|(entryid, entry)| (entryid.clone(), Some(entry.clone())))
+                .collect();
+
+            for (entryid, _) in read.iter() {
+                group.last_delivered_id = entryid.clone();
+                if !noack {
+                    let pending = PendingEntry {
+                        consumer: consumer.to_string(),
+                        delivery_time: now,
+                        delivery_count: 1,
+                    };
+                    group.pending.insert(entryid.clone(), pending);
+                }
+            }
+            return Some(read);
+        };
+
+        Some(
+            group
+                .pending
+                .range_mut((Excluded(after), Unbounded))
+                .filter(|(_, pending)| pending.consumer == consumer)
+                .take(count)
+                .map(|(entryid, pending)| {
+                    pending.delivery_time = now;
+                    pending.delivery_count += 1;
+                    (entryid.clone(), self.entries.get(entryid).cloned())
+                })
+                .collect(),
+        )
+    }
+
+    /// Gives the pending entries `entry_ids` of `group` to `consumer`, or
+    /// returns `None` if there's no such group. Entries are skipped if they
+    /// aren't pending, unless forced, or idle for less than the minimum.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        entry_ids: &[EntryId],
+        options: &ClaimOptions,
+        now: SystemTime,
+    ) -> Option<Vec<Claimed>> {
+        let group = self.groups.get_mut(group)?;
+        Some(
+            entry_ids
+                .iter()
+                .filter_map(|entryid| {
+                    claim_entry(group, &self.entries, entryid, consumer, options, now)
+                })
+                .collect(),
+        )
+    }
+
+    /// Claims the entries of the PEL of `group` from `start` on, like
+    /// `claim`, until `count` are claimed or deleted, looking at no more
+    /// than 10 times as many. Also returns the id to continue from, which is
+    /// 0-0 once the end of the PEL is reached.
+    pub fn auto_claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        start: Bound<EntryId>,
+        count: usize,
+        options: &ClaimOptions,
+        now: SystemTime,
+    ) -> Option<(EntryId, Vec<Claimed>)> {
+        let group = self.groups.get_mut(group)?;
+        let attempts = count.saturating_mul(10);
+        // One more, to know where to continue from
+        let entry_ids: Vec<EntryId> = group
+            .pending
+            .range((start, Unbounded))
+            .take(attempts.saturating_add(1))
+            .map(|(entryid, _)| entryid.clone())
+            .collect();
+
+        let mut claimed = Vec::new();
+        for (i, entryid) in entry_ids.iter().enumerate() {
+            if claimed.len() == count || i == attempts {
+                return Some((entryid.clone(), claimed));
+            }
+            claimed.extend(claim_entry(
+                group,
+                &self.entries,
+                entryid,
+                consumer,
+                options,
+                now,
+            ));
+        }
+        Some((EntryId::default(), claimed))
+    }
+
+    /// Removes the given entries from the PEL of `group`, returning how many
+    /// were pending
+    pub fn ack(&mut self, group: &str, entry_ids: &[EntryId]) -> usize {
+        let Some(group) = self.groups.get_mut(group) else {
+            return 0;
+        };
+        entry_ids
             .iter()
-            .max_by_key(|e| e.0)
-            .map(|v| v.0.clone())
-            .unwrap_or(EntryId { ms: 0, seq: 0 })
+            .filter(|entryid| group.pending.remove(entryid).is_some())
+            .count()
     }
 
-    pub fn subscribe_entries_after(&mut self, entryid: EntryId) -> Receiver<()> {
-        let (rx, tx) = unbounded();
-        self.subscribers.insert(entryid, rx);
-        tx
+    pub fn max_entry_id(&self) -> EntryId {
+        self.last_id.clone()
     }
 }
 
@@ -203,4 +687,221 @@ mod tests {
         let x = EntryId { ms: 1, seq: 0 };
         assert!(min < x);
     }
+
+    #[test]
+    fn malformed_ids() {
+        let curr_max = EntryId::default();
+        for s in ["notanid", "1-x", "x-1", "1-2-3", "-1", "", "1-", "*-1"] {
+            let err = EntryId::create(s.into(), &curr_max).unwrap_err();
+            assert_eq!(err.to_string(), INVALID_ID_ERR_MSG, "{}", s);
+        }
+        for s in ["notanid", "1-x", "(x", "1.5"] {
+            let err = EntryId::start_bound(s.into()).unwrap_err();
+            assert_eq!(err.to_string(), INVALID_ID_ERR_MSG, "{}", s);
+            let err = EntryId::end_bound(s.into()).unwrap_err();
+            assert_eq!(err.to_string(), INVALID_ID_ERR_MSG, "{}", s);
+        }
+    }
+
+    fn id(ms: u64) -> EntryId {
+        EntryId { ms, seq: 0 }
+    }
+
+    #[test]
+    fn wildcard_ids_increase() {
+        // With the clock frozen, ids go on within the millisecond
+        let mut stream = Stream::new();
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let entryid = EntryId::create_at("*".into(), &stream.max_entry_id(), 1000).unwrap();
+            stream.append(entryid.clone(), Vec::new()).unwrap();
+            ids.push(entryid.to_string());
+        }
+        assert_eq!(ids, ["1000-0", "1000-1", "1000-2", "1000-3", "1000-4"]);
+
+        // The clock moving on starts a new millisecond
+        let curr_max = EntryId { ms: 1000, seq: 4 };
+        assert_eq!(
+            EntryId::create_at("*".into(), &curr_max, 1001).unwrap(),
+            EntryId { ms: 1001, seq: 0 }
+        );
+        // and it going back doesn't make ids decrease
+        assert_eq!(
+            EntryId::create_at("*".into(), &curr_max, 10).unwrap(),
+            EntryId { ms: 1000, seq: 5 }
+        );
+        // Nor does an exhausted sequence
+        let curr_max = EntryId {
+            ms: 1000,
+            seq: u64::MAX,
+        };
+        assert_eq!(
+            EntryId::create_at("*".into(), &curr_max, 1000).unwrap(),
+            EntryId { ms: 1001, seq: 0 }
+        );
+        assert_eq!(
+            EntryId::create_at("1000-*".into(), &curr_max, 1000).unwrap(),
+            curr_max
+        );
+    }
+
+    fn stream(n: u64) -> Stream {
+        let mut stream = Stream::new();
+        for ms in 1..=n {
+            stream.append(id(ms), Vec::new()).unwrap();
+        }
+        stream
+    }
+
+    fn ids(entries: Vec<(EntryId, Vec<Entry>)>) -> Vec<EntryId> {
+        entries.into_iter().map(|(entryid, _)| entryid).collect()
+    }
+
+    #[test]
+    fn rev_range() {
+        let stream = stream(5);
+        assert_eq!(
+            ids(stream.rev_range(Bound::Included(id(2)), Unbounded, None)),
+            vec![id(5), id(4), id(3), id(2)]
+        );
+        assert_eq!(
+            ids(stream.rev_range(Unbounded, Unbounded, Some(2))),
+            vec![id(5), id(4)]
+        );
+        assert!(stream
+            .rev_range(Bound::Included(id(3)), Bound::Included(id(2)), None)
+            .is_empty());
+        assert!(stream
+            .range(Excluded(id(3)), Excluded(id(3)), None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn remove_and_trim() {
+        let mut stream = stream(5);
+        assert_eq!(stream.remove(&[id(2), id(9), id(2)]), 1);
+        assert_eq!(stream.len(), 4);
+
+        assert_eq!(stream.trim(&Trim::MaxLen(2), None), 2);
+        assert_eq!(
+            ids(stream.range(Unbounded, Unbounded, None).unwrap()),
+            vec![id(4), id(5)]
+        );
+        assert_eq!(stream.trim(&Trim::MaxLen(10), None), 0);
+        assert_eq!(stream.remove(&[id(4), id(5)]), 2);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn trim_by_min_id_and_limit() {
+        let mut stream = stream(5);
+        // Entries with the minimum id are kept
+        assert_eq!(stream.trim(&Trim::MinId(id(3)), Some(1)), 1);
+        assert_eq!(stream.trim(&Trim::MinId(id(3)), None), 1);
+        assert_eq!(
+            ids(stream.range(Unbounded, Unbounded, None).unwrap()),
+            vec![id(3), id(4), id(5)]
+        );
+        assert_eq!(
+            stream.trim(&Trim::MinId(EntryId { ms: 3, seq: 1 }), None),
+            1
+        );
+        assert_eq!(stream.trim(&Trim::MaxLen(0), Some(1)), 1);
+        assert_eq!(stream.trim(&Trim::MinId(id(9)), None), 1);
+        assert!(stream.is_empty());
+        assert_eq!(stream.info().max_deleted_entry_id, id(5));
+    }
+
+    #[test]
+    fn removing_top_entry_keeps_max_id() {
+        let mut stream = stream(3);
+        stream.remove(&[id(3)]);
+        assert_eq!(stream.max_entry_id(), id(3));
+        assert!(stream.append(id(3), Vec::new()).is_err());
+        assert!(stream.append(id(4), Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn consumer_group() {
+        let mut stream = stream(3);
+        assert!(stream.create_group("g".into(), id(1)));
+        assert!(!stream.create_group("g".into(), id(0)));
+        assert_eq!(stream.read_group("missing", "c", None, None, false), None);
+
+        let read = stream
+            .read_group("g", "alice", None, Some(1), false)
+            .unwrap();
+        assert_eq!(read[0].0, id(2));
+        let read = stream.read_group("g", "bob", None, None, false).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].0, id(3));
+        assert_eq!(
+            stream.read_group("g", "bob", None, None, false),
+            Some(Vec::new())
+        );
+
+        let group = stream.group("g").unwrap();
+        assert_eq!(group.last_delivered_id(), &id(3));
+        assert_eq!(group.num_consumers(), 2);
+        assert_eq!(group.pending()[&id(2)].consumer, "alice");
+
+        // History is the consumer's pending entries, including deleted ones
+        stream.remove(&[id(2)]);
+        assert_eq!(
+            stream.read_group("g", "alice", Some(id(0)), None, false),
+            Some(vec![(id(2), None)])
+        );
+        // which were delivered again
+        assert_eq!(
+            stream.group("g").unwrap().pending()[&id(2)].delivery_count,
+            2
+        );
+        assert_eq!(
+            stream.group("g").unwrap().pending()[&id(3)].delivery_count,
+            1
+        );
+
+        assert_eq!(stream.ack("g", &[id(2), id(9)]), 1);
+        assert_eq!(stream.ack("missing", &[id(3)]), 0);
+        assert_eq!(
+            stream
+                .group("g")
+                .unwrap()
+                .pending()
+                .keys()
+                .collect::<Vec<_>>(),
+            [&id(3)]
+        );
+    }
+
+    #[test]
+    fn read_group_noack() {
+        let mut stream = stream(2);
+        stream.create_group("g".into(), id(0));
+        let read = stream.read_group("g", "c", None, None, true).unwrap();
+        assert_eq!(read.len(), 2);
+        assert!(stream.group("g").unwrap().pending().is_empty());
+        assert_eq!(stream.group("g").unwrap().last_delivered_id(), &id(2));
+    }
+
+    #[test]
+    fn info() {
+        let mut stream = stream(4);
+        stream.remove(&[id(2)]);
+        stream.trim(&Trim::MaxLen(2), None);
+
+        let info = stream.info();
+        assert_eq!(info.length, 2);
+        assert_eq!(info.radix_tree_keys, 1);
+        assert_eq!(info.last_generated_id, id(4));
+        assert_eq!(info.max_deleted_entry_id, id(2));
+        assert_eq!(info.entries_added, 4);
+        assert_eq!(info.first_entry.unwrap().0, id(3));
+        assert_eq!(info.last_entry.unwrap().0, id(4));
+
+        let info = Stream::new().info();
+        assert_eq!((info.length, info.radix_tree_keys), (0, 0));
+        assert!(info.first_entry.is_none());
+    }
 }
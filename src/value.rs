@@ -1,15 +1,110 @@
+use crate::hash::Hash;
+use crate::scan::ScanSet;
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use anyhow::{bail, Result};
+use std::fmt;
+
+pub const WRONG_TYPE_ERR_MSG: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 #[derive(Clone, Debug)]
 pub enum Value {
-    String(String),
+    /// Raw bytes, which don't have to be UTF-8
+    String(Vec<u8>),
+    Hash(Hash),
+    Set(ScanSet),
+    SortedSet(SortedSet),
+    Stream(Stream),
 }
 
 impl Value {
     pub fn type_string(&self) -> String {
-        "string".into()
+        match self {
+            Self::String(_) => "string".into(),
+            Self::Hash(_) => "hash".into(),
+            Self::Set(_) => "set".into(),
+            Self::SortedSet(_) => "zset".into(),
+            Self::Stream(_) => "stream".into(),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Vec<u8>> {
+        match self {
+            Self::String(s) => Ok(s),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Vec<u8>> {
+        match self {
+            Self::String(s) => Ok(s),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&Hash> {
+        match self {
+            Self::Hash(hash) => Ok(hash),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut Hash> {
+        match self {
+            Self::Hash(hash) => Ok(hash),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&ScanSet> {
+        match self {
+            Self::Set(set) => Ok(set),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
     }
 
-    pub fn to_string(&self) -> String {
-        let Self::String(s) = self;
-        s.clone()
+    pub fn as_set_mut(&mut self) -> Result<&mut ScanSet> {
+        match self {
+            Self::Set(set) => Ok(set),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Result<&SortedSet> {
+        match self {
+            Self::SortedSet(zset) => Ok(zset),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Result<&mut SortedSet> {
+        match self {
+            Self::SortedSet(zset) => Ok(zset),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_stream(&self) -> Result<&Stream> {
+        match self {
+            Self::Stream(stream) => Ok(stream),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+
+    pub fn as_stream_mut(&mut self) -> Result<&mut Stream> {
+        match self {
+            Self::Stream(stream) => Ok(stream),
+            _ => bail!(WRONG_TYPE_ERR_MSG),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            v => write!(f, "{:?}", v),
+        }
     }
 }
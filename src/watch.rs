@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+/// A key in a database
+pub type DbKey = (usize, String);
+
+/// Keys watched by connections with WATCH, for optimistic locking. When a
+/// watched key is modified, the connections watching it become dirty, and
+/// their next EXEC fails.
+#[derive(Default)]
+pub struct Watches {
+    // Key -> ids of the connections watching it
+    keys: HashMap<DbKey, HashSet<usize>>,
+    dirty: HashSet<usize>,
+}
+
+impl Watches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn watch(&mut self, id: usize, key: DbKey) {
+        self.keys.entry(key).or_default().insert(id);
+    }
+
+    /// Stops watching `keys`, which are the keys the connection watched, and
+    /// clears the connection's dirty flag
+    pub fn unwatch<'a>(&mut self, id: usize, keys: impl IntoIterator<Item = &'a DbKey>) {
+        for key in keys {
+            if let Some(ids) = self.keys.get_mut(key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.keys.remove(key);
+                }
+            }
+        }
+        self.dirty.remove(&id);
+    }
+
+    /// Marks the connections watching `key` in `db` as dirty
+    pub fn touch(&mut self, db: usize, key: &str) {
+        if let Some(ids) = self.keys.get(&(db, key.to_string())) {
+            self.dirty.extend(ids);
+        }
+    }
+
+    pub fn is_dirty(&self, id: usize) -> bool {
+        self.dirty.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touch_marks_watchers_dirty() {
+        let mut watches = Watches::new();
+        watches.watch(1, (0, "a".into()));
+        watches.watch(2, (0, "a".into()));
+        watches.watch(2, (0, "b".into()));
+
+        watches.touch(0, "c");
+        // Same key in another database
+        watches.touch(1, "a");
+        assert!(!watches.is_dirty(1));
+        assert!(!watches.is_dirty(2));
+
+        watches.touch(0, "b");
+        assert!(!watches.is_dirty(1));
+        assert!(watches.is_dirty(2));
+
+        watches.touch(0, "a");
+        assert!(watches.is_dirty(1));
+    }
+
+    #[test]
+    fn unwatch() {
+        let mut watches = Watches::new();
+        let keys = vec![(0, "a".to_string()), (0, "b".to_string())];
+        for key in keys.iter() {
+            watches.watch(1, key.clone());
+        }
+        watches.touch(0, "a");
+
+        watches.unwatch(1, &keys);
+        assert!(!watches.is_dirty(1));
+        assert!(watches.keys.is_empty());
+
+        // Touching after unwatching doesn't make it dirty again
+        watches.touch(0, "a");
+        assert!(!watches.is_dirty(1));
+    }
+}